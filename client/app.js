@@ -12,6 +12,11 @@ const {h, render, Component} = window.preact;
 
 const SENTINEL_ERROR = -2100000000;
 const SENTINEL_NODATA = -2000000000;
+
+// header of persistent data from the server (must match reader.rs)
+const WIRE_MAGIC = 0x53;
+const WIRE_VERSION = 3;
+const WIRE_HEADER_LEN = 8;
 const TARGET_KINDS = [
     {
         name: 'tcpping',
@@ -20,6 +25,38 @@ const TARGET_KINDS = [
         valFormatter: function(val) {
             return (val / 1000).toFixed() + ' ms';
         }
+    },
+    {
+        name: 'icmpping',
+        prettyName: 'ICMP Ping',
+        addrsPrompt: 'Hosts to ping',
+        valFormatter: function(val) {
+            return (val / 1000).toFixed() + ' ms';
+        }
+    },
+    {
+        name: 'httpping',
+        prettyName: 'HTTP Ping',
+        addrsPrompt: 'URLs (http://host/path) to request',
+        valFormatter: function(val) {
+            return (val / 1000).toFixed() + ' ms';
+        }
+    },
+    {
+        name: 'dnsping',
+        prettyName: 'DNS Ping',
+        addrsPrompt: 'Hostnames (optionally hostname@resolver) to look up',
+        valFormatter: function(val) {
+            return (val / 1000).toFixed() + ' ms';
+        }
+    },
+    {
+        name: 'udpping',
+        prettyName: 'UDP Ping',
+        addrsPrompt: 'Addresses (host:port, optionally #hex-payload) to probe',
+        valFormatter: function(val) {
+            return (val / 1000).toFixed() + ' ms';
+        }
     }
     /*
     {
@@ -64,6 +101,28 @@ class SPSocket {
     }
 }
 
+/*
+ * Reads the given buffer (starting at the given byte offset) as the series of
+ * little-endian 32-bit integers the server sends, regardless of the byte order
+ * of this machine.
+ */
+function readWireInts(buf, offset) {
+    var view = new DataView(buf, offset);
+    var ints = new Int32Array(Math.floor(view.byteLength / 4));
+    for (let i = 0; i < ints.length; i++) {
+        ints[i] = view.getInt32(i * 4, true);
+    }
+    return ints;
+}
+
+/*
+ * Joins the two 32-bit integers (low word first) the server sends times as
+ * back into the time (in seconds from epoch).
+ */
+function wireTime(lo, hi) {
+    return hi * 4294967296 + (lo >>> 0);
+}
+
 /*
  * Performs an AJAX (XMLHttpRequest) request where
  *     - method is the HTTP verb to use (e.g. 'POST')
@@ -201,7 +260,11 @@ class Graph extends Component {
          */
         if (!this.graph.isZoomed()) {
             g.isZoomedIgnoreProgrammaticZoom = true;
-            g.labels = ['Time'].concat(this.props.options.addrs);
+            // show addrs by their labels (if they have them)
+            var addrLabels = this.props.options.labels || {};
+            g.labels = ['Time'].concat(this.props.options.addrs.map(function(addr) {
+                return addrLabels[addr] || addr;
+            }));
 
             var h = hoursBack(this.props.preset);
             g.dateWindow = h == 0 ? null : [h, this.props.data.slice(-1)[0][0]];
@@ -382,18 +445,26 @@ class Target extends Component {
         var leftTarget = hoursBack(hoursPreset);
         var leftLimit = this.state.leftLimit;
 
-        var elementLength = this.state.options.addrs.length + 1;
+        // each segment is a time (in two halves), the millis past it, and a value per addr
+        var segmentLength = this.state.options.addrs.length + 3;
         var nonce = this.state.options.nonce;
 
         // only hit the server for the data if we don't already have it in-browser
         if (leftTarget < leftLimit) {
             ajax('POST', '/api/target/' + this.props.kind.name, 'arraybuffer', function(res) {
                 if (nonce == this.state.options.nonce) {
-                    // read the response from the server as a Int32 Typed Array
-                    var raw = new Int32Array(res);
+                    // check the header preceding the data is one we understand
+                    var header = new Uint8Array(res, 0, WIRE_HEADER_LEN);
+                    if (header[0] != WIRE_MAGIC || header[1] != WIRE_VERSION) {
+                        console.log('Persistent data in unknown format (version ' + header[1] + ')!');
+                        return;
+                    }
+
+                    // read the rest of the response from the server as a Int32 Typed Array
+                    var raw = readWireInts(res, WIRE_HEADER_LEN);
 
                     // pre-allocate a large buffer array that will be assimilated into this.data
-                    var newData = new Array(Math.ceil(raw.length / elementLength));
+                    var newData = new Array(Math.ceil(raw.length / segmentLength));
                     let k = 0;
 
                     /*
@@ -401,10 +472,11 @@ class Target extends Component {
                      * segments, creating a new [time, datapoint1, datapoint2, ...]
                      * array for each segment and appending it to newData.
                      */
-                    for (let j = 0; j < raw.length; j += elementLength) {
-                        let arr = new Array(elementLength);
-                        for (let i = 0; i < arr.length; i++) {
-                            let n = raw[j + i];
+                    for (let j = 0; j < raw.length; j += segmentLength) {
+                        let arr = new Array(segmentLength - 2);
+                        arr[0] = wireTime(raw[j], raw[j + 1]) + raw[j + 2] / 1000;
+                        for (let i = 1; i < arr.length; i++) {
+                            let n = raw[j + i + 2];
                             arr[i] = n >= 0 ? n : null;
                         }
                         newData[k++] = arr;
@@ -621,9 +693,10 @@ class Target extends Component {
          * read in the actual data, converting it to Dygraph-friendly format,
          * and append it to this.data
          */
-        var arr = new Array(inArr.length);
-        for (let i = 0; i < arr.length; i++) {
-            let n = inArr[i];
+        var arr = new Array(inArr.length - 1);
+        arr[0] = wireTime(inArr[0], inArr[1]);
+        for (let i = 1; i < arr.length; i++) {
+            let n = inArr[i + 1];
             arr[i] = n >= 0 ? n : null;
         }
         this.data.push(arr);
@@ -650,7 +723,7 @@ class App extends Component {
     handleSocketMessage(message) {
         // on receiving a websockets message, read it as an Int32 Typed Array
         var buf = message.data;
-        var raw = new Int32Array(buf);
+        var raw = readWireInts(buf, 0);
 
         // separate the target kind and nonce from the actual data
         var kind_id = raw[0];
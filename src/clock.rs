@@ -0,0 +1,104 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Clocks for telling the time, so that whatever depends on the time (e.g. the
+ * workers, and the managers' notion of staleness) can be handed a clock that
+ * only moves when told to in tests, rather than the system's.
+ */
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use std::time::Duration;
+
+use time::precise_time_ns;
+
+use helpers::now_millis;
+#[cfg(test)]
+use helpers::recover;
+
+/**
+ * A source of the current time.
+ */
+pub trait Clock: Send + Sync {
+    /**
+     * Gets the current (wall clock) time in milliseconds from epoch.
+     */
+    fn now(&self) -> i64;
+
+    /**
+     * Gets the current time in nanoseconds from some arbitrary (but fixed)
+     * point, which never goes backward, for measuring how much time passed.
+     */
+    fn monotonic(&self) -> u64;
+}
+
+/**
+ * The system's clock, the default for everything.
+ */
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        now_millis()
+    }
+
+    fn monotonic(&self) -> u64 {
+        precise_time_ns()
+    }
+}
+
+/**
+ * A clock that starts at the given time (in milliseconds from epoch) and only
+ * moves forward when advanced, for tests.
+ */
+#[cfg(test)]
+pub struct ManualClock {
+    // the wall clock time (in millis) and monotonic time (in nanoseconds)
+    times: Mutex<(i64, u64)>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new(now: i64) -> Self {
+        ManualClock {
+            times: Mutex::new((now, 0)),
+        }
+    }
+
+    /**
+     * Moves this clock forward by the given duration.
+     */
+    pub fn advance(&self, by: Duration) {
+        let nanos = by.as_secs() * 1_000_000_000 + by.subsec_nanos() as u64;
+        let mut times = recover(self.times.lock());
+        times.0 += (nanos / 1_000_000) as i64;
+        times.1 += nanos;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> i64 {
+        recover(self.times.lock()).0
+    }
+
+    fn monotonic(&self) -> u64 {
+        recover(self.times.lock()).1
+    }
+}
+
+#[test]
+fn manual_clock_only_moves_when_advanced() {
+    let clock = ManualClock::new(1_000);
+    assert_eq!((clock.now(), clock.monotonic()), (1_000, 0));
+    assert_eq!((clock.now(), clock.monotonic()), (1_000, 0));
+
+    clock.advance(Duration::from_millis(1_500));
+    assert_eq!((clock.now(), clock.monotonic()), (2_500, 1_500_000_000));
+}
@@ -0,0 +1,243 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * A command server through which scripts can inspect and reconfigure a
+ * running stabping over a Unix domain socket (built with the
+ * `command-socket` feature).
+ *
+ * Each request is a JSON object on a line of its own, giving the `command`
+ * and the target `kind` (by compact name, e.g. `tcpping`) it's for, and is
+ * answered by a line of either `{"ok":<result>}` or `{"error":"<message>"}`.
+ * The commands are:
+ *
+ * - `get_options`: the result is the target's options
+ * - `set_options` (with `options`): replaces the target's options, which must
+ *   have the current nonce; the result is the new nonce
+ * - `add_addr` and `remove_addr` (with `addr`): adds or removes an addr; the
+ *   result is the new nonce
+ * - `rename_addr` (with `addr` and `new_addr`): renames an addr, keeping its
+ *   data; the result is the new nonce
+ * - `compact_index`: tombstones the addrs no longer in the options that have
+ *   no data left (see `TargetManager::compact_index()`); the result is the
+ *   addrs tombstoned
+ * - `query_range` (with `start` and `end` in seconds, and optionally the
+ *   `feed` id, raw data if not given): the result is the data in the range,
+ *   as an array of the integers the web API would send
+ */
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+
+use rustc_serialize::Decodable;
+use rustc_serialize::json::{self, Json};
+
+use helpers::VecFromWireBytes;
+use options::TargetOptions;
+use persist::{ManagerSet, Feed};
+
+/**
+ * Gets the given field of a request, or a message saying it's missing.
+ */
+fn field<'a>(request: &'a Json, name: &str) -> Result<&'a Json, String> {
+    request.find(name).ok_or_else(|| format!("Missing field '{}'", name))
+}
+
+fn string_field<'a>(request: &'a Json, name: &str) -> Result<&'a str, String> {
+    try!(field(request, name)).as_string().ok_or_else(|| format!("Field '{}' must be a string", name))
+}
+
+fn int_field(request: &Json, name: &str) -> Result<i64, String> {
+    try!(field(request, name)).as_i64().ok_or_else(|| format!("Field '{}' must be an integer", name))
+}
+
+/**
+ * Carries out a single request (a line of JSON), giving back its result
+ * encoded as JSON, or an error message.
+ */
+fn handle(targets: &ManagerSet, line: &str) -> Result<String, String> {
+    let request = try!(Json::from_str(line).map_err(|e| format!("Malformed request: {}", e)));
+    let command = try!(string_field(&request, "command"));
+    let kind = try!(string_field(&request, "kind"));
+    let tm = try!(
+        targets.iter().find(|tm| tm.kind.compact_name() == kind)
+        .ok_or_else(|| format!("No target kind '{}'", kind))
+    );
+
+    match command {
+        "get_options" => Ok(json::encode(&*tm.options_read()).unwrap()),
+        "set_options" => {
+            let mut decoder = json::Decoder::new(try!(field(&request, "options")).clone());
+            let new_options = try!(
+                TargetOptions::decode(&mut decoder)
+                .map_err(|e| format!("Malformed options: {}", e))
+            );
+            tm.options_replace(new_options).map(|n| n.to_string()).map_err(|e| e.to_string())
+        },
+        "add_addr" => {
+            let addr = try!(string_field(&request, "addr"));
+            tm.add_addr(addr).map(|n| n.to_string()).map_err(|e| e.to_string())
+        },
+        "remove_addr" => {
+            let addr = try!(string_field(&request, "addr"));
+            tm.remove_addr(addr).map(|n| n.to_string()).map_err(|e| e.to_string())
+        },
+        "rename_addr" => {
+            let addr = try!(string_field(&request, "addr"));
+            let new_addr = try!(string_field(&request, "new_addr"));
+            tm.rename_addr(addr, new_addr).map(|n| n.to_string()).map_err(|e| e.to_string())
+        },
+        "compact_index" => tm.compact_index().map(|a| json::encode(&a).unwrap()).map_err(|e| e.to_string()),
+        "query_range" => {
+            let start = try!(int_field(&request, "start"));
+            let end = try!(int_field(&request, "end"));
+            let feed_id = match request.find("feed") {
+                Some(_) => try!(int_field(&request, "feed")),
+                None => 0,
+            };
+            let feed = match feed_id {
+                0...255 => Feed::from_feed_id(feed_id as u8),
+                _ => None,
+            };
+            let feed = try!(feed.ok_or_else(|| format!("No feed {}", feed_id)));
+            let data = try!(tm.query_range(feed, start, end).map_err(|e| e.to_string()));
+            Ok(json::encode(&data.from_wire_bytes()).unwrap())
+        },
+        _ => Err(format!("Unknown command '{}'", command)),
+    }
+}
+
+/**
+ * Answers the requests of a single connection, one line at a time, until it
+ * is closed.
+ */
+fn serve(stream: UnixStream, targets: ManagerSet) {
+    let mut writer = match stream.try_clone() {
+        Ok(w) => w,
+        Err(e) => {
+            warn!("failed to set up command connection error=\"{}\"", e);
+            return;
+        }
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match handle(&targets, &line) {
+            Ok(result) => format!("{{\"ok\":{}}}", result),
+            Err(message) => {
+                debug!("command failed request={} error=\"{}\"", line, message);
+                format!("{{\"error\":{}}}", Json::String(message))
+            },
+        };
+        if writeln!(writer, "{}", response).is_err() {
+            break;
+        }
+    }
+}
+
+/**
+ * Creates and starts the command server (see the module documentation),
+ * listening on a Unix domain socket at the given path for commands for the
+ * given targets.
+ *
+ * A socket left behind at the path (by an earlier run) is replaced, but
+ * anything else there is left alone, failing to bind.
+ */
+pub fn command_server<P: AsRef<Path>>(path: P, targets: ManagerSet) -> io::Result<thread::JoinHandle<()>> {
+    let path = path.as_ref();
+    if fs::symlink_metadata(path).map(|m| m.file_type().is_socket()).unwrap_or(false) {
+        try!(fs::remove_file(path));
+    }
+    let listener = try!(UnixListener::bind(path));
+
+    info!("command server listening path={}", path.display());
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let targets = targets.clone();
+                    thread::spawn(move || serve(stream, targets));
+                },
+                Err(e) => warn!("failed to accept command connection error=\"{}\"", e),
+            }
+        }
+    }))
+}
+
+#[test]
+fn commands_are_answered_over_the_socket() {
+    use datafile::DataFileOptions;
+    use options::{TargetKind, TargetResults};
+    use persist::test_data_dir;
+
+    let data_path = test_data_dir("commands_are_answered_over_the_socket");
+    let kind = &TargetKind::all()[0];
+    let targets = ManagerSet::new(&TargetKind::all()[..1], &data_path, DataFileOptions::default(), false, false).unwrap();
+    let socket = data_path.join("command.sock");
+    command_server(&socket, targets.clone()).unwrap();
+
+    let mut stream = UnixStream::connect(&socket).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut ask = |request: &str| -> Json {
+        writeln!(stream, "{}", request).unwrap();
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        Json::from_str(&response).unwrap()
+    };
+
+    let options = ask(&format!("{{\"command\":\"get_options\",\"kind\":\"{}\"}}", kind.compact_name()));
+    let nonce = options.find_path(&["ok", "nonce"]).unwrap().as_i64().unwrap();
+
+    // addrs can be added and removed, changing the nonce each time
+    let added = ask(&format!("{{\"command\":\"add_addr\",\"kind\":\"{}\",\"addr\":\"example.com:80\"}}",
+                             kind.compact_name()));
+    assert_eq!(added.find("ok").unwrap().as_i64(), Some(nonce + 1));
+    assert!(targets.get(kind.kind_id()).unwrap().options_read().addrs.contains(&"example.com:80".to_owned()));
+    let renamed = ask(&format!("{{\"command\":\"rename_addr\",\"kind\":\"{}\",\"addr\":\"example.com:80\",\"new_addr\":\"example.org:80\"}}",
+                               kind.compact_name()));
+    assert_eq!(renamed.find("ok").unwrap().as_i64(), Some(nonce + 2));
+    let removed = ask(&format!("{{\"command\":\"remove_addr\",\"kind\":\"{}\",\"addr\":\"example.org:80\"}}",
+                               kind.compact_name()));
+    assert_eq!(removed.find("ok").unwrap().as_i64(), Some(nonce + 3));
+    let compacted = ask(&format!("{{\"command\":\"compact_index\",\"kind\":\"{}\"}}", kind.compact_name()));
+    assert_eq!(compacted.find("ok").unwrap(), &Json::from_str("[\"example.org:80\"]").unwrap());
+
+    // errors come back as the manager describes them
+    let removed_again = ask(&format!("{{\"command\":\"remove_addr\",\"kind\":\"{}\",\"addr\":\"example.com:80\"}}",
+                                     kind.compact_name()));
+    assert_eq!(removed_again.find("error").unwrap().as_string(), Some("Invalid address 'example.com:80'"));
+    let stale = ask(&format!("{{\"command\":\"set_options\",\"kind\":\"{}\",\"options\":{}}}",
+                             kind.compact_name(), options.find("ok").unwrap()));
+    assert_eq!(stale.find("error").unwrap().as_string(),
+               Some(&*format!("Nonce {} does not match the current nonce, refusing update", nonce)));
+
+    let tm = targets.get(kind.kind_id()).unwrap();
+    let num_addrs = tm.options_read().addrs.len();
+    let mut data = vec![kind.kind_id(), nonce as i32 + 3, 100, 0];
+    data.extend((0..num_addrs as i32).map(|i| i + 1));
+    tm.append_data(&TargetResults(data, Vec::new())).unwrap();
+    let queried = ask(&format!("{{\"command\":\"query_range\",\"kind\":\"{}\",\"start\":0,\"end\":200}}",
+                               kind.compact_name()));
+    let expected = tm.query_range(Feed::Raw, 0, 200).unwrap().from_wire_bytes();
+    assert!(!expected.is_empty());
+    assert_eq!(queried.find("ok").unwrap(), &Json::from_str(&json::encode(&expected).unwrap()).unwrap());
+
+    assert!(ask("{\"command\":\"reboot\",\"kind\":\"tcpping\"}").find("error").is_some());
+    assert!(ask("{\"command\":\"get_options\",\"kind\":\"nope\"}").find("error").is_some());
+    assert!(ask("not json").find("error").is_some());
+}
@@ -0,0 +1,1868 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * Data files of back-to-back fixed-size records (stored in time order), split
+ * into "segments" so that no single file grows beyond a maximum size (or so
+ * that each day's records are in a file of their own).
+ */
+use std::cmp;
+use std::mem;
+use std::slice;
+use std::fs;
+use std::fs::{OpenOptions, File};
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use chrono::{Datelike, Local, TimeZone};
+
+use helpers::{SPIOError, SPFile, VecIntoRawBytes, VecFromRawBytes, crc32, time_from_words, overwrite_atomic};
+use options::SENTINEL_NODATA;
+use reader::with_mapped_records;
+
+/*
+ * data files (segments) with checksums or in a newer record layout begin with
+ * a header of these three bytes followed by a format version byte; those
+ * without (as all data files once were) have no header at all
+ */
+static HEADER_MAGIC: &'static [u8] = b"SPD";
+// version 1 headers mean checksums (and the original record layout)
+static VERSION_CHECKSUMMED: u8 = 1;
+const HEADER_LEN: usize = 4;
+/*
+ * version 2 headers are followed by a flags byte, a record layout byte, and
+ * two reserved bytes
+ */
+static VERSION_LAYOUT: u8 = 2;
+const HEADER_LEN_LAYOUT: usize = 8;
+static FLAG_CHECKSUMMED: u8 = 0x01;
+const CHECKSUM_LEN: usize = 4;
+
+/**
+ * Options for how data files are stored.
+ */
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DataFileOptions {
+    // size (in bytes) at which to split data files into segments, if any
+    pub max_size: Option<u64>,
+    // whether to store a CRC32 checksum after each record in new data files
+    pub checksums: bool,
+    // size (in bytes) of records to accumulate before writing them out at once
+    pub buffer_size: usize,
+    // whether to sync records to disk (`File::sync_data()`) as they are written out
+    pub sync: bool,
+    // whether to split data files by (local) day rather than by size
+    pub shard_by_day: bool,
+}
+
+/**
+ * A version of the layout of the records in a data file (see `DataFile`):
+ * the size of each record, and whether it begins with a 64-bit time (as two
+ * 32-bit integers, low word first) rather than a 32-bit one.
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Layout {
+    pub record_size: usize,
+    pub wide_time: bool,
+}
+
+/**
+ * How the records in a data file (segment) are laid out on-disk.
+ *
+ * `layout` is the version of the record layout (see `DataFile`), and
+ * `record_size` the size of records in that layout.
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Format {
+    checksummed: bool,
+    layout: u8,
+    record_size: usize,
+    wide_time: bool,
+}
+
+impl Format {
+    /**
+     * The format for new data files (segments), in the latest of the given
+     * record layouts.
+     */
+    fn fresh(checksummed: bool, layouts: &[Layout]) -> Format {
+        let latest = layouts[layouts.len() - 1];
+        Format {
+            checksummed: checksummed,
+            layout: (layouts.len() - 1) as u8,
+            record_size: latest.record_size,
+            wide_time: latest.wide_time,
+        }
+    }
+
+    /**
+     * Reads the format of the file at the given path (holding records in one
+     * of the given layouts) from its header, or `None` if the file is empty
+     * (and thus doesn't have a format yet).
+     */
+    fn of<'b>(file: &mut File, path: &'b Path, layouts: &[Layout]) -> Result<Option<Format>, SPIOError> {
+        let read_err = |_| SPIOError::Read(Some(path.to_owned()));
+        // a format from the future; better to refuse than misread it
+        let parse_err = || SPIOError::Parse(Some(path.to_owned()));
+
+        let len = try!(file.length_p(path));
+        if len == 0 {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; HEADER_LEN_LAYOUT];
+        let header_len = if len >= HEADER_LEN_LAYOUT as u64 { HEADER_LEN_LAYOUT } else { HEADER_LEN };
+        if len >= HEADER_LEN as u64 {
+            try!(file.seek(SeekFrom::Start(0)).map_err(&read_err));
+            try!(file.read_exact(&mut header[..header_len]).map_err(&read_err));
+        }
+
+        let (checksummed, layout) = if &header[..HEADER_MAGIC.len()] != HEADER_MAGIC {
+            (false, 0)
+        } else if header[3] == VERSION_CHECKSUMMED {
+            (true, 0)
+        } else if header[3] == VERSION_LAYOUT && header_len == HEADER_LEN_LAYOUT {
+            (header[4] & FLAG_CHECKSUMMED != 0, header[5])
+        } else {
+            return Err(parse_err());
+        };
+
+        match layouts.get(layout as usize) {
+            Some(l) => Ok(Some(Format {
+                checksummed: checksummed,
+                layout: layout,
+                record_size: l.record_size,
+                wide_time: l.wide_time,
+            })),
+            None => Err(parse_err()),
+        }
+    }
+
+    fn header_len(&self) -> usize {
+        if self.layout > 0 {
+            HEADER_LEN_LAYOUT
+        } else if self.checksummed {
+            HEADER_LEN
+        } else {
+            0
+        }
+    }
+
+    /**
+     * The on-disk size of each record.
+     */
+    fn stride(&self) -> usize {
+        if self.checksummed { self.record_size + CHECKSUM_LEN } else { self.record_size }
+    }
+
+    /**
+     * Encodes the given (whole) records' worth of bytes for appending to a
+     * data file of this format, including the header if the file is new.
+     */
+    fn encode(&self, bytes: &[u8], new_file: bool) -> Vec<u8> {
+        if self.header_len() == 0 {
+            return bytes.to_owned();
+        }
+
+        let mut out = Vec::with_capacity(self.header_len() + bytes.len() / self.record_size * self.stride());
+        if new_file {
+            out.extend_from_slice(HEADER_MAGIC);
+            if self.layout > 0 {
+                let flags = if self.checksummed { FLAG_CHECKSUMMED } else { 0 };
+                out.extend_from_slice(&[VERSION_LAYOUT, flags, self.layout, 0, 0]);
+            } else {
+                out.push(VERSION_CHECKSUMMED);
+            }
+        }
+        for record in bytes.chunks(self.record_size) {
+            out.extend_from_slice(record);
+            if self.checksummed {
+                let crc = crc32(record);
+                out.extend_from_slice(&[crc as u8, (crc >> 8) as u8, (crc >> 16) as u8, (crc >> 24) as u8]);
+            }
+        }
+        out
+    }
+
+    /**
+     * Whether records of this format can be used directly (e.g. `mmap`ed) as
+     * records of the given size (with a 64-bit time).
+     */
+    fn is_native(&self, size: usize) -> bool {
+        !self.checksummed && self.wide_time && self.record_size == size
+    }
+
+    /**
+     * The size of the time each record of this format begins with.
+     */
+    fn time_len(&self) -> usize {
+        if self.wide_time { 2 * mem::size_of::<i32>() } else { mem::size_of::<i32>() }
+    }
+
+    /**
+     * Decodes the given on-disk records (without the header) of this format
+     * into (back-to-back integers of) records of the given size (with a
+     * 64-bit time), verifying their checksums (if they have any).
+     *
+     * Records with a 32-bit time have it widened, and records in older
+     * (smaller) layouts are padded out with `SENTINEL_NODATA` values for the
+     * fields they don't have.
+     */
+    fn decode<'b>(&self, bytes: &[u8], size: usize, path: &'b Path) -> Result<Vec<i32>, SPIOError> {
+        let stride = self.stride();
+        // the widened time takes up more room than it did on-disk
+        let widening = 2 * mem::size_of::<i32>() - self.time_len();
+        if bytes.len() % stride != 0 || self.record_size + widening > size {
+            return Err(SPIOError::Parse(Some(path.to_owned())));
+        }
+
+        let mut record = vec![SENTINEL_NODATA; size / mem::size_of::<i32>()].into_raw_bytes();
+        let mut out = Vec::with_capacity(bytes.len() / stride * size);
+        for chunk in bytes.chunks(stride) {
+            if self.checksummed {
+                let c = &chunk[self.record_size..];
+                let stored = c[0] as u32 | (c[1] as u32) << 8 | (c[2] as u32) << 16 | (c[3] as u32) << 24;
+                if crc32(&chunk[..self.record_size]) != stored {
+                    return Err(SPIOError::Corrupt(Some(path.to_owned())));
+                }
+            }
+            let chunk = &chunk[..self.record_size];
+            if widening == 0 {
+                record[..self.record_size].copy_from_slice(chunk);
+            } else {
+                // sign-extend the time into the high word
+                let time = chunk[..4].from_raw_bytes()[0];
+                let high = if time < 0 { -1 } else { 0 };
+                record[..4].copy_from_slice(&chunk[..4]);
+                record[4..8].copy_from_slice(&vec![high].into_raw_bytes());
+                record[8..self.record_size + widening].copy_from_slice(&chunk[4..]);
+            }
+            out.extend_from_slice(&record);
+        }
+        Ok(out.from_raw_bytes())
+    }
+}
+
+/**
+ * A data file, backed by the file `<stem>.dat` currently being appended to,
+ * and any number of older segments `<stem>.<n>.dat` which were sealed (and
+ * are no longer appended to) once the current file reached the maximum size.
+ *
+ * If sharded by day (see `DataFileOptions`), each (local) day's records are
+ * instead in a file of their own, `<stem>.<YYYY-MM-DD>.dat`, the latest of
+ * which is the current file, so that files of past days are left untouched
+ * (and only need backing up once). A new day's file is started once a record
+ * falling on that day is written out; records that come in late for an
+ * earlier day go into the current file regardless, and are moved into place
+ * the next time the data file is compacted. Anything stored before sharding
+ * (in `<stem>.dat` and its segments) is read as older than all the per-day
+ * files, but per-day files aren't read at all if sharding is later turned
+ * off again.
+ *
+ * Records always begin with their time (as a 64-bit integer, or a 32-bit one
+ * in older layouts, which is widened when read). Each segment
+ * may or may not have checksums (see `DataFileOptions`), so data files
+ * written before checksums existed keep working.
+ *
+ * Records may also gain fields over time, so each data file has a list of the
+ * record sizes of each version of its record layout ("layouts"), of which
+ * new records are always in the latest. Each layout must only add fields to
+ * the end of the previous one, or widen the time (so older records can be
+ * padded out to newer ones when read).
+ *
+ * Appended records are buffered in memory until the buffer fills up or
+ * `flush()` is called, and are written out in one go. Buffered records are
+ * nonetheless visible to reads as if they were already on-disk.
+ *
+ * Records are meant to be appended in time order, as range reads rely on it.
+ * Any appended out of order are noted, and put back in order (see
+ * `compact()`) the next time the data file is pruned.
+ *
+ * Reads take `&self` and appends (and anything else that changes the file)
+ * `&mut self`, so a data file is meant to sit behind an `RwLock`: any number
+ * of readers at once, or a single writer. Reads always open handles of their
+ * own rather than using the one being appended to, so they never move its
+ * cursor, and only ever see whole records.
+ */
+pub struct DataFile {
+    stem: PathBuf,
+    layouts: Vec<Layout>,
+    record_size: usize,
+    options: DataFileOptions,
+    segments: Vec<(u32, PathBuf)>,
+    // the file being appended to, and the day it's for if sharded by day
+    current: PathBuf,
+    current_day: Option<u32>,
+    file: File,
+    format: Format,
+    buffer: Vec<u8>,
+    // time of the newest record appended, and whether any came before it
+    newest: Option<i64>,
+    out_of_order: bool,
+    // number of records (buffered ones included), once known
+    count: Option<u64>,
+}
+
+/**
+ * Appends the given suffix to the file name of the given path.
+ */
+fn with_suffix<'b>(path: &'b Path, suffix: &str) -> PathBuf {
+    let mut p = path.to_owned().into_os_string();
+    p.push(suffix);
+    PathBuf::from(p)
+}
+
+/**
+ * Gets the (local) day the given time (in seconds from epoch) falls on, as a
+ * number of the form `YYYYMMDD` (so that days sort in order).
+ */
+fn day_of(time: i64) -> u32 {
+    let date = Local.timestamp(time, 0).date();
+    date.year() as u32 * 10000 + date.month() * 100 + date.day()
+}
+
+/**
+ * Parses a day (see `day_of()`) as it appears in the names of per-day files,
+ * e.g. `2024-01-15`.
+ */
+fn parse_day(name: &str) -> Option<u32> {
+    let parts: Vec<&str> = name.split('-').collect();
+    if parts.len() != 3 || parts[0].len() != 4 || parts[1].len() != 2 || parts[2].len() != 2 {
+        return None;
+    }
+
+    let mut day = 0;
+    for part in parts {
+        if !part.chars().all(|c| c.is_digit(10)) {
+            return None;
+        }
+        day = day * 100 + part.parse::<u32>().unwrap();
+    }
+    Some(day)
+}
+
+/**
+ * Gets the path of the per-day file of the data file with the given stem for
+ * the given day (see `day_of()`).
+ */
+fn day_path<'b>(stem: &'b Path, day: u32) -> PathBuf {
+    with_suffix(stem, &format!(".{:04}-{:02}-{:02}.dat", day / 10000, day / 100 % 100, day % 100))
+}
+
+/**
+ * Drops the given number of bytes following the given position in the file at
+ * the given path, by copying what comes after them forward and truncating the
+ * rest.
+ */
+fn drop_bytes<'b>(path: &'b Path, at: u64, count: u64) -> Result<(), SPIOError> {
+    let write_err = |_| SPIOError::Write(Some(path.to_owned()));
+
+    /*
+     * any handle we already have on the file only ever appends, so open
+     * another one that we can write to anywhere in the file with
+     */
+    let mut writer = try!(File::open_from(OpenOptions::new().read(true).write(true), path));
+    let len = try!(writer.length_p(path));
+
+    // copy the data to keep forward in chunks
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut copied: u64 = 0;
+    while at + count + copied < len {
+        try!(writer.seek(SeekFrom::Start(at + count + copied)).map_err(&write_err));
+        let n = try!(writer.read(&mut buf).map_err(&write_err));
+        if n == 0 {
+            break;
+        }
+        try!(writer.seek(SeekFrom::Start(at + copied)).map_err(&write_err));
+        try!(writer.write_all(&buf[..n]).map_err(&write_err));
+        copied += n as u64;
+    }
+
+    try!(writer.set_len(at + copied).map_err(&write_err));
+    Ok(())
+}
+
+/**
+ * A data file segment opened for reading, along with its format.
+ */
+struct SegmentReader<'b> {
+    path: &'b Path,
+    file: File,
+    format: Format,
+    // number of records in the segment
+    len: u64,
+}
+
+impl<'b> SegmentReader<'b> {
+    /**
+     * Opens the segment at the given path for reading records in one of the
+     * given layouts, or `None` if the segment is empty.
+     *
+     * The segment gets a handle of its own (see `DataFile`), which is free to
+     * seek around.
+     */
+    fn open(path: &'b Path, layouts: &[Layout]) -> Result<Option<Self>, SPIOError> {
+        let mut file = try!(File::open_from(OpenOptions::new().read(true), path));
+        let format = match try!(Format::of(&mut file, path, layouts)) {
+            Some(f) => f,
+            None => return Ok(None),
+        };
+        let len = (try!(file.length_p(path)) - format.header_len() as u64)
+                  / format.stride() as u64;
+
+        Ok(Some(SegmentReader {
+            path: path,
+            file: file,
+            format: format,
+            len: len,
+        }))
+    }
+
+    /**
+     * Gets the on-disk position of the record at the given position.
+     */
+    fn offset(&self, pos: u64) -> u64 {
+        self.format.header_len() as u64 + pos * self.format.stride() as u64
+    }
+
+    /**
+     * Reads the time of the record at the given position.
+     */
+    fn time_at(&mut self, pos: u64) -> Result<i64, SPIOError> {
+        let path = self.path;
+        let read_err = |_| SPIOError::Read(Some(path.to_owned()));
+        let mut buf = [0u8; 8];
+        let time_len = self.format.time_len();
+        let offset = self.offset(pos);
+        try!(self.file.seek(SeekFrom::Start(offset)).map_err(&read_err));
+        try!(self.file.read_exact(&mut buf[..time_len]).map_err(&read_err));
+
+        let words = buf[..time_len].from_raw_bytes();
+        if self.format.wide_time {
+            Ok(time_from_words(words[0], words[1]))
+        } else {
+            Ok(words[0] as i64)
+        }
+    }
+
+    /**
+     * Finds the position of the first record whose time is at or after the
+     * given time, by binary searching (seeking around) the segment.
+     */
+    fn lower_bound(&mut self, time: i64) -> Result<u64, SPIOError> {
+        let mut lo = 0;
+        let mut hi = self.len;
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if try!(self.time_at(mid)) < time {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        Ok(lo)
+    }
+
+    /**
+     * Reads the records at positions `begin` up to `end` (as records of the
+     * given size).
+     */
+    fn read(&mut self, begin: u64, end: u64, size: usize) -> Result<Vec<i32>, SPIOError> {
+        let path = self.path;
+        let read_err = |_| SPIOError::Read(Some(path.to_owned()));
+        if begin >= end {
+            return Ok(Vec::new());
+        }
+
+        // the records are contiguous, so read them all in one go
+        let mut buf = vec![0u8; ((end - begin) as usize) * self.format.stride()];
+        let offset = self.offset(begin);
+        try!(self.file.seek(SeekFrom::Start(offset)).map_err(&read_err));
+        try!(self.file.read_exact(&mut buf).map_err(&read_err));
+        self.format.decode(&buf, size, self.path)
+    }
+}
+
+/**
+ * The most records `DataFile::iter()` reads at a time.
+ */
+static ITER_CHUNK_LEN: u64 = 4096;
+
+/**
+ * An iterator over the records of a data file in chunks (see
+ * `DataFile::iter()`), each read only as it's asked for, so that going
+ * through a data file of any size (e.g. to export it) only ever holds a
+ * chunk's worth of records in memory.
+ *
+ * Buffered records come last, in a chunk of their own. Iteration stops after
+ * the first error.
+ */
+pub struct RecordChunks<'a> {
+    data_file: &'a DataFile,
+    paths: Vec<PathBuf>,
+    chunk_len: u64,
+    // the segment being read, and the position of its next record
+    segment: usize,
+    pos: u64,
+    done: bool,
+}
+
+impl<'a> Iterator for RecordChunks<'a> {
+    type Item = Result<Vec<i32>, SPIOError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        while self.segment < self.paths.len() {
+            let chunk = match SegmentReader::open(&self.paths[self.segment], &self.data_file.layouts) {
+                Ok(Some(ref mut reader)) if self.pos < reader.len => {
+                    let end = cmp::min(self.pos + self.chunk_len, reader.len);
+                    let chunk = reader.read(self.pos, end, self.data_file.record_size);
+                    self.pos = end;
+                    chunk
+                },
+                Ok(_) => {
+                    self.segment += 1;
+                    self.pos = 0;
+                    continue;
+                },
+                Err(e) => Err(e),
+            };
+            self.done = chunk.is_err();
+            return Some(chunk);
+        }
+
+        self.done = true;
+        match self.data_file.buffered() {
+            Ok(ref buffered) if buffered.is_empty() => None,
+            buffered => Some(buffered),
+        }
+    }
+}
+
+impl DataFile {
+    /**
+     * Opens the data file with the given stem (path without the `.dat`
+     * extension) holding records in the given layouts (see `DataFile`),
+     * creating it if necessary.
+     */
+    pub fn open<'b>(stem: &'b Path, layouts: &[Layout],
+                    options: DataFileOptions) -> Result<Self, SPIOError> {
+        let mut segments = try!(DataFile::find_segments(stem));
+        let (path, current_day) = if options.shard_by_day {
+            // anything stored before sharding is older than all the per-day files
+            let unsharded = with_suffix(stem, ".dat");
+            if fs::metadata(&unsharded).map(|m| m.len() > 0).unwrap_or(false) {
+                let n = segments.last().map(|&(n, _)| n + 1).unwrap_or(0);
+                segments.push((n, unsharded));
+            }
+
+            // keep appending to the latest day's file (or start today's)
+            let mut days = try!(DataFile::find_days(stem));
+            let (day, path) = days.pop().unwrap_or_else(|| {
+                let today = day_of(Local::now().timestamp());
+                (today, day_path(stem, today))
+            });
+            segments.extend(days);
+            (path, Some(day))
+        } else {
+            (with_suffix(stem, ".dat"), None)
+        };
+        let mut file = try!(File::open_from(OpenOptions::new().read(true).append(true).create(true),
+                                            &path));
+
+        // keep appending in the current file's format, whatever it may be
+        let fresh = Format::fresh(options.checksums, layouts);
+        let format = try!(Format::of(&mut file, &path, layouts));
+
+        let mut data_file = DataFile {
+            stem: stem.to_owned(),
+            layouts: layouts.to_owned(),
+            record_size: fresh.record_size,
+            options: options,
+            segments: segments,
+            current: path,
+            current_day: current_day,
+            file: file,
+            format: format.unwrap_or(fresh),
+            buffer: Vec::with_capacity(options.buffer_size),
+            newest: None,
+            out_of_order: false,
+            count: None,
+        };
+
+        /*
+         * ...unless its records are in an older layout, in which case seal it
+         * (or, as a day's file can't be sealed before the day is over,
+         * rewrite it in the latest layout)
+         */
+        if data_file.format.layout != fresh.layout {
+            if data_file.current_day.is_some() {
+                try!(data_file.upgrade_current());
+            } else {
+                try!(data_file.rotate());
+            }
+        }
+
+        // (records are only ever read back in a layout with a 64-bit time)
+        if fresh.wide_time {
+            let mut newest = None;
+            try!(data_file.read_backward(1, |r| {
+                newest = r.get(1).map(|_| time_from_words(r[0], r[1]));
+                false
+            }));
+            data_file.newest = newest;
+        }
+        Ok(data_file)
+    }
+
+    /**
+     * Finds the (sealed) segments of the data file with the given stem, in
+     * order from oldest to newest.
+     */
+    fn find_segments<'b>(stem: &'b Path) -> Result<Vec<(u32, PathBuf)>, SPIOError> {
+        // segments are named <stem>.<n>.dat
+        DataFile::find_files(stem, |n| n.parse::<u32>().ok())
+    }
+
+    /**
+     * Finds the per-day files of the data file with the given stem (see
+     * `DataFile`), in order from oldest to newest.
+     */
+    fn find_days<'b>(stem: &'b Path) -> Result<Vec<(u32, PathBuf)>, SPIOError> {
+        // per-day files are named <stem>.<YYYY-MM-DD>.dat
+        DataFile::find_files(stem, parse_day)
+    }
+
+    /**
+     * Finds the files named `<stem>.<x>.dat` for which `parse` gives a key for
+     * `x`, in order of their keys.
+     */
+    fn find_files<'b, F>(stem: &'b Path, parse: F) -> Result<Vec<(u32, PathBuf)>, SPIOError>
+            where F: Fn(&str) -> Option<u32> {
+        let dir = match stem.parent() {
+            Some(d) => d,
+            None => return Ok(Vec::new()),
+        };
+        let prefix = match stem.file_name().and_then(|n| n.to_str()) {
+            Some(n) => format!("{}.", n),
+            None => return Ok(Vec::new()),
+        };
+
+        let entries = try!(fs::read_dir(dir).map_err(|_| SPIOError::Read(Some(dir.to_owned()))));
+        let mut files = Vec::new();
+        for entry in entries {
+            let entry = try!(entry.map_err(|_| SPIOError::Read(Some(dir.to_owned()))));
+            let name = match entry.file_name().into_string() {
+                Ok(n) => n,
+                Err(_) => continue,
+            };
+
+            if name.starts_with(&prefix) && name.ends_with(".dat") && name.len() > prefix.len() + 4 {
+                if let Some(key) = parse(&name[prefix.len()..name.len() - 4]) {
+                    files.push((key, entry.path()));
+                }
+            }
+        }
+
+        files.sort();
+        Ok(files)
+    }
+
+    /**
+     * Gets the paths of all the segments of this data file, from oldest to
+     * newest (ending with the current one).
+     */
+    fn segment_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.segments.iter().map(|&(_, ref p)| p.clone()).collect();
+        paths.push(self.current.clone());
+        paths
+    }
+
+    /**
+     * Appends the given (whole) records' worth of bytes to this data file's
+     * buffer, writing the buffer out if it has filled up.
+     *
+     * Gives back the record number (counting records from the oldest in this
+     * data file, from 0) the first of the records was appended as, so that
+     * reading can later resume from there (see `read_from()`). Record numbers
+     * only hold until records are dropped or moved, i.e. until this data file
+     * is next pruned, compacted, cleared, or otherwise rewritten.
+     */
+    pub fn append(&mut self, bytes: &[u8]) -> Result<u64, SPIOError> {
+        let first = match self.count {
+            Some(c) => c,
+            None => try!(self.record_count()),
+        };
+        self.count = Some(first + (bytes.len() / self.record_size) as u64);
+
+        for record in bytes.chunks(self.record_size) {
+            let time = match self.record_time(record) {
+                Some(t) => t,
+                None => continue,
+            };
+            if self.newest.map_or(false, |newest| time < newest) {
+                self.out_of_order = true;
+            } else {
+                self.newest = Some(time);
+            }
+        }
+        self.buffer.extend_from_slice(bytes);
+        if self.buffer.len() >= self.options.buffer_size {
+            try!(self.flush());
+        }
+        Ok(first)
+    }
+
+    /**
+     * Gets the time of the given record (its bytes, as appended).
+     */
+    fn record_time(&self, record: &[u8]) -> Option<i64> {
+        let words = record.from_raw_bytes();
+        match (self.format.wide_time, words.len()) {
+            (true, n) if n >= 2 => Some(time_from_words(words[0], words[1])),
+            (false, n) if n >= 1 => Some(words[0] as i64),
+            _ => None,
+        }
+    }
+
+    /**
+     * Writes out all buffered records to this data file (in one write), first
+     * sealing the current segment if they would take it past the maximum size
+     * (or, if sharded by day, in one write to each day's file).
+     *
+     * As the buffer is written out whole, a segment can exceed the maximum
+     * size by up to the buffer size.
+     */
+    pub fn flush(&mut self) -> Result<(), SPIOError> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let buffer = mem::replace(&mut self.buffer, Vec::new());
+        let written = if self.current_day.is_some() {
+            self.write_by_day(&buffer)
+        } else {
+            self.write_by_size(&buffer)
+        };
+
+        // (hold on to records that couldn't be written out, to try again)
+        self.buffer = buffer;
+        if written.is_ok() {
+            self.buffer.clear();
+        }
+        written
+    }
+
+    /**
+     * Writes out the given records to the current file, first sealing it if
+     * they would take it past the maximum size.
+     */
+    fn write_by_size(&mut self, bytes: &[u8]) -> Result<(), SPIOError> {
+        if let Some(max_size) = self.options.max_size {
+            let len = try!(self.file.length_p(&self.current));
+            let encoded_len = self.format.encode(bytes, len == 0).len() as u64;
+            if len > 0 && len + encoded_len > max_size {
+                try!(self.rotate());
+            }
+        }
+        self.write_current(bytes)
+    }
+
+    /**
+     * Writes out the given records to the files of the days they fall on (see
+     * `DataFile`), starting a later day's file as soon as a record falls on
+     * that day.
+     */
+    fn write_by_day(&mut self, bytes: &[u8]) -> Result<(), SPIOError> {
+        let mut start = 0;
+        for (i, record) in bytes.chunks(self.record_size).enumerate() {
+            let day = match self.record_time(record) {
+                Some(t) => day_of(t),
+                None => continue,
+            };
+            let at = i * self.record_size;
+
+            /*
+             * an earlier day's file is only started if nothing has been
+             * written to the current one yet (as when it was just started for
+             * today on opening), and the day has no file already
+             */
+            let current = self.current_day.unwrap_or(0);
+            let switch = if day > current {
+                true
+            } else if day < current && start == at {
+                let path = day_path(&self.stem, day);
+                try!(self.file.length_p(&self.current)) == 0 && !self.segments.iter().any(|&(_, ref p)| *p == path)
+            } else {
+                false
+            };
+
+            if switch {
+                if at > start {
+                    try!(self.write_current(&bytes[start..at]));
+                }
+                try!(self.start_day(day));
+                start = at;
+            }
+        }
+
+        if start < bytes.len() {
+            try!(self.write_current(&bytes[start..]));
+        }
+        Ok(())
+    }
+
+    /**
+     * Writes out the given records to the current file (in one write).
+     */
+    fn write_current(&mut self, bytes: &[u8]) -> Result<(), SPIOError> {
+        let len = try!(self.file.length_p(&self.current));
+        let encoded = self.format.encode(bytes, len == 0);
+        try!(self.file.write_all(&encoded).map_err(|_| SPIOError::Write(Some(self.current.clone()))));
+        if self.options.sync {
+            try!(self.file.sync_data().map_err(|_| SPIOError::Write(Some(self.current.clone()))));
+        }
+        Ok(())
+    }
+
+    /**
+     * Seals the current file and starts appending to the (new) file of the
+     * given day, if sharded by day (see `DataFile`). A current file that is
+     * still empty is deleted rather than sealed.
+     */
+    fn start_day(&mut self, day: u32) -> Result<(), SPIOError> {
+        if try!(self.file.length_p(&self.current)) == 0 {
+            try!(fs::remove_file(&self.current).map_err(|_| SPIOError::Write(Some(self.current.clone()))));
+        } else {
+            self.segments.push((self.current_day.unwrap_or(0), self.current.clone()));
+        }
+
+        self.current = day_path(&self.stem, day);
+        self.current_day = Some(day);
+        self.file = try!(File::open_from(OpenOptions::new().read(true).append(true).create(true),
+                                         &self.current));
+        self.format = Format::fresh(self.options.checksums, &self.layouts);
+        Ok(())
+    }
+
+    /**
+     * Rewrites the current file in the latest layout, for when it can't
+     * simply be sealed (see `DataFile::open()`).
+     */
+    fn upgrade_current(&mut self) -> Result<(), SPIOError> {
+        let records = match try!(SegmentReader::open(&self.current, &self.layouts)) {
+            Some(mut reader) => {
+                let len = reader.len;
+                try!(reader.read(0, len, self.record_size))
+            },
+            None => Vec::new(),
+        };
+
+        let format = Format::fresh(self.options.checksums, &self.layouts);
+        try!(overwrite_atomic(&format.encode(&records.into_raw_bytes(), true), &self.current, self.options.sync));
+        self.file = try!(File::open_from(OpenOptions::new().read(true).append(true).create(true),
+                                         &self.current));
+        self.format = format;
+        Ok(())
+    }
+
+    /**
+     * Seals the current segment (as the newest `<stem>.<n>.dat`) and starts
+     * appending to a fresh one.
+     */
+    fn rotate(&mut self) -> Result<(), SPIOError> {
+        let n = self.segments.last().map(|&(n, _)| n + 1).unwrap_or(0);
+        let current = self.current.clone();
+        let sealed = with_suffix(&self.stem, &format!(".{}.dat", n));
+
+        try!(fs::rename(&current, &sealed).map_err(|_| SPIOError::Write(Some(sealed.clone()))));
+        self.segments.push((n, sealed));
+
+        self.file = try!(File::open_from(OpenOptions::new().read(true).append(true).create(true),
+                                         &current));
+        self.format = Format::fresh(self.options.checksums, &self.layouts);
+        Ok(())
+    }
+
+    /**
+     * Calls `f` with the records of each segment of this data file (as
+     * back-to-back integers of records in the latest layout) in turn, from
+     * oldest to newest.
+     *
+     * Segments without checksums (in the latest layout) are `mmap`ed and
+     * handed to `f` directly, while others are read (and verified, or padded
+     * out) in full first.
+     */
+    pub fn for_each_segment<F>(&self, mut f: F) -> Result<(), SPIOError>
+            where F: FnMut(&[i32]) {
+        let size = self.record_size;
+        for path in self.segment_paths() {
+            let mut file = try!(File::open_from(OpenOptions::new().read(true), &path));
+            let format = match try!(Format::of(&mut file, &path, &self.layouts)) {
+                Some(format) => format,
+                None => continue,
+            };
+
+            try!(
+                with_mapped_records(&file, |bytes: &[u8]| {
+                    let records = &bytes[format.header_len()..];
+                    if !format.is_native(size) {
+                        format.decode(records, size, &path).map(|r| f(&r))
+                    } else if records.len() % size != 0 {
+                        Err(SPIOError::Parse(Some(path.clone())))
+                    } else {
+                        f(unsafe {
+                            slice::from_raw_parts(records.as_ptr() as *const i32,
+                                                  records.len() / mem::size_of::<i32>())
+                        });
+                        Ok(())
+                    }
+                })
+                .map_err(|_| SPIOError::Read(Some(path.clone())))
+                .and_then(|r| r)
+            );
+        }
+
+        // buffered records come after everything on-disk
+        if !self.buffer.is_empty() {
+            f(&try!(self.buffered()));
+        }
+        Ok(())
+    }
+
+    /**
+     * Iterates over the records of this data file (as back-to-back integers
+     * of records in the latest layout) in chunks of at most `ITER_CHUNK_LEN`
+     * records, from oldest to newest (see `RecordChunks`).
+     */
+    pub fn iter(&self) -> RecordChunks {
+        self.iter_by(ITER_CHUNK_LEN)
+    }
+
+    fn iter_by(&self, chunk_len: u64) -> RecordChunks {
+        RecordChunks {
+            data_file: self,
+            paths: self.segment_paths(),
+            chunk_len: chunk_len,
+            segment: 0,
+            pos: 0,
+            done: false,
+        }
+    }
+
+    /**
+     * Reads the buffered (not yet written out) records.
+     */
+    fn buffered(&self) -> Result<Vec<i32>, SPIOError> {
+        Format::fresh(false, &self.layouts)
+            .decode(&self.buffer, self.record_size, &self.current)
+    }
+
+    /**
+     * Reads the records (as back-to-back integers of records in the latest
+     * layout) with times in the range `start` to `end` (inclusive) from this
+     * data file.
+     *
+     * As records are of a fixed size and stored in time order, the range is
+     * found by binary searching each segment by seeking to records, so only
+     * the records in range are actually read.
+     */
+    pub fn read_range(&self, start: i64, end: i64) -> Result<Vec<i32>, SPIOError> {
+        let mut out = Vec::new();
+        for path in self.segment_paths() {
+            if cfg!(feature = "mmap-reads") {
+                try!(self.map_segment_range(&path, start, end, &mut out));
+            } else {
+                try!(self.seek_segment_range(&path, start, end, &mut out));
+            }
+        }
+
+        // few records are ever buffered, so just check the times of them all
+        let buffered = try!(self.buffered());
+        for record in buffered.chunks(self.record_size / mem::size_of::<i32>()) {
+            let time = time_from_words(record[0], record[1]);
+            if time >= start && time <= end {
+                out.extend_from_slice(record);
+            }
+        }
+        Ok(out)
+    }
+
+    /**
+     * Reads the records of the segment at the given path with times in the
+     * range `start` to `end` (inclusive) onto the end of `out`, by binary
+     * searching the segment by seeking to records.
+     */
+    fn seek_segment_range<'b>(&self, path: &'b Path, start: i64, end: i64,
+                              out: &mut Vec<i32>) -> Result<(), SPIOError> {
+        if let Some(mut reader) = try!(SegmentReader::open(path, &self.layouts)) {
+            let begin = try!(reader.lower_bound(start));
+            let end = try!(reader.lower_bound(end.saturating_add(1)));
+            out.extend(try!(reader.read(begin, end, self.record_size)));
+        }
+        Ok(())
+    }
+
+    /**
+     * Reads the records of the segment at the given path with times in the
+     * range `start` to `end` (inclusive) onto the end of `out`, by mapping
+     * the segment into memory and binary searching its records in place.
+     * Segments whose records can't be used directly (see `Format`) are
+     * searched by seeking instead.
+     *
+     * Only whole records as of when the segment is mapped are searched, and
+     * as this data file is borrowed (i.e. read locked, see `DataFile`)
+     * meanwhile, the segment can't be appended to or rotated under the map.
+     */
+    fn map_segment_range<'b>(&self, path: &'b Path, start: i64, end: i64,
+                             out: &mut Vec<i32>) -> Result<(), SPIOError> {
+        let mut file = try!(File::open_from(OpenOptions::new().read(true), path));
+        let format = match try!(Format::of(&mut file, path, &self.layouts)) {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+        if !format.is_native(self.record_size) {
+            return self.seek_segment_range(path, start, end, out);
+        }
+
+        let ints_per_record = self.record_size / mem::size_of::<i32>();
+        with_mapped_records(&file, |bytes: &[u8]| {
+            let records = &bytes[format.header_len()..];
+            let whole = records.len() / self.record_size * self.record_size;
+            let ints = unsafe {
+                slice::from_raw_parts(records.as_ptr() as *const i32, whole / mem::size_of::<i32>())
+            };
+
+            // first record at or after the given time
+            let lower_bound = |time: i64| {
+                let (mut lo, mut hi) = (0, ints.len() / ints_per_record);
+                while lo < hi {
+                    let mid = lo + (hi - lo) / 2;
+                    let r = mid * ints_per_record;
+                    if time_from_words(ints[r], ints[r + 1]) < time {
+                        lo = mid + 1;
+                    } else {
+                        hi = mid;
+                    }
+                }
+                lo
+            };
+            let begin = lower_bound(start);
+            let end = lower_bound(end.saturating_add(1));
+            if begin < end {
+                out.extend_from_slice(&ints[begin * ints_per_record..end * ints_per_record]);
+            }
+        })
+        .map_err(|_| SPIOError::Read(Some(path.to_owned())))
+    }
+
+    /**
+     * Reads the records (as back-to-back integers of records in the latest
+     * layout) from the given record number (as given back by `append()`)
+     * onward, e.g. to pick up where an earlier read left off.
+     *
+     * Segments entirely before the record are skipped by their lengths alone,
+     * so only the records from it onward are actually read.
+     *
+     * (Only built for tests for now, as the aggregation picks up where it left
+     * off by time instead; see `TargetManager::compact_averages()`.)
+     */
+    #[cfg(test)]
+    pub fn read_from(&self, record: u64) -> Result<Vec<i32>, SPIOError> {
+        let mut out = Vec::new();
+        let mut skip = record;
+        for path in self.segment_paths() {
+            if let Some(mut reader) = try!(SegmentReader::open(&path, &self.layouts)) {
+                if skip >= reader.len {
+                    skip -= reader.len;
+                } else {
+                    let len = reader.len;
+                    out.extend(try!(reader.read(skip, len, self.record_size)));
+                    skip = 0;
+                }
+            }
+        }
+
+        let buffered = try!(self.buffered());
+        let ints_per_record = self.record_size / mem::size_of::<i32>();
+        let skipped = cmp::min(skip as usize * ints_per_record, buffered.len());
+        out.extend_from_slice(&buffered[skipped..]);
+        Ok(out)
+    }
+
+    /**
+     * Calls `f` with blocks of (at most `block_len`) records of this data file
+     * (as back-to-back integers of records in the latest layout), starting
+     * from the newest and working backward, until `f` returns `false` or
+     * there are no records left. Records within each block are still in time
+     * order.
+     *
+     * Only the blocks handed to `f` are actually read, so finding recent
+     * records this way doesn't read the whole data file.
+     */
+    pub fn read_backward<F>(&self, block_len: u64, mut f: F) -> Result<(), SPIOError>
+            where F: FnMut(&[i32]) -> bool {
+        // buffered records are the newest of all
+        let ints_per_record = self.record_size / mem::size_of::<i32>();
+        let buffered = try!(self.buffered());
+        let mut end = buffered.len();
+        while end > 0 {
+            let begin = end.saturating_sub(block_len as usize * ints_per_record);
+            if !f(&buffered[begin..end]) {
+                return Ok(());
+            }
+            end = begin;
+        }
+
+        for path in self.segment_paths().iter().rev() {
+            if let Some(mut reader) = try!(SegmentReader::open(path, &self.layouts)) {
+                let mut end = reader.len;
+                while end > 0 {
+                    let begin = end.saturating_sub(block_len);
+                    if !f(&try!(reader.read(begin, end, self.record_size))) {
+                        return Ok(());
+                    }
+                    end = begin;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Counts the records in this data file (buffered ones included), going by
+     * the lengths of its segments alone.
+     */
+    pub fn record_count(&self) -> Result<u64, SPIOError> {
+        let mut count = (self.buffer.len() / self.record_size) as u64;
+        for path in self.segment_paths() {
+            if let Some(reader) = try!(SegmentReader::open(&path, &self.layouts)) {
+                count += reader.len;
+            }
+        }
+        Ok(count)
+    }
+
+    /**
+     * Gets the size (in bytes) of all the segments of this data file on-disk.
+     */
+    pub fn disk_size(&self) -> Result<u64, SPIOError> {
+        let mut size = 0;
+        for path in self.segment_paths() {
+            size += try!(fs::metadata(&path).map_err(|_| SPIOError::Metadata(Some(path.clone())))).len();
+        }
+        Ok(size)
+    }
+
+    /**
+     * Gets the times of the first and last records of this data file (if it
+     * has any), reading only those two records.
+     */
+    pub fn time_span(&self) -> Result<Option<(i64, i64)>, SPIOError> {
+        let mut first = None;
+        for path in self.segment_paths() {
+            if let Some(mut reader) = try!(SegmentReader::open(&path, &self.layouts)) {
+                if reader.len > 0 {
+                    first = Some(try!(reader.time_at(0)));
+                    break;
+                }
+            }
+        }
+        if first.is_none() {
+            let buffered = try!(self.buffered());
+            first = buffered.get(1).map(|_| time_from_words(buffered[0], buffered[1]));
+        }
+
+        let mut last = None;
+        try!(self.read_backward(1, |r| {
+            last = r.get(1).map(|_| time_from_words(r[0], r[1]));
+            false
+        }));
+        Ok(first.and_then(|f| last.map(|l| (f, l))))
+    }
+
+    /**
+     * Drops all records with times before the given cutoff from this data
+     * file.
+     *
+     * Sealed segments entirely before the cutoff are simply deleted, leaving
+     * at most one segment that needs its front truncated. As this relies on
+     * records being in time order, any appended out of order are first put
+     * back in order (see `compact()`).
+     */
+    pub fn prune_before(&mut self, cutoff: i64) -> Result<(), SPIOError> {
+        if self.out_of_order {
+            try!(self.compact());
+        }
+        try!(self.flush());
+        self.count = None;
+
+        for path in self.segment_paths() {
+            let is_current = self.segments.is_empty();
+            let reader = if path.exists() {
+                try!(SegmentReader::open(&path, &self.layouts))
+            } else {
+                None
+            };
+            let (begin, len, at, stride) = match reader {
+                Some(mut reader) => (try!(reader.lower_bound(cutoff)), reader.len,
+                                     reader.offset(0), reader.format.stride()),
+                None if is_current => return Ok(()),
+                None => {
+                    // an empty (or missing) sealed segment has nothing to keep, but newer ones may
+                    if path.exists() {
+                        try!(fs::remove_file(&path).map_err(|_| SPIOError::Write(Some(path.clone()))));
+                    }
+                    self.segments.remove(0);
+                    continue;
+                },
+            };
+
+            if begin < len || is_current {
+                // the cutoff falls within this segment, so newer ones are kept
+                if begin > 0 {
+                    try!(drop_bytes(&path, at, begin * stride as u64));
+                }
+                return Ok(());
+            }
+
+            try!(fs::remove_file(&path).map_err(|_| SPIOError::Write(Some(path.clone()))));
+            self.segments.remove(0);
+        }
+        Ok(())
+    }
+
+    /**
+     * Puts the records of this data file back in order of time and index
+     * (keeping records of the same time and index in the order they were
+     * appended) and drops exact duplicates, if there are any records out of
+     * order or duplicated.
+     *
+     * The records are rewritten (in the latest layout) into the current file
+     * in one go, which replaces it atomically before the sealed segments are
+     * deleted, so crashing partway through at worst leaves duplicates for the
+     * next compaction to drop. Everything ends up in the current file, which
+     * is sealed as usual once it's next appended to (if past the maximum size),
+     * or, if sharded by day, in the file of the day it falls on.
+     */
+    pub fn compact(&mut self) -> Result<(), SPIOError> {
+        try!(self.flush());
+
+        let ints_per_record = self.record_size / mem::size_of::<i32>();
+        let mut all = Vec::new();
+        try!(self.for_each_segment(|ints| all.extend_from_slice(ints)));
+
+        let mut records: Vec<&[i32]> = all.chunks(ints_per_record).collect();
+        records.sort_by_key(|r| (time_from_words(r[0], r[1]), r[2]));
+
+        // duplicates sort next to each other (amongst records of the same time and index)
+        let mut compacted = Vec::with_capacity(all.len());
+        let mut group_start = 0;
+        for (i, r) in records.iter().enumerate() {
+            if i > 0 && (records[i - 1][0], records[i - 1][1], records[i - 1][2]) != (r[0], r[1], r[2]) {
+                group_start = compacted.len();
+            }
+            let is_duplicate = compacted[group_start..].chunks(ints_per_record).any(|c: &[i32]| c == *r);
+            if !is_duplicate {
+                compacted.extend_from_slice(r);
+            }
+        }
+        self.out_of_order = false;
+        if compacted == all {
+            return Ok(());
+        }
+        self.replace_records(compacted)
+    }
+
+    /**
+     * Drops all records of this data file, buffered ones included.
+     *
+     * The current file is atomically replaced by an empty one before the
+     * sealed segments are deleted (oldest first), so crashing partway through
+     * at worst leaves some of the newer sealed records to clear again.
+     */
+    pub fn clear(&mut self) -> Result<(), SPIOError> {
+        self.buffer.clear();
+        self.out_of_order = false;
+        self.replace_records(Vec::new())
+    }
+
+    /**
+     * Drops the records of this data file (buffered ones included) for which
+     * `keep` returns `false`, rewriting the rest (like `compact()`) if any
+     * were dropped.
+     */
+    pub fn retain<F>(&mut self, mut keep: F) -> Result<(), SPIOError>
+        where F: FnMut(&[i32]) -> bool {
+        try!(self.flush());
+
+        let ints_per_record = self.record_size / mem::size_of::<i32>();
+        let mut all = Vec::new();
+        try!(self.for_each_segment(|ints| all.extend_from_slice(ints)));
+
+        let mut kept = Vec::with_capacity(all.len());
+        for r in all.chunks(ints_per_record) {
+            if keep(r) {
+                kept.extend_from_slice(r);
+            }
+        }
+        if kept.len() == all.len() {
+            return Ok(());
+        }
+        self.replace_records(kept)
+    }
+
+    /**
+     * Atomically replaces the current file with one holding the given records
+     * (in the latest layout), then deletes the sealed segments. Anything
+     * buffered must already have been written out (or discarded).
+     */
+    fn replace_records(&mut self, records: Vec<i32>) -> Result<(), SPIOError> {
+        self.count = None;
+        if self.current_day.is_some() {
+            return self.replace_records_by_day(records);
+        }
+
+        let current = self.current.clone();
+        let format = Format::fresh(self.options.checksums, &self.layouts);
+        // (without any records, leave the file as empty as a newly created one)
+        let encoded = if records.is_empty() {
+            Vec::new()
+        } else {
+            format.encode(&records.into_raw_bytes(), true)
+        };
+        try!(overwrite_atomic(&encoded, &current, self.options.sync));
+        for (_, path) in self.segments.drain(..) {
+            try!(fs::remove_file(&path).map_err(|_| SPIOError::Write(Some(path.clone()))));
+        }
+
+        // our handle still refers to the replaced file, so reopen it
+        self.file = try!(File::open_from(OpenOptions::new().read(true).append(true).create(true),
+                                         &current));
+        self.format = format;
+        if encoded.is_empty() {
+            self.newest = None;
+        }
+        Ok(())
+    }
+
+    /**
+     * Replaces the files of this data file with the given records (in the
+     * latest layout) like `replace_records()`, but with the records of each
+     * day in the file of that day (see `DataFile`). Each day's file is
+     * replaced atomically before the files left without any records are
+     * deleted.
+     */
+    fn replace_records_by_day(&mut self, records: Vec<i32>) -> Result<(), SPIOError> {
+        let format = Format::fresh(self.options.checksums, &self.layouts);
+        let bytes = records.into_raw_bytes();
+
+        // (the records are in time order, so each day's are all together)
+        let mut days: Vec<(u32, Vec<u8>)> = Vec::new();
+        for record in bytes.chunks(self.record_size) {
+            let day = self.record_time(record).map(day_of).unwrap_or(0);
+            match days.last_mut() {
+                Some(&mut (d, ref mut day_bytes)) if d == day => {
+                    day_bytes.extend_from_slice(record);
+                    continue;
+                },
+                _ => (),
+            }
+            days.push((day, record.to_vec()));
+        }
+
+        let mut written = Vec::with_capacity(days.len());
+        for &(day, ref day_bytes) in days.iter() {
+            let path = day_path(&self.stem, day);
+            try!(overwrite_atomic(&format.encode(day_bytes, true), &path, self.options.sync));
+            written.push((day, path));
+        }
+
+        // (without any records, start afresh with today's file)
+        let (day, current) = written.pop().unwrap_or_else(|| {
+            let today = day_of(Local::now().timestamp());
+            (today, day_path(&self.stem, today))
+        });
+        let mut old = mem::replace(&mut self.segments, written);
+        old.push((0, self.current.clone()));
+        for (_, path) in old {
+            if path != current && !self.segments.iter().any(|&(_, ref p)| *p == path) {
+                try!(fs::remove_file(&path).map_err(|_| SPIOError::Write(Some(path.clone()))));
+            }
+        }
+
+        self.file = try!(File::open_from(OpenOptions::new().read(true).append(true).create(true),
+                                         &current));
+        self.current = current;
+        self.current_day = Some(day);
+        self.format = format;
+        if days.is_empty() {
+            self.newest = None;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn read_range_reads_only_records_in_range() {
+    use helpers::VecIntoRawBytes;
+    use persist::test_data_dir;
+    use reader::Records;
+
+    let mut stem = test_data_dir("read_range_reads_only_records_in_range");
+    stem.push("test.data");
+
+    // small segments, so the range spans several of them
+    let layout = Layout { record_size: Records::record_size(1), wide_time: true };
+    let options = DataFileOptions { max_size: Some(3 * layout.record_size as u64), ..DataFileOptions::default() };
+    let mut df = DataFile::open(&stem, &[layout], options).unwrap();
+    for (i, &t) in [1, 2, 2, 3, 5, 8, 8, 13].iter().enumerate() {
+        df.append(&vec![t, 0, i as i32, t * 10].into_raw_bytes()).unwrap();
+    }
+
+    let ints = df.read_range(2, 8).unwrap();
+    let in_range = Records::new(&ints, 1);
+    let got: Vec<(i64, i32)> = (0..in_range.len()).map(|i| (in_range.time(i), in_range.index(i).word())).collect();
+    assert_eq!(got, vec![(2, 1), (2, 2), (3, 3), (5, 4), (8, 5), (8, 6)]);
+
+    assert!(df.read_range(9, 12).unwrap().is_empty());
+    assert_eq!(df.read_range(0, 100).unwrap().len(), 8 * 4);
+}
+
+#[test]
+fn sharded_records_are_split_by_local_day() {
+    use helpers::{VecIntoRawBytes, time_words};
+    use persist::test_data_dir;
+    use reader::Records;
+
+    let dir = test_data_dir("sharded_records_are_split_by_local_day");
+    let stem = dir.join("test.data");
+    let layout = Layout { record_size: Records::record_size(1), wide_time: true };
+    let options = DataFileOptions { buffer_size: 1 << 20, shard_by_day: true, ..DataFileOptions::default() };
+    let record = |t: i64, v: i32| {
+        let mut r = time_words(t).to_vec();
+        r.extend_from_slice(&[0, v]);
+        r.into_raw_bytes()
+    };
+    let files = || {
+        let mut names: Vec<String> = fs::read_dir(&dir).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        names.sort();
+        names
+    };
+
+    // late on one day, and just after midnight (on the local clock) the next
+    let evening = Local.ymd(2024, 1, 15).and_hms(23, 59, 0).timestamp();
+    let midnight = Local.ymd(2024, 1, 16).and_hms(0, 0, 30).timestamp();
+    {
+        let mut df = DataFile::open(&stem, &[layout], options).unwrap();
+        df.append(&record(evening, 1)).unwrap();
+        df.append(&record(evening + 30, 2)).unwrap();
+        df.append(&record(midnight, 3)).unwrap();
+        df.flush().unwrap();
+    }
+    // (the file started for today on opening went unused, so it's gone)
+    assert_eq!(files(), vec!["test.data.2024-01-15.dat", "test.data.2024-01-16.dat"]);
+
+    // reads span the days, and appends carry on in the latest day's file
+    let mut df = DataFile::open(&stem, &[layout], options).unwrap();
+    assert_eq!(df.segments.len(), 1);
+    df.append(&record(midnight + 60, 4)).unwrap();
+    df.flush().unwrap();
+    let vals = |df: &DataFile| {
+        let ints = df.read_range(evening, midnight + 60).unwrap();
+        let records = Records::new(&ints, 1);
+        (0..records.len()).map(|i| records.vals(i)[0]).collect::<Vec<i32>>()
+    };
+    assert_eq!(vals(&df), vec![1, 2, 3, 4]);
+    assert_eq!(df.record_count().unwrap(), 4);
+
+    // records that come in late for the previous day are moved to its file
+    df.append(&record(evening + 45, 5)).unwrap();
+    df.compact().unwrap();
+    assert_eq!(vals(&df), vec![1, 2, 5, 3, 4]);
+    assert_eq!(files(), vec!["test.data.2024-01-15.dat", "test.data.2024-01-16.dat"]);
+
+    // and a day left without records loses its file
+    df.retain(|r| time_from_words(r[0], r[1]) >= midnight).unwrap();
+    assert_eq!(vals(&df), vec![3, 4]);
+    assert_eq!(files(), vec!["test.data.2024-01-16.dat"]);
+}
+
+#[test]
+fn pruning_goes_past_empty_and_missing_segments() {
+    use helpers::VecIntoRawBytes;
+    use persist::test_data_dir;
+    use reader::Records;
+
+    let stem = test_data_dir("pruning_goes_past_empty_and_missing_segments").join("test.data");
+    let layout = Layout { record_size: Records::record_size(1), wide_time: true };
+    let options = DataFileOptions { max_size: Some(2 * layout.record_size as u64), ..DataFileOptions::default() };
+    let mut df = DataFile::open(&stem, &[layout], options).unwrap();
+    for t in 0..7 {
+        df.append(&vec![t, 0, 0, t * 10].into_raw_bytes()).unwrap();
+    }
+    assert_eq!(df.segments.len(), 3);
+
+    // the oldest segment is emptied, the next one lost altogether
+    File::create(&df.segments[0].1).unwrap();
+    fs::remove_file(&df.segments[1].1).unwrap();
+
+    df.prune_before(5).unwrap();
+    assert_eq!(df.segments.len(), 1);
+    assert_eq!(df.read_range(0, 100).unwrap(), vec![5, 0, 0, 50, 6, 0, 0, 60]);
+}
+
+#[test]
+fn compaction_sorts_and_dedups_out_of_order_records() {
+    use helpers::VecIntoRawBytes;
+    use persist::test_data_dir;
+    use reader::Records;
+
+    let stem = test_data_dir("compaction_sorts_and_dedups_out_of_order_records").join("test.data");
+    let layout = Layout { record_size: Records::record_size(1), wide_time: true };
+    let options = DataFileOptions { max_size: Some(3 * layout.record_size as u64), ..DataFileOptions::default() };
+    let mut df = DataFile::open(&stem, &[layout], options).unwrap();
+    let appended = vec![(5, 0, 50), (2, 1, 20), (2, 0, 21), (7, 0, 70), (2, 1, 20), (5, 0, 51), (1, 0, 10)];
+    for &(t, i, v) in appended.iter() {
+        df.append(&vec![t, 0, i, v].into_raw_bytes()).unwrap();
+    }
+    assert!(df.out_of_order);
+
+    // pruning puts them back in order first, so it finds the right cutoff
+    df.prune_before(2).unwrap();
+    assert!(!df.out_of_order);
+    assert!(df.segments.is_empty());
+
+    let ints = df.read_range(0, 100).unwrap();
+    let records = Records::new(&ints, 1);
+    let got: Vec<(i64, i32, i32)> = (0..records.len())
+        .map(|i| (records.time(i), records.index(i).word(), records.vals(i)[0])).collect();
+    assert_eq!(got, vec![(2, 0, 21), (2, 1, 20), (5, 0, 50), (5, 0, 51), (7, 0, 70)]);
+
+    // compacting again changes nothing, and the order is known when reopened
+    df.compact().unwrap();
+    assert_eq!(df.read_range(0, 100).unwrap(), ints);
+    let mut df = DataFile::open(&stem, &[layout], options).unwrap();
+    df.append(&vec![6, 0, 0, 60].into_raw_bytes()).unwrap();
+    assert!(df.out_of_order);
+}
+
+#[test]
+fn flipped_byte_is_detected_by_checksum() {
+    use helpers::VecIntoRawBytes;
+    use persist::test_data_dir;
+    use reader::Records;
+
+    let dir = test_data_dir("flipped_byte_is_detected_by_checksum");
+    let layout = Layout { record_size: Records::record_size(1), wide_time: true };
+    let with_checksums = DataFileOptions { checksums: true, ..DataFileOptions::default() };
+
+    // data files written without checksums still load (and stay that way)
+    let legacy_stem = dir.join("legacy.data");
+    DataFile::open(&legacy_stem, &[layout], DataFileOptions::default()).unwrap()
+        .append(&vec![1, 0, 0, 10].into_raw_bytes()).unwrap();
+    let mut legacy = DataFile::open(&legacy_stem, &[layout], with_checksums).unwrap();
+    legacy.append(&vec![2, 0, 0, 20].into_raw_bytes()).unwrap();
+    assert_eq!(legacy.read_range(0, 10).unwrap().len(), 2 * 4);
+
+    let stem = dir.join("test.data");
+    let mut df = DataFile::open(&stem, &[layout], with_checksums).unwrap();
+    for t in 1..4 {
+        df.append(&vec![t, 0, 0, t * 10].into_raw_bytes()).unwrap();
+    }
+    assert_eq!(df.read_range(0, 10).unwrap().len(), 3 * 4);
+
+    // flip a bit in the value of the second record
+    let path = dir.join("test.data.dat");
+    let mut bytes = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+    bytes[HEADER_LEN + (layout.record_size + CHECKSUM_LEN) + 12] ^= 0x01;
+    File::create(&path).unwrap().write_all(&bytes).unwrap();
+
+    match df.read_range(0, 10) {
+        Err(SPIOError::Corrupt(_)) => (),
+        _ => panic!("Expected the corruption to be detected"),
+    }
+    match df.for_each_segment(|_| ()) {
+        Err(SPIOError::Corrupt(_)) => (),
+        _ => panic!("Expected the corruption to be detected"),
+    }
+}
+
+#[test]
+fn appends_give_back_record_numbers_to_read_from() {
+    use helpers::VecIntoRawBytes;
+    use persist::test_data_dir;
+    use reader::Records;
+
+    let dir = test_data_dir("appends_give_back_record_numbers_to_read_from");
+    let layout = Layout { record_size: Records::record_size(1), wide_time: true };
+    let record_size = layout.record_size as u64;
+    let stem = dir.join("test.data");
+    let path = dir.join("test.data.dat");
+
+    // each record number is where the record lands in the file
+    let options = DataFileOptions::default();
+    {
+        let mut df = DataFile::open(&stem, &[layout], options).unwrap();
+        for t in 0..3 {
+            let at = fs::metadata(&path).unwrap().len();
+            assert_eq!(df.append(&vec![t, 0, 0, t * 10].into_raw_bytes()).unwrap() * record_size, at);
+        }
+        assert_eq!(df.append(&vec![3, 0, 0, 30, 4, 0, 0, 40].into_raw_bytes()).unwrap(), 3);
+    }
+
+    // numbering carries on after reopening, across segments and the buffer
+    let options = DataFileOptions { max_size: Some(6 * record_size), buffer_size: 2 * layout.record_size,
+                                    ..DataFileOptions::default() };
+    let mut df = DataFile::open(&stem, &[layout], options).unwrap();
+    let mut cursor = 0;
+    for t in 5..9 {
+        cursor = df.append(&vec![t, 0, 0, t * 10].into_raw_bytes()).unwrap();
+        assert_eq!(cursor, t as u64);
+    }
+    assert_eq!(df.read_from(cursor).unwrap(), vec![8, 0, 0, 80]);
+    assert_eq!(df.read_from(4).unwrap(), vec![4, 0, 0, 40, 5, 0, 0, 50, 6, 0, 0, 60, 7, 0, 0, 70, 8, 0, 0, 80]);
+    assert_eq!(df.read_from(0).unwrap().len(), 9 * 4);
+    assert!(df.read_from(9).unwrap().is_empty());
+
+    // but starts over from the records left after pruning
+    df.prune_before(7).unwrap();
+    assert_eq!(df.append(&vec![9, 0, 0, 90].into_raw_bytes()).unwrap(), 2);
+}
+
+#[test]
+fn records_are_iterated_a_chunk_at_a_time() {
+    use helpers::VecIntoRawBytes;
+    use persist::test_data_dir;
+    use reader::Records;
+
+    let dir = test_data_dir("records_are_iterated_a_chunk_at_a_time");
+    let layout = Layout { record_size: Records::record_size(1), wide_time: true };
+    let options = DataFileOptions { max_size: Some(4 * layout.record_size as u64), buffer_size: 2 * layout.record_size,
+                                    ..DataFileOptions::default() };
+    let mut df = DataFile::open(&dir.join("test.data"), &[layout], options).unwrap();
+    assert_eq!(df.iter().count(), 0);
+    for t in 0..11 {
+        df.append(&vec![t, 0, 0, t * 10].into_raw_bytes()).unwrap();
+    }
+
+    // chunks never span segments (of 4 records here), nor the buffer
+    let chunks: Vec<Vec<i32>> = df.iter_by(3).map(|c| c.unwrap()).collect();
+    let lens: Vec<usize> = chunks.iter().map(|c| c.len() / 4).collect();
+    assert_eq!(lens, vec![3, 1, 3, 1, 2, 1]);
+    let all: Vec<i32> = chunks.concat();
+    assert_eq!(all, df.read_range(0, 100).unwrap());
+
+    let whole: Vec<Vec<i32>> = df.iter().map(|c| c.unwrap()).collect();
+    assert_eq!(whole.concat(), all);
+}
+
+#[test]
+fn buffered_records_are_written_on_flush() {
+    use helpers::VecIntoRawBytes;
+    use persist::test_data_dir;
+    use reader::Records;
+
+    let dir = test_data_dir("buffered_records_are_written_on_flush");
+    let layout = Layout { record_size: Records::record_size(1), wide_time: true };
+    let record_size = layout.record_size;
+    let options = DataFileOptions { buffer_size: 3 * record_size, ..DataFileOptions::default() };
+
+    let stem = dir.join("test.data");
+    let mut df = DataFile::open(&stem, &[layout], options).unwrap();
+    df.append(&vec![1, 0, 0, 10, 2, 0, 0, 20].into_raw_bytes()).unwrap();
+
+    // nothing is written out yet, but reads see the buffered records anyway
+    let path = dir.join("test.data.dat");
+    assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+    assert_eq!(df.read_range(2, 10).unwrap(), vec![2, 0, 0, 20]);
+
+    // filling up the buffer writes it out
+    df.append(&vec![3, 0, 0, 30].into_raw_bytes()).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 3 * record_size as u64);
+
+    df.append(&vec![4, 0, 0, 40].into_raw_bytes()).unwrap();
+    df.flush().unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 4 * record_size as u64);
+    assert_eq!(df.read_range(0, 10).unwrap().len(), 4 * 4);
+}
+
+#[test]
+fn older_record_layouts_are_padded() {
+    use helpers::VecIntoRawBytes;
+    use persist::test_data_dir;
+    use reader::Records;
+
+    let dir = test_data_dir("older_record_layouts_are_padded");
+    let stem = dir.join("test.averaged");
+    // a 32-bit time and one value, then a 64-bit time and four values
+    let old = Layout { record_size: 3 * mem::size_of::<i32>(), wide_time: false };
+    let new = Layout { record_size: Records::record_size(4), wide_time: true };
+
+    DataFile::open(&stem, &[old], DataFileOptions::default()).unwrap()
+        .append(&vec![-1, 0, 5, 1, 0, 10].into_raw_bytes()).unwrap();
+
+    // the old file is sealed, and new records go in a fresh one
+    let mut df = DataFile::open(&stem, &[old, new], DataFileOptions::default()).unwrap();
+    df.append(&vec![2, 0, 0, 20, 2, 18, 22].into_raw_bytes()).unwrap();
+    assert!(fs::metadata(dir.join("test.averaged.0.dat")).is_ok());
+
+    // old times are widened (keeping their sign), and old records padded
+    let n = SENTINEL_NODATA;
+    assert_eq!(df.read_range(0, 10).unwrap(), vec![1, 0, 0, 10, n, n, n, 2, 0, 0, 20, 2, 18, 22]);
+    assert_eq!(df.read_range(-10, -1).unwrap(), vec![-1, -1, 0, 5, n, n, n]);
+
+    let mut mins = Vec::new();
+    df.for_each_segment(|ints| {
+        let avgs = Records::new(ints, 4);
+        mins.extend((0..avgs.len()).map(|i| avgs.vals(i)[2]));
+    }).unwrap();
+    assert_eq!(mins, vec![SENTINEL_NODATA, SENTINEL_NODATA, 18]);
+}
+
+#[test]
+fn concurrent_reads_never_see_torn_records() {
+    use std::i64;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use helpers::VecIntoRawBytes;
+    use persist::test_data_dir;
+    use reader::Records;
+
+    let stem = test_data_dir("concurrent_reads_never_see_torn_records").join("test.data");
+    let layout = Layout { record_size: Records::record_size(1), wide_time: true };
+    // rotate (and write out the buffer) often, so reads overlap both
+    let options = DataFileOptions { max_size: Some(64 * layout.record_size as u64), buffer_size: 4 * layout.record_size,
+                                    ..DataFileOptions::default() };
+    let df = Arc::new(RwLock::new(DataFile::open(&stem, &[layout], options).unwrap()));
+    let count = 2000;
+
+    let appender = {
+        let df = df.clone();
+        thread::spawn(move || {
+            for t in 0..count {
+                df.write().unwrap().append(&vec![t, 0, 0, t * 10].into_raw_bytes()).unwrap();
+            }
+        })
+    };
+
+    let readers: Vec<_> = (0..4).map(|n| {
+        let df = df.clone();
+        thread::spawn(move || {
+            let mut seen = 0;
+            while seen < count as usize {
+                let mut ints = Vec::new();
+                {
+                    let df = df.read().unwrap();
+                    if n % 2 == 0 {
+                        ints = df.read_range(0, i64::MAX).unwrap();
+                    } else {
+                        df.for_each_segment(|s| ints.extend_from_slice(s)).unwrap();
+                    }
+                }
+
+                // every record is whole and in order, and none go missing
+                let records = Records::new(&ints, 1);
+                assert!(records.len() >= seen);
+                for i in 0..records.len() {
+                    assert_eq!((records.time(i), records.vals(i)[0]), (i as i64, i as i32 * 10));
+                }
+                seen = records.len();
+            }
+        })
+    }).collect();
+
+    appender.join().unwrap();
+    for r in readers {
+        r.join().unwrap();
+    }
+}
+
+#[test]
+fn mapped_and_seeked_reads_agree() {
+    use helpers::VecIntoRawBytes;
+    use persist::test_data_dir;
+    use reader::Records;
+
+    let dir = test_data_dir("mapped_and_seeked_reads_agree");
+    let old = Layout { record_size: 3 * mem::size_of::<i32>(), wide_time: false };
+    let new = Layout { record_size: Records::record_size(1), wide_time: true };
+
+    // a sealed segment in an older layout (so it can't be mapped) and a current one
+    let stem = dir.join("test.data");
+    DataFile::open(&stem, &[old], DataFileOptions::default()).unwrap()
+        .append(&vec![1, 0, 10, 3, 0, 30].into_raw_bytes()).unwrap();
+    let mut df = DataFile::open(&stem, &[old, new], DataFileOptions::default()).unwrap();
+    for t in 4..9 {
+        df.append(&vec![t, 0, 0, t * 10].into_raw_bytes()).unwrap();
+    }
+
+    for &(start, end) in [(0, 100), (2, 5), (3, 3), (9, 12), (-5, 0)].iter() {
+        let (mut mapped, mut seeked) = (Vec::new(), Vec::new());
+        for path in df.segment_paths() {
+            df.map_segment_range(&path, start, end, &mut mapped).unwrap();
+            df.seek_segment_range(&path, start, end, &mut seeked).unwrap();
+        }
+        assert_eq!(mapped, seeked);
+    }
+}
+
+/*
+ * Compares range queries on a 1GB data file read by mapping it against
+ * reading it by seeking. Run with:
+ *
+ *     cargo test --release read_range_bench -- --ignored --nocapture
+ */
+#[test]
+#[ignore]
+fn read_range_bench() {
+    use time::precise_time_ns;
+    use helpers::VecIntoRawBytes;
+    use persist::test_data_dir;
+    use reader::Records;
+
+    let dir = test_data_dir("read_range_bench");
+    let layout = Layout { record_size: Records::record_size(1), wide_time: true };
+    let count = (1 << 30) / layout.record_size as i32;
+    let mut df = DataFile::open(&dir.join("test.data"), &[layout], DataFileOptions::default()).unwrap();
+    let block = 1 << 16;
+    for b in 0..(count / block) {
+        let mut ints = Vec::with_capacity(block as usize * 4);
+        for t in (b * block)..((b + 1) * block) {
+            ints.extend_from_slice(&[t, 0, 0, t]);
+        }
+        df.append(&ints.into_raw_bytes()).unwrap();
+    }
+
+    let path = df.segment_paths().pop().unwrap();
+    let queries = 1000;
+    let time_queries = |mapped: bool| {
+        let begin = precise_time_ns();
+        for q in 0..queries {
+            // an hour's worth of one-second data somewhere in the file
+            let start = (q * 7919 % (count - 3600)) as i64;
+            let mut out = Vec::new();
+            if mapped {
+                df.map_segment_range(&path, start, start + 3599, &mut out).unwrap();
+            } else {
+                df.seek_segment_range(&path, start, start + 3599, &mut out).unwrap();
+            }
+            assert_eq!(out.len(), 3600 * 4);
+        }
+        (precise_time_ns() - begin) / queries as u64 / 1000
+    };
+
+    let seeked = time_queries(false);
+    let mapped = time_queries(true);
+    println!("\n1GB data file, 3600-record range queries: {}us seeking, {}us mapped", seeked, mapped);
+}
@@ -0,0 +1,222 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * The DNS Ping target, measuring how long it takes to resolve a hostname,
+ * either via the system's resolver or via a specific DNS server.
+ */
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use std::time::Duration;
+use time::precise_time_ns;
+
+use std::net::{IpAddr, SocketAddr, UdpSocket, ToSocketAddrs};
+
+use helpers::{is_valid_host, remaining};
+use persist::TargetManager;
+use worker::{run_worker, Worker, ResultsSender};
+
+static DNS_PORT: u16 = 53;
+static DNS_RCODE_NOERROR: u8 = 0;
+
+// identifiers handed out to DNS queries, shared by all probes
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/**
+ * Splits an address of the form `hostname[@resolver[:port]]` into the
+ * hostname to look up and the DNS server to query (if one is given).
+ */
+fn parse_addr(addr: &str) -> Option<(&str, Option<SocketAddr>)> {
+    let mut parts = addr.splitn(2, '@');
+    let host = parts.next().unwrap_or("");
+    if host.is_empty() {
+        return None;
+    }
+
+    match parts.next() {
+        None => Some((host, None)),
+        Some(r) => {
+            if let Ok(sa) = r.parse::<SocketAddr>() {
+                Some((host, Some(sa)))
+            } else if let Ok(ip) = r.parse::<IpAddr>() {
+                Some((host, Some(SocketAddr::new(ip, DNS_PORT))))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/**
+ * Builds a recursive DNS query for the A record of the given hostname.
+ */
+fn dns_query(id: u16, host: &str) -> Option<Vec<u8>> {
+    let mut packet = vec![
+        (id >> 8) as u8, id as u8,
+        0x01, 0x00,  // standard query, recursion desired
+        0x00, 0x01,  // one question
+        0x00, 0x00,  // no answers
+        0x00, 0x00,  // no authority records
+        0x00, 0x00,  // no additional records
+    ];
+
+    for label in host.trim_right_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        packet.push(label.len() as u8);
+        packet.extend(label.as_bytes());
+    }
+    packet.push(0);
+
+    packet.extend(&[
+        0x00, 0x01,  // type A
+        0x00, 0x01,  // class IN
+    ]);
+    Some(packet)
+}
+
+/**
+ * Determines whether the given DNS response is a successful answer to the
+ * query with the given identifier.
+ */
+fn is_answer(buf: &[u8], id: u16) -> bool {
+    buf.len() >= 12
+        && (((buf[0] as u16) << 8) | buf[1] as u16) == id
+        && (buf[2] & 0x80) != 0  // is a response
+        && (buf[3] & 0x0f) == DNS_RCODE_NOERROR
+        && (((buf[6] as u16) << 8) | buf[7] as u16) > 0  // has answers
+}
+
+/**
+ * Measures the time (in microseconds) to resolve the given hostname by
+ * querying the given DNS server directly, giving up after the given timeout.
+ */
+fn resolve_with(host: &str, resolver: SocketAddr, timeout: Duration) -> Option<i32> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed) as u16;
+    let query = match dns_query(id, host) {
+        Some(q) => q,
+        None => return None,
+    };
+
+    let bind_addr = match resolver {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = match UdpSocket::bind(bind_addr) {
+        Ok(s) => s,
+        Err(_) => return None,
+    };
+
+    let start = precise_time_ns();
+    if socket.send_to(&query, resolver).is_err() {
+        return None;
+    }
+
+    /*
+     * wait only as long as is left of the timeout each time, so that packets
+     * that aren't our response can't keep us waiting past it
+     */
+    let mut buf = [0u8; 512];
+    loop {
+        let left = match remaining(start, timeout) {
+            Some(d) => d,
+            None => return None,
+        };
+        if socket.set_read_timeout(Some(left)).is_err() {
+            return None;
+        }
+        match socket.recv_from(&mut buf) {
+            Ok((n, from)) => {
+                // ignore anything that isn't the response to our query
+                if from != resolver || n < 2 || (((buf[0] as u16) << 8) | buf[1] as u16) != id {
+                    continue;
+                }
+                return if is_answer(&buf[..n], id) {
+                    Some(((precise_time_ns() - start) / 1000) as i32)
+                } else {
+                    None
+                };
+            },
+            Err(_) => return None,
+        }
+    }
+}
+
+/**
+ * Measures the time (in microseconds) to resolve the given address (of the
+ * form `hostname[@resolver[:port]]`), giving up after the given timeout.
+ *
+ * Lookups through the system's resolver can't be cut short, so for those the
+ * timeout is only enforced by the worker no longer waiting for the result.
+ */
+fn dnsping(addr: &str, timeout: Duration) -> Option<i32> {
+    match parse_addr(addr) {
+        Some((host, Some(resolver))) => resolve_with(host, resolver, timeout),
+        Some((host, None)) => {
+            let start = precise_time_ns();
+            match (host, 0).to_socket_addrs() {
+                Ok(_) => Some(((precise_time_ns() - start) / 1000) as i32),
+                Err(_) => None,
+            }
+        },
+        None => None,
+    }
+}
+
+/**
+ * Checks (syntactically, without looking anything up) that the given address
+ * is a valid `hostname[@resolver[:port]]` address (see `parse_addr()`).
+ */
+pub fn is_valid_addr(addr: &str) -> bool {
+    parse_addr(addr).map(|(host, _)| is_valid_host(host)).unwrap_or(false)
+}
+
+/**
+ * Runs the DNS Ping target's data-collection worker.
+ */
+pub fn run_dnsping_worker(manager: Arc<TargetManager>,
+                          results_out: ResultsSender) -> Worker {
+    run_worker(manager, results_out, dnsping)
+}
+
+#[test]
+fn foreign_packets_dont_extend_the_timeout() {
+    use std::thread;
+
+    let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let resolver_addr = resolver.local_addr().unwrap();
+    thread::spawn(move || {
+        // answer the query with a steady stream of responses to other queries
+        let mut buf = [0u8; 512];
+        let (n, from) = resolver.recv_from(&mut buf).unwrap();
+        buf[0] = !buf[0];
+        for _ in 0..100 {
+            if resolver.send_to(&buf[..n], from).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    });
+
+    let start = precise_time_ns();
+    assert_eq!(resolve_with("example.com", resolver_addr, Duration::from_millis(100)), None);
+    assert!(precise_time_ns() - start < 500_000_000);
+}
+
+#[test]
+fn parse_addr_splits_resolver() {
+    assert_eq!(parse_addr("example.com"), Some(("example.com", None)));
+    assert_eq!(parse_addr("example.com@8.8.8.8"),
+               Some(("example.com", Some("8.8.8.8:53".parse().unwrap()))));
+    assert_eq!(parse_addr("example.com@[::1]:5353"),
+               Some(("example.com", Some("[::1]:5353".parse().unwrap()))));
+    assert_eq!(parse_addr("example.com@nonsense"), None);
+    assert_eq!(parse_addr("@8.8.8.8"), None);
+}
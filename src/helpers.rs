@@ -10,13 +10,23 @@
  * Helper traits and functions for reducing verbosity, wraping errors, and
  * containing unsafe code for many commonly used I/O and parsing operations.
  */
+use std::collections::BTreeMap;
 use std::mem;
+use std::ptr;
+use std::error;
 use std::fmt::{self, Display};
 use std::path::{Path, PathBuf};
+use std::fs;
 use std::fs::{OpenOptions, File};
 use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::sync::LockResult;
+use std::time::Duration;
 
+use chrono::{Local, Timelike};
+use time::precise_time_ns;
 use rustc_serialize::{json, Encodable, Decodable};
+use rustc_serialize::json::Json;
 
 /**
  * Stabping-specific I/O error container, representing the possible failrue
@@ -30,6 +40,7 @@ pub enum SPIOError {
     Metadata(Option<PathBuf>),
     Write(Option<PathBuf>),
     Parse(Option<PathBuf>),
+    Corrupt(Option<PathBuf>),
 }
 
 impl SPIOError {
@@ -40,6 +51,7 @@ impl SPIOError {
             SPIOError::Metadata(ref p) => ("get metadata", p),
             SPIOError::Write(ref p) => ("write", p),
             SPIOError::Parse(ref p) => ("parse", p),
+            SPIOError::Corrupt(ref p) => ("verify checksums of", p),
         };
 
         let path_str = match maybe_path {
@@ -60,6 +72,19 @@ impl Display for SPIOError {
     }
 }
 
+impl error::Error for SPIOError {
+    fn description(&self) -> &str {
+        match *self {
+            SPIOError::Open(_) => "unable to open file",
+            SPIOError::Read(_) => "unable to read file",
+            SPIOError::Metadata(_) => "unable to get file metadata",
+            SPIOError::Write(_) => "unable to write file",
+            SPIOError::Parse(_) => "unable to parse file",
+            SPIOError::Corrupt(_) => "unable to verify file checksums",
+        }
+    }
+}
+
 
 /**
  * Trait for turning arbitrary data into a series of bytes that can be put directly
@@ -83,6 +108,69 @@ impl VecIntoRawBytes for Vec<i32> {
     }
 }
 
+/**
+ * Trait for turning a series of bytes read directly from a file or off the
+ * network back into the data they represent (the inverse of
+ * `VecIntoRawBytes`).
+ */
+pub trait VecFromRawBytes {
+    fn from_raw_bytes(&self) -> Vec<i32>;
+}
+
+impl VecFromRawBytes for [u8] {
+    fn from_raw_bytes(&self) -> Vec<i32> {
+        let new_len = self.len() / mem::size_of::<i32>();
+        let mut data: Vec<i32> = vec![0; new_len];
+
+        unsafe {
+            // copy over whole i32s worth of bytes (ignoring any trailing ones)
+            ptr::copy_nonoverlapping(self.as_ptr(), data.as_mut_ptr() as *mut u8,
+                                     new_len * mem::size_of::<i32>());
+        }
+        data
+    }
+}
+
+/**
+ * Trait for turning data into a series of (little-endian) bytes that can be
+ * sent over the network, regardless of the byte order of the host.
+ */
+pub trait VecIntoWireBytes {
+    fn into_wire_bytes(self) -> Vec<u8>;
+}
+
+impl VecIntoWireBytes for Vec<i32> {
+    fn into_wire_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len() * mem::size_of::<i32>());
+        for n in self {
+            bytes.push(n as u8);
+            bytes.push((n >> 8) as u8);
+            bytes.push((n >> 16) as u8);
+            bytes.push((n >> 24) as u8);
+        }
+        bytes
+    }
+}
+
+/**
+ * Trait for turning a series of (little-endian) bytes received over the
+ * network back into the data they represent (the inverse of
+ * `VecIntoWireBytes`).
+ */
+pub trait VecFromWireBytes {
+    fn from_wire_bytes(&self) -> Vec<i32>;
+}
+
+impl VecFromWireBytes for [u8] {
+    fn from_wire_bytes(&self) -> Vec<i32> {
+        // decode whole i32s worth of bytes (ignoring any trailing ones)
+        self.chunks(mem::size_of::<i32>())
+            .filter(|b| b.len() == mem::size_of::<i32>())
+            .map(|b| (b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24) as i32)
+            .collect()
+    }
+}
+
 /**
  * Expands the functionality of `File` to include JSON encoding, a
  * generalized `open()` and streamlined access to `metadata.length`. All
@@ -101,7 +189,8 @@ pub trait SPFile {
 
     /**
      * Attempts to read from this file and decode all its contents as a JSON
-     * object (`rustc::Decodable`).
+     * object (`rustc::Decodable`), unwrapping it first if it's wrapped (see
+     * `unwrap_json()`).
      */
     fn read_json<T: Decodable>(&mut self) -> Result<T, SPIOError> {
         self._read_json(None)
@@ -113,15 +202,17 @@ pub trait SPFile {
 
 
     /**
-     * Attempts to write a JSON object (`rustc::Encodable`) to this file.
+     * Attempts to write a JSON object (`rustc::Encodable`), wrapped (see
+     * `wrap_json()`), to this file. If `sync` is set, the file is also synced
+     * to disk (`File::sync_all()`) before returning.
      */
-    fn write_json<'b, T: Encodable>(&mut self, obj: &'b T) -> Result<(), SPIOError> {
-        self._write_json(obj, None)
+    fn write_json<'b, T: Encodable>(&mut self, obj: &'b T, sync: bool) -> Result<(), SPIOError> {
+        self._write_json(obj, None, sync)
     }
-    fn write_json_p<'a, 'b, T: Encodable>(&mut self, obj: &'b T, path: &'a Path) -> Result<(), SPIOError> {
-        self._write_json(obj, Some(path))
+    fn write_json_p<'a, 'b, T: Encodable>(&mut self, obj: &'b T, path: &'a Path, sync: bool) -> Result<(), SPIOError> {
+        self._write_json(obj, Some(path), sync)
     }
-    fn _write_json<'a, 'b, T: Encodable>(&mut self, obj: &'b T, path: Option<&'a Path>) -> Result<(), SPIOError>;
+    fn _write_json<'a, 'b, T: Encodable>(&mut self, obj: &'b T, path: Option<&'a Path>, sync: bool) -> Result<(), SPIOError>;
 
 
     /**
@@ -150,12 +241,11 @@ impl SPFile for File {
             self.read_to_string(&mut buffer)
             .map_err(|_| SPIOError::Read(path.map(|p| p.to_owned())))
         );
-        json::decode::<T>(&buffer)
-            .map_err(|_| SPIOError::Parse(path.map(|p| p.to_owned())))
+        unwrap_json(&buffer, path)
     }
 
-    fn _write_json<'a, 'b, T: Encodable>(&mut self, obj: &'b T, path: Option<&'a Path>) -> Result<(), SPIOError> {
-        let buffer = json::encode(obj).unwrap();
+    fn _write_json<'a, 'b, T: Encodable>(&mut self, obj: &'b T, path: Option<&'a Path>, sync: bool) -> Result<(), SPIOError> {
+        let buffer = wrap_json(obj);
         try!(
             self.write_all(buffer.as_bytes())
             .map_err(|_| SPIOError::Write(path.map(|p| p.to_owned())))
@@ -164,6 +254,12 @@ impl SPFile for File {
             self.flush()
             .map_err(|_| SPIOError::Write(path.map(|p| p.to_owned())))
         );
+        if sync {
+            try!(
+                self.sync_all()
+                .map_err(|_| SPIOError::Write(path.map(|p| p.to_owned())))
+            );
+        }
         Ok(())
     }
 
@@ -177,15 +273,341 @@ impl SPFile for File {
 }
 
 /**
- * Overwrite (create if necessary, truncate if already exists) the file
- * residing at the given path with the given JSON object (`rustc::Encodable`).
+ * Overwrite (create if necessary, replace if already exists) the file
+ * residing at the given path with the given contents.
+ *
+ * The contents are first written out to a temporary file alongside the file,
+ * which is then renamed over it (atomic on POSIX), so that crashing partway
+ * through leaves the previous contents of the file intact.
+ *
+ * If `sync` is set, the new contents and the rename are also synced to disk
+ * before returning, so that they survive a power failure too (rather than
+ * possibly reverting to the previous contents).
  */
-pub fn overwrite_json<'a, 'b, T: Encodable>(obj: &'a T, path: &'b Path) -> Result<(), SPIOError> {
-    let mut file = try!(
-        OpenOptions::new().write(true).truncate(true).create(true).open(path)
-        .map_err(|_| SPIOError::Open(Some(path.to_owned())))
+pub fn overwrite_atomic<'a, 'b>(contents: &'a [u8], path: &'b Path, sync: bool) -> Result<(), SPIOError> {
+    let tmp_path = {
+        let mut p = path.to_owned().into_os_string();
+        p.push(".tmp");
+        PathBuf::from(p)
+    };
+
+    {
+        let mut tmp_file = try!(
+            OpenOptions::new().write(true).truncate(true).create(true).open(&tmp_path)
+            .map_err(|_| SPIOError::Open(Some(tmp_path.clone())))
+        );
+        try!(
+            tmp_file.write_all(contents)
+            .and_then(|_| if sync { tmp_file.sync_all() } else { Ok(()) })
+            .map_err(|_| SPIOError::Write(Some(tmp_path.clone())))
+        );
+    }
+
+    try!(
+        fs::rename(&tmp_path, path)
+        .map_err(|_| SPIOError::Write(Some(path.to_owned())))
     );
 
-    try!(file.write_json_p(obj, path));
+    // the rename itself is only durable once the directory holding it is
+    if sync {
+        if let Some(dir) = path.parent() {
+            let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+            try!(
+                File::open(dir).and_then(|d| d.sync_all())
+                .map_err(|_| SPIOError::Write(Some(dir.to_owned())))
+            );
+        }
+    }
     Ok(())
 }
+
+/**
+ * The version of the wrapper JSON files are written in (see `wrap_json()`).
+ * Files from before there was a wrapper are read as version 0.
+ */
+pub static JSON_FILE_VERSION: u64 = 1;
+
+/**
+ * Wraps the given JSON object (`rustc::Encodable`) for writing to a file,
+ * along with the version of the wrapper and a CRC32 checksum of the object,
+ * as `{"contents":<object>,"crc32":<checksum>,"version":<version>}`.
+ *
+ * The checksum is of the object's canonical encoding (with the keys of
+ * objects sorted), so that it still holds after a round trip through `Json`.
+ */
+pub fn wrap_json<'a, T: Encodable>(obj: &'a T) -> String {
+    let contents = Json::from_str(&json::encode(obj).unwrap()).unwrap();
+    let mut wrapper = BTreeMap::new();
+    wrapper.insert("crc32".to_owned(), Json::U64(crc32(contents.to_string().as_bytes()) as u64));
+    wrapper.insert("version".to_owned(), Json::U64(JSON_FILE_VERSION));
+    wrapper.insert("contents".to_owned(), contents);
+    Json::Object(wrapper).to_string()
+}
+
+/**
+ * Decodes a JSON object (`rustc::Decodable`) from the given contents of a
+ * file, as written by `wrap_json()`. Contents without a wrapper (i.e.
+ * written before there was one) are taken as version 0, and decoded as they
+ * are.
+ *
+ * Fails with `SPIOError::Corrupt` if the checksum doesn't match (i.e. the
+ * file was changed by something else, or only partly written), and with
+ * `SPIOError::Parse` if the contents are malformed or of a newer version.
+ */
+pub fn unwrap_json<'a, T: Decodable>(buffer: &str, path: Option<&'a Path>) -> Result<T, SPIOError> {
+    let parse_error = || SPIOError::Parse(path.map(|p| p.to_owned()));
+    let parsed = try!(Json::from_str(buffer).map_err(|_| parse_error()));
+
+    let version = match parsed.find("version") {
+        Some(v) => try!(v.as_u64().ok_or_else(&parse_error)),
+        None => 0,
+    };
+    let contents = if version == 0 {
+        parsed
+    } else {
+        if version > JSON_FILE_VERSION {
+            return Err(parse_error());
+        }
+        let crc = parsed.find("crc32").and_then(|c| c.as_u64());
+        let contents = match parsed {
+            Json::Object(mut wrapper) => try!(wrapper.remove("contents").ok_or_else(&parse_error)),
+            _ => return Err(parse_error()),
+        };
+        if crc != Some(crc32(contents.to_string().as_bytes()) as u64) {
+            return Err(SPIOError::Corrupt(path.map(|p| p.to_owned())));
+        }
+        contents
+    };
+
+    T::decode(&mut json::Decoder::new(contents)).map_err(|_| parse_error())
+}
+
+/**
+ * Overwrite (create if necessary, replace if already exists) the file
+ * residing at the given path with the given JSON object (`rustc::Encodable`),
+ * wrapped (see `wrap_json()`), atomically and synced to disk if `sync` is set
+ * (see `overwrite_atomic()`).
+ */
+pub fn overwrite_json<'a, 'b, T: Encodable>(obj: &'a T, path: &'b Path, sync: bool) -> Result<(), SPIOError> {
+    let buffer = wrap_json(obj);
+    overwrite_atomic(buffer.as_bytes(), path, sync)
+}
+
+/**
+ * An exclusive advisory lock (`flock()`) on a lock file, held for as long as
+ * this is alive and released when it is dropped.
+ *
+ * On platforms without `flock()`, taking the lock always succeeds.
+ */
+#[derive(Debug)]
+pub struct LockFile {
+    file: File,
+}
+
+impl LockFile {
+    /**
+     * Attempts to take the lock on the lock file residing at the given path
+     * (creating it if necessary) without waiting, giving back `None` if it is
+     * already held (by this or any other process).
+     */
+    pub fn try_lock<'a>(path: &'a Path) -> Result<Option<LockFile>, SPIOError> {
+        let file = try!(File::open_from(OpenOptions::new().write(true).create(true), path));
+        if LockFile::flock(&file, true) {
+            Ok(Some(LockFile { file: file }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    #[cfg(unix)]
+    fn flock(file: &File, lock: bool) -> bool {
+        use std::os::unix::io::AsRawFd;
+        use libc;
+
+        let op = if lock { libc::LOCK_EX | libc::LOCK_NB } else { libc::LOCK_UN };
+        unsafe { libc::flock(file.as_raw_fd(), op) == 0 }
+    }
+
+    #[cfg(not(unix))]
+    fn flock(_: &File, _: bool) -> bool {
+        true
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        LockFile::flock(&self.file, false);
+    }
+}
+
+/**
+ * Recovers the guard from the result of acquiring a lock, even if the lock
+ * was poisoned (by a thread panicking while holding it), so that one
+ * panicking thread doesn't make every later access panic in turn.
+ *
+ * Only for locks whose data is never left half-updated by a panic.
+ */
+pub fn recover<G>(res: LockResult<G>) -> G {
+    res.unwrap_or_else(|e| e.into_inner())
+}
+
+/**
+ * Computes the (IEEE) CRC32 checksum of the given bytes.
+ */
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in bytes {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/**
+ * Gets the current time in milliseconds from epoch.
+ */
+pub fn now_millis() -> i64 {
+    let now = Local::now();
+    now.timestamp() * 1000 + (now.nanosecond() / 1_000_000) as i64
+}
+
+/**
+ * Splits the given time in milliseconds from epoch into its seconds from epoch
+ * and the milliseconds past them (always 0 to 999, even before epoch).
+ */
+pub fn split_millis(millis: i64) -> (i64, i32) {
+    let secs = if millis < 0 { (millis - 999) / 1000 } else { millis / 1000 };
+    (secs, (millis - secs * 1000) as i32)
+}
+
+/**
+ * Splits the given (64-bit) time into the two 32-bit integers it is stored
+ * and sent as, low word first.
+ */
+pub fn time_words(time: i64) -> [i32; 2] {
+    [time as i32, (time >> 32) as i32]
+}
+
+/**
+ * Joins the two 32-bit integers (low word first) a time is stored and sent as
+ * back into the time.
+ */
+pub fn time_from_words(lo: i32, hi: i32) -> i64 {
+    (hi as i64) << 32 | (lo as u32 as i64)
+}
+
+/**
+ * Gets how much of the given timeout remains since the given start time (in
+ * nanoseconds, see `precise_time_ns()`), or `None` if the timeout has already
+ * passed.
+ */
+pub fn remaining(start: u64, timeout: Duration) -> Option<Duration> {
+    let timeout_ns = timeout.as_secs() * 1_000_000_000 + timeout.subsec_nanos() as u64;
+    let elapsed = precise_time_ns() - start;
+    if elapsed >= timeout_ns {
+        None
+    } else {
+        let r = timeout_ns - elapsed;
+        Some(Duration::new(r / 1_000_000_000, (r % 1_000_000_000) as u32))
+    }
+}
+
+/**
+ * Checks (syntactically, without looking anything up) that the given host is
+ * either an IP address or a valid DNS name.
+ */
+pub fn is_valid_host(host: &str) -> bool {
+    if host.parse::<IpAddr>().is_ok() {
+        return true;
+    }
+
+    !host.is_empty() && host.len() <= 253 && host.trim_right_matches('.').split('.').all(|label| {
+        !label.is_empty() && label.len() <= 63 && !label.starts_with('-') && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    })
+}
+
+#[test]
+fn wire_bytes_are_little_endian() {
+    let bytes = vec![0x01020304, -2].into_wire_bytes();
+    assert_eq!(bytes, vec![0x04, 0x03, 0x02, 0x01, 0xfe, 0xff, 0xff, 0xff]);
+    assert_eq!(bytes.from_wire_bytes(), vec![0x01020304, -2]);
+}
+
+#[test]
+fn millis_split_into_seconds_and_remainder() {
+    assert_eq!(split_millis(1_500_250), (1500, 250));
+    assert_eq!(split_millis(2000), (2, 0));
+    assert_eq!(split_millis(-1), (-1, 999));
+}
+
+#[test]
+fn times_round_trip_through_words() {
+    // past 2038 and 2106, and before epoch
+    for &t in [0, 1_470_000_000, 1 << 31, 1 << 32, 5_000_000_000, -1, -5_000_000_000].iter() {
+        let words = time_words(t);
+        assert_eq!(time_from_words(words[0], words[1]), t);
+    }
+    assert_eq!(time_words(-1), [-1, -1]);
+}
+
+#[test]
+fn valid_hosts_are_names_or_ips() {
+    assert!(is_valid_host("example.com"));
+    assert!(is_valid_host("example.com."));
+    assert!(is_valid_host("localhost"));
+    assert!(is_valid_host("8.8.8.8"));
+    assert!(is_valid_host("::1"));
+    assert!(!is_valid_host(""));
+    assert!(!is_valid_host("a b"));
+    assert!(!is_valid_host("example..com"));
+    assert!(!is_valid_host("-example.com"));
+}
+
+#[test]
+fn wrapped_json_is_verified() {
+    let wrapped = wrap_json(&vec![1, 2, 3]);
+    assert_eq!(wrapped, format!("{{\"contents\":[1,2,3],\"crc32\":{},\"version\":{}}}",
+                                crc32(b"[1,2,3]"), JSON_FILE_VERSION));
+    assert_eq!(unwrap_json::<Vec<i32>>(&wrapped, None).unwrap(), vec![1, 2, 3]);
+
+    // files from before the wrapper are version 0, taken as they are
+    assert_eq!(unwrap_json::<Vec<i32>>("[4, 5]", None).unwrap(), vec![4, 5]);
+
+    match unwrap_json::<Vec<i32>>(&wrapped.replace("[1,2,3]", "[1,2,4]"), None) {
+        Err(SPIOError::Corrupt(_)) => (),
+        _ => panic!("Expected a corrupt file error"),
+    }
+    let newer = wrapped.replace(&format!("\"version\":{}", JSON_FILE_VERSION),
+                                &format!("\"version\":{}", JSON_FILE_VERSION + 1));
+    match unwrap_json::<Vec<i32>>(&newer, None) {
+        Err(SPIOError::Parse(_)) => (),
+        _ => panic!("Expected a parse error"),
+    }
+}
+
+#[test]
+fn interrupted_overwrite_keeps_previous_file() {
+    use std::env;
+    use std::process;
+
+    let dir = env::temp_dir().join(format!("stabping-test-{}-interrupted_overwrite", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("options.json");
+
+    overwrite_json(&vec![1, 2, 3], &path, false).unwrap();
+
+    // a crash partway through the next overwrite leaves a partial temporary file
+    File::create(dir.join("options.json.tmp")).unwrap().write_all(b"[4, 5").unwrap();
+
+    let good: Vec<i32> = File::open(&path).unwrap().read_json().unwrap();
+    assert_eq!(good, vec![1, 2, 3]);
+
+    // and the next overwrite goes through regardless (synced or not)
+    overwrite_json(&vec![6], &path, true).unwrap();
+    let good: Vec<i32> = File::open(&path).unwrap().read_json().unwrap();
+    assert_eq!(good, vec![6]);
+}
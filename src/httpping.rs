@@ -0,0 +1,409 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * The HTTP Ping target, measuring the time to first byte of the response to
+ * an HTTP (or, when built with the `https` feature, HTTPS) GET request.
+ */
+use std::str;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use std::time::Duration;
+use time::precise_time_ns;
+
+use std::net::{TcpStream, ToSocketAddrs};
+
+#[cfg(feature = "https")]
+use openssl::ssl::{SslConnector, SslConnectorBuilder, SslMethod, SslStream, HandshakeError};
+
+use helpers::{is_valid_host, remaining};
+use options::FailureReason;
+use persist::TargetManager;
+use worker::{run_worker, Worker, ResultsSender};
+
+/**
+ * The schemes of the URLs that can be measured.
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Scheme {
+    Http,
+    Https,
+}
+
+impl Scheme {
+    fn default_port(&self) -> u16 {
+        match *self {
+            Scheme::Http => 80,
+            Scheme::Https => 443,
+        }
+    }
+}
+
+/**
+ * Splits a URL of the form `http[s]://host[:port][/path]` into its scheme,
+ * host (without the brackets of an IPv6 address, e.g. `http://[::1]:8080/`),
+ * port and path.
+ */
+fn parse_url(url: &str) -> Option<(Scheme, &str, u16, &str)> {
+    let (scheme, rest) = if url.starts_with("http://") {
+        (Scheme::Http, &url["http://".len()..])
+    } else if url.starts_with("https://") {
+        (Scheme::Https, &url["https://".len()..])
+    } else {
+        return None;
+    };
+
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+
+    // the port (if any) follows the closing bracket of an IPv6 address
+    let (host, port) = if authority.starts_with('[') {
+        match authority.find(']') {
+            Some(i) => (&authority[1..i], &authority[i + 1..]),
+            None => return None,
+        }
+    } else {
+        match authority.rfind(':') {
+            Some(i) => (&authority[..i], &authority[i..]),
+            None => (authority, ""),
+        }
+    };
+    let port = if port.is_empty() {
+        scheme.default_port()
+    } else if port.starts_with(':') {
+        match port[1..].parse() {
+            Ok(port) => port,
+            Err(_) => return None,
+        }
+    } else {
+        return None;
+    };
+
+    if host.is_empty() {
+        None
+    } else {
+        Some((scheme, host, port, path))
+    }
+}
+
+/**
+ * Gives the value of the `Host` header for the given host and port: the host
+ * (in brackets if an IPv6 address), and the port unless it's the default for
+ * the given scheme.
+ */
+fn host_header(scheme: Scheme, host: &str, port: u16) -> String {
+    let host = if host.contains(':') { format!("[{}]", host) } else { host.to_owned() };
+    if port == scheme.default_port() {
+        host
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/**
+ * Determines whether the given (beginning of an) HTTP response has a success
+ * (2xx) or redirect (3xx) status code.
+ */
+fn is_success_status(head: &[u8]) -> bool {
+    let status_line = match str::from_utf8(head).ok().and_then(|s| s.lines().next()) {
+        Some(l) => l,
+        None => return false,
+    };
+
+    match status_line.split_whitespace().nth(1).and_then(|c| c.parse::<u16>().ok()) {
+        Some(code) => code >= 200 && code < 400,
+        None => false,
+    }
+}
+
+/**
+ * A connection a request can be sent over, i.e. a TCP stream, either as-is
+ * or with TLS on top.
+ */
+trait Connection: Read + Write {
+    /**
+     * The underlying TCP stream (e.g. to set timeouts on).
+     */
+    fn tcp(&self) -> &TcpStream;
+}
+
+impl Connection for TcpStream {
+    fn tcp(&self) -> &TcpStream {
+        self
+    }
+}
+
+#[cfg(feature = "https")]
+impl Connection for SslStream<TcpStream> {
+    fn tcp(&self) -> &TcpStream {
+        self.get_ref()
+    }
+}
+
+/**
+ * Sends the given request over the given connection, and gives the time (in
+ * microseconds) from the given start time (see `precise_time_ns()`) to the
+ * first byte of the response, giving up once the given timeout (since the
+ * start) has passed. Responses without a success or redirect status count as
+ * failures (for other reasons).
+ */
+fn time_to_first_byte<C: Connection>(conn: &mut C, request: &str, start: u64,
+                                     timeout: Duration) -> Result<i32, FailureReason> {
+    let left = match remaining(start, timeout) {
+        Some(d) => d,
+        None => return Err(FailureReason::TimedOut),
+    };
+    if conn.tcp().set_write_timeout(Some(left)).is_err() {
+        return Err(FailureReason::Other);
+    }
+    if let Err(e) = conn.write_all(request.as_bytes()) {
+        return Err(FailureReason::of_error(&e));
+    }
+
+    /*
+     * the time to first byte is when the first read comes back, but keep
+     * reading until we have the whole status line so we can check it
+     */
+    let mut head = Vec::with_capacity(512);
+    let mut buf = [0u8; 512];
+    let mut ttfb = None;
+    while !head.windows(2).any(|w| w == b"\r\n") && head.len() < 4096 {
+        let left = match remaining(start, timeout) {
+            Some(d) => d,
+            None => return Err(FailureReason::TimedOut),
+        };
+        if conn.tcp().set_read_timeout(Some(left)).is_err() {
+            return Err(FailureReason::Other);
+        }
+        match conn.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                if ttfb.is_none() {
+                    ttfb = Some(precise_time_ns() - start);
+                }
+                head.extend_from_slice(&buf[..n]);
+            },
+            Err(ref e) if ttfb.is_none() => return Err(FailureReason::of_error(e)),
+            Err(_) => break,
+        }
+    }
+
+    match ttfb {
+        Some(t) if is_success_status(&head) => Ok((t / 1000) as i32),
+        _ => Err(FailureReason::Other),
+    }
+}
+
+/**
+ * What HTTPS URLs are measured with: a TLS connector verifying certificates
+ * (and hostnames) against the system's trusted roots.
+ */
+#[cfg(feature = "https")]
+struct Tls(SslConnector);
+
+/**
+ * (Never constructed, as HTTPS URLs aren't measured without the `https`
+ * feature.)
+ */
+#[cfg(not(feature = "https"))]
+enum Tls {}
+
+impl Tls {
+    #[cfg(feature = "https")]
+    fn new() -> Option<Tls> {
+        match SslConnectorBuilder::new(SslMethod::tls()) {
+            Ok(builder) => Some(Tls(builder.build())),
+            Err(e) => {
+                error!("failed to set up TLS, so HTTPS addrs will fail error=\"{}\"", e);
+                None
+            },
+        }
+    }
+
+    #[cfg(not(feature = "https"))]
+    fn new() -> Option<Tls> {
+        None
+    }
+
+    /**
+     * Makes a TLS connection to the given host over the given stream, then
+     * measures the time to first byte of the given request over it (as for
+     * `time_to_first_byte()`, including the handshake). Also gives how long
+     * (in microseconds) the handshake itself took.
+     */
+    #[cfg(feature = "https")]
+    fn time_to_first_byte(&self, host: &str, stream: TcpStream, request: &str, start: u64,
+                          timeout: Duration) -> Result<(i32, i32), FailureReason> {
+        let left = match remaining(start, timeout) {
+            Some(d) => d,
+            None => return Err(FailureReason::TimedOut),
+        };
+        if stream.set_read_timeout(Some(left)).is_err() || stream.set_write_timeout(Some(left)).is_err() {
+            return Err(FailureReason::Other);
+        }
+
+        let handshake_start = precise_time_ns();
+        let mut conn = match self.0.connect(host, stream) {
+            Ok(c) => c,
+            // (a handshake cut short by the timeout is interrupted, as if the stream were non-blocking)
+            Err(HandshakeError::Interrupted(_)) => return Err(FailureReason::TimedOut),
+            Err(HandshakeError::Failure(ref mid)) if mid.ssl().verify_result().is_some() => {
+                return Err(FailureReason::Certificate);
+            },
+            Err(_) => return Err(FailureReason::Other),
+        };
+        let handshake = ((precise_time_ns() - handshake_start) / 1000) as i32;
+
+        time_to_first_byte(&mut conn, request, start, timeout).map(|ttfb| (ttfb, handshake))
+    }
+
+    #[cfg(not(feature = "https"))]
+    fn time_to_first_byte(&self, _: &str, _: TcpStream, _: &str, _: u64,
+                          _: Duration) -> Result<(i32, i32), FailureReason> {
+        match *self {}
+    }
+}
+
+/**
+ * Measures the time to first byte (in microseconds) of a GET request to the
+ * given URL, giving up after the given timeout. HTTPS URLs are measured over
+ * the given TLS connector (and fail without one), also giving how long their
+ * handshake took. Otherwise gives why the measurement failed.
+ */
+fn httpping(addr: &str, timeout: Duration, tls: Option<&Tls>) -> Result<(i32, Option<i32>), FailureReason> {
+    let (scheme, host, port, path) = match parse_url(addr) {
+        Some(parts) => parts,
+        None => return Err(FailureReason::Other),
+    };
+
+    let sock_addr = match (host, port).to_socket_addrs().ok().and_then(|mut i| i.next()) {
+        Some(sa) => sa,
+        None => return Err(FailureReason::Unresolved),
+    };
+
+    let start = precise_time_ns();
+    let mut stream = match TcpStream::connect_timeout(&sock_addr, timeout) {
+        Ok(s) => s,
+        Err(e) => return Err(FailureReason::of_error(&e)),
+    };
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: stabping\r\nConnection: close\r\n\r\n",
+        path, host_header(scheme, host, port)
+    );
+    match (scheme, tls) {
+        (Scheme::Http, _) => time_to_first_byte(&mut stream, &request, start, timeout).map(|t| (t, None)),
+        (Scheme::Https, Some(tls)) => {
+            tls.time_to_first_byte(host, stream, &request, start, timeout).map(|(t, h)| (t, Some(h)))
+        },
+        (Scheme::Https, None) => Err(FailureReason::Other),
+    }
+}
+
+/**
+ * Checks (syntactically, without looking anything up) that the given address
+ * is a valid URL (see `parse_url()`), of a scheme that can be measured.
+ */
+pub fn is_valid_addr(addr: &str) -> bool {
+    match parse_url(addr) {
+        Some((Scheme::Https, _, _, _)) if !cfg!(feature = "https") => false,
+        Some((_, host, _, _)) => is_valid_host(host),
+        None => false,
+    }
+}
+
+/**
+ * Runs the HTTP Ping target's data-collection worker.
+ */
+pub fn run_httpping_worker(manager: Arc<TargetManager>,
+                           results_out: ResultsSender) -> Worker {
+    let m = manager.clone();
+    let tls = Tls::new();
+    run_worker(manager, results_out, move |addr: &str, timeout: Duration| {
+        match httpping(addr, timeout, tls.as_ref()) {
+            Ok((val, handshake)) => {
+                if let Some(micros) = handshake {
+                    m.record_handshake(addr, micros);
+                }
+                Some(val)
+            },
+            Err(reason) => {
+                m.record_failure(addr, reason);
+                None
+            },
+        }
+    })
+}
+
+#[test]
+fn parse_url_splits_host_port_path() {
+    assert_eq!(parse_url("http://example.com"), Some((Scheme::Http, "example.com", 80, "/")));
+    assert_eq!(parse_url("http://example.com:8080/a/b?c"), Some((Scheme::Http, "example.com", 8080, "/a/b?c")));
+    assert_eq!(parse_url("http://[::1]:8080/a"), Some((Scheme::Http, "::1", 8080, "/a")));
+    assert_eq!(parse_url("http://[::1]/"), Some((Scheme::Http, "::1", 80, "/")));
+    assert_eq!(parse_url("https://example.com/"), Some((Scheme::Https, "example.com", 443, "/")));
+    assert_eq!(parse_url("https://[::1]:8443"), Some((Scheme::Https, "::1", 8443, "/")));
+    assert_eq!(parse_url("ftp://example.com/"), None);
+    assert_eq!(parse_url("http://:80/"), None);
+    assert_eq!(parse_url("http://[::1/"), None);
+    assert_eq!(parse_url("http://[::1]8080/"), None);
+}
+
+#[test]
+fn host_header_gives_port_unless_default() {
+    assert_eq!(host_header(Scheme::Http, "example.com", 80), "example.com");
+    assert_eq!(host_header(Scheme::Http, "example.com", 8080), "example.com:8080");
+    assert_eq!(host_header(Scheme::Http, "::1", 8080), "[::1]:8080");
+    assert_eq!(host_header(Scheme::Http, "::1", 80), "[::1]");
+    assert_eq!(host_header(Scheme::Https, "example.com", 443), "example.com");
+    assert_eq!(host_header(Scheme::Https, "example.com", 80), "example.com:80");
+}
+
+#[test]
+fn addrs_are_validated() {
+    assert!(is_valid_addr("http://example.com/"));
+    assert!(is_valid_addr("http://[::1]:8080/"));
+    assert!(is_valid_addr("http://[::1]/"));
+    assert_eq!(is_valid_addr("https://example.com/"), cfg!(feature = "https"));
+    assert!(!is_valid_addr("example.com"));
+    assert!(!is_valid_addr("http://exa mple.com/"));
+}
+
+#[test]
+fn responses_are_timed_and_checked() {
+    use std::net::TcpListener;
+    use std::thread;
+
+    let timeout = Duration::from_millis(1000);
+    for &(status, expected_ok) in [("200 OK", true), ("301 Moved Permanently", true),
+                                   ("500 Internal Server Error", false)].iter() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://127.0.0.1:{}/", listener.local_addr().unwrap().port());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 512];
+            stream.read(&mut buf).unwrap();
+            stream.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes()).unwrap();
+        });
+
+        match httpping(&url, timeout, None) {
+            Ok((_, None)) if expected_ok => (),
+            Err(FailureReason::Other) if !expected_ok => (),
+            r => panic!("Unexpected result {:?} for status {}", r, status),
+        }
+        server.join().unwrap();
+    }
+
+    // and failures to connect give why
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let url = format!("http://127.0.0.1:{}/", port);
+    assert_eq!(httpping(&url, timeout, None), Err(FailureReason::Refused));
+}
@@ -0,0 +1,312 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * The ICMP Ping target, measuring the round-trip time of ICMP echo requests
+ * sent over a raw socket.
+ */
+use std::io;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use std::time::Duration;
+use time::precise_time_ns;
+
+use std::net::{Ipv4Addr, IpAddr, ToSocketAddrs};
+
+use helpers::is_valid_host;
+use persist::{TargetManager, ManagerError};
+use worker::{run_worker, Worker, ResultsSender};
+
+static ICMP_ECHO_REQUEST: u8 = 8;
+static ICMP_ECHO_REPLY: u8 = 0;
+
+// sequence numbers handed out to echo requests, shared by all probes
+static NEXT_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/**
+ * Computes the internet checksum (RFC 1071) of the given bytes.
+ */
+fn checksum(buf: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in buf.chunks(2) {
+        let word = if chunk.len() == 2 {
+            ((chunk[0] as u32) << 8) | chunk[1] as u32
+        } else {
+            (chunk[0] as u32) << 8
+        };
+        sum = sum.wrapping_add(word);
+    }
+    while (sum >> 16) != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/**
+ * Builds an ICMP echo request packet with the given identifier and sequence
+ * number.
+ */
+fn echo_request(ident: u16, seq: u16) -> Vec<u8> {
+    let mut packet = vec![
+        ICMP_ECHO_REQUEST, 0, 0, 0,
+        (ident >> 8) as u8, ident as u8,
+        (seq >> 8) as u8, seq as u8,
+    ];
+    packet.extend(b"stabping-icmping");
+
+    let sum = checksum(&packet);
+    packet[2] = (sum >> 8) as u8;
+    packet[3] = sum as u8;
+    packet
+}
+
+/**
+ * Determines whether the given received datagram (including its IPv4 header)
+ * is the echo reply to the request with the given identifier and sequence
+ * number.
+ */
+fn is_echo_reply(buf: &[u8], ident: u16, seq: u16) -> bool {
+    if buf.is_empty() {
+        return false;
+    }
+
+    // skip past the IPv4 header, the length of which is in the low nibble
+    let ihl = ((buf[0] & 0x0f) as usize) * 4;
+    if buf.len() < ihl + 8 {
+        return false;
+    }
+
+    let icmp = &buf[ihl..];
+    icmp[0] == ICMP_ECHO_REPLY
+        && (((icmp[4] as u16) << 8) | icmp[5] as u16) == ident
+        && (((icmp[6] as u16) << 8) | icmp[7] as u16) == seq
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::io;
+    use std::mem;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use libc;
+
+    // the protocol number of ICMP is the same across platforms
+    const IPPROTO_ICMP: libc::c_int = 1;
+
+    /**
+     * A minimal wrapper around a raw IPv4 ICMP socket.
+     */
+    pub struct IcmpSocket(libc::c_int);
+
+    impl IcmpSocket {
+        /**
+         * Opens a new raw ICMP socket. This usually requires elevated
+         * privileges.
+         */
+        pub fn new() -> io::Result<Self> {
+            let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_RAW, IPPROTO_ICMP) };
+            if fd < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(IcmpSocket(fd))
+            }
+        }
+
+        pub fn set_read_timeout(&self, dur: Duration) -> io::Result<()> {
+            let mut tv = libc::timeval {
+                tv_sec: dur.as_secs() as libc::time_t,
+                tv_usec: (dur.subsec_nanos() / 1000) as libc::suseconds_t,
+            };
+            // a zero timeout would mean to block forever
+            if tv.tv_sec == 0 && tv.tv_usec == 0 {
+                tv.tv_usec = 1;
+            }
+            let ret = unsafe {
+                libc::setsockopt(self.0, libc::SOL_SOCKET, libc::SO_RCVTIMEO,
+                                 &tv as *const _ as *const libc::c_void,
+                                 mem::size_of::<libc::timeval>() as libc::socklen_t)
+            };
+            if ret < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        }
+
+        pub fn send_to(&self, buf: &[u8], addr: &Ipv4Addr) -> io::Result<usize> {
+            let ret = unsafe {
+                let mut sin: libc::sockaddr_in = mem::zeroed();
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                let o = addr.octets();
+                sin.sin_addr = libc::in_addr {
+                    s_addr: ((o[0] as u32) << 24 | (o[1] as u32) << 16
+                             | (o[2] as u32) << 8 | o[3] as u32).to_be(),
+                };
+                libc::sendto(self.0, buf.as_ptr() as *const libc::c_void, buf.len(), 0,
+                             &sin as *const _ as *const libc::sockaddr,
+                             mem::size_of::<libc::sockaddr_in>() as libc::socklen_t)
+            };
+            if ret < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(ret as usize)
+            }
+        }
+
+        pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            let ret = unsafe {
+                libc::recv(self.0, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0)
+            };
+            if ret < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(ret as usize)
+            }
+        }
+    }
+
+    impl Drop for IcmpSocket {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.0); }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::io;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    /**
+     * Placeholder for platforms where raw ICMP sockets are not supported.
+     */
+    pub struct IcmpSocket;
+
+    impl IcmpSocket {
+        pub fn new() -> io::Result<Self> {
+            Err(io::Error::new(io::ErrorKind::Other,
+                               "raw ICMP sockets are not supported on this platform"))
+        }
+
+        pub fn set_read_timeout(&self, _: Duration) -> io::Result<()> {
+            unreachable!()
+        }
+
+        pub fn send_to(&self, _: &[u8], _: &Ipv4Addr) -> io::Result<usize> {
+            unreachable!()
+        }
+
+        pub fn recv(&self, _: &mut [u8]) -> io::Result<usize> {
+            unreachable!()
+        }
+    }
+}
+
+use self::sys::IcmpSocket;
+
+/**
+ * Resolves a host (either an IPv4 address or a DNS name) to an IPv4 address.
+ */
+fn resolve_v4(host: &str) -> Option<Ipv4Addr> {
+    let addrs = match (host, 0).to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(_) => return None,
+    };
+    for sa in addrs {
+        if let IpAddr::V4(ip) = sa.ip() {
+            return Some(ip);
+        }
+    }
+    None
+}
+
+/**
+ * Measures the round-trip time (in microseconds) of an ICMP echo request to
+ * the given host, giving up after the given timeout.
+ */
+fn icmpping(addr: &str, timeout: Duration) -> Option<i32> {
+    let ip = match resolve_v4(addr) {
+        Some(ip) => ip,
+        None => return None,
+    };
+
+    let socket = match IcmpSocket::new() {
+        Ok(s) => s,
+        Err(_) => return None,
+    };
+
+    let ident = process::id() as u16;
+    let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed) as u16;
+    let packet = echo_request(ident, seq);
+
+    let timeout_ns = timeout.as_secs() * 1_000_000_000 + timeout.subsec_nanos() as u64;
+    let start = precise_time_ns();
+    if socket.send_to(&packet, &ip).is_err() {
+        return None;
+    }
+
+    /*
+     * a raw socket receives every ICMP packet arriving at this host, so keep
+     * reading until we see the reply to our own request or we run out of time
+     */
+    let mut buf = [0u8; 1024];
+    loop {
+        let elapsed = precise_time_ns() - start;
+        if elapsed >= timeout_ns {
+            return None;
+        }
+        let remaining = timeout_ns - elapsed;
+        let remaining = Duration::new(remaining / 1_000_000_000,
+                                      (remaining % 1_000_000_000) as u32);
+        if socket.set_read_timeout(remaining).is_err() {
+            return None;
+        }
+
+        match socket.recv(&mut buf) {
+            Ok(n) => {
+                if is_echo_reply(&buf[..n], ident, seq) {
+                    return Some(((precise_time_ns() - start) / 1000) as i32);
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(_) => return None,
+        }
+    }
+}
+
+/**
+ * Checks (syntactically, without looking anything up) that the given address
+ * is a valid host (an IPv4 address or a DNS name).
+ */
+pub fn is_valid_addr(addr: &str) -> bool {
+    is_valid_host(addr) && !addr.contains(':')
+}
+
+/**
+ * Runs the ICMP Ping target's data-collection worker.
+ *
+ * Fails with `ManagerError::RawSocketUnavailable` if we are not able to open
+ * a raw socket (e.g. we are lacking the privileges to do so), as otherwise
+ * every datapoint we collect would silently be an error.
+ */
+pub fn run_icmpping_worker(manager: Arc<TargetManager>,
+                           results_out: ResultsSender)
+                           -> Result<Worker, ManagerError> {
+    try!(IcmpSocket::new().map_err(|e| ManagerError::RawSocketUnavailable(e)));
+    Ok(run_worker(manager, results_out, icmpping))
+}
+
+#[test]
+fn echo_request_checksum_verifies() {
+    // a packet containing its own correct checksum sums to zero
+    assert_eq!(checksum(&echo_request(0x1234, 7)), 0);
+}
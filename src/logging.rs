@@ -0,0 +1,52 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * A minimal logger for the `log` crate, writing log events (at or above the
+ * level given by the `STABPING_LOG` environment variable, `info` if not
+ * given) to stderr, one per line.
+ */
+use std::env;
+use std::io::{self, Write};
+
+use chrono::Local;
+use log::{self, Log, LogLevelFilter, LogMetadata, LogRecord, SetLoggerError};
+
+pub static LOG_LEVEL_VAR: &'static str = "STABPING_LOG";
+
+struct StderrLogger {
+    level: LogLevelFilter,
+}
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if self.enabled(record.metadata()) {
+            let _ = writeln!(io::stderr(), "{} {:<5} {}: {}",
+                             Local::now().to_rfc3339(), record.level(),
+                             record.target(), record.args());
+        }
+    }
+}
+
+/**
+ * Installs the logger (see the module documentation). Can only be done once.
+ */
+pub fn init() -> Result<(), SetLoggerError> {
+    let level = env::var(LOG_LEVEL_VAR).ok()
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(LogLevelFilter::Info);
+
+    log::set_logger(|max_level| {
+        max_level.set(level);
+        Box::new(StderrLogger { level: level })
+    })
+}
@@ -10,18 +10,35 @@ extern crate chrono;
 extern crate time;
 extern crate rustc_serialize;
 extern crate memmap;
+#[cfg(feature = "wire-compression")]
+extern crate flate2;
+#[cfg(feature = "https")]
+extern crate openssl;
+extern crate libc;
+#[macro_use]
+extern crate log;
 extern crate ws;
 extern crate iron;
 extern crate router;
 extern crate mount;
 
 mod helpers;
+mod clock;
+mod logging;
 mod options;
 mod persist;
+mod datafile;
 mod reader;
 mod webserver;
 mod wsserver;
+mod worker;
 mod tcpping;
+mod icmpping;
+mod httpping;
+mod dnsping;
+mod udpping;
+#[cfg(all(unix, feature = "command-socket"))]
+mod cmdserver;
 
 use std::env;
 use std::path::PathBuf;
@@ -29,18 +46,29 @@ use std::fs;
 use std::fs::{OpenOptions, File};
 use std::sync::Arc;
 use std::sync::RwLock;
-use std::sync::mpsc::channel;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Local;
 
 use rustc_serialize::json;
 
 use wsserver::Broadcaster;
 
-use helpers::{SPIOError, SPFile, VecIntoRawBytes};
-use options::{TargetKind, MainConfiguration};
-use persist::ManagerError;
+use helpers::{SPIOError, SPFile, VecIntoWireBytes, recover};
+use options::{TargetKind, MainConfiguration, AGGREGATE_INTERVAL, PRUNE_INTERVAL};
+use persist::{ManagerSet, ManagerError};
+use worker::{Worker, results_channel, run_periodically};
 
 static CONFIG_FILENAME: &'static str = "stabping_config.json";
 
+// set (by `handle_shutdown_signal()`) once stabping has been asked to shut down
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// time (in millis) between checks of whether stabping has been asked to shut down
+static SHUTDOWN_CHECK_INTERVAL: u64 = 100;
+
 /**
  * Attempts to discover the configuration file and associated data directory.
  *
@@ -132,6 +160,10 @@ fn get_configuration() -> Option<(Arc<RwLock<MainConfiguration>>, PathBuf)> {
 }
 
 fn main() {
+    if let Err(e) = logging::init() {
+        panic!("Failed to set up logging: {}", e);
+    }
+
     // try and obtain our configuration and data directory path
     let (configuration, data_path) = match get_configuration() {
         Some(c) => c,
@@ -141,10 +173,21 @@ fn main() {
     };
 
     // create managers for all the targets
-    let targets = match TargetKind::new_managers_for_all(&data_path) {
+    let (data_file_options, sync_metadata, recover_corrupt, recent_capacity, max_addrs, reuse_tombstones) = {
+        let c = recover(configuration.read());
+        (c.data_file_options(), c.sync_metadata_files(), c.recover_corrupt_files(), c.recent_capacity(),
+         c.max_addrs(), c.reuse_index_tombstones())
+    };
+    let targets = match ManagerSet::new(TargetKind::all(), &data_path,
+                                        data_file_options, sync_metadata, recover_corrupt) {
         Ok(targets) => targets,
         Err(e) => handle_fatal_error(e),
     };
+    for tm in targets.iter() {
+        tm.set_recent_capacity(recent_capacity);
+        tm.set_max_addrs(max_addrs);
+        tm.set_reuse_tombstones(reuse_tombstones);
+    }
 
     // create a broadcaster to be initialized with the websockets server
     let broadcaster = Arc::new(Broadcaster::new());
@@ -152,34 +195,144 @@ fn main() {
     // start the web and websockets servers
     webserver::web_server(configuration.clone(), targets.iter());
     wsserver::ws_server(configuration.clone(), broadcaster.clone());
+    let ws_frames = recover(configuration.read()).ws_frames();
+    if ws_frames {
+        for tm in targets.iter() {
+            wsserver::forward_live(tm, broadcaster.clone());
+        }
+    }
+    command_server(&configuration, &targets);
 
     /*
-     * start the workers for all the targets, passing them one end of an MPSC
-     * communications channel so that we can receive all the data
+     * start the workers for all the targets, passing them one end of a
+     * (bounded) communications channel so that we can receive all the data
      */
-    let (sender, results) = channel();
+    let (sender, results) = {
+        let c = recover(configuration.read());
+        results_channel(c.results_capacity(), c.results_backpressure())
+    };
+    let mut workers = Vec::new();
     for tm in targets.iter() {
-        tm.kind.run_worker(tm.clone(), sender.clone());
+        match tm.kind.run_worker(tm.clone(), sender.clone()) {
+            Ok(worker) => workers.push(worker),
+            /*
+             * a worker that can't start shouldn't prevent the others from
+             * collecting data, so just let the user know
+             */
+            Err(e) => error!("failed to start worker kind={} error=\"{}\"", tm.kind.compact_name(), e),
+        }
     }
+    // (the workers hold the only senders left, so the results end with them)
+    drop(sender);
+    stop_workers_on_signal(workers);
+
+    // periodically drop data older than the targets' retention
+    let pruned_targets = targets.clone();
+    let pruner = run_periodically(Duration::from_secs(PRUNE_INTERVAL), move || {
+        let now = Local::now().timestamp();
+        if let Err(e) = pruned_targets.prune_expired(now) {
+            error!("failed to prune data error=\"{}\"", e);
+        }
+    });
+
+    // periodically average (etc.) newly completed windows of data
+    let aggregator = targets.spawn_aggregator(Duration::from_secs(AGGREGATE_INTERVAL));
 
     /*
-     * receive the live data coming from the workers and process it
+     * receive the live data coming from the workers and process it, until
+     * the workers have all stopped (see `stop_workers_on_signal()`)
      */
     for r in results {
-        // detect which target kind these data are for
-        let kind_id = r.0[0];
-
         // append the data to the data file via the appropriate manager
-        if let Err(e) = targets[kind_id as usize].append_data(&r) {
+        if let Err(e) = targets.append_data(&r) {
+            handle_fatal_error(e);
+        }
+
+        // write out everything appended this round in one go
+        let tm = match targets.get(r.0[0]) {
+            Ok(tm) => tm,
+            Err(e) => handle_fatal_error(e),
+        };
+        if let Err(e) = tm.flush() {
             handle_fatal_error(e);
         }
 
-        // broadcast the live data over websockets
-        let raw_data_bytes = r.0.into_raw_bytes();
-        let _ = broadcaster.send(raw_data_bytes);
+        // broadcast the live data over websockets (unless forwarded framed)
+        if !ws_frames {
+            let data_bytes = r.0.into_wire_bytes();
+            let _ = broadcaster.send(data_bytes);
+        }
+    }
+
+    // let the pruner and aggregator finish what they're doing, and stop
+    for worker in vec![pruner, aggregator] {
+        worker.stop();
+        if worker.join().is_err() {
+            error!("failed to stop background worker cleanly");
+        }
+    }
+
+    // (the servers' threads still hold on to the managers, so they're never dropped)
+    if let Err(e) = targets.flush_all() {
+        error!("failed to flush on shutdown error=\"{}\"", e);
+    }
+    info!("stopped collecting data, shutting down");
+}
+
+extern "C" fn handle_shutdown_signal(_: libc::c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/**
+ * Starts a thread that waits until stabping is asked to shut down (by SIGINT
+ * or SIGTERM), and then stops the given workers, letting each finish the
+ * round it's collecting. Once they've all stopped, and their last results
+ * have been received, the results channel ends.
+ */
+fn stop_workers_on_signal(workers: Vec<Worker>) -> thread::JoinHandle<()> {
+    unsafe {
+        libc::signal(libc::SIGINT, handle_shutdown_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handle_shutdown_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+
+    thread::spawn(move || {
+        while !SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(SHUTDOWN_CHECK_INTERVAL));
+        }
+
+        info!("asked to shut down, stopping workers");
+        for worker in workers.iter() {
+            worker.stop();
+        }
+        for worker in workers {
+            if worker.join().is_err() {
+                error!("worker panicked while stopping");
+            }
+        }
+    })
+}
+
+/**
+ * Starts the command server (see `cmdserver`) for the given targets, if a
+ * socket for it is given in the configuration.
+ */
+#[cfg(all(unix, feature = "command-socket"))]
+fn command_server(configuration: &Arc<RwLock<MainConfiguration>>, targets: &ManagerSet) {
+    if let Some(ref path) = recover(configuration.read()).command_socket {
+        if let Err(e) = cmdserver::command_server(path, targets.clone()) {
+            error!("failed to start command server path={} error=\"{}\"", path, e);
+        }
+    }
+}
+
+#[cfg(not(all(unix, feature = "command-socket")))]
+fn command_server(configuration: &Arc<RwLock<MainConfiguration>>, _: &ManagerSet) {
+    if recover(configuration.read()).command_socket.is_some() {
+        warn!("command_socket is given, but stabping was built without the command-socket feature");
     }
 }
 
 fn handle_fatal_error(e: ManagerError) -> ! {
+    error!("fatal error=\"{}\"", e);
     panic!("{}", e);
 }
@@ -6,91 +6,497 @@
  * details.
  */
 
-use std::path::Path;
-use std::thread;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io;
 use std::sync::Arc;
-use std::sync::mpsc::Sender;
 
-use persist::{TargetManager, ManagerError};
-use tcpping::run_tcpping_worker;
+use libc;
 
-#[derive(RustcEncodable, RustcDecodable, Debug)]
+use persist::{TargetManager, ManagerError, Feed};
+use datafile::DataFileOptions;
+use worker::{Worker, ResultsSender, Backpressure};
+use tcpping::{self, run_tcpping_worker};
+use icmpping::{self, run_icmpping_worker};
+use httpping::{self, run_httpping_worker};
+use dnsping::{self, run_dnsping_worker};
+use udpping::{self, run_udpping_worker};
+
+#[derive(RustcEncodable, RustcDecodable, Clone, PartialEq, Debug)]
 pub struct TargetOptions {
     pub nonce: i32,
     pub addrs: Vec<String>,  // Vec of addresses (IPs to hit with TCP, files to download, etc.)
     pub interval: u32,  // interval between collection attempts, in millis
     pub avg_across: u32,  // number of sub-attempts average across for each interval
     pub pause: u32,  // pause between sub-attempts, in millis
+    pub timeout: Option<u32>,  // longest each sub-attempt may take, in millis (a share of interval if not given)
+    pub labels: Option<BTreeMap<String, String>>,  // human-readable labels for (some) addrs
+    pub addr_intervals: Option<BTreeMap<String, u32>>,  // per-addr overrides of interval, in millis
+    pub retention: Option<u32>,  // how long to keep data for, in seconds (forever if not given)
+    pub prefer_ipv6: Option<bool>,  // whether to connect over IPv6 when hostnames have both families
+    pub addr_ports: Option<BTreeMap<String, Vec<u16>>>,  // hosts to add to addrs once for each port
+    pub retry_attempts: Option<u32>,  // attempts at each measurement before it fails (1 if not given)
+    pub retry_backoff: Option<u32>,  // pause before the first retry (doubling after each), in millis
+    pub maintenance: Option<Vec<MaintenanceWindow>>,  // windows of time during which nothing is measured
+    pub resolve_every: Option<u32>,  // measurements after which hostnames are looked up again
+    pub resolve_ttl: Option<u32>,  // seconds after which hostnames are looked up again
+    pub max_concurrent_probes: Option<u32>,  // most measurements to take at once (unlimited if not given)
+    pub record_failure_reasons: Option<bool>,  // whether to record why each failed measurement failed
+    pub feeds_enabled: Option<BTreeMap<String, bool>>,  // whether each derived feed (by name) is kept up to date
+    pub spread_measurements: Option<bool>,  // whether to spread the addrs' measurements out over each round
+}
+
+/**
+ * A window of time (e.g. planned maintenance) during which a target's addrs
+ * aren't measured, and so nothing (not even errors) is recorded for them.
+ */
+#[derive(RustcEncodable, RustcDecodable, Clone, PartialEq, Debug)]
+pub struct MaintenanceWindow {
+    pub start: i64,  // when the window (first) begins, in seconds from epoch
+    pub end: i64,  // when the window (first) ends, in seconds from epoch
+    pub every: Option<u32>,  // how often (in seconds) the window recurs after that, if at all
+}
+
+impl MaintenanceWindow {
+    /**
+     * Determines whether the given time (in seconds from epoch) falls within
+     * this window (or any of its recurrences).
+     */
+    pub fn contains(&self, time: i64) -> bool {
+        if time < self.start {
+            return false;
+        }
+        match self.every {
+            Some(every) if every > 0 => (time - self.start) % (every as i64) < self.end - self.start,
+            _ => time < self.end,
+        }
+    }
+}
+
+impl TargetOptions {
+    /**
+     * Gets the interval (in millis) between collection attempts for the given
+     * addr, taking into account any per-addr override.
+     */
+    pub fn addr_interval(&self, addr: &str) -> u32 {
+        self.addr_intervals.as_ref()
+            .and_then(|m| m.get(addr).cloned())
+            .unwrap_or(self.interval)
+    }
+
+    /**
+     * Determines whether the given (derived) feed is kept up to date, i.e.
+     * whether it hasn't been turned off in `feeds_enabled`.
+     */
+    pub fn feed_enabled(&self, feed: Feed) -> bool {
+        self.feeds_enabled.as_ref()
+            .and_then(|m| m.get(feed.compact_name()).cloned())
+            .unwrap_or(true)
+    }
+
+    /**
+     * Determines whether the given time (in seconds from epoch) falls within
+     * any of the maintenance windows of these options.
+     */
+    pub fn in_maintenance(&self, time: i64) -> bool {
+        self.maintenance.as_ref().map_or(false, |ws| ws.iter().any(|w| w.contains(time)))
+    }
+
+    /**
+     * Checks that these options make sense for the given kind of target,
+     * returning the problem if they don't.
+     */
+    pub fn validate(&self, kind: &TargetKind) -> Result<(), ManagerError> {
+        /*
+         * as addrs are only looked up when measured, make sure they at least
+         * make sense (rather than being silently measured as errors forever)
+         */
+        if let Some(addr) = self.addrs.iter().find(|a| !kind.is_valid_addr(a)) {
+            // (HTTP Ping only measures over TLS with the https feature, but is bound to be given such URLs)
+            if addr.starts_with("https://") && !cfg!(feature = "https") {
+                return Err(ManagerError::InvalidOptions(
+                    format!("HTTPS is not supported, so '{}' can't be measured \
+                             (stabping was built without the https feature)", addr)));
+            }
+            return Err(ManagerError::InvalidAddrArgument(addr.clone()));
+        }
+
+        /*
+         * too short an interval would have the worker spinning (and flooding
+         * the addrs) rather than sleeping between collection attempts
+         */
+        if self.interval < MIN_INTERVAL {
+            return Err(ManagerError::InvalidInterval(self.interval));
+        }
+
+        if self.retention == Some(0) {
+            return Err(ManagerError::InvalidOptions("retention must be non-zero".to_owned()));
+        }
+
+        if let Some(attempts) = self.retry_attempts {
+            if attempts == 0 || attempts > MAX_RETRY_ATTEMPTS {
+                return Err(ManagerError::InvalidOptions(
+                    format!("retry_attempts must be between 1 and {}", MAX_RETRY_ATTEMPTS)));
+            }
+        }
+
+        if self.max_concurrent_probes == Some(0) {
+            return Err(ManagerError::InvalidOptions("max_concurrent_probes must be non-zero".to_owned()));
+        }
+
+        if self.resolve_every == Some(0) || self.resolve_ttl == Some(0) {
+            return Err(ManagerError::InvalidOptions("resolve_every and resolve_ttl must be non-zero".to_owned()));
+        }
+
+        if let Some(ref intervals) = self.addr_intervals {
+            for (addr, &interval) in intervals.iter() {
+                if !self.addrs.contains(addr) {
+                    return Err(ManagerError::InvalidOptions(
+                        format!("interval given for '{}', which is not in addrs", addr)));
+                }
+                if interval < MIN_INTERVAL {
+                    return Err(ManagerError::InvalidInterval(interval));
+                }
+            }
+        }
+
+        // only derived feeds are computed, and so can be turned off
+        if let Some(ref feeds) = self.feeds_enabled {
+            for name in feeds.keys() {
+                match Feed::from_compact_name(name) {
+                    Some(Feed::Raw) | Some(Feed::Failures) | Some(Feed::Handshakes) | None => return Err(ManagerError::InvalidOptions(
+                        format!("'{}' is not a derived feed that can be turned off", name))),
+                    _ => (),
+                }
+            }
+        }
+
+        if let Some(ref windows) = self.maintenance {
+            for w in windows.iter() {
+                let recurs_before_ending = w.every.map_or(false, |e| e as i64 <= w.end - w.start);
+                if w.end <= w.start || w.every == Some(0) || recurs_before_ending {
+                    return Err(ManagerError::InvalidOptions(
+                        "maintenance windows must end after they start, and before they recur".to_owned()));
+                }
+            }
+        }
+
+        // a measurement can't be allowed to run into the next one
+        if let Some(timeout) = self.timeout {
+            let shortest = self.addrs.iter().map(|a| self.addr_interval(a)).min().unwrap_or(self.interval);
+            if timeout == 0 || timeout > cmp::min(shortest, self.interval) {
+                return Err(ManagerError::InvalidOptions(
+                    format!("timeout must be between 1 and the shortest interval ({} ms)",
+                            cmp::min(shortest, self.interval))));
+            }
+        }
+        Ok(())
+    }
 }
 
 pub static SENTINEL_ERROR: i32 = -2_100_000_000;
 pub static SENTINEL_NODATA: i32 = -2_000_000_000;
 
+// smallest interval (in millis) between collection attempts we allow
+pub static MIN_INTERVAL: u32 = 100;
+
+// most attempts (including retries) allowed at each measurement
+pub static MAX_RETRY_ATTEMPTS: u32 = 10;
+
+// measurements after which TCP Ping looks hostnames up again, by default
+pub static DEFAULT_RESOLVE_EVERY: u32 = 100;
+
+// seconds after which TCP Ping looks hostnames up again, by default
+pub static DEFAULT_RESOLVE_TTL: u32 = 5 * 60;
+
+// most results from workers held while they wait to be stored, by default
+pub static DEFAULT_RESULTS_CAPACITY: usize = 64;
+
+// most recent records of each feed kept in memory for queries, by default
+pub static DEFAULT_RECENT_CAPACITY: usize = 4096;
+
+// most addrs a target may be given, by default
+pub static DEFAULT_MAX_ADDRS: usize = 1000;
+
+// how often (in seconds) to prune data older than targets' retention
+pub static PRUNE_INTERVAL: u64 = 60 * 60;
+
+// how often (in seconds) to compute the derived feeds from newly collected data
+pub static AGGREGATE_INTERVAL: u64 = 30;
+
+// length (in seconds) of the windows the averaged feed averages over
+pub static AVERAGED_WINDOW: i32 = 5 * 60;
+
 /*
  * Data for each address. Structured as:
- * [kind, nonce, timestamp, datapoint1, datapoint2, ...]
+ * [kind, nonce, timestamp_lo, timestamp_hi, datapoint1, datapoint2, ...]
  *
  * where kind is the kind_id for the TargetKind this result is coming from
  * where nonce determines the state of TargetOptions when these data were collected
- * where timestamp is in seconds from epoch,
+ * where timestamp is in seconds from epoch, as a 64-bit integer split into
+ * two (see `helpers::time_words()`),
  *
  * each datapoint is for each address in TargetOptions.addrs
  * (encoding of data inside the i32 is target-defined, or one of the
  * sentinel values for error or nodata),
+ *
+ * along with the time (in milliseconds from epoch) each datapoint was actually
+ * collected, in the same order (or empty if they were all collected at the
+ * timestamp).
+ */
+#[derive(Debug)]
+pub struct TargetResults(pub Vec<i32>, pub Vec<i64>);
+
+impl TargetResults {
+    /**
+     * Gets the datapoints (one for each address) of these results.
+     */
+    pub fn vals(&self) -> &[i32] {
+        if self.0.len() > 4 { &self.0[4..] } else { &[] }
+    }
+
+    /**
+     * Counts the addresses with an actual value in these results (neither
+     * errored nor without data).
+     */
+    pub fn alive_count(&self) -> usize {
+        self.vals().iter().filter(|&&v| v != SENTINEL_ERROR && v != SENTINEL_NODATA).count()
+    }
+
+    /**
+     * Counts the addresses whose measurement errored (or timed out) in these
+     * results. Addresses that weren't due (nodata) count as neither alive nor
+     * lost.
+     */
+    pub fn loss_count(&self) -> usize {
+        self.vals().iter().filter(|&&v| v == SENTINEL_ERROR).count()
+    }
+}
+
+/**
+ * The address family (IPv4 or IPv6) an address was last measured over.
  */
-pub struct TargetResults(pub Vec<i32>);
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AddrFamily {
+    V4,
+    V6,
+}
+
+impl AddrFamily {
+    /**
+     * Gets the short name of the address family (as in the `/v4` and `/v6`
+     * suffixes of addrs), e.g. for exports.
+     */
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AddrFamily::V4 => "v4",
+            AddrFamily::V6 => "v6",
+        }
+    }
+}
+
+/**
+ * Why a measurement failed, as far as can be told from the error it failed
+ * with.
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FailureReason {
+    Other,
+    // the host actively refused the connection
+    Refused,
+    // the host never answered in time
+    TimedOut,
+    // there was no route to the host (or its network)
+    Unreachable,
+    // the hostname couldn't be looked up
+    Unresolved,
+    // the host's certificate failed verification (e.g. it expired, or is for another host)
+    Certificate,
+}
+
+static ALL_FAILURE_REASONS: [FailureReason; 6] = [FailureReason::Other, FailureReason::Refused,
+                                                  FailureReason::TimedOut, FailureReason::Unreachable,
+                                                  FailureReason::Unresolved, FailureReason::Certificate];
+
+impl FailureReason {
+    /**
+     * Tells why an attempt to connect failed with the given error.
+     */
+    pub fn of_error(e: &io::Error) -> Self {
+        match e.raw_os_error() {
+            Some(libc::EHOSTUNREACH) | Some(libc::ENETUNREACH) => return FailureReason::Unreachable,
+            _ => (),
+        }
+        match e.kind() {
+            io::ErrorKind::ConnectionRefused => FailureReason::Refused,
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => FailureReason::TimedOut,
+            _ => FailureReason::Other,
+        }
+    }
+
+    /**
+     * The code this reason is stored (and sent over the wire) as.
+     */
+    pub fn code(&self) -> i32 {
+        match *self {
+            FailureReason::Other => 0,
+            FailureReason::Refused => 1,
+            FailureReason::TimedOut => 2,
+            FailureReason::Unreachable => 3,
+            FailureReason::Unresolved => 4,
+            FailureReason::Certificate => 5,
+        }
+    }
+
+    pub fn from_code(code: i32) -> Option<Self> {
+        ALL_FAILURE_REASONS.iter().find(|r| r.code() == code).cloned()
+    }
+
+    /**
+     * Gets the short name of the reason, e.g. for exports.
+     */
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FailureReason::Other => "other",
+            FailureReason::Refused => "refused",
+            FailureReason::TimedOut => "timeout",
+            FailureReason::Unreachable => "unreachable",
+            FailureReason::Unresolved => "unresolved",
+            FailureReason::Certificate => "certificate",
+        }
+    }
+}
 
 pub enum TargetKind {
     TcpPing,
+    IcmpPing,
+    HttpPing,
+    DnsPing,
+    UdpPing,
     HttpDownload,
 }
 
-static ALL_KINDS: [TargetKind; 1] = [TargetKind::TcpPing];
+static ALL_KINDS: [TargetKind; 5] = [
+    TargetKind::TcpPing,
+    TargetKind::IcmpPing,
+    TargetKind::HttpPing,
+    TargetKind::DnsPing,
+    TargetKind::UdpPing,
+];
 
 impl TargetKind {
+    /**
+     * All the target kinds that are actually implemented, in order of their
+     * kind ids.
+     */
+    pub fn all() -> &'static [TargetKind] {
+        &ALL_KINDS
+    }
+
     pub fn kind_id(&self) -> i32 {
         match *self {
             TargetKind::TcpPing => 0,
-            TargetKind::HttpDownload => 1
+            TargetKind::IcmpPing => 1,
+            TargetKind::HttpPing => 2,
+            TargetKind::DnsPing => 3,
+            TargetKind::UdpPing => 4,
+            TargetKind::HttpDownload => 5,
         }
     }
 
     pub fn compact_name(&self) -> &'static str {
         match *self {
             TargetKind::TcpPing => "tcpping",
+            TargetKind::IcmpPing => "icmpping",
+            TargetKind::HttpPing => "httpping",
+            TargetKind::DnsPing => "dnsping",
+            TargetKind::UdpPing => "udpping",
             TargetKind::HttpDownload => "httpdownload",
         }
     }
 
     pub fn default_options(&self) -> TargetOptions {
+        // what all kinds have in common, for each to override as needed
+        let base = TargetOptions {
+            nonce: 0,
+            addrs: Vec::new(),
+            interval: 10_000,
+            avg_across: 3,
+            pause: 100,
+            timeout: None,
+            labels: None,
+            addr_intervals: None,
+            retention: None,
+            prefer_ipv6: None,
+            addr_ports: None,
+            retry_attempts: None,
+            retry_backoff: None,
+            maintenance: None,
+            resolve_every: None,
+            resolve_ttl: None,
+            max_concurrent_probes: None,
+            record_failure_reasons: None,
+            feeds_enabled: None,
+            spread_measurements: None,
+        };
+
         match *self {
             TargetKind::TcpPing => TargetOptions {
-                nonce: 0,
                 addrs: vec!["google.com:80".to_owned(), "8.8.8.8:53".to_owned()],
-                interval: 10_000,
-                avg_across: 3,
-                pause: 100,
+                ..base
+            },
+            TargetKind::IcmpPing => TargetOptions {
+                addrs: vec!["google.com".to_owned(), "8.8.8.8".to_owned()],
+                ..base
+            },
+            TargetKind::HttpPing => TargetOptions {
+                addrs: vec!["http://google.com/".to_owned()],
+                interval: 30_000,
+                avg_across: 1,
+                ..base
+            },
+            TargetKind::DnsPing => TargetOptions {
+                addrs: vec!["google.com".to_owned(), "google.com@8.8.8.8".to_owned()],
+                interval: 30_000,
+                avg_across: 1,
+                ..base
+            },
+            TargetKind::UdpPing => TargetOptions {
+                // a DNS query for google.com, as DNS servers reliably answer
+                addrs: vec!["8.8.8.8:53#12340100000100000000000006676f6f676c6503636f6d0000010001".to_owned()],
+                timeout: Some(2_000),
+                ..base
             },
             _ => unimplemented!()
         }
     }
 
-    pub fn run_worker(&self, manager: Arc<TargetManager>,
-                             results_out: Sender<TargetResults>) -> thread::JoinHandle<()> {
+    /**
+     * Checks (syntactically, without looking anything up) that the given
+     * string is a valid address for this kind of target.
+     */
+    pub fn is_valid_addr(&self, addr: &str) -> bool {
         match *self {
-            TargetKind::TcpPing => run_tcpping_worker(manager, results_out),
+            TargetKind::TcpPing => tcpping::is_valid_addr(addr),
+            TargetKind::IcmpPing => icmpping::is_valid_addr(addr),
+            TargetKind::HttpPing => httpping::is_valid_addr(addr),
+            TargetKind::DnsPing => dnsping::is_valid_addr(addr),
+            TargetKind::UdpPing => udpping::is_valid_addr(addr),
             _ => unimplemented!()
         }
     }
 
-    pub fn new_managers_for_all<'a>(data_path: &'a Path) -> Result<Vec<Arc<TargetManager>>, ManagerError> {
-        let mut targets = Vec::with_capacity(ALL_KINDS.len());
-        for k in ALL_KINDS.iter() {
-            targets.push(
-                Arc::new(try!(TargetManager::new(k, data_path)))
-            );
+    pub fn run_worker(&self, manager: Arc<TargetManager>,
+                             results_out: ResultsSender)
+                             -> Result<Worker, ManagerError> {
+        match *self {
+            TargetKind::TcpPing => Ok(run_tcpping_worker(manager, results_out)),
+            TargetKind::IcmpPing => run_icmpping_worker(manager, results_out),
+            TargetKind::HttpPing => Ok(run_httpping_worker(manager, results_out)),
+            TargetKind::DnsPing => Ok(run_dnsping_worker(manager, results_out)),
+            TargetKind::UdpPing => Ok(run_udpping_worker(manager, results_out)),
+            _ => unimplemented!()
         }
-        Ok(targets)
     }
 }
 
@@ -98,6 +504,106 @@ impl TargetKind {
 pub struct MainConfiguration {
     pub web_port: u16,
     pub ws_port: u16,
+    pub max_data_file_size: Option<u64>,  // size (in bytes) at which data files are split, if any
+    pub shard_data_files_by_day: Option<bool>,  // whether to split data files by (local) day instead
+    pub checksum_data_files: Option<bool>,  // whether to checksum each record in new data files
+    pub data_file_buffer_size: Option<usize>,  // size (in bytes) of data to buffer before writing, if any
+    pub sync_data_files: Option<bool>,  // whether to sync data files to disk as they are written
+    pub sync_metadata_files: Option<bool>,  // whether to sync options and index files to disk as they are written
+    pub results_capacity: Option<usize>,  // most results from workers to hold while they wait to be stored
+    pub drop_results_when_full: Option<bool>,  // whether to drop the oldest results (rather than wait) when full
+    pub recent_capacity: Option<usize>,  // most recent records of each feed to keep in memory for queries
+    pub command_socket: Option<String>,  // path of the Unix domain socket to accept commands on, if any
+    pub ws_frames: Option<bool>,  // whether live data is sent over websockets framed (with its feed)
+    pub max_addrs: Option<usize>,  // most addrs a target may be given
+    pub recover_corrupt_files: Option<bool>,  // whether to set aside (rather than fail on) unreadable options and index files
+    pub reuse_index_tombstones: Option<bool>,  // whether new addrs take the indices of removed ones
+}
+
+impl MainConfiguration {
+    /**
+     * Gets the `DataFileOptions` data files should be stored with.
+     */
+    pub fn data_file_options(&self) -> DataFileOptions {
+        DataFileOptions {
+            max_size: self.max_data_file_size,
+            checksums: self.checksum_data_files.unwrap_or(false),
+            buffer_size: self.data_file_buffer_size.unwrap_or(0),
+            sync: self.sync_data_files.unwrap_or(false),
+            shard_by_day: self.shard_data_files_by_day.unwrap_or(false),
+        }
+    }
+
+    /**
+     * Gets whether options and index files should be synced to disk as they
+     * are written. Being small and rarely written, they are by default.
+     */
+    pub fn sync_metadata_files(&self) -> bool {
+        self.sync_metadata_files.unwrap_or(true)
+    }
+
+    /**
+     * Gets whether options and index files that can't be read should be set
+     * aside (see `TargetManager::new()`) rather than keeping stabping from
+     * starting. Off by default, as what they held is lost.
+     */
+    pub fn recover_corrupt_files(&self) -> bool {
+        self.recover_corrupt_files.unwrap_or(false)
+    }
+
+    /**
+     * Gets whether new addrs should take the indices of removed ones (see
+     * `TargetManager::set_reuse_tombstones()`). Off by default, as the data
+     * of the removed addrs would read back as the new ones'.
+     */
+    pub fn reuse_index_tombstones(&self) -> bool {
+        self.reuse_index_tombstones.unwrap_or(false)
+    }
+
+    /**
+     * Gets how many results from workers may be held (see
+     * `worker::results_channel()`) while they wait to be stored.
+     */
+    pub fn results_capacity(&self) -> usize {
+        self.results_capacity.unwrap_or(DEFAULT_RESULTS_CAPACITY)
+    }
+
+    /**
+     * Gets how many of the most recent records of each feed should be kept
+     * in memory (see `TargetManager::set_recent_capacity()`).
+     */
+    pub fn recent_capacity(&self) -> usize {
+        self.recent_capacity.unwrap_or(DEFAULT_RECENT_CAPACITY)
+    }
+
+    /**
+     * Gets how many addrs a target may be given at most (see
+     * `TargetManager::set_max_addrs()`).
+     */
+    pub fn max_addrs(&self) -> usize {
+        self.max_addrs.unwrap_or(DEFAULT_MAX_ADDRS)
+    }
+
+    /**
+     * Gets what workers should do when there are as many results waiting to
+     * be stored as may be held: by default, wait for there to be room.
+     */
+    pub fn results_backpressure(&self) -> Backpressure {
+        if self.drop_results_when_full.unwrap_or(false) {
+            Backpressure::DropOldest
+        } else {
+            Backpressure::Block
+        }
+    }
+
+    /**
+     * Gets whether live data should be sent over websockets framed (see
+     * `wsserver::frame_results()`) rather than as the workers' results as
+     * they are. Not by default, as that's what the web client expects.
+     */
+    pub fn ws_frames(&self) -> bool {
+        self.ws_frames.unwrap_or(false)
+    }
 }
 
 impl Default for MainConfiguration {
@@ -105,6 +611,20 @@ impl Default for MainConfiguration {
         MainConfiguration {
             web_port: 5001,
             ws_port: 5002,
+            max_data_file_size: None,
+            shard_data_files_by_day: None,
+            checksum_data_files: None,
+            data_file_buffer_size: None,
+            sync_data_files: None,
+            sync_metadata_files: None,
+            results_capacity: None,
+            drop_results_when_full: None,
+            recent_capacity: None,
+            command_socket: None,
+            ws_frames: None,
+            max_addrs: None,
+            recover_corrupt_files: None,
+            reuse_index_tombstones: None,
         }
     }
 }
@@ -115,3 +635,141 @@ fn ensure_kind_id_and_all_kinds_order_match() {
         assert!(i as i32 == k.kind_id());
     }
 }
+
+#[test]
+fn default_options_are_valid() {
+    for k in ALL_KINDS.iter() {
+        k.default_options().validate(k).unwrap();
+    }
+}
+
+#[test]
+fn invalid_addrs_are_rejected() {
+    let kind = TargetKind::TcpPing;
+    let mut options = kind.default_options();
+    options.addrs.push("not a host:80".to_owned());
+    match options.validate(&kind) {
+        Err(ManagerError::InvalidAddrArgument(ref a)) if a == "not a host:80" => (),
+        _ => panic!("Expected an invalid addr error"),
+    }
+
+    // and HTTPS URLs are refused as unsupported (rather than as invalid) without the https feature
+    let kind = TargetKind::HttpPing;
+    let mut options = kind.default_options();
+    options.addrs.push("https://example.com/".to_owned());
+    match options.validate(&kind) {
+        Ok(()) if cfg!(feature = "https") => (),
+        Err(ManagerError::InvalidOptions(ref m)) if m.contains("HTTPS is not supported") => (),
+        _ => panic!("Expected an unsupported HTTPS error"),
+    }
+}
+
+#[test]
+fn intervals_below_the_floor_are_rejected() {
+    let kind = TargetKind::TcpPing;
+    let mut options = kind.default_options();
+    options.interval = MIN_INTERVAL - 1;
+    match options.validate(&kind) {
+        Err(ManagerError::InvalidInterval(i)) if i == MIN_INTERVAL - 1 => (),
+        _ => panic!("Expected an invalid interval error"),
+    }
+
+    // per-addr overrides have the same floor, and must be for addrs in addrs
+    let mut options = kind.default_options();
+    let addr = options.addrs[0].clone();
+    options.addr_intervals = Some(vec![(addr.clone(), 0)].into_iter().collect());
+    assert!(options.validate(&kind).is_err());
+    options.addr_intervals = Some(vec![("example.com:1".to_owned(), MIN_INTERVAL)].into_iter().collect());
+    assert!(options.validate(&kind).is_err());
+    options.addr_intervals = Some(vec![(addr, MIN_INTERVAL)].into_iter().collect());
+    options.validate(&kind).unwrap();
+}
+
+#[test]
+fn timeouts_must_fit_in_the_shortest_interval() {
+    let kind = TargetKind::TcpPing;
+    let mut options = kind.default_options();
+    options.timeout = Some(0);
+    assert!(options.validate(&kind).is_err());
+    options.timeout = Some(options.interval + 1);
+    assert!(options.validate(&kind).is_err());
+    options.timeout = Some(options.interval);
+    options.validate(&kind).unwrap();
+
+    let addr = options.addrs[0].clone();
+    options.addr_intervals = Some(vec![(addr, options.interval - 1)].into_iter().collect());
+    assert!(options.validate(&kind).is_err());
+}
+
+#[test]
+fn retention_and_retries_must_be_in_range() {
+    let kind = TargetKind::TcpPing;
+    let mut options = kind.default_options();
+    options.retention = Some(0);
+    assert!(options.validate(&kind).is_err());
+
+    let mut options = kind.default_options();
+    options.retry_attempts = Some(0);
+    assert!(options.validate(&kind).is_err());
+    options.retry_attempts = Some(MAX_RETRY_ATTEMPTS + 1);
+    assert!(options.validate(&kind).is_err());
+    options.retry_attempts = Some(MAX_RETRY_ATTEMPTS);
+    options.validate(&kind).unwrap();
+}
+
+#[test]
+fn results_count_alive_and_lost_addrs() {
+    let res = TargetResults(vec![0, 1, 1000, 0, 12, SENTINEL_ERROR, 0, SENTINEL_NODATA, 7, SENTINEL_ERROR],
+                            Vec::new());
+    assert_eq!(res.vals().len(), 6);
+    assert_eq!(res.alive_count(), 3);
+    assert_eq!(res.loss_count(), 2);
+
+    let empty = TargetResults(vec![0, 1, 1000, 0], Vec::new());
+    assert_eq!((empty.alive_count(), empty.loss_count()), (0, 0));
+}
+
+#[test]
+fn maintenance_windows_contain_their_recurrences() {
+    let once = MaintenanceWindow { start: 1000, end: 1600, every: None };
+    assert!(!once.contains(999));
+    assert!(once.contains(1000));
+    assert!(once.contains(1599));
+    assert!(!once.contains(1600));
+    assert!(!once.contains(1000 + 86_400));
+
+    let daily = MaintenanceWindow { start: 1000, end: 1600, every: Some(86_400) };
+    assert!(!daily.contains(999));
+    assert!(daily.contains(1000 + 2 * 86_400 + 300));
+    assert!(!daily.contains(1600 + 2 * 86_400));
+
+    let kind = TargetKind::TcpPing;
+    let mut options = kind.default_options();
+    assert!(!options.in_maintenance(1300));
+    options.maintenance = Some(vec![once]);
+    assert!(options.in_maintenance(1300));
+    options.validate(&kind).unwrap();
+
+    // windows must end after they start, and before they recur
+    options.maintenance = Some(vec![MaintenanceWindow { start: 1000, end: 1000, every: None }]);
+    assert!(options.validate(&kind).is_err());
+    options.maintenance = Some(vec![MaintenanceWindow { start: 1000, end: 1600, every: Some(600) }]);
+    assert!(options.validate(&kind).is_err());
+}
+
+#[test]
+fn only_derived_feeds_can_be_turned_off() {
+    let kind = TargetKind::TcpPing;
+    let mut options = kind.default_options();
+    assert!(options.feed_enabled(Feed::Jitter));
+
+    options.feeds_enabled = Some(vec![("jitter".to_owned(), false), ("daily".to_owned(), true)].into_iter().collect());
+    options.validate(&kind).unwrap();
+    assert!(!options.feed_enabled(Feed::Jitter));
+    assert!(options.feed_enabled(Feed::Daily) && options.feed_enabled(Feed::Loss));
+
+    for name in ["data", "failures", "nope"].iter() {
+        options.feeds_enabled = Some(vec![(name.to_string(), false)].into_iter().collect());
+        assert!(options.validate(&kind).is_err());
+    }
+}
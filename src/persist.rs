@@ -7,20 +7,41 @@
  */
 
 use std::fmt;
+use std::io;
+use std::error;
 use std::fmt::Display;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, BTreeMap, VecDeque};
 use std::path::{Path, PathBuf};
+use std::fs;
 use std::fs::OpenOptions;
 use std::fs::File;
+use std::cmp;
+use std::i32;
+use std::i64;
+use std::mem;
 use std::io::Write;
-use std::io::BufReader;
-use std::sync::{Mutex, RwLock, RwLockReadGuard};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+#[cfg(test)]
+use std::sync::mpsc::channel;
 use std::ops::Deref;
 use std::iter;
 use std::iter::Extend;
+use std::slice;
+use std::time::Duration;
 
-use helpers::{SPIOError, SPFile, VecIntoRawBytes, overwrite_json};
-use options::{TargetKind, TargetOptions, TargetResults, SENTINEL_NODATA};
+use chrono::{Local, TimeZone};
+use rustc_serialize::json::{self, Json, ToJson};
+
+use clock::{Clock, SystemClock};
+use helpers::{SPIOError, SPFile, LockFile, VecIntoRawBytes, overwrite_json, overwrite_atomic, recover, split_millis,
+              time_words, time_from_words, crc32};
+use options::{TargetKind, TargetOptions, TargetResults, AddrFamily, FailureReason, SENTINEL_ERROR, SENTINEL_NODATA, MIN_INTERVAL,
+              AVERAGED_WINDOW, DEFAULT_RECENT_CAPACITY, DEFAULT_MAX_ADDRS};
+use reader::{Records, encode_range};
+use datafile::{DataFile, DataFileOptions, Layout};
+use worker::{Worker, run_periodically};
 
 /**
  * A stabping-specific error container for errors incurred during TargetManager
@@ -31,6 +52,20 @@ pub enum ManagerError {
     IndexFileIO(SPIOError),
     DataFileIO(SPIOError),
     OptionsFileIO(SPIOError),
+    RawSocketUnavailable(io::Error),
+    InvalidOptions(String),
+    InvalidInterval(u32),
+    InvalidAddrArgument(String),
+    FeedNotFound(Feed),
+    KindNotFound(i32),
+    CorruptRecord(Option<PathBuf>),
+    ExportIO(io::Error),
+    ImportIO(io::Error),
+    InvalidImport(usize, String),
+    LockFileIO(SPIOError),
+    AlreadyLocked(PathBuf),
+    NonceConflict(i32),
+    TooManyAddrs(usize, usize),
 }
 
 impl ManagerError {
@@ -39,6 +74,22 @@ impl ManagerError {
             ManagerError::IndexFileIO(ref e) => format!("{} index file", e.description()),
             ManagerError::DataFileIO(ref e) => format!("{} data file", e.description()),
             ManagerError::OptionsFileIO(ref e) => format!("{} options file", e.description()),
+            ManagerError::RawSocketUnavailable(ref e) => format!("Unable to open raw socket (are we running with sufficient privileges?): {}", e),
+            ManagerError::InvalidOptions(ref reason) => format!("Invalid options: {}", reason),
+            ManagerError::InvalidInterval(i) => format!("Invalid interval of {} ms (must be at least {} ms)", i, MIN_INTERVAL),
+            ManagerError::InvalidAddrArgument(ref addr) => format!("Invalid address '{}'", addr),
+            ManagerError::FeedNotFound(feed) => format!("No data file for the '{}' feed", feed.compact_name()),
+            ManagerError::KindNotFound(kind_id) => format!("No manager for target kind {}", kind_id),
+            ManagerError::CorruptRecord(ref p) => format!("Corrupt record (checksum mismatch) in data file '{}'",
+                                                          p.as_ref().and_then(|p| p.to_str()).unwrap_or("")),
+            ManagerError::ExportIO(ref e) => format!("Unable to write export: {}", e),
+            ManagerError::ImportIO(ref e) => format!("Unable to read import: {}", e),
+            ManagerError::InvalidImport(line, ref reason) => format!("Invalid import at line {}: {}", line, reason),
+            ManagerError::LockFileIO(ref e) => format!("{} lock file", e.description()),
+            ManagerError::AlreadyLocked(ref p) => format!("Data directory is in use by another instance (lock file '{}' is held)",
+                                                          p.to_str().unwrap_or("")),
+            ManagerError::NonceConflict(nonce) => format!("Nonce {} does not match the current nonce, refusing update", nonce),
+            ManagerError::TooManyAddrs(count, max) => format!("Too many addresses ({}, at most {} allowed)", count, max),
         }
     }
 }
@@ -49,283 +100,4530 @@ impl Display for ManagerError {
     }
 }
 
+impl error::Error for ManagerError {
+    fn description(&self) -> &str {
+        match *self {
+            ManagerError::IndexFileIO(_) => "index file I/O error",
+            ManagerError::DataFileIO(_) => "data file I/O error",
+            ManagerError::OptionsFileIO(_) => "options file I/O error",
+            ManagerError::RawSocketUnavailable(_) => "raw socket unavailable",
+            ManagerError::InvalidOptions(_) => "invalid options",
+            ManagerError::InvalidInterval(_) => "invalid interval",
+            ManagerError::InvalidAddrArgument(_) => "invalid address",
+            ManagerError::FeedNotFound(_) => "no data file for feed",
+            ManagerError::KindNotFound(_) => "no manager for target kind",
+            ManagerError::CorruptRecord(_) => "corrupt record",
+            ManagerError::ExportIO(_) => "export I/O error",
+            ManagerError::ImportIO(_) => "import I/O error",
+            ManagerError::InvalidImport(..) => "invalid import",
+            ManagerError::LockFileIO(_) => "lock file I/O error",
+            ManagerError::AlreadyLocked(_) => "data directory in use by another instance",
+            ManagerError::NonceConflict(_) => "nonce conflict",
+            ManagerError::TooManyAddrs(..) => "too many addresses",
+        }
+    }
+
+    /**
+     * Gets the underlying error this wraps, if any.
+     */
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            ManagerError::IndexFileIO(ref e) |
+            ManagerError::DataFileIO(ref e) |
+            ManagerError::OptionsFileIO(ref e) |
+            ManagerError::LockFileIO(ref e) => Some(e),
+            ManagerError::RawSocketUnavailable(ref e) |
+            ManagerError::ExportIO(ref e) |
+            ManagerError::ImportIO(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Wraps an error incurred with a data file, singling out corrupt records.
+ */
+fn data_file_error(e: SPIOError) -> ManagerError {
+    match e {
+        SPIOError::Corrupt(p) => ManagerError::CorruptRecord(p),
+        e => ManagerError::DataFileIO(e),
+    }
+}
+
+/**
+ * Moves the (unreadable) file residing at the given path out of the way, to
+ * the same path with a `.bad` extension appended, giving back where it went.
+ */
+fn move_aside<'b>(path: &'b Path) -> Result<PathBuf, SPIOError> {
+    let bad_path = {
+        let mut p = path.to_owned().into_os_string();
+        p.push(".bad");
+        PathBuf::from(p)
+    };
+    try!(fs::rename(path, &bad_path).map_err(|_| SPIOError::Write(Some(bad_path.clone()))));
+    Ok(bad_path)
+}
+
+/**
+ * The start of the header line of index files (see `AddrIndex`), which is
+ * followed by the version of the index file and a checksum of the rest of it.
+ */
+static INDEX_HEADER: &'static str = "#stabping-index ";
+
+/**
+ * The version index files are written in. Index files from before there was
+ * a header line are read as version 0.
+ */
+pub static INDEX_FILE_VERSION: u32 = 1;
+
+/**
+ * The index of an address in a target's index (see `AddrIndex`), under which
+ * its data is recorded (as a 32-bit integer, see `word()`) in the target's
+ * data files. Converts from and into a `u32`.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[repr(C)]
+pub struct AddrId(u32);
+
+impl AddrId {
+    /**
+     * Gets the index recorded as the given integer of a record.
+     */
+    pub fn from_word(word: i32) -> AddrId {
+        AddrId(word as u32)
+    }
+
+    /**
+     * Gets the integer this index is recorded as in records.
+     */
+    pub fn word(self) -> i32 {
+        self.0 as i32
+    }
+}
+
+impl From<u32> for AddrId {
+    fn from(index: u32) -> AddrId {
+        AddrId(index)
+    }
+}
+
+impl From<AddrId> for u32 {
+    fn from(index: AddrId) -> u32 {
+        index.0
+    }
+}
+
+impl fmt::Display for AddrId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
 
 /**
  * A per-target global persistent mapping of index (an integer) to an address
  * (a string used in `TargetOptions.addrs`) backed by an index file.
+ *
+ * Each line of the index file is an address, optionally followed by a tab and
+ * a human-readable label for the address. They're preceded by a header line
+ * giving the version of the index file and a CRC32 checksum of the lines
+ * that follow, so that an index file changed by anything but stabping (or
+ * only partly written) isn't trusted.
+ *
+ * Indices of removed addresses are by default never reused (so that the data
+ * recorded under them stays unambiguous), and are instead left as empty
+ * "tombstone" lines in the index file (see `set_reuse_tombstones()` for the
+ * alternative).
  */
 #[derive(Debug)]
 struct AddrIndex {
+    path: PathBuf,
     file: File,
-    data: Vec<String>,
-    map: HashMap<String, i32>,
+    sync: bool,
+    data: Vec<Option<String>>,
+    labels: Vec<Option<String>>,
+    map: HashMap<String, AddrId>,
+    reuse_tombstones: bool,
+    // number of times the index file has been rewritten (see `rewrite()`)
+    #[cfg(test)]
+    rewrites: usize,
 }
 
 impl AddrIndex {
     /**
      * Creates an `AddrIndex` backed by the index file residing at the given
      * path.
+     *
+     * If `recover` is set, an index file that can't be parsed (or fails its
+     * checksum) is backed up (see `move_aside()`) and replaced with a fresh
+     * empty one, rather than failing. An index file of version 0 (i.e. without
+     * a header line) is rewritten with one.
+     *
+     * If `sync` is set, every change to the index file is synced to disk as
+     * it is written.
      */
-    fn from_path<'b>(path: &'b Path) -> Result<Self, ManagerError> {
+    fn from_path<'b>(path: &'b Path, recover: bool, sync: bool) -> Result<Self, ManagerError> {
         // attempt to open the index file
         let mut index_file = try!(
             File::open_from(OpenOptions::new().read(true).append(true).create(true), path)
             .map_err(|e| ManagerError::IndexFileIO(e))
         );
 
-        let mut index_data = Vec::new();
-        /*
-         * if the index file is non-empty, read the data into a list that will
-         * function as the index -> addr mapping
-         */
-        if try!(index_file.length_p(path)
-                .map_err(|e| ManagerError::IndexFileIO(e))) > 0 {
-            use std::io::BufRead;
-            let reader = BufReader::new(&mut index_file);
+        let (index_data, index_labels, version) = match AddrIndex::read_lines(&mut index_file, path) {
+            Ok(lines) => lines,
+            Err(e) => {
+                if !recover {
+                    return Err(e);
+                }
+
+                let bad_path = try!(move_aside(path).map_err(|e| ManagerError::IndexFileIO(e)));
+                warn!("starting over with an empty index error=\"{}\" moved_to=\"{}\"",
+                      e, bad_path.to_str().unwrap_or(""));
 
-            for line_res in reader.lines() {
-                let line = try!(line_res
-                                .map_err(|_| ManagerError::IndexFileIO(
-                                             SPIOError::Parse(Some(path.to_owned())))));
-                index_data.push(line);
+                index_file = try!(
+                    File::open_from(OpenOptions::new().read(true).append(true).create(true), path)
+                    .map_err(|e| ManagerError::IndexFileIO(e))
+                );
+                (Vec::new(), Vec::new(), INDEX_FILE_VERSION)
             }
-        }
+        };
 
         // create the map that will contain the reverse addr -> index mapping
         let mut index_map = HashMap::new();
-        for (i, addr) in index_data.iter().enumerate() {
-            index_map.insert(addr.clone(), i as i32);
+        for (i, maybe_addr) in index_data.iter().enumerate() {
+            if let &Some(ref addr) = maybe_addr {
+                index_map.insert(addr.clone(), AddrId::from(i as u32));
+            }
         }
 
-        Ok(AddrIndex {
+        let mut index = AddrIndex {
+            path: path.to_owned(),
             file: index_file,
+            sync: sync,
             data: index_data,
+            labels: index_labels,
             map: index_map,
-        })
+            reuse_tombstones: false,
+            #[cfg(test)]
+            rewrites: 0,
+        };
+        if version == 0 && index.len() > 0 {
+            info!("upgrading index file path=\"{}\" version={}", path.to_str().unwrap_or(""), INDEX_FILE_VERSION);
+            try!(index.rewrite());
+        }
+        Ok(index)
     }
 
     /**
-     * Adds an addr into this index as necessary (if it does not already
-     * exist in the index).
+     * Reads the addrs (or tombstones) and their labels from the given index
+     * file, along with its version, after checking the checksum in its header
+     * line (if it has one).
      */
-    fn add_addr(&mut self, addr: &str) -> Result<(), ManagerError> {
-        if let None = self.map.get(addr) {
-            // only deal with it if we don't already have it
-            self.map.insert(addr.to_owned(), self.data.len() as i32);
-            self.data.push(addr.to_owned());
-            try!(self.file.write_all(format!("{}\n", addr).as_bytes())
-                 .map_err(|_| ManagerError::IndexFileIO(
-                              SPIOError::Write(None))));
+    fn read_lines<'b>(index_file: &mut File, path: &'b Path)
+                      -> Result<(Vec<Option<String>>, Vec<Option<String>>, u32), ManagerError> {
+        use std::io::Read;
+
+        let parse_error = || ManagerError::IndexFileIO(SPIOError::Parse(Some(path.to_owned())));
+        let mut contents = String::new();
+        try!(index_file.read_to_string(&mut contents).map_err(|_| parse_error()));
+
+        let (lines, version) = if contents.starts_with(INDEX_HEADER) {
+            let (header, lines) = match contents.find('\n') {
+                Some(i) => (&contents[..i], &contents[i + 1..]),
+                None => (&contents[..], ""),
+            };
+            let mut fields = header[INDEX_HEADER.len()..].split(' ');
+            let version = fields.next().and_then(|v| v.parse::<u32>().ok());
+            let crc = fields.next().and_then(|c| u32::from_str_radix(c, 16).ok());
+            match (version, crc) {
+                (Some(v), Some(c)) if v <= INDEX_FILE_VERSION => {
+                    if crc32(lines.as_bytes()) != c {
+                        return Err(ManagerError::IndexFileIO(SPIOError::Corrupt(Some(path.to_owned()))));
+                    }
+                    (lines, v)
+                },
+                _ => return Err(parse_error()),
+            }
+        } else {
+            (&contents[..], 0)
+        };
+
+        // read the data into a list that will function as the index -> addr mapping
+        let mut index_data = Vec::new();
+        let mut index_labels = Vec::new();
+        for line in lines.lines() {
+            // an empty line is the tombstone of a removed addr
+            let mut parts = line.splitn(2, '\t');
+            index_data.push(parts.next().and_then(|a| {
+                if a.is_empty() { None } else { Some(a.to_owned()) }
+            }));
+            index_labels.push(parts.next().map(|l| l.to_owned()));
         }
-        Ok(())
+
+        Ok((index_data, index_labels, version))
     }
 
     /**
-     * Ensures (adding them if necessary) that all addrs in the given iterator
-     * exist in this index.
+     * Makes this index reach (at least) the given index, tombstoning the
+     * indices it didn't have, so that they are never given to new addrs
+     * (unless reusing tombstones, see `set_reuse_tombstones()`).
      */
-    fn ensure_for_addrs<'a, I, K>(&mut self, addrs: I) -> Result<(), ManagerError>
-            where I: Iterator<Item=&'a K>, K: 'a + Deref<Target=str> {
-        for addr in addrs {
-            try!(self.add_addr(&addr));
+    fn reserve_through(&mut self, index: AddrId) -> Result<(), ManagerError> {
+        if (index.0 as usize) < self.data.len() {
+            return Ok(());
         }
-        Ok(())
+        let len = index.0 as usize + 1;
+        self.data.resize(len, None);
+        self.labels.resize(len, None);
+        self.rewrite()
     }
 
     /**
-     * Retrieves the index associated with the given address.
+     * Sets whether new addrs are given the lowest tombstoned index (if there
+     * is one) rather than a fresh index at the end of the index file.
+     *
+     * Reusing tombstones keeps the index (and the records of data files,
+     * which are as wide as the highest index) compact when addrs come and go
+     * often, but at the cost of the removed addrs' history: any data still
+     * recorded under a reused index reads back as the new addr's. Off by
+     * default, so that indices stay monotonic.
      */
-    fn get_index(&self, addr: &str) -> i32 {
-        self.map.get(addr).cloned().expect("Non-existant addr requested from AddrIndex!")
+    fn set_reuse_tombstones(&mut self, reuse: bool) {
+        self.reuse_tombstones = reuse;
     }
 
     /**
-     * Retrieves the adress associated with the given index.
+     * Adds an addr into this index as necessary (if it does not already
+     * exist in the index), returning its index.
+     *
+     * The addr is given a fresh index at the end, unless reusing tombstones
+     * (see `set_reuse_tombstones()`) and there's a tombstone to reuse, in
+     * which case it takes the lowest tombstoned index, with no label.
      */
-    fn get_addr(&self, index: i32) -> &String {
-        self.data.get(index as usize).expect("Non-existant index requested from AddrIndex!")
+    fn add_addr(&mut self, addr: &str) -> Result<AddrId, ManagerError> {
+        let (i, added) = self.insert_addr(addr);
+        if added {
+            try!(self.rewrite());
+        }
+        Ok(i)
     }
 
     /**
-     * Returns the length (as in number of unique addresses) in this index.
+     * Adds an addr into this index as necessary (see `add_addr()`), but
+     * without writing the index file, returning its index and whether it was
+     * added.
      */
-    fn len(&self) -> usize {
-        self.data.len()
+    fn insert_addr(&mut self, addr: &str) -> (AddrId, bool) {
+        // only deal with it if we don't already have it
+        if let Some(i) = self.index_of(addr) {
+            return (i, false);
+        }
+
+        if self.reuse_tombstones {
+            if let Some(i) = self.data.iter().position(|a| a.is_none()) {
+                self.map.insert(addr.to_owned(), AddrId::from(i as u32));
+                self.data[i] = Some(addr.to_owned());
+                self.labels[i] = None;
+                return (AddrId::from(i as u32), true);
+            }
+        }
+
+        let i = AddrId::from(self.data.len() as u32);
+        self.map.insert(addr.to_owned(), i);
+        self.data.push(Some(addr.to_owned()));
+        self.labels.push(None);
+        (i, true)
     }
-}
 
-/**
- * Master control structure managing all I/O backed resources (with the
- * exception of running workers which is handled by `TargetKind` and the main
- * thread directly) of a given target.
- *
- * This is include most notably, the target's data file, address index (and
- * associated index file), and options (and associated options file).
- */
-pub struct TargetManager {
-    pub kind: &'static TargetKind,
-    index: RwLock<AddrIndex>,
-    data_file: RwLock<File>,
-    options_path: Mutex<PathBuf>,
-    options: RwLock<TargetOptions>,
-}
+    /**
+     * Adds each of the given addrs into this index as necessary (see
+     * `add_addr()`), returning their indices (in the same order). The index
+     * file is written once for all of them, if any were added.
+     */
+    fn add_addrs(&mut self, addrs: &[String]) -> Result<Vec<AddrId>, ManagerError> {
+        let (indices, added) = self.insert_addrs(addrs);
+        if added {
+            try!(self.rewrite());
+        }
+        Ok(indices)
+    }
 
-impl TargetManager {
     /**
-     * Creates a new `TargetManager` for the given target kind that will store
-     * persistent data at the given location path.
+     * Adds each of the given addrs into this index as necessary (see
+     * `add_addrs()`), but without writing the index file, returning their
+     * indices and whether any were added.
      */
-    pub fn new<'b>(kind: &'static TargetKind, data_path: &'b Path) -> Result<Self, ManagerError> {
-        let mut path = data_path.to_owned();
+    fn insert_addrs(&mut self, addrs: &[String]) -> (Vec<AddrId>, bool) {
+        let mut added = false;
+        let indices = addrs.iter().map(|a| {
+            let (i, a) = self.insert_addr(a);
+            added |= a;
+            i
+        }).collect();
+        (indices, added)
+    }
 
-        // attempt to open the target's data file
-        path.push(format!("{}.data.dat", kind.compact_name()));
-        let data_file = try!(
-            File::open_from(OpenOptions::new().read(true).append(true).create(true), &path)
-            .map_err(|e| ManagerError::DataFileIO(e))
-        );
-        path.pop();
+    /**
+     * Adds an addr with the given label into this index as necessary (if it
+     * does not already exist in the index), otherwise updating its label.
+     * Returns its index.
+     */
+    fn add_addr_labeled(&mut self, addr: &str, label: &str) -> Result<AddrId, ManagerError> {
+        let (i, added) = self.insert_addr(addr);
+        if self.insert_label(i, label) || added {
+            try!(self.rewrite());
+        }
+        Ok(i)
+    }
 
-        // attempt to open the target's options file
-        let options_file_name = format!("{}.options.json", kind.compact_name());
-        path.push(&options_file_name);
-        let mut options_file = try!(
-            File::open_from(OpenOptions::new().read(true).write(true).create(true), &path)
-            .map_err(|e| ManagerError::OptionsFileIO(e))
-        );
+    /**
+     * Gives the addr with the given index the given label, but without
+     * writing the index file, returning whether its label changed.
+     */
+    fn insert_label(&mut self, index: AddrId, label: &str) -> bool {
+        let slot = &mut self.labels[index.0 as usize];
+        if slot.as_ref().map(|l| l.as_str()) == Some(label) {
+            return false;
+        }
+        *slot = Some(label.to_owned());
+        true
+    }
 
-        /*
-         * read back existing options from the options file, or write out
-         * default options for this target to the options file
-         */
-        let options = if try!(options_file.length_p(&path)
-                              .map_err(|e| ManagerError::OptionsFileIO(e))) > 0 {
-            try!(
-                options_file.read_json_p(&path)
-                .map_err(|e| ManagerError::OptionsFileIO(e))
-            )
-        } else {
-            let default_options = kind.default_options();
-            try!(
-                options_file.write_json_p(&default_options, &path)
-                .map_err(|e| ManagerError::OptionsFileIO(e))
-            );
-            default_options
-        };
+    /**
+     * Removes the addr with the given index from this index, leaving a
+     * tombstone in its place so that no other addr is ever given the index.
+     */
+    fn remove_addr(&mut self, index: AddrId) -> Result<(), ManagerError> {
+        self.remove_addrs(&[index])
+    }
 
-        path.pop();
+    /**
+     * Removes the addrs with the given indices from this index (see
+     * `remove_addr()`), rewriting the index file once for all of them.
+     */
+    fn remove_addrs(&mut self, indices: &[AddrId]) -> Result<(), ManagerError> {
+        let mut removed_any = false;
+        for &index in indices.iter() {
+            let removed = match self.data.get_mut(index.0 as usize) {
+                Some(slot) => slot.take(),
+                None => None,
+            };
+            if let Some(addr) = removed {
+                self.map.remove(&addr);
+                self.labels[index.0 as usize] = None;
+                removed_any = true;
+            }
+        }
 
-        /*
-         * attempt to open the target's index file and create an index out of
-         * it; additionally ensure that all addresses present in the options
-         * are present in the index
-         */
-        path.push(format!("{}.index.json", kind.compact_name()));
-        let mut index = try!(AddrIndex::from_path(&path));
-        try!(index.ensure_for_addrs(options.addrs.iter()));
-        path.pop();
+        if removed_any {
+            try!(self.rewrite());
+        }
+        Ok(())
+    }
 
-        // leave the path to the options file here so we can store it
-        path.push(options_file_name);
+    /**
+     * Renames the addr with the given index to the given new addr, keeping
+     * its index (and so all the data recorded under it) and label. The index
+     * file is rewritten (see `rewrite()`).
+     *
+     * Fails if the index has no addr (or has been removed), or the new addr
+     * is already in this index under another index.
+     */
+    fn rename_addr(&mut self, index: AddrId, new_addr: &str) -> Result<(), ManagerError> {
+        let old_addr = match self.data.get(index.0 as usize) {
+            Some(&Some(ref a)) => a.clone(),
+            _ => return Err(ManagerError::InvalidAddrArgument(index.to_string())),
+        };
+        if old_addr == new_addr {
+            return Ok(());
+        }
+        if self.map.contains_key(new_addr) {
+            return Err(ManagerError::InvalidAddrArgument(new_addr.to_owned()));
+        }
 
-        Ok(TargetManager {
-            kind: kind,
-            index: RwLock::new(index),
-            data_file: RwLock::new(data_file),
-            options_path: Mutex::new(path),
-            options: RwLock::new(options),
-        })
+        self.map.remove(&old_addr);
+        self.map.insert(new_addr.to_owned(), index);
+        self.data[index.0 as usize] = Some(new_addr.to_owned());
+        self.rewrite()
     }
 
     /**
-     * Acquires a read lock on this target's options.
+     * Formats the line of the index file for the given index.
      */
-    pub fn options_read<'a>(&'a self) -> RwLockReadGuard<'a, TargetOptions> {
-        self.options.read().unwrap()
+    fn line_for(&self, index: usize) -> String {
+        match (&self.data[index], &self.labels[index]) {
+            (&Some(ref a), &Some(ref l)) => format!("{}\t{}\n", a, l),
+            (&Some(ref a), &None) => format!("{}\n", a),
+            (&None, _) => "\n".to_owned(),
+        }
     }
 
     /**
-     * Attempts to update this target's options with the given new options.
+     * Rewrites the entire index file (with its header line) from this index.
+     * The index file is rewritten atomically (see `overwrite_atomic()`), so it
+     * is never left half-written, and synced to disk if the index is.
      */
-    pub fn options_update(&self, new_options: TargetOptions) -> Result<(), ManagerError> {
-        let mut guard = self.options.write().unwrap();
-        let mut options_path = self.options_path.lock().unwrap();
-        *guard = new_options;
+    fn rewrite(&mut self) -> Result<(), ManagerError> {
+        #[cfg(test)]
+        {
+            self.rewrites += 1;
+        }
+
+        let mut lines = String::new();
+        for i in 0..self.data.len() {
+            lines.push_str(&self.line_for(i));
+        }
+        let contents = format!("{}{} {:08x}\n{}", INDEX_HEADER, INDEX_FILE_VERSION,
+                               crc32(lines.as_bytes()), lines);
+
         try!(
-            overwrite_json(&*guard, &*options_path)
-            .map_err(|e| ManagerError::OptionsFileIO(e))
+            overwrite_atomic(contents.as_bytes(), &self.path, self.sync)
+            .map_err(|e| ManagerError::IndexFileIO(e))
+        );
+
+        // our handle still refers to the replaced file, so reopen it
+        self.file = try!(
+            File::open_from(OpenOptions::new().read(true).append(true).create(true), &self.path)
+            .map_err(|e| ManagerError::IndexFileIO(e))
         );
-        try!(self.index.write().unwrap().ensure_for_addrs(guard.addrs.iter()));
-        println!("Updated {} options: {:?}", self.kind.compact_name(), *guard);
         Ok(())
     }
 
     /**
-     * Acquires a read lock on this target's data file.
+     * Ensures everything written to the index file so far is on-disk.
      */
-    pub fn data_file_read<'a>(&'a self) -> RwLockReadGuard<'a, File> {
-        self.data_file.read().unwrap()
+    fn sync(&self) -> Result<(), ManagerError> {
+        self.file.sync_all()
+            .map_err(|_| ManagerError::IndexFileIO(SPIOError::Write(Some(self.path.clone()))))
     }
 
     /**
-     * Appends the given live-collected data (`TargetResults`) to this target's
-     * data file.
+     * Ensures (adding them if necessary) that all addrs in the given iterator
+     * exist in this index.
      */
-    pub fn append_data(&self, data_res: &TargetResults) -> Result<(), ManagerError> {
-        let ref in_data = data_res.0;
-
-        assert!(in_data[0] == self.kind.kind_id());
+    fn ensure_for_addrs<'a, I, K>(&mut self, addrs: I) -> Result<(), ManagerError>
+            where I: Iterator<Item=&'a K>, K: 'a + Deref<Target=str> {
+        let mut added = false;
+        for addr in addrs {
+            added |= self.insert_addr(&addr).1;
+        }
+        if added {
+            try!(self.rewrite());
+        }
+        Ok(())
+    }
 
-        let nonce = in_data[1];
-        if nonce != self.options_read().nonce {
-            println!("Nonce mismatch for data append! Silently ignoring.");
-            return Ok(());
+    /**
+     * Ensures (adding them if necessary) that all addrs in the given options
+     * exist in this index, along with any labels given for them, writing the
+     * index file (at most) once for all of them.
+     */
+    fn ensure_for_options(&mut self, options: &TargetOptions) -> Result<(), ManagerError> {
+        let (indices, mut changed) = self.insert_addrs(&options.addrs);
+        if let Some(ref labels) = options.labels {
+            for (addr, i) in options.addrs.iter().zip(indices.into_iter()) {
+                if let Some(label) = labels.get(addr) {
+                    changed |= self.insert_label(i, label);
+                }
+            }
+        }
+        if changed {
+            try!(self.rewrite());
         }
+        Ok(())
+    }
 
-        let mut out_data: Vec<i32> = Vec::with_capacity((in_data.len() - 3) * 3);
-        let time = in_data[2];
-        let index = self.index.read().unwrap();
-        for (addr, val) in self.options_read().addrs.iter().zip(in_data[3..].iter()) {
-            out_data.push(time);
-            out_data.push(index.get_index(addr));
-            out_data.push(*val);
+    /**
+     * Makes the given kind's default options over the addrs (and labels) of
+     * this index instead, e.g. for when its options file was lost. If this
+     * index has no addrs, they're just the default options.
+     */
+    fn rebuilt_options(&self, kind: &TargetKind) -> TargetOptions {
+        let mut options = kind.default_options();
+        let addrs: Vec<String> = self.data.iter().filter_map(|a| a.clone()).collect();
+        if addrs.is_empty() {
+            return options;
         }
 
-        let ref mut file = *self.data_file.write().unwrap();
-        try!(file.write_all(&out_data.into_raw_bytes())
-             .map_err(|_| ManagerError::DataFileIO(
-                          SPIOError::Write(None))));
-        Ok(())
+        let labels: BTreeMap<String, String> = self.data.iter().zip(self.labels.iter())
+            .filter_map(|(a, l)| match (a, l) {
+                (&Some(ref a), &Some(ref l)) => Some((a.clone(), l.clone())),
+                _ => None,
+            })
+            .collect();
+        options.addrs = addrs;
+        options.labels = if labels.is_empty() { None } else { Some(labels) };
+        options
     }
 
     /**
-     * Gets the current addrs in options as (nonce, ordered_list, membership)
-     * where 'ordered_list' is the list of address indices in order in which
-     * they appear in options, and where 'membership' is the set of indices
-     * present (i.e. if membership[i] != 0, then the addr with index i is
-     * currently present in options).
+     * Looks up the index associated with the given address, if it has one.
      */
-    pub fn get_current_indices(&self) -> (i32, Vec<i32>, Vec<i32>) {
-        let options = self.options_read();
+    fn index_of(&self, addr: &str) -> Option<AddrId> {
+        self.map.get(addr).cloned()
+    }
 
-        let index = self.index.read().unwrap();
+    /**
+     * Retrieves the index associated with the given address.
+     */
+    fn get_index(&self, addr: &str) -> AddrId {
+        self.index_of(addr).expect("Non-existant addr requested from AddrIndex!")
+    }
 
-        let mut ordered_list = Vec::with_capacity(options.addrs.len());
+    /**
+     * Retrieves the adress associated with the given index, or `None` if the
+     * addr has since been removed (or the index was never in this index,
+     * e.g. in data recorded before the index file was lost).
+     */
+    fn get_addr(&self, index: AddrId) -> Option<&String> {
+        self.data.get(index.0 as usize).and_then(|a| a.as_ref())
+    }
 
-        let mut membership = {
-            let len = index.len();
-            let mut v = Vec::with_capacity(len);
-            v.extend(iter::repeat(0).take(len));
-            v
-        };
+    /**
+     * Retrieves the label of the address associated with the given index, if
+     * it has one.
+     */
+    fn get_label(&self, index: AddrId) -> Option<&String> {
+        self.labels.get(index.0 as usize).and_then(|l| l.as_ref())
+    }
 
-        for addr in options.addrs.iter() {
-            let i = index.get_index(addr);
-            ordered_list.push(i);
-            membership[i as usize] = SENTINEL_NODATA;
-        }
+    /**
+     * Returns the length (as in number of unique addresses) in this index.
+     */
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+}
 
-        (options.nonce, ordered_list, membership)
+/**
+ * Formats the given host and port as a `host:port` addr, bracketing the host
+ * if it is an IPv6 literal.
+ */
+fn host_port(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
     }
 }
 
+/**
+ * The different series of data ("feeds") kept for each target, each backed by
+ * its own data file.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Feed {
+    // the data as collected live by the target's worker
+    Raw,
+    // averages (and standard deviations) of the raw data over fixed windows
+    Averaged,
+    // jitter (mean absolute difference of consecutive values) over the same
+    Jitter,
+    // percentage of values that were errors (i.e. loss) over the same
+    Loss,
+    // 50th, 95th, and 99th percentiles of the (non-error) values over the same
+    Percentiles,
+    // mean, minimum, maximum, and loss of the averaged data over each (local) day
+    Daily,
+    // why failed measurements in the raw data failed (see `FailureReason`), if recorded
+    Failures,
+    // how long the TLS handshakes of successful measurements in the raw data took, if any
+    Handshakes,
+}
+
+static ALL_FEEDS: [Feed; 8] = [Feed::Raw, Feed::Averaged, Feed::Jitter, Feed::Loss, Feed::Percentiles,
+                               Feed::Daily, Feed::Failures, Feed::Handshakes];
+
+// feeds computed from the raw data over windows (averaged comes first)
+static DERIVED_FEEDS: [Feed; 4] = [Feed::Averaged, Feed::Jitter, Feed::Loss, Feed::Percentiles];
+
+/**
+ * Statistics about a target's data file for a feed (see
+ * `TargetManager::stats()`).
+ */
+#[derive(PartialEq, Debug)]
+pub struct FeedStats {
+    pub feed: Feed,
+    // number of records (one for each addr in each round or window)
+    pub records: u64,
+    // times of the first and last records, if there are any
+    pub earliest: Option<i64>,
+    pub latest: Option<i64>,
+    // size (in bytes) on-disk, across all segments
+    pub disk_size: u64,
+}
+
+impl ToJson for FeedStats {
+    /**
+     * Gives these statistics as an object of {feed, records, earliest, latest,
+     * disk_size}, naming the feed as its data file does (see
+     * `Feed::compact_name()`), with times there are none of as `null`.
+     */
+    fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert("feed".to_owned(), Json::String(self.feed.compact_name().to_owned()));
+        obj.insert("records".to_owned(), Json::U64(self.records));
+        obj.insert("earliest".to_owned(), self.earliest.map_or(Json::Null, Json::I64));
+        obj.insert("latest".to_owned(), self.latest.map_or(Json::Null, Json::I64));
+        obj.insert("disk_size".to_owned(), Json::U64(self.disk_size));
+        Json::Object(obj)
+    }
+}
+
+// number of records to read at a time when reading data files backward
+static LATEST_BLOCK_LEN: u64 = 64;
+
+// placeholder given (by `query_resolved()`) for the addr of a removed index
+pub static REMOVED_ADDR: &'static str = "<removed>";
+
+// furthest (in seconds from epoch, either way) imported times may be, so that they fit in nanoseconds
+static MAX_IMPORT_TIME: i64 = i64::MAX / 1_000_000_000;
+
+// number of sets of results a subscriber (see `TargetManager::subscribe()`) may fall behind by
+static SUBSCRIBER_CAPACITY: usize = 256;
+
+impl Feed {
+    /**
+     * The discriminant identifying this feed on the wire.
+     */
+    pub fn feed_id(&self) -> u8 {
+        match *self {
+            Feed::Raw => 0,
+            Feed::Averaged => 1,
+            Feed::Jitter => 2,
+            Feed::Loss => 3,
+            Feed::Percentiles => 4,
+            Feed::Daily => 5,
+            Feed::Failures => 6,
+            Feed::Handshakes => 7,
+        }
+    }
+
+    /**
+     * Gets the feed identified by the given discriminant on the wire.
+     */
+    pub fn from_feed_id(feed_id: u8) -> Option<Feed> {
+        ALL_FEEDS.iter().find(|f| f.feed_id() == feed_id).cloned()
+    }
+
+    /**
+     * Gets the feed with the given name (see `compact_name()`).
+     */
+    pub fn from_compact_name(name: &str) -> Option<Feed> {
+        ALL_FEEDS.iter().find(|f| f.compact_name() == name).cloned()
+    }
+
+    /**
+     * The name of this feed as used in the name of its data file.
+     */
+    pub fn compact_name(&self) -> &'static str {
+        match *self {
+            Feed::Raw => "data",
+            Feed::Averaged => "averaged",
+            Feed::Jitter => "jitter",
+            Feed::Loss => "loss",
+            Feed::Percentiles => "percentiles",
+            Feed::Daily => "daily",
+            Feed::Failures => "failures",
+            Feed::Handshakes => "handshakes",
+        }
+    }
+
+    /**
+     * Each version of the layout of records in this feed's data file (see
+     * `DataFile`), the last being the current one.
+     */
+    pub fn layouts(&self) -> Vec<Layout> {
+        // records originally had a 32-bit time, one integer short of today's
+        let narrow = |size: usize| Layout { record_size: size - mem::size_of::<i32>(), wide_time: false };
+        let wide = |size: usize| Layout { record_size: size, wide_time: true };
+        match *self {
+            // raw records originally had no millis
+            Feed::Raw => vec![narrow(Records::record_size(1)),
+                              narrow(Records::record_size_with_millis(1)),
+                              wide(Records::record_size_with_millis(1))],
+            // averaged records originally had no min and max
+            Feed::Averaged => vec![narrow(Records::record_size(2)),
+                                   narrow(Records::record_size(4)),
+                                   wide(Records::record_size(4))],
+            // (daily, failure and handshake records came after times were 64-bit)
+            Feed::Daily => vec![wide(Records::record_size(self.val_count()))],
+            Feed::Failures | Feed::Handshakes => vec![wide(Records::record_size_with_millis(self.val_count()))],
+            _ => vec![narrow(Records::record_size(self.val_count())),
+                      wide(Records::record_size(self.val_count()))],
+        }
+    }
+
+    /**
+     * A view of the given records of this feed (as read from its data file).
+     * Only raw records (and the failures and handshakes among them) have sub-second times,
+     * as windows of the other feeds always start on the second.
+     */
+    pub fn records<'a>(&self, ints: &'a [i32]) -> Records<'a> {
+        match *self {
+            Feed::Raw | Feed::Failures | Feed::Handshakes => Records::with_millis(ints, self.val_count()),
+            _ => Records::new(ints, self.val_count()),
+        }
+    }
+
+    /**
+     * The number of values each record in this feed has (following its time
+     * and index), both on-disk and on the wire.
+     */
+    pub fn val_count(&self) -> usize {
+        match *self {
+            Feed::Raw | Feed::Jitter | Feed::Loss | Feed::Failures | Feed::Handshakes => 1,
+            Feed::Averaged | Feed::Daily => 4,
+            Feed::Percentiles => 3,
+        }
+    }
+
+    /**
+     * The names of the values each record in this feed has, as used in
+     * exports.
+     */
+    pub fn val_names(&self) -> &'static [&'static str] {
+        match *self {
+            Feed::Raw => &["value"],
+            Feed::Averaged => &["value", "sd", "min", "max"],
+            Feed::Jitter => &["jitter"],
+            Feed::Loss => &["loss"],
+            Feed::Percentiles => &["p50", "p95", "p99"],
+            Feed::Daily => &["value", "min", "max", "loss"],
+            Feed::Failures => &["reason"],
+            Feed::Handshakes => &["handshake"],
+        }
+    }
+}
+
+/**
+ * Computes the mean and (population) standard deviation of the given values,
+ * or `None` if there are no values.
+ */
+fn mean_sd(vals: &[i32]) -> Option<(i32, i32)> {
+    if vals.is_empty() {
+        return None;
+    }
+
+    let n = vals.len() as f64;
+    let mean = vals.iter().fold(0.0, |acc, &v| acc + v as f64) / n;
+    let variance = vals.iter().fold(0.0, |acc, &v| {
+        let diff = v as f64 - mean;
+        acc + diff * diff
+    }) / n;
+
+    Some((mean.round() as i32, variance.sqrt().round() as i32))
+}
+
+/**
+ * Computes the jitter (mean absolute difference between consecutive values)
+ * of the given values, or `None` if no two values are consecutive.
+ *
+ * Errors (given as `None`) break the chain of consecutive values rather than
+ * counting as differences of their own.
+ */
+fn jitter(vals: &[Option<i32>]) -> Option<i32> {
+    let mut total: i64 = 0;
+    let mut count = 0;
+    for pair in vals.windows(2) {
+        if let (Some(a), Some(b)) = (pair[0], pair[1]) {
+            total += (b as i64 - a as i64).abs();
+            count += 1;
+        }
+    }
+
+    if count == 0 {
+        None
+    } else {
+        Some((total as f64 / count as f64).round() as i32)
+    }
+}
+
+/**
+ * Computes the given percentile of the given (sorted) values by the
+ * nearest-rank method, or `None` if there are no values.
+ */
+fn percentile(sorted_vals: &[i32], p: u32) -> Option<i32> {
+    if sorted_vals.is_empty() {
+        return None;
+    }
+    let rank = (p as usize * sorted_vals.len() + 99) / 100;
+    Some(sorted_vals[if rank > 0 { rank - 1 } else { 0 }])
+}
+
+/**
+ * Computes the percentage (rounded) of the given values that are errors
+ * (given as `None`).
+ */
+fn loss_percent(vals: &[Option<i32>]) -> i32 {
+    let errors = vals.iter().filter(|v| v.is_none()).count();
+    (errors as f64 * 100.0 / vals.len() as f64).round() as i32
+}
+
+/**
+ * Lays out the given (time, millis, index, value) records as records of the
+ * raw (or failures) feed, as back-to-back integers in time order (as data
+ * files must stay in).
+ */
+fn timed_records(mut records: Vec<(i64, i32, AddrId, i32)>) -> Vec<i32> {
+    records.sort_by_key(|&(time, millis, _, _)| (time, millis));
+    let mut out = Vec::with_capacity(records.len() * 5);
+    for &(time, millis, index, val) in records.iter() {
+        out.extend_from_slice(&time_words(time));
+        out.extend_from_slice(&[index.word(), val, millis]);
+    }
+    out
+}
+
+/**
+ * Computes the records of each derived feed (as back-to-back integers, see
+ * `Feed`) of the given raw records for every complete window of the given
+ * length (in seconds) they span.
+ */
+fn stats_over_windows(data: &Records, window: i32) -> HashMap<Feed, Vec<i32>> {
+    let mut out: HashMap<Feed, Vec<i32>> = DERIVED_FEEDS.iter().map(|f| (*f, Vec::new())).collect();
+    let latest = match data.len() {
+        0 => return out,
+        n => data.time(n - 1),
+    };
+
+    let window = window as i64;
+    let mut i = 0;
+    while i < data.len() {
+        let start = data.time(i) - ((data.time(i) % window) + window) % window;
+        let t = time_words(start);
+
+        // a window is complete once we've seen data from after it
+        if start + window > latest {
+            break;
+        }
+
+        // gather the values (errors as None) in this window for each index
+        let mut vals_by_index: BTreeMap<AddrId, Vec<Option<i32>>> = BTreeMap::new();
+        while i < data.len() && data.time(i) < start + window {
+            let vals = vals_by_index.entry(data.index(i)).or_insert_with(Vec::new);
+            let val = data.vals(i)[0];
+            vals.push(if val != SENTINEL_ERROR && val != SENTINEL_NODATA { Some(val) } else { None });
+            i += 1;
+        }
+
+        for (index, vals) in vals_by_index.iter() {
+            let index = index.word();
+            let mut ok_vals: Vec<i32> = vals.iter().filter_map(|v| *v).collect();
+            let (mean, sd) = mean_sd(&ok_vals).unwrap_or((SENTINEL_ERROR, SENTINEL_ERROR));
+            let min = ok_vals.iter().cloned().min().unwrap_or(SENTINEL_ERROR);
+            let max = ok_vals.iter().cloned().max().unwrap_or(SENTINEL_ERROR);
+            out.get_mut(&Feed::Averaged).unwrap().extend(&[t[0], t[1], index, mean, sd, min, max]);
+            out.get_mut(&Feed::Jitter).unwrap()
+                .extend(&[t[0], t[1], index, jitter(vals).unwrap_or(SENTINEL_ERROR)]);
+            out.get_mut(&Feed::Loss).unwrap().extend(&[t[0], t[1], index, loss_percent(vals)]);
+
+            ok_vals.sort();
+            out.get_mut(&Feed::Percentiles).unwrap().extend(&[t[0], t[1], index]);
+            for p in [50, 95, 99].iter() {
+                let val = percentile(&ok_vals, *p).unwrap_or(SENTINEL_ERROR);
+                out.get_mut(&Feed::Percentiles).unwrap().push(val);
+            }
+        }
+    }
+
+    out
+}
+
+/**
+ * Gets the start (in seconds from epoch) of the day the given time falls in,
+ * by the clock of the given time zone.
+ */
+fn day_start<Tz: TimeZone>(tz: &Tz, time: i64) -> i64 {
+    let date = tz.timestamp(time, 0).date();
+    // a daylight saving change can skip midnight, starting the day an hour later
+    date.and_hms_opt(0, 0, 0).or_else(|| date.and_hms_opt(1, 0, 0))
+        .map(|d| d.timestamp())
+        .unwrap_or(time)
+}
+
+/**
+ * Computes the records of the daily feed (as back-to-back integers, see
+ * `Feed`) of the given averaged and loss records (of the same windows) for
+ * every complete day they span, after the day starting at `after` (if
+ * given). Days begin at midnight by the clock of the given time zone.
+ *
+ * The mean is that of the windows' means (which are all the same length),
+ * and windows without any values are left out entirely.
+ */
+fn stats_over_days<Tz: TimeZone>(averaged: &Records, loss: &Records, tz: &Tz, after: Option<i64>) -> Vec<i32> {
+    // a day is complete once we've seen windows from after it
+    let latest_day = match averaged.len() {
+        0 => return Vec::new(),
+        n => day_start(tz, averaged.time(n - 1)),
+    };
+
+    let mut loss_by_window = HashMap::new();
+    for i in 0..loss.len() {
+        loss_by_window.insert((loss.time(i), loss.index(i)), loss.vals(i)[0]);
+    }
+
+    // (sum of means, count of means, min, max, sum of losses, count of losses) for each day and index
+    let mut days: BTreeMap<(i64, AddrId), (i64, i64, i32, i32, i64, i64)> = BTreeMap::new();
+    for i in 0..averaged.len() {
+        let day = day_start(tz, averaged.time(i));
+        if day >= latest_day || after.map_or(false, |a| day <= a) {
+            continue;
+        }
+
+        let acc = days.entry((day, averaged.index(i))).or_insert((0, 0, i32::MAX, i32::MIN, 0, 0));
+        let vals = averaged.vals(i);
+        if vals[0] != SENTINEL_ERROR && vals[0] != SENTINEL_NODATA {
+            acc.0 += vals[0] as i64;
+            acc.1 += 1;
+            acc.2 = cmp::min(acc.2, vals[2]);
+            acc.3 = cmp::max(acc.3, vals[3]);
+        }
+        if let Some(&l) = loss_by_window.get(&(averaged.time(i), averaged.index(i))) {
+            acc.4 += l as i64;
+            acc.5 += 1;
+        }
+    }
+
+    let mut out = Vec::with_capacity(days.len() * (3 + Feed::Daily.val_count()));
+    for (&(day, index), &(sum, count, min, max, loss_sum, loss_count)) in days.iter() {
+        let index = index.word();
+        let t = time_words(day);
+        let mean_of = |sum: i64, count: i64| (sum as f64 / count as f64).round() as i32;
+        if count > 0 {
+            out.extend(&[t[0], t[1], index, mean_of(sum, count), min, max]);
+        } else {
+            out.extend(&[t[0], t[1], index, SENTINEL_ERROR, SENTINEL_ERROR, SENTINEL_ERROR]);
+        }
+        out.push(if loss_count > 0 { mean_of(loss_sum, loss_count) } else { SENTINEL_ERROR });
+    }
+    out
+}
+
+/**
+ * Escapes the given string for use as a CSV cell, quoting it if necessary.
+ */
+fn csv_escape(s: &str) -> String {
+    if s.contains(',') || s.contains('"') || s.contains('\n') {
+        format!("\"{}\"", s.replace("\"", "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/**
+ * Splits the given line of CSV (as written by `csv_escape()`) into its fields,
+ * or `None` if it has an unterminated quoted field.
+ */
+fn csv_fields(line: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            },
+            (true, '"') => quoted = false,
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => fields.push(mem::replace(&mut field, String::new())),
+            (_, c) => field.push(c),
+        }
+    }
+    if quoted {
+        return None;
+    }
+    fields.push(field);
+    Some(fields)
+}
+
+/**
+ * Escapes the given string for use as a Prometheus label value.
+ */
+fn prometheus_escape(s: &str) -> String {
+    s.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+}
+
+/**
+ * Escapes the given string for use as an InfluxDB line protocol tag value.
+ */
+fn influx_escape(s: &str) -> String {
+    s.replace("\\", "\\\\").replace(",", "\\,").replace(" ", "\\ ").replace("=", "\\=")
+}
+
+/**
+ * Finds the highest index that any record of the given data files is
+ * recorded under, if they have any records at all.
+ */
+fn highest_index(data_files: &HashMap<Feed, RwLock<DataFile>>) -> Result<Option<AddrId>, ManagerError> {
+    let mut highest = None;
+    for (feed, file) in data_files.iter() {
+        let file = recover(file.read());
+        for chunk in file.iter() {
+            let ints = try!(chunk.map_err(data_file_error));
+            let records = feed.records(&ints);
+            highest = cmp::max(highest, (0..records.len()).map(|i| records.index(i)).max());
+        }
+    }
+    Ok(highest)
+}
+
+/**
+ * Calls `f` with each of the given records as a row of (time, index, addr,
+ * values), resolving indices to addrs with the given index. Errors (and
+ * missing data) are given as `None` values.
+ *
+ * Stops at (and returns) the first error returned by `f`.
+ */
+fn resolve_rows<F>(records: &Records, index: &AddrIndex, f: &mut F) -> Result<(), ManagerError>
+        where F: FnMut(i64, AddrId, Option<&String>, &[Option<i32>]) -> Result<(), ManagerError> {
+    let mut vals = Vec::with_capacity(records.val_count());
+    for r in 0..records.len() {
+        vals.clear();
+        vals.extend(records.vals(r).iter().map(|&v| {
+            if v == SENTINEL_ERROR || v == SENTINEL_NODATA { None } else { Some(v) }
+        }));
+
+        try!(f(records.time(r), records.index(r), index.get_addr(records.index(r)), &vals));
+    }
+    Ok(())
+}
+
+/**
+ * The most recent records of a feed, kept in memory (in time order) as they
+ * are appended to its data file, so that queries for them (e.g. by
+ * dashboards, which mostly want the last few minutes) needn't read the file.
+ */
+struct RecentRecords {
+    feed: Feed,
+    records: VecDeque<Vec<i32>>,
+    capacity: usize,
+    // time from which these are all of the feed's records (i64::MAX if none yet)
+    complete_from: i64,
+}
+
+impl RecentRecords {
+    fn new(feed: Feed, capacity: usize) -> Self {
+        RecentRecords {
+            feed: feed,
+            records: VecDeque::new(),
+            capacity: capacity,
+            complete_from: i64::MAX,
+        }
+    }
+
+    fn time_of(&self, record: &[i32]) -> (i64, i32) {
+        let r = self.feed.records(record);
+        (r.time(0), r.millis(0))
+    }
+
+    /**
+     * Adds the given records (as just appended to the feed's data file),
+     * dropping the oldest ones if that makes for more than the capacity.
+     */
+    fn add(&mut self, ints: &[i32]) {
+        let count = self.feed.records(ints).len();
+        if self.capacity == 0 || count == 0 {
+            return;
+        }
+
+        for record in ints.chunks(ints.len() / count) {
+            let time = self.time_of(record);
+            if self.complete_from == i64::MAX {
+                self.complete_from = time.0;
+            }
+            if time.0 < self.complete_from {
+                continue;
+            }
+            // records are nearly always appended in order, so look from the back
+            let pos = self.records.iter().rposition(|r| self.time_of(r) <= time).map_or(0, |p| p + 1);
+            self.records.insert(pos, record.to_vec());
+        }
+        self.shrink();
+    }
+
+    fn shrink(&mut self) {
+        while self.records.len() > self.capacity {
+            if let Some(oldest) = self.records.pop_front() {
+                // others at the same time may still be here, but not all of them
+                self.complete_from = cmp::max(self.complete_from, self.time_of(&oldest).0 + 1);
+            }
+        }
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.shrink();
+    }
+
+    /**
+     * Keeps only the records for which the given predicate holds, as the
+     * feed's data file just did.
+     */
+    fn retain<F: Fn(&[i32]) -> bool>(&mut self, predicate: F) {
+        let records = self.records.drain(..).filter(|r| predicate(r)).collect();
+        self.records = records;
+    }
+
+    /**
+     * Forgets all records, as the feed's data file (just emptied) has none.
+     */
+    fn clear(&mut self) {
+        self.records.clear();
+        self.complete_from = i64::MIN;
+    }
+
+    /**
+     * Encodes the records with times in the range `start` to `end`
+     * (inclusive) as `TargetManager::query_range()` does, if all of the
+     * feed's records from `start` onward are here.
+     */
+    fn encode_range(&self, start: i64, end: i64, ordered_list: &[AddrId]) -> Option<Vec<u8>> {
+        if self.capacity == 0 || start < self.complete_from {
+            return None;
+        }
+
+        let mut ints = Vec::new();
+        for r in self.records.iter().filter(|r| self.time_of(r).0 >= start) {
+            ints.extend_from_slice(r);
+        }
+        Some(encode_range(&self.feed.records(&ints), start, end, ordered_list))
+    }
+}
+
+/**
+ * How an addr fared when it was last measured.
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AddrStatus {
+    // it answered
+    Up,
+    // it failed to answer, for the reason given (if one was recorded)
+    Down(Option<FailureReason>),
+}
+
+/**
+ * The result of the latest measurement of an addr (see
+ * `TargetManager::live_status()`).
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LastResult {
+    pub time: i64, // when it was measured, in milliseconds from epoch
+    pub val: i32, // the value measured (`SENTINEL_ERROR` if it failed)
+    pub status: AddrStatus,
+}
+
+/**
+ * Master control structure managing all I/O backed resources (with the
+ * exception of running workers which is handled by `TargetKind` and the main
+ * thread directly) of a given target.
+ *
+ * This is include most notably, the target's data files (one for each feed),
+ * address index (and associated index file), and options (and associated
+ * options file).
+ */
+pub struct TargetManager {
+    pub kind: &'static TargetKind,
+    index: RwLock<AddrIndex>,
+    data_files: HashMap<Feed, RwLock<DataFile>>,
+    options_path: Mutex<PathBuf>,
+    options: RwLock<TargetOptions>,
+    sync_metadata: bool,
+    addr_families: RwLock<HashMap<String, AddrFamily>>,
+    // how many times each addr's prober has retried a failed attempt, as of its worker's last round
+    retries: RwLock<HashMap<String, usize>>,
+    // why each addr's latest measurement failed, until its results are appended
+    failures: Mutex<HashMap<String, FailureReason>>,
+    // how long (in microseconds) each addr's latest TLS handshake took, until its results are appended
+    handshakes: Mutex<HashMap<String, i32>>,
+    subscribers: Mutex<Vec<SyncSender<Arc<TargetResults>>>>,
+    // each addr's latest result, as of the results last appended
+    live: RwLock<HashMap<AddrId, LastResult>>,
+    // the time of the results last appended, and how many of their addrs were alive and lost
+    last_round: Mutex<Option<(i64, usize, usize)>>,
+    // when (in seconds from epoch, by its clock) the manager was created, and each feed last appended to
+    created: Mutex<i64>,
+    last_appends: HashMap<Feed, Mutex<Option<i64>>>,
+    // most addrs the options may have (see `set_max_addrs()`)
+    max_addrs: AtomicUsize,
+    recent: HashMap<Feed, Mutex<RecentRecords>>,
+    clock: RwLock<Arc<Clock>>,
+    // held (and so declared, and dropped, last) for as long as the manager lives
+    _lock: LockFile,
+}
+
+impl TargetManager {
+    /**
+     * Creates a new `TargetManager` for the given target kind that will store
+     * persistent data at the given location path, storing data files with the
+     * given `DataFileOptions`. If `sync_metadata` is set, writes to the
+     * target's options and index files are synced to disk before returning.
+     *
+     * If `recover_corrupt` is set, an index file that can't be read is set
+     * aside for a fresh one (see `AddrIndex::from_path()`), and an options
+     * file that can't be read for options rebuilt from the index (see
+     * `AddrIndex::rebuilt_options()`), rather than failing. Either way, an empty index is first made to reach past the
+     * highest index found in the data files, so that data recorded under
+     * indices it lost reads back as removed addrs, never as new ones.
+     *
+     * The manager holds a lock on the target's `<kind>.lock` file for as long
+     * as it lives, failing with `ManagerError::AlreadyLocked` if another
+     * manager (in this or another instance) already holds it, so that two
+     * instances can't clobber each other's files.
+     */
+    pub fn new<'b>(kind: &'static TargetKind, data_path: &'b Path,
+                   data_file_options: DataFileOptions,
+                   sync_metadata: bool, recover_corrupt: bool) -> Result<Self, ManagerError> {
+        let mut path = data_path.to_owned();
+
+        // lock the target's files before touching any of them
+        path.push(format!("{}.lock", kind.compact_name()));
+        let lock = match try!(LockFile::try_lock(&path).map_err(|e| ManagerError::LockFileIO(e))) {
+            Some(lock) => lock,
+            None => return Err(ManagerError::AlreadyLocked(path)),
+        };
+        path.pop();
+
+        // attempt to open the target's data files
+        let mut data_files = HashMap::new();
+        for feed in ALL_FEEDS.iter() {
+            path.push(format!("{}.{}", kind.compact_name(), feed.compact_name()));
+            let data_file = try!(
+                DataFile::open(&path, &feed.layouts(), data_file_options)
+                .map_err(data_file_error)
+            );
+            data_files.insert(*feed, RwLock::new(data_file));
+            path.pop();
+        }
+
+        /*
+         * attempt to open the target's index file and create an index out of
+         * it (before the options, which may have to be rebuilt from it)
+         */
+        path.push(format!("{}.index.json", kind.compact_name()));
+        let mut index = try!(AddrIndex::from_path(&path, recover_corrupt, sync_metadata));
+        if index.len() == 0 {
+            if let Some(highest) = try!(highest_index(&data_files)) {
+                try!(index.reserve_through(highest));
+            }
+        }
+        path.pop();
+
+        // attempt to open the target's options file
+        let options_file_name = format!("{}.options.json", kind.compact_name());
+        path.push(&options_file_name);
+        let mut options_file = try!(
+            File::open_from(OpenOptions::new().read(true).write(true).create(true), &path)
+            .map_err(|e| ManagerError::OptionsFileIO(e))
+        );
+
+        /*
+         * read back existing options from the options file, or write out
+         * default options for this target to the options file
+         */
+        let options = if try!(options_file.length_p(&path)
+                              .map_err(|e| ManagerError::OptionsFileIO(e))) > 0 {
+            let read = match options_file.read_json_p::<TargetOptions>(&path) {
+                // (options edited by hand never went through `validate()`)
+                Ok(options) => match options.validate(kind) {
+                    Ok(()) => Ok(options),
+                    Err(e) => Err(e),
+                },
+                Err(e @ SPIOError::Parse(_)) | Err(e @ SPIOError::Corrupt(_)) => Err(ManagerError::OptionsFileIO(e)),
+                Err(e) => return Err(ManagerError::OptionsFileIO(e)),
+            };
+            match read {
+                Ok(options) => options,
+                /*
+                 * an options file that can't be trusted is set aside (if asked
+                 * to recover) for options with the addrs of the index
+                 */
+                Err(e) => {
+                    if !recover_corrupt {
+                        warn!("refusing options file path=\"{}\" error=\"{}\"", path.to_str().unwrap_or(""), e);
+                        return Err(e);
+                    }
+                    let bad_path = try!(move_aside(&path).map_err(|e| ManagerError::OptionsFileIO(e)));
+                    warn!("rebuilding options from the index error=\"{}\" moved_to=\"{}\"",
+                          e, bad_path.to_str().unwrap_or(""));
+                    let rebuilt_options = index.rebuilt_options(kind);
+                    try!(
+                        overwrite_json(&rebuilt_options, &path, sync_metadata)
+                        .map_err(|e| ManagerError::OptionsFileIO(e))
+                    );
+                    rebuilt_options
+                },
+            }
+        } else {
+            let default_options = kind.default_options();
+            try!(
+                options_file.write_json_p(&default_options, &path, sync_metadata)
+                .map_err(|e| ManagerError::OptionsFileIO(e))
+            );
+            default_options
+        };
+
+        path.pop();
+
+        // ensure that all addresses present in the options are present in the index
+        try!(index.ensure_for_options(&options));
+
+        // leave the path to the options file here so we can store it
+        path.push(options_file_name);
+
+        Ok(TargetManager {
+            kind: kind,
+            index: RwLock::new(index),
+            data_files: data_files,
+            options_path: Mutex::new(path),
+            options: RwLock::new(options),
+            sync_metadata: sync_metadata,
+            addr_families: RwLock::new(HashMap::new()),
+            retries: RwLock::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+            handshakes: Mutex::new(HashMap::new()),
+            created: Mutex::new(split_millis(SystemClock.now()).0),
+            last_appends: ALL_FEEDS.iter().map(|f| (*f, Mutex::new(None))).collect(),
+            max_addrs: AtomicUsize::new(DEFAULT_MAX_ADDRS),
+            recent: ALL_FEEDS.iter()
+                .map(|f| (*f, Mutex::new(RecentRecords::new(*f, DEFAULT_RECENT_CAPACITY))))
+                .collect(),
+            subscribers: Mutex::new(Vec::new()),
+            live: RwLock::new(HashMap::new()),
+            last_round: Mutex::new(None),
+            clock: RwLock::new(Arc::new(SystemClock)),
+            _lock: lock,
+        })
+    }
+
+    /**
+     * Records the address family the given addr was last measured over.
+     */
+    pub fn record_addr_family(&self, addr: &str, family: AddrFamily) {
+        let mut families = recover(self.addr_families.write());
+        if families.get(addr) != Some(&family) {
+            families.insert(addr.to_owned(), family);
+        }
+    }
+
+    /**
+     * Records how many times (in all) the prober of the given addr has retried
+     * a failed attempt, as counted by the worker (see `worker::measure()`).
+     */
+    pub fn record_retries(&self, addr: &str, count: usize) {
+        let mut retries = recover(self.retries.write());
+        if retries.get(addr) != Some(&count) {
+            retries.insert(addr.to_owned(), count);
+        }
+    }
+
+    /**
+     * Gets how many times the prober of the given addr has retried a failed
+     * attempt (see `record_retries()`), 0 if it hasn't been measured.
+     */
+    pub fn retries(&self, addr: &str) -> usize {
+        recover(self.retries.read()).get(addr).cloned().unwrap_or(0)
+    }
+
+    /**
+     * Sets how many of the most recent records of each feed are kept in
+     * memory (see `query_range()`), none at all if 0.
+     */
+    pub fn set_recent_capacity(&self, capacity: usize) {
+        for recent in self.recent.values() {
+            recover(recent.lock()).set_capacity(capacity);
+        }
+    }
+
+    /**
+     * Sets how many addrs this target's options may have at most (after
+     * spreading out `addr_ports` and dropping duplicates), so that a client
+     * can't have the worker spawn a prober thread for each of thousands of
+     * addrs. Options updates with more are refused.
+     */
+    pub fn set_max_addrs(&self, max: usize) {
+        self.max_addrs.store(max, Ordering::SeqCst);
+    }
+
+    /**
+     * Sets whether new addrs of this target take the lowest tombstoned index
+     * of its index (see `AddrIndex::set_reuse_tombstones()` for the
+     * trade-off), rather than a fresh one. Off to begin with.
+     */
+    pub fn set_reuse_tombstones(&self, reuse: bool) {
+        recover(self.index.write()).set_reuse_tombstones(reuse);
+    }
+
+    /**
+     * Sets the clock this target (and its worker, if started afterwards) tells
+     * the time by, the system's (see `SystemClock`) to begin with. The manager
+     * counts as created (see `is_stale()`) as of the new clock's time.
+     */
+    pub fn set_clock(&self, clock: Arc<Clock>) {
+        let mut guard = recover(self.clock.write());
+        *recover(self.created.lock()) = split_millis(clock.now()).0;
+        *guard = clock;
+    }
+
+    /**
+     * Gets the clock this target tells the time by (see `set_clock()`).
+     */
+    pub fn clock(&self) -> Arc<Clock> {
+        recover(self.clock.read()).clone()
+    }
+
+    /**
+     * Records why the latest measurement of the given addr failed, to be
+     * stored in the failures feed along with the error in the next results
+     * appended (if the target's options ask for it). Reasons of measurements
+     * that end up succeeding (e.g. on a retry) are dropped.
+     */
+    pub fn record_failure(&self, addr: &str, reason: FailureReason) {
+        recover(self.failures.lock()).insert(addr.to_owned(), reason);
+    }
+
+    /**
+     * Records how long (in microseconds) the TLS handshake of the latest
+     * measurement of the given addr took, to be stored in the handshakes feed
+     * along with its value in the next results appended. Handshakes of
+     * measurements that end up failing are dropped.
+     */
+    pub fn record_handshake(&self, addr: &str, micros: i32) {
+        recover(self.handshakes.lock()).insert(addr.to_owned(), micros);
+    }
+
+    /**
+     * Gets the address family the given addr was last measured over (if it
+     * has been measured over a known family).
+     */
+    pub fn addr_family(&self, addr: &str) -> Option<AddrFamily> {
+        recover(self.addr_families.read()).get(addr).cloned()
+    }
+
+    /**
+     * Acquires a read lock on this target's options.
+     */
+    pub fn options_read<'a>(&'a self) -> RwLockReadGuard<'a, TargetOptions> {
+        recover(self.options.read())
+    }
+
+    /**
+     * Gets a copy of this target's current options, releasing the read lock
+     * on them straight away, so that holding on to them (e.g. over a long
+     * measurement or query) never holds up `options_update()`.
+     */
+    pub fn options_snapshot(&self) -> TargetOptions {
+        self.options_read().clone()
+    }
+
+    /**
+     * Attempts to update this target's options with the given new options.
+     *
+     * Hosts given with several ports (`addr_ports`) are spread out into an
+     * addr for each port, which are then stored (and measured) like any
+     * other addrs. Addrs given more than once are only kept the first time,
+     * so that they aren't measured (and counted) twice, and the order they
+     * are shown in stays as given.
+     *
+     * Nothing is written if the new options are the same as the current
+     * ones, and the index is only touched if the addrs (or their labels)
+     * changed.
+     */
+    pub fn options_update(&self, new_options: TargetOptions) -> Result<(), ManagerError> {
+        let new_options = try!(self.normalized_options(new_options));
+        let mut guard = recover(self.options.write());
+        self.store_options(&mut guard, new_options)
+    }
+
+    /**
+     * Gets the given options as they would be stored (see
+     * `options_update()`), with duplicate addrs dropped and `addr_ports`
+     * spread out, or refuses them if they aren't valid.
+     */
+    fn normalized_options(&self, mut new_options: TargetOptions) -> Result<TargetOptions, ManagerError> {
+        let mut addrs = Vec::with_capacity(new_options.addrs.len());
+        for addr in new_options.addrs.drain(..) {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        new_options.addrs = addrs;
+
+        let addr_ports = new_options.addr_ports.take().unwrap_or(BTreeMap::new());
+        for (host, ports) in addr_ports.iter() {
+            for port in ports.iter() {
+                let addr = host_port(host, *port);
+                if !new_options.addrs.contains(&addr) {
+                    new_options.addrs.push(addr);
+                }
+            }
+        }
+
+        // refuse bad options before they ever make it to disk
+        let max_addrs = self.max_addrs.load(Ordering::SeqCst);
+        if new_options.addrs.len() > max_addrs {
+            return Err(ManagerError::TooManyAddrs(new_options.addrs.len(), max_addrs));
+        }
+        try!(new_options.validate(self.kind));
+        Ok(new_options)
+    }
+
+    /**
+     * Replaces the given (locked) options of this target with the given
+     * (normalized) ones, writing them out and bringing the index up to date
+     * (see `options_update()`).
+     */
+    fn store_options(&self, guard: &mut TargetOptions, new_options: TargetOptions) -> Result<(), ManagerError> {
+        if *guard == new_options {
+            return Ok(());
+        }
+        let addrs_changed = guard.addrs != new_options.addrs || guard.labels != new_options.labels;
+
+        let options_path = recover(self.options_path.lock());
+        *guard = new_options;
+        try!(
+            overwrite_json(&*guard, &*options_path, self.sync_metadata)
+            .map_err(|e| ManagerError::OptionsFileIO(e))
+        );
+        if addrs_changed {
+            // (the addrs of addr_ports are among the addrs by now)
+            try!(recover(self.index.write()).ensure_for_options(&*guard));
+        }
+        info!("updated options kind={} options={:?}", self.kind.compact_name(), *guard);
+        Ok(())
+    }
+
+    /**
+     * Replaces this target's options with the given ones (see
+     * `options_update()`) on behalf of a client, provided they're based on
+     * the current options (i.e. have the current nonce).
+     *
+     * Gives back the nonce of the resulting options, which is incremented if
+     * they changed, so that clients can tell they need to refetch them.
+     *
+     * The options stay locked from checking the nonce through to storing
+     * them, so that of several replacements based on the same options, only
+     * the first succeeds.
+     */
+    pub fn options_replace(&self, mut new_options: TargetOptions) -> Result<i32, ManagerError> {
+        let mut guard = recover(self.options.write());
+
+        // make sure the given nonce matches the existing nonce
+        let nonce = guard.nonce;
+        if new_options.nonce != nonce {
+            return Err(ManagerError::NonceConflict(new_options.nonce));
+        }
+
+        // options re-sent unchanged keep their nonce (and aren't rewritten)
+        if new_options == *guard {
+            return Ok(nonce);
+        }
+
+        // increment (and wrap-around if necessary) the nonce
+        new_options.nonce = nonce.checked_add(1).unwrap_or(0);
+        let new_nonce = new_options.nonce;
+        let new_options = try!(self.normalized_options(new_options));
+        try!(self.store_options(&mut guard, new_options));
+        Ok(new_nonce)
+    }
+
+    /**
+     * Adds the given addr to this target's options (see `options_replace()`),
+     * if it isn't there already, giving back the nonce of the resulting
+     * options.
+     */
+    pub fn add_addr(&self, addr: &str) -> Result<i32, ManagerError> {
+        let mut new_options = self.options_snapshot();
+        if !new_options.addrs.iter().any(|a| a == addr) {
+            new_options.addrs.push(addr.to_owned());
+        }
+        self.options_replace(new_options)
+    }
+
+    /**
+     * Removes the given addr (along with its label and interval override, if
+     * any) from this target's options (see `options_replace()`), giving back
+     * the nonce of the resulting options. Its data is kept.
+     */
+    pub fn remove_addr(&self, addr: &str) -> Result<i32, ManagerError> {
+        let mut new_options = self.options_snapshot();
+        if !new_options.addrs.iter().any(|a| a == addr) {
+            return Err(ManagerError::InvalidAddrArgument(addr.to_owned()));
+        }
+        new_options.addrs.retain(|a| a != addr);
+        if let Some(ref mut labels) = new_options.labels {
+            labels.remove(addr);
+        }
+        if let Some(ref mut addr_intervals) = new_options.addr_intervals {
+            addr_intervals.remove(addr);
+        }
+        self.options_replace(new_options)
+    }
+
+    /**
+     * Gets the index of the given addr in this target's index, if it has one.
+     */
+    pub fn addr_index(&self, addr: &str) -> Option<AddrId> {
+        recover(self.index.read()).index_of(addr)
+    }
+
+    /**
+     * Removes the addrs from this target's index that are no longer in its
+     * options (see `remove_addr()`) and have no records left in any feed
+     * (e.g. as they were never measured, or their data has since been
+     * pruned), leaving tombstones in their place. Gives back the addrs
+     * removed.
+     *
+     * Addrs that still have data are kept no matter how old it is, so that it
+     * can still be queried by addr.
+     */
+    pub fn compact_index(&self) -> Result<Vec<String>, ManagerError> {
+        // hold on to the options and index so nothing new refers to an addr meanwhile
+        let options = self.options_read();
+        let mut index = recover(self.index.write());
+
+        let mut has_data = HashSet::new();
+        for feed in ALL_FEEDS.iter() {
+            let file = self.data_file_read(*feed);
+            for chunk in file.iter() {
+                let ints = try!(chunk.map_err(data_file_error));
+                let records = feed.records(&ints);
+                has_data.extend((0..records.len()).map(|i| records.index(i)));
+            }
+        }
+
+        let in_options = |addr: &String| {
+            options.addrs.contains(addr) || options.labels.as_ref().map_or(false, |l| l.contains_key(addr))
+        };
+        let (indices, addrs): (Vec<AddrId>, Vec<String>) = index.data.iter().enumerate()
+            .filter_map(|(i, a)| a.as_ref().map(|a| (AddrId::from(i as u32), a.clone())))
+            .filter(|&(i, ref a)| !has_data.contains(&i) && !in_options(a))
+            .unzip();
+        try!(index.remove_addrs(&indices));
+
+        info!("compacted index kind={} removed={:?}", self.kind.compact_name(), addrs);
+        Ok(addrs)
+    }
+
+    /**
+     * Renames the given addr of this target's options to the given new addr
+     * (see `options_replace()`), carrying over its label and interval
+     * override, if any, and giving back the nonce of the resulting options.
+     *
+     * Unlike removing the addr and adding the new one, the new addr takes
+     * over the old one's index (see `AddrIndex::rename_addr()`), so the data
+     * recorded so far reads back under the new addr. Meant for when a host's
+     * name changes, but it's still the same host.
+     */
+    pub fn rename_addr(&self, addr: &str, new_addr: &str) -> Result<i32, ManagerError> {
+        let mut new_options = self.options_snapshot();
+        if !new_options.addrs.iter().any(|a| a == addr) {
+            return Err(ManagerError::InvalidAddrArgument(addr.to_owned()));
+        }
+        if addr == new_addr {
+            return Ok(new_options.nonce);
+        }
+        if new_options.addrs.iter().any(|a| a == new_addr) {
+            return Err(ManagerError::InvalidAddrArgument(new_addr.to_owned()));
+        }
+
+        for a in new_options.addrs.iter_mut().filter(|a| *a == addr) {
+            *a = new_addr.to_owned();
+        }
+        if let Some(ref mut labels) = new_options.labels {
+            if let Some(label) = labels.remove(addr) {
+                labels.insert(new_addr.to_owned(), label);
+            }
+        }
+        if let Some(ref mut addr_intervals) = new_options.addr_intervals {
+            if let Some(interval) = addr_intervals.remove(addr) {
+                addr_intervals.insert(new_addr.to_owned(), interval);
+            }
+        }
+        // refuse a bad new addr before the index is touched
+        try!(new_options.validate(self.kind));
+
+        {
+            let mut index = recover(self.index.write());
+            let i = index.get_index(addr);
+            try!(index.rename_addr(i, new_addr));
+        }
+        {
+            let mut families = recover(self.addr_families.write());
+            if let Some(family) = families.remove(addr) {
+                families.insert(new_addr.to_owned(), family);
+            }
+        }
+        info!("renamed addr kind={} from={} to={}", self.kind.compact_name(), addr, new_addr);
+        self.options_replace(new_options)
+    }
+
+    /**
+     * Acquires a read lock on this target's data file for the given feed.
+     */
+    pub fn data_file_read<'a>(&'a self, feed: Feed) -> RwLockReadGuard<'a, DataFile> {
+        recover(self.data_files[&feed].read())
+    }
+
+    /**
+     * Acquires a write lock on this target's data file for the given feed.
+     */
+    fn data_file_write<'a>(&'a self, feed: Feed) -> Result<RwLockWriteGuard<'a, DataFile>, ManagerError> {
+        match self.data_files.get(&feed) {
+            Some(lock) => Ok(recover(lock.write())),
+            None => Err(ManagerError::FeedNotFound(feed)),
+        }
+    }
+
+    /**
+     * Appends the given live-collected data (`TargetResults`) to this target's
+     * data file.
+     */
+    pub fn append_data(&self, data_res: &TargetResults) -> Result<(), ManagerError> {
+        let ref in_data = data_res.0;
+
+        assert!(in_data[0] == self.kind.kind_id());
+
+        let nonce = in_data[1];
+        if nonce != self.options_read().nonce {
+            debug!("ignoring data from stale options kind={} nonce={}", self.kind.compact_name(), nonce);
+            return Ok(());
+        }
+
+        let round_time = time_from_words(in_data[2], in_data[3]);
+        let record_failures = self.options_read().record_failure_reasons.unwrap_or(false);
+        let mut pending_failures = mem::replace(&mut *recover(self.failures.lock()), HashMap::new());
+        let mut pending_handshakes = mem::replace(&mut *recover(self.handshakes.lock()), HashMap::new());
+        let mut records = Vec::with_capacity(in_data.len() - 4);
+        let mut failures = Vec::new();
+        let mut handshakes = Vec::new();
+        let mut latest = Vec::new();
+        let index = recover(self.index.read());
+        for (i, (addr, val)) in self.options_read().addrs.iter().zip(in_data[4..].iter()).enumerate() {
+            let failure = pending_failures.remove(addr);
+            let handshake = pending_handshakes.remove(addr);
+            // addrs that weren't measured this time have nothing to store
+            if *val == SENTINEL_NODATA {
+                continue;
+            }
+            // datapoints are stored at the time they were measured, if known
+            let (time, millis) = match data_res.1.get(i) {
+                Some(&t) => split_millis(t),
+                None => (round_time, 0),
+            };
+            records.push((time, millis, index.get_index(addr), *val));
+            latest.push((index.get_index(addr), LastResult {
+                time: time * 1000 + millis as i64,
+                val: *val,
+                status: if *val == SENTINEL_ERROR { AddrStatus::Down(failure) } else { AddrStatus::Up },
+            }));
+            match failure {
+                Some(reason) if record_failures && *val == SENTINEL_ERROR => {
+                    failures.push((time, millis, index.get_index(addr), reason.code()));
+                },
+                _ => (),
+            }
+            match handshake {
+                Some(micros) if *val != SENTINEL_ERROR => {
+                    handshakes.push((time, millis, index.get_index(addr), micros));
+                },
+                _ => (),
+            }
+        }
+
+        let out_data = timed_records(records);
+        {
+            let ref mut file = *try!(self.data_file_write(Feed::Raw));
+            try!(file.append(&out_data.clone().into_raw_bytes())
+                 .map_err(data_file_error));
+        }
+        self.note_append(Feed::Raw, &out_data);
+        recover(self.live.write()).extend(latest);
+        *recover(self.last_round.lock()) = Some((round_time, data_res.alive_count(), data_res.loss_count()));
+
+        if !failures.is_empty() {
+            let failure_data = timed_records(failures);
+            {
+                let ref mut file = *try!(self.data_file_write(Feed::Failures));
+                try!(file.append(&failure_data.clone().into_raw_bytes())
+                     .map_err(data_file_error));
+            }
+            self.note_append(Feed::Failures, &failure_data);
+        }
+
+        if !handshakes.is_empty() {
+            let handshake_data = timed_records(handshakes);
+            {
+                let ref mut file = *try!(self.data_file_write(Feed::Handshakes));
+                try!(file.append(&handshake_data.clone().into_raw_bytes())
+                     .map_err(data_file_error));
+            }
+            self.note_append(Feed::Handshakes, &handshake_data);
+        }
+
+        /*
+         * hand each subscriber the same copy of the results, forgetting about
+         * those that have since hung up or fallen too far behind (sending
+         * never blocks)
+         */
+        let mut subscribers = recover(self.subscribers.lock());
+        if !subscribers.is_empty() {
+            let shared = Arc::new(TargetResults(in_data.clone(), data_res.1.clone()));
+            subscribers.retain(|s| match s.try_send(shared.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("subscriber fell behind, dropping it kind={}", self.kind.compact_name());
+                    false
+                },
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+        }
+        Ok(())
+    }
+
+    /**
+     * Records that the given records were just appended to the given feed
+     * (see `last_append_time()`), keeping them in memory.
+     */
+    fn note_append(&self, feed: Feed, ints: &[i32]) {
+        if let Some(last) = self.last_appends.get(&feed) {
+            *recover(last.lock()) = Some(split_millis(self.clock().now()).0);
+        }
+        if let Some(recent) = self.recent.get(&feed) {
+            recover(recent.lock()).add(ints);
+        }
+    }
+
+    /**
+     * Gets the result of the latest measurement of each addr (by index), as
+     * of the results last appended, without reading the data files. Addrs
+     * not yet measured since the manager was created have none.
+     */
+    pub fn live_status(&self) -> HashMap<AddrId, LastResult> {
+        recover(self.live.read()).clone()
+    }
+
+    /**
+     * Gets when (in seconds from epoch) data was last appended to this
+     * target's data file for the given feed, if it has been at all since the
+     * manager was created. Results appended with stale options (and so
+     * dropped) don't count.
+     */
+    pub fn last_append_time(&self, feed: Feed) -> Option<i64> {
+        self.last_appends.get(&feed).and_then(|l| *recover(l.lock()))
+    }
+
+    /**
+     * Checks whether nothing has been appended to this target's data file for
+     * the given feed for longer than the given age (counting from when the
+     * manager was created, if nothing has been appended yet).
+     *
+     * For the raw feed, this means the target's worker has stopped sending
+     * back results (e.g. because it died or wedged), so a supervisor can poll
+     * this to notice and restart it.
+     */
+    pub fn is_stale(&self, feed: Feed, max_age: Duration) -> bool {
+        self.is_stale_at(feed, max_age, split_millis(self.clock().now()).0)
+    }
+
+    fn is_stale_at(&self, feed: Feed, max_age: Duration, now: i64) -> bool {
+        let last = self.last_append_time(feed).unwrap_or(*recover(self.created.lock()));
+        now - last > max_age.as_secs() as i64
+    }
+
+    /**
+     * Subscribes to this target's live data, giving a receiver of every set
+     * of results (`TargetResults`) appended from now on. Dropping the
+     * receiver unsubscribes.
+     *
+     * A receiver that falls more than `SUBSCRIBER_CAPACITY` sets of results
+     * behind is unsubscribed (its channel hangs up once it catches up), so
+     * that a stalled subscriber can't have results pile up without limit.
+     */
+    pub fn subscribe(&self) -> Receiver<Arc<TargetResults>> {
+        let (tx, rx) = sync_channel(SUBSCRIBER_CAPACITY);
+        recover(self.subscribers.lock()).push(tx);
+        rx
+    }
+
+    /**
+     * Writes out any data still buffered in this target's data files.
+     */
+    pub fn flush(&self) -> Result<(), ManagerError> {
+        for feed in self.data_files.keys() {
+            try!(try!(self.data_file_write(*feed)).flush().map_err(data_file_error));
+        }
+        Ok(())
+    }
+
+    /**
+     * Writes out any data still buffered in this target's data files, and
+     * ensures that its index and options are persisted, e.g. before shutting
+     * down.
+     */
+    pub fn flush_all(&self) -> Result<(), ManagerError> {
+        try!(self.flush());
+        try!(recover(self.index.read()).sync());
+
+        let options = self.options_read();
+        let options_path = recover(self.options_path.lock());
+        overwrite_json(&*options, &*options_path, true)
+            .map_err(|e| ManagerError::OptionsFileIO(e))
+    }
+
+    /**
+     * Computes averages (and standard deviations) of the raw data over
+     * consecutive windows of the given length (in seconds), appending them to
+     * this target's averaged data file.
+     *
+     * Only windows not yet averaged and that are complete (i.e. for which
+     * raw data after the window has already been collected) are processed.
+     * Error values are excluded from the averages entirely.
+     *
+     * The jitter over each window is computed at the same time, and appended
+     * to this target's jitter data file.
+     *
+     * Feeds turned off in the options (see `TargetOptions::feeds_enabled`)
+     * are left as they are, and each feed picks up from its own last window,
+     * so one turned back on catches up on the windows it missed (as far as
+     * the raw data for them is still around).
+     */
+    pub fn compact_averages(&self, window: i32) -> Result<(), ManagerError> {
+        let options = self.options_snapshot();
+
+        // hold on to the derived data files so no one else compacts meanwhile
+        let mut files = Vec::with_capacity(DERIVED_FEEDS.len());
+        for feed in DERIVED_FEEDS.iter().filter(|f| options.feed_enabled(**f)) {
+            files.push((*feed, try!(self.data_file_write(*feed))));
+        }
+
+        /*
+         * figure out where each left off from the last window it computed, by
+         * time rather than by where in the raw data file (see
+         * `DataFile::append()`), as the raw data is likely to have been pruned
+         * (so renumbered) while a feed was turned off
+         */
+        let mut next_windows = HashMap::with_capacity(files.len());
+        for &(feed, ref file) in files.iter() {
+            let span = try!(file.time_span().map_err(data_file_error));
+            next_windows.insert(feed, span.map_or(i64::MIN, |s| s.1 + window as i64));
+        }
+        let start = match next_windows.values().min() {
+            Some(start) => *start,
+            None => return Ok(()),
+        };
+
+        // read back the raw data we haven't yet computed them over
+        let data = try!(
+            self.data_file_read(Feed::Raw)
+            .read_range(start, i64::MAX)
+            .map_err(data_file_error)
+        );
+
+        let mut stats = stats_over_windows(&Feed::Raw.records(&data), window);
+        for &mut (feed, ref mut file) in files.iter_mut() {
+            let next_window = next_windows[&feed];
+            let out_data: Vec<i32> = stats.remove(&feed).unwrap_or(Vec::new())
+                .chunks(Records::record_size(feed.val_count()) / mem::size_of::<i32>())
+                .filter(|r| time_from_words(r[0], r[1]) >= next_window)
+                .flat_map(|r| r.iter().cloned())
+                .collect();
+            if !out_data.is_empty() {
+                try!(file.append(&out_data.clone().into_raw_bytes())
+                     .map_err(data_file_error));
+                self.note_append(feed, &out_data);
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Rolls up the averaged data (and loss) over each day, by the local clock,
+     * appending the results to this target's daily data file.
+     *
+     * Only days not yet rolled up and that are complete (i.e. for which
+     * averaged data after the day has already been computed) are processed.
+     */
+    pub fn compact_daily(&self) -> Result<(), ManagerError> {
+        if !self.options_read().feed_enabled(Feed::Daily) {
+            return Ok(());
+        }
+
+        // hold on to the daily data file so no one else compacts meanwhile
+        let ref mut daily = *try!(self.data_file_write(Feed::Daily));
+
+        // figure out where we left off from the last day we rolled up
+        let mut last_day = None;
+        try!(
+            daily.read_backward(1, |r| {
+                last_day = r.get(1).map(|_| time_from_words(r[0], r[1]));
+                false
+            })
+            .map_err(data_file_error)
+        );
+
+        // read back the averaged data (and loss) from the last day onward
+        let start = last_day.map_or(i64::MIN, |d| d.saturating_add(1));
+        let averaged = try!(self.data_file_read(Feed::Averaged).read_range(start, i64::MAX).map_err(data_file_error));
+        let loss = try!(self.data_file_read(Feed::Loss).read_range(start, i64::MAX).map_err(data_file_error));
+
+        let out_data = stats_over_days(&Feed::Averaged.records(&averaged), &Feed::Loss.records(&loss),
+                                       &Local, last_day);
+        if !out_data.is_empty() {
+            try!(daily.append(&out_data.clone().into_raw_bytes()).map_err(data_file_error));
+            self.note_append(Feed::Daily, &out_data);
+        }
+        Ok(())
+    }
+
+    /**
+     * Gets statistics about this target's data file for each feed (see
+     * `FeedStats`), without reading more than the first and last records of
+     * each.
+     */
+    pub fn stats(&self) -> Result<Vec<FeedStats>, ManagerError> {
+        let mut stats = Vec::with_capacity(ALL_FEEDS.len());
+        for feed in ALL_FEEDS.iter() {
+            let file = self.data_file_read(*feed);
+            let span = try!(file.time_span().map_err(data_file_error));
+            stats.push(FeedStats {
+                feed: *feed,
+                records: try!(file.record_count().map_err(data_file_error)),
+                earliest: span.map(|s| s.0),
+                latest: span.map(|s| s.1),
+                disk_size: try!(file.disk_size().map_err(data_file_error)),
+            });
+        }
+        Ok(stats)
+    }
+
+    /**
+     * Drops all data older than the given cutoff time from this target's data
+     * file for the given feed.
+     *
+     * As data is stored in time order, this amounts to deleting segments
+     * entirely before the cutoff, and copying the data from the cutoff
+     * onwards to the front of the segment it falls in.
+     */
+    pub fn prune_before(&self, feed: Feed, cutoff: i64) -> Result<(), ManagerError> {
+        // hold on to the data file so no one reads or appends meanwhile
+        let ref mut file = *try!(self.data_file_write(feed));
+
+        try!(file.prune_before(cutoff).map_err(data_file_error));
+        recover(self.recent[&feed].lock()).retain(|r| time_from_words(r[0], r[1]) >= cutoff);
+        Ok(())
+    }
+
+    /**
+     * Drops all data from this target's data file for the given feed, leaving
+     * its index and options as they are.
+     */
+    pub fn clear_feed(&self, feed: Feed) -> Result<(), ManagerError> {
+        let ref mut file = *try!(self.data_file_write(feed));
+
+        try!(file.clear().map_err(data_file_error));
+        recover(self.recent[&feed].lock()).clear();
+        info!("cleared feed kind={} feed={:?}", self.kind.compact_name(), feed);
+        Ok(())
+    }
+
+    /**
+     * Drops the data for the addr with the given index (see `AddrIndex`) from
+     * this target's data file for the given feed, leaving the data for other
+     * addrs (and the index and options) as they are.
+     */
+    pub fn clear_index(&self, feed: Feed, index: AddrId) -> Result<(), ManagerError> {
+        let ref mut file = *try!(self.data_file_write(feed));
+
+        // every record has the index of its addr right after its time
+        try!(file.retain(|r| r[2] != index.word()).map_err(data_file_error));
+        recover(self.recent[&feed].lock()).retain(|r| r[2] != index.word());
+        info!("cleared addr index kind={} feed={:?} index={}", self.kind.compact_name(), feed, index);
+        Ok(())
+    }
+
+    /**
+     * Drops all data older than this target's retention (if it has one) from
+     * all of its data files, given the current time.
+     *
+     * Data files are pruned regardless, so that any data appended out of
+     * order is put back in order (see `DataFile::prune_before()`).
+     */
+    pub fn prune_expired(&self, now: i64) -> Result<(), ManagerError> {
+        let cutoff = match self.options_read().retention {
+            Some(r) => now.saturating_sub(r as i64),
+            None => i64::MIN,
+        };
+
+        for feed in ALL_FEEDS.iter() {
+            try!(self.prune_before(*feed, cutoff));
+        }
+        Ok(())
+    }
+
+    /**
+     * Queries this target's data file for the given feed for data with times
+     * in the range `start` to `end` (inclusive), encoded in the wire format
+     * (without a header) in the order of the current addrs in options.
+     *
+     * The result is empty if there is no data in the range.
+     *
+     * Ranges covered by the most recent records of the feed (see
+     * `set_recent_capacity()`) are answered from memory, without reading the
+     * data file at all.
+     */
+    pub fn query_range(&self, feed: Feed, start: i64, end: i64) -> Result<Vec<u8>, ManagerError> {
+        let (_, ordered_list, _) = self.get_current_indices();
+        if let Some(out) = self.recent.get(&feed)
+                               .and_then(|r| recover(r.lock()).encode_range(start, end, &ordered_list)) {
+            return Ok(out);
+        }
+        let file = self.data_file_read(feed);
+
+        /*
+         * a round of data is never split across segments, so each segment can
+         * be encoded on its own
+         */
+        let mut out = Vec::new();
+        try!(
+            file.for_each_segment(|ints| {
+                let records = feed.records(ints);
+                out.extend(encode_range(&records, start, end, &ordered_list))
+            })
+            .map_err(data_file_error)
+        );
+        Ok(out)
+    }
+
+    /**
+     * Queries this target's data file for the given feed for data with times
+     * in the range `start` to `end` (inclusive), as rows of (addr, time,
+     * values) with errors (and missing data) as `None` values.
+     *
+     * Unlike `query_range()`, data of every addr ever measured is included,
+     * with the addrs of indices that have since been removed given as
+     * `REMOVED_ADDR`.
+     */
+    pub fn query_resolved(&self, feed: Feed, start: i64, end: i64)
+                          -> Result<Vec<(String, i64, Vec<Option<i32>>)>, ManagerError> {
+        let ints = try!(self.data_file_read(feed).read_range(start, end).map_err(data_file_error));
+
+        // resolve all the indices under the one lock
+        let index = recover(self.index.read());
+        let mut out = Vec::new();
+        try!(resolve_rows(&feed.records(&ints), &index, &mut |time, _, addr, vals| {
+            let addr = addr.map(|a| a.clone()).unwrap_or(REMOVED_ADDR.to_owned());
+            out.push((addr, time, vals.to_vec()));
+            Ok(())
+        }));
+        Ok(out)
+    }
+
+    /**
+     * Queries this target's data for the given feed (as `query_resolved()`
+     * does) as JSON, for APIs that would rather not decode the wire format: an
+     * array of objects of {addr, time, <values>...} (see `Feed::val_names()`),
+     * with errors (and missing data) as `null`.
+     */
+    pub fn query_json(&self, feed: Feed, start: i64, end: i64) -> Result<Json, ManagerError> {
+        let rows = try!(self.query_resolved(feed, start, end));
+        Ok(Json::Array(rows.into_iter().map(|(addr, time, vals)| {
+            let mut row = BTreeMap::new();
+            row.insert("addr".to_owned(), Json::String(addr));
+            row.insert("time".to_owned(), Json::I64(time));
+            for (name, val) in feed.val_names().iter().zip(vals.iter()) {
+                row.insert(name.to_string(), val.map_or(Json::Null, |v| Json::I64(v as i64)));
+            }
+            Json::Object(row)
+        }).collect()))
+    }
+
+    /**
+     * Calls `f` with each record of this target's data for the given feed as
+     * a row (see `resolve_rows()`), for exporting the data.
+     *
+     * The records are read a chunk at a time (see `DataFile::iter()`), so
+     * exporting doesn't take more memory the more data there is.
+     */
+    fn for_each_row<F>(&self, feed: Feed, mut f: F) -> Result<(), ManagerError>
+            where F: FnMut(i64, AddrId, Option<&String>, &[Option<i32>]) -> Result<(), ManagerError> {
+        let index = recover(self.index.read());
+        let file = self.data_file_read(feed);
+
+        // stop at the first error
+        for chunk in file.iter() {
+            let ints = try!(chunk.map_err(data_file_error));
+            try!(resolve_rows(&feed.records(&ints), &index, &mut f));
+        }
+        Ok(())
+    }
+
+    /**
+     * Gets the name of the address family each addr was last measured over
+     * (see `record_addr_family()`), for exports. As families aren't recorded
+     * along with the data, this is only a best guess for older records.
+     */
+    fn family_names(&self) -> HashMap<String, &'static str> {
+        recover(self.addr_families.read()).iter()
+            .map(|(addr, family)| (addr.clone(), family.name()))
+            .collect()
+    }
+
+    /**
+     * Gets the name of the reason (see `FailureReason`) each failed
+     * measurement of the raw data failed for, by its time (to the second)
+     * and index, for those recorded in the failures feed.
+     */
+    fn failure_names(&self) -> Result<HashMap<(i64, AddrId), &'static str>, ManagerError> {
+        let mut names = HashMap::new();
+        try!(
+            self.data_file_read(Feed::Failures).for_each_segment(|ints| {
+                let records = Feed::Failures.records(ints);
+                for r in 0..records.len() {
+                    if let Some(reason) = FailureReason::from_code(records.vals(r)[0]) {
+                        names.insert((records.time(r), records.index(r)), reason.name());
+                    }
+                }
+            })
+            .map_err(data_file_error)
+        );
+        Ok(names)
+    }
+
+    /**
+     * Exports all of this target's data for the given feed as CSV (with a
+     * header row) to the given writer, one row per record. Each row gives the
+     * target's kind, and the address family the addr was last measured over
+     * (see `family_names()`), if known. Rows of raw data also give the reason
+     * errors failed (see `failure_names()`), if recorded.
+     *
+     * Errors (and unknown families and reasons) are written as empty cells, so
+     * that importers treat them as missing.
+     */
+    pub fn export_csv<W: Write>(&self, feed: Feed, mut writer: W) -> Result<(), ManagerError> {
+        let reasons = if feed == Feed::Raw { Some(try!(self.failure_names())) } else { None };
+        let header = format!("time,index,addr,kind,family,{}{}\n", feed.val_names().join(","),
+                             if reasons.is_some() { ",reason" } else { "" });
+        try!(writer.write_all(header.as_bytes()).map_err(|e| ManagerError::ExportIO(e)));
+
+        let kind = self.kind.compact_name();
+        let families = self.family_names();
+        self.for_each_row(feed, |time, index, addr, vals| {
+            let family = addr.and_then(|a| families.get(a)).cloned().unwrap_or("");
+            let addr = addr.map(|a| csv_escape(a)).unwrap_or(String::new());
+            let mut row = format!("{},{},{},{},{}", time, index, addr, kind, family);
+            for v in vals {
+                row.push(',');
+                if let Some(v) = *v {
+                    row.push_str(&v.to_string());
+                }
+            }
+            if let Some(ref reasons) = reasons {
+                row.push(',');
+                row.push_str(reasons.get(&(time, index)).cloned().unwrap_or(""));
+            }
+            row.push('\n');
+            writer.write_all(row.as_bytes()).map_err(|e| ManagerError::ExportIO(e))
+        })
+    }
+
+    /**
+     * Imports data for the given feed from CSV in the format `export_csv()`
+     * writes, read from the given reader (e.g. to migrate data from another
+     * tool, or restore a backup), returning the number of records imported.
+     *
+     * Columns are found by their names in the header row, so only `time`,
+     * `addr` and the feed's values (see `Feed::val_names()`) are needed, in any
+     * order, and a `kind` (if given) must be this target's. Addrs must be valid
+     * for the target's kind, and are resolved to their indices, adding them to
+     * the index as needed (in one go, but not to the options), and empty
+     * values are imported as errors. Times must be within `MAX_IMPORT_TIME` of
+     * epoch (as every export must be able to give them). Raw data is imported
+     * as measured on the second, as exports don't have the millis.
+     *
+     * Rows whose time goes backward are refused, unless `sort` is set, in
+     * which case all the rows are sorted by time first. Nothing is imported if
+     * any row is refused. Data from before what's already in the data file is
+     * put in place (see `DataFile::compact()`) straight away.
+     */
+    pub fn import_csv<R: io::BufRead>(&self, feed: Feed, reader: R, sort: bool) -> Result<usize, ManagerError> {
+        let mut lines = reader.lines().enumerate();
+        let header = match lines.next() {
+            Some((_, line)) => try!(line.map_err(|e| ManagerError::ImportIO(e))),
+            None => return Ok(0),
+        };
+        let columns = try!(
+            csv_fields(&header).ok_or(ManagerError::InvalidImport(1, "Malformed header".to_owned()))
+        );
+        let column = |name: &str| {
+            columns.iter().position(|c| c == name)
+                .ok_or(ManagerError::InvalidImport(1, format!("Missing column '{}'", name)))
+        };
+        let time_column = try!(column("time"));
+        let addr_column = try!(column("addr"));
+        let kind_column = columns.iter().position(|c| c == "kind");
+        let mut val_columns = Vec::with_capacity(feed.val_count());
+        for name in feed.val_names() {
+            val_columns.push(try!(column(name)));
+        }
+
+        let mut rows: Vec<(i64, String, Vec<i32>)> = Vec::new();
+        for (i, line) in lines {
+            let line = try!(line.map_err(|e| ManagerError::ImportIO(e)));
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |reason: String| ManagerError::InvalidImport(i + 1, reason);
+            let fields = try!(csv_fields(&line).ok_or_else(|| invalid("Malformed row".to_owned())));
+            let field = |column: usize| fields.get(column).map(|f| f.as_str()).unwrap_or("");
+
+            let time = try!(field(time_column).parse::<i64>()
+                            .map_err(|_| invalid(format!("Invalid time '{}'", field(time_column)))));
+            if time < -MAX_IMPORT_TIME || time > MAX_IMPORT_TIME {
+                return Err(invalid(format!("Time {} is out of range", time)));
+            }
+            if !sort && rows.last().map_or(false, |r| time < r.0) {
+                return Err(invalid("Time goes backward (rows must be in time order unless sorted)".to_owned()));
+            }
+            let kind = kind_column.map(|c| field(c)).unwrap_or("");
+            if !kind.is_empty() && kind != self.kind.compact_name() {
+                return Err(invalid(format!("Row is for kind '{}'", kind)));
+            }
+            let addr = field(addr_column);
+            if addr.is_empty() {
+                return Err(invalid("Missing addr".to_owned()));
+            }
+            if !self.kind.is_valid_addr(addr) {
+                return Err(invalid(format!("Invalid addr '{}'", addr)));
+            }
+
+            let mut vals = Vec::with_capacity(val_columns.len());
+            for &c in val_columns.iter() {
+                vals.push(match field(c) {
+                    "" => SENTINEL_ERROR,
+                    v => try!(v.parse::<i32>().map_err(|_| invalid(format!("Invalid value '{}'", v)))),
+                });
+            }
+            rows.push((time, addr.to_owned(), vals));
+        }
+        if sort {
+            rows.sort_by_key(|r| r.0);
+        }
+
+        // (holding on to the index until appended, so the addrs aren't compacted away)
+        let mut index = recover(self.index.write());
+        let mut new_addrs: Vec<String> = Vec::new();
+        for &(_, ref addr, _) in rows.iter() {
+            if index.index_of(addr).is_none() && !new_addrs.contains(addr) {
+                new_addrs.push(addr.clone());
+            }
+        }
+        try!(index.add_addrs(&new_addrs));
+
+        let mut out_data = Vec::new();
+        for &(time, ref addr, ref vals) in rows.iter() {
+            out_data.extend_from_slice(&time_words(time));
+            out_data.push(index.get_index(addr).word());
+            out_data.extend_from_slice(vals);
+            if feed == Feed::Raw {
+                out_data.push(0);
+            }
+        }
+
+        let ref mut file = *try!(self.data_file_write(feed));
+        let newest = try!(file.time_span().map_err(data_file_error)).map(|(_, last)| last);
+        try!(file.append(&out_data.clone().into_raw_bytes()).map_err(data_file_error));
+        drop(index);
+        // (not counting as the feed being appended to, which is up to its worker)
+        recover(self.recent[&feed].lock()).add(&out_data);
+        if let (Some(newest), Some(first)) = (newest, rows.first()) {
+            if first.0 < newest {
+                try!(file.compact().map_err(data_file_error));
+            }
+        }
+        info!("imported csv kind={} feed={:?} records={}", self.kind.compact_name(), feed, rows.len());
+        Ok(rows.len())
+    }
+
+    /**
+     * Exports all of this target's data for the given feed as newline-delimited
+     * JSON to the given writer, one object of {time, addr, kind, family, val,
+     * sd} per line for the raw and averaged feeds, or of {time, addr, kind,
+     * family, <values>...} (see `Feed::val_names()`) for the others, where
+     * `family` is as in `export_csv()`. Raw data also gives the `reason` errors
+     * failed, as in `export_csv()`.
+     *
+     * Errors (unknown families and reasons, and the standard deviations of raw
+     * data) are written as `null`.
+     */
+    pub fn export_ndjson<W: Write>(&self, feed: Feed, mut writer: W) -> Result<(), ManagerError> {
+        let names: &[&str] = match feed {
+            Feed::Raw => &["val", "sd"],
+            Feed::Averaged => &["val", "sd", "min", "max"],
+            _ => feed.val_names(),
+        };
+        let reasons = if feed == Feed::Raw { Some(try!(self.failure_names())) } else { None };
+        let kind = self.kind.compact_name();
+        let families = self.family_names();
+        self.for_each_row(feed, |time, index, addr, vals| {
+            let family = match addr.and_then(|a| families.get(a)) {
+                Some(f) => format!("\"{}\"", f),
+                None => "null".to_owned(),
+            };
+            let addr = addr.map(|a| json::encode(a).unwrap()).unwrap_or("null".to_owned());
+            let mut line = format!("{{\"time\":{},\"addr\":{},\"kind\":\"{}\",\"family\":{}",
+                                   time, addr, kind, family);
+            for (i, name) in names.iter().enumerate() {
+                match vals.get(i) {
+                    Some(&Some(v)) => line.push_str(&format!(",\"{}\":{}", name, v)),
+                    _ => line.push_str(&format!(",\"{}\":null", name)),
+                }
+            }
+            if let Some(ref reasons) = reasons {
+                match reasons.get(&(time, index)) {
+                    Some(reason) => line.push_str(&format!(",\"reason\":\"{}\"", reason)),
+                    None => line.push_str(",\"reason\":null"),
+                }
+            }
+            line.push_str("}\n");
+            writer.write_all(line.as_bytes()).map_err(|e| ManagerError::ExportIO(e))
+        })
+    }
+
+    /**
+     * Exports all of this target's data for the given feed in the InfluxDB
+     * line protocol to the given writer, one line per record, e.g.
+     * `ping,kind=tcpping,addr=8.8.8.8:53,family=v4 latency_ms=12.3 1470000000000000000`,
+     * where `family` is as in `export_csv()`, and left out if unknown.
+     *
+     * Errors are skipped entirely, as InfluxDB has no notion of missing
+     * values (as are records of addrs no longer in the index).
+     */
+    pub fn to_influx_lines<W: Write>(&self, feed: Feed, mut writer: W) -> Result<(), ManagerError> {
+        let kind = self.kind.compact_name();
+        // the fields (and the scale of their values) to write the values as
+        let fields: &[(&str, f64)] = match feed {
+            Feed::Raw => &[("latency_ms", 1000.0)],
+            Feed::Averaged => &[("latency_ms", 1000.0), ("sd_ms", 1000.0), ("min_ms", 1000.0), ("max_ms", 1000.0)],
+            Feed::Jitter => &[("jitter_ms", 1000.0)],
+            Feed::Loss => &[("loss_percent", 1.0)],
+            Feed::Percentiles => &[("p50_ms", 1000.0), ("p95_ms", 1000.0), ("p99_ms", 1000.0)],
+            Feed::Daily => &[("latency_ms", 1000.0), ("min_ms", 1000.0), ("max_ms", 1000.0), ("loss_percent", 1.0)],
+            Feed::Failures => &[("failure_reason", 1.0)],
+            Feed::Handshakes => &[("handshake_ms", 1000.0)],
+        };
+        let families = self.family_names();
+        self.for_each_row(feed, |time, _, addr, vals| {
+            let (addr, val) = match (addr, vals[0]) {
+                (Some(a), Some(v)) => (a, v),
+                _ => return Ok(()),
+            };
+
+            let mut line = format!("ping,kind={},addr={}", kind, influx_escape(addr));
+            if let Some(family) = families.get(addr) {
+                line.push_str(&format!(",family={}", family));
+            }
+            line.push_str(&format!(" {}={}", fields[0].0, val as f64 / fields[0].1));
+            for (&(field, scale), v) in fields[1..].iter().zip(vals[1..].iter()) {
+                if let Some(v) = *v {
+                    line.push_str(&format!(",{}={}", field, v as f64 / scale));
+                }
+            }
+            line.push_str(&format!(" {}\n", time * 1_000_000_000));
+            writer.write_all(line.as_bytes()).map_err(|e| ManagerError::ExportIO(e))
+        })
+    }
+
+    /**
+     * Gets the latest value of each current addr in options as gauges in the
+     * Prometheus text exposition format, along with a `stabping_up` gauge
+     * that is 0 if the latest value was an error, given the current time.
+     *
+     * Values older than twice an addr's interval are considered stale, and
+     * the addr is left out entirely. Only the kind and addr are used as
+     * labels, so as to keep the number of time series small.
+     *
+     * Gauges of how many addrs were alive and lost in the latest round (see
+     * `TargetResults::alive_count()`), labeled by kind alone, give a quick
+     * summary of the target's health, unless that round is stale too.
+     */
+    pub fn prometheus_metrics(&self, now: i64) -> Result<String, ManagerError> {
+        let (addrs, stale_before) = {
+            let options = self.options_read();
+            let longest = options.addrs.iter().map(|a| options.addr_interval(a)).max().unwrap_or(0);
+            (options.addrs.clone(), now.saturating_sub(2 * longest as i64 / 1000 + 1))
+        };
+
+        let ints = try!(
+            self.data_file_read(Feed::Raw)
+            .read_range(stale_before, i64::MAX)
+            .map_err(data_file_error)
+        );
+
+        // data is in time order, so later values replace earlier ones
+        let data = Feed::Raw.records(&ints);
+        let mut latest = HashMap::new();
+        for i in 0..data.len() {
+            latest.insert(data.index(i), data.vals(i)[0]);
+        }
+
+        let mut latency = String::from("# HELP stabping_latency_ms Latest measured value (in milliseconds).\n\
+                                        # TYPE stabping_latency_ms gauge\n");
+        let mut up = String::from("# HELP stabping_up Whether the latest measurement succeeded.\n\
+                                   # TYPE stabping_up gauge\n");
+        let mut retries = String::from("# HELP stabping_retries_total Failed attempts retried while measuring.\n\
+                                        # TYPE stabping_retries_total counter\n");
+        let index = recover(self.index.read());
+        let retried = recover(self.retries.read());
+        for addr in addrs.iter() {
+            let labels = format!("{{kind=\"{}\",addr=\"{}\"}}",
+                                 self.kind.compact_name(), prometheus_escape(addr));
+            if let Some(count) = retried.get(addr) {
+                retries.push_str(&format!("stabping_retries_total{} {}\n", labels, count));
+            }
+
+            let val = match index.index_of(addr).and_then(|i| latest.get(&i)) {
+                Some(v) => *v,
+                None => continue,
+            };
+
+            if val == SENTINEL_ERROR {
+                up.push_str(&format!("stabping_up{} 0\n", labels));
+            } else {
+                up.push_str(&format!("stabping_up{} 1\n", labels));
+                latency.push_str(&format!("stabping_latency_ms{} {}\n", labels, val as f64 / 1000.0));
+            }
+        }
+
+        latency.push_str(&up);
+        if let Some((time, alive, lost)) = *recover(self.last_round.lock()) {
+            if time >= stale_before {
+                let labels = format!("{{kind=\"{}\"}}", self.kind.compact_name());
+                latency.push_str(&format!("# HELP stabping_addrs_alive Addresses measured in the latest round.\n\
+                                           # TYPE stabping_addrs_alive gauge\n\
+                                           stabping_addrs_alive{} {}\n", labels, alive));
+                latency.push_str(&format!("# HELP stabping_addrs_lost Addresses that failed in the latest round.\n\
+                                           # TYPE stabping_addrs_lost gauge\n\
+                                           stabping_addrs_lost{} {}\n", labels, lost));
+            }
+        }
+        latency.push_str(&retries);
+        Ok(latency)
+    }
+
+    /**
+     * Gets the most recent record of this target's data for the given feed
+     * for each of the current addrs in options (as back-to-back integers, in
+     * the order of the addrs), leaving out addrs without any data at all.
+     * Errors are given as is, so the latest record of an addr that's down is
+     * an error.
+     *
+     * The data file is read backward from its end, only as far back as
+     * needed to find a record for every addr.
+     */
+    pub fn latest(&self, feed: Feed) -> Result<Vec<i32>, ManagerError> {
+        let (_, ordered_list, _) = self.get_current_indices();
+
+        let mut found: HashMap<AddrId, Vec<i32>> = HashMap::new();
+        try!(
+            self.data_file_read(feed).read_backward(LATEST_BLOCK_LEN, |ints| {
+                // the newest records of each block are at its end
+                let records = feed.records(ints);
+                for r in (0..records.len()).rev() {
+                    let i = records.index(r);
+                    if ordered_list.contains(&i) && !found.contains_key(&i) {
+                        let mut record = time_words(records.time(r)).to_vec();
+                        record.push(i.word());
+                        record.extend_from_slice(records.vals(r));
+                        found.insert(i, record);
+                    }
+                }
+                found.len() < ordered_list.len()
+            })
+            .map_err(data_file_error)
+        );
+
+        Ok(ordered_list.iter().filter_map(|i| found.remove(i)).flat_map(|r| r).collect())
+    }
+
+    /**
+     * Gets the current addrs in options as (nonce, ordered_list, membership)
+     * where 'ordered_list' is the list of address indices in order in which
+     * they appear in options, and where 'membership' is the set of indices
+     * present (i.e. if membership[i] != 0, then the addr with index i is
+     * currently present in options).
+     */
+    pub fn get_current_indices(&self) -> (i32, Vec<AddrId>, Vec<i32>) {
+        let options = self.options_read();
+
+        let index = recover(self.index.read());
+
+        let mut ordered_list = Vec::with_capacity(options.addrs.len());
+
+        let mut membership = {
+            let len = index.len();
+            let mut v = Vec::with_capacity(len);
+            v.extend(iter::repeat(0).take(len));
+            v
+        };
+
+        for addr in options.addrs.iter() {
+            let i = index.get_index(addr);
+            ordered_list.push(i);
+            membership[i.0 as usize] = SENTINEL_NODATA;
+        }
+
+        (options.nonce, ordered_list, membership)
+    }
+}
+
+impl Drop for TargetManager {
+    fn drop(&mut self) {
+        // there's no one left to handle errors by now, so just report them
+        if let Err(e) = self.flush_all() {
+            error!("failed to flush on shutdown kind={} error=\"{}\"", self.kind.compact_name(), e);
+        }
+    }
+}
+
+/**
+ * The managers of several target kinds, all storing their data in the same
+ * data directory, through which data can be appended and queried by kind (as
+ * given by its kind id).
+ */
+#[derive(Clone)]
+pub struct ManagerSet {
+    managers: Vec<Arc<TargetManager>>,
+}
+
+impl ManagerSet {
+    /**
+     * Creates a `TargetManager` (see `TargetManager::new()`) for each of the
+     * given target kinds, all storing persistent data at the given location
+     * path with the given options.
+     */
+    pub fn new<'b>(kinds: &'static [TargetKind], data_path: &'b Path,
+                   data_file_options: DataFileOptions,
+                   sync_metadata: bool, recover_corrupt: bool) -> Result<Self, ManagerError> {
+        let mut managers = Vec::with_capacity(kinds.len());
+        for k in kinds.iter() {
+            managers.push(Arc::new(try!(TargetManager::new(k, data_path, data_file_options,
+                                                           sync_metadata, recover_corrupt))));
+        }
+        Ok(ManagerSet {
+            managers: managers,
+        })
+    }
+
+    /**
+     * Gets the manager of the target kind with the given kind id.
+     */
+    pub fn get(&self, kind_id: i32) -> Result<&Arc<TargetManager>, ManagerError> {
+        self.managers.iter()
+            .find(|tm| tm.kind.kind_id() == kind_id)
+            .ok_or(ManagerError::KindNotFound(kind_id))
+    }
+
+    /**
+     * Iterates over the managers in this set.
+     */
+    pub fn iter(&self) -> slice::Iter<Arc<TargetManager>> {
+        self.managers.iter()
+    }
+
+    /**
+     * Appends the given live-collected data (see
+     * `TargetManager::append_data()`) via the manager of the target kind it
+     * came from.
+     */
+    pub fn append_data(&self, data_res: &TargetResults) -> Result<(), ManagerError> {
+        try!(self.get(data_res.0[0])).append_data(data_res)
+    }
+
+    /**
+     * Queries the data of the target kind with the given kind id (see
+     * `TargetManager::query_range()`).
+     */
+    pub fn query_range(&self, kind_id: i32, feed: Feed,
+                       start: i64, end: i64) -> Result<Vec<u8>, ManagerError> {
+        try!(self.get(kind_id)).query_range(feed, start, end)
+    }
+
+    /**
+     * Writes out any data still buffered by all the managers in this set, and
+     * ensures that their indices and options are persisted (see
+     * `TargetManager::flush_all()`), e.g. before shutting down. Every manager
+     * is flushed regardless of errors, with the first error (if any) given
+     * back.
+     */
+    pub fn flush_all(&self) -> Result<(), ManagerError> {
+        let mut res = Ok(());
+        for tm in self.managers.iter() {
+            let flushed = tm.flush_all();
+            if res.is_ok() {
+                res = flushed;
+            }
+        }
+        res
+    }
+
+    /**
+     * Drops expired data (see `TargetManager::prune_expired()`) of all the
+     * managers in this set, given the current time. Every manager is pruned
+     * regardless of errors, with the first error (if any) given back.
+     */
+    pub fn prune_expired(&self, now: i64) -> Result<(), ManagerError> {
+        let mut res = Ok(());
+        for tm in self.managers.iter() {
+            let pruned = tm.prune_expired(now);
+            if res.is_ok() {
+                res = pruned;
+            }
+        }
+        res
+    }
+
+    /**
+     * Computes the derived feeds (see `TargetManager::compact_averages()`,
+     * and `TargetManager::compact_daily()` for the daily feed) of all the
+     * managers in this set over windows of the given length, writing
+     * them out straight away. Every manager is compacted regardless of
+     * errors, with the first error (if any) given back.
+     */
+    pub fn compact_averages(&self, window: i32) -> Result<(), ManagerError> {
+        let mut res = Ok(());
+        for tm in self.managers.iter() {
+            let compacted = tm.compact_averages(window)
+                .and_then(|_| tm.compact_daily())
+                .and_then(|_| tm.flush());
+            if res.is_ok() {
+                res = compacted;
+            }
+        }
+        res
+    }
+
+    /**
+     * Starts a worker that computes the derived feeds of all the managers in
+     * this set (over `AVERAGED_WINDOW`) right away, and then every `period`
+     * until it's stopped.
+     *
+     * The raw data file is only locked (for reading) while the data not yet
+     * averaged is read back, so live data is never held up for long.
+     */
+    pub fn spawn_aggregator(&self, period: Duration) -> Worker {
+        let targets = self.clone();
+        run_periodically(period, move || {
+            if let Err(e) = targets.compact_averages(AVERAGED_WINDOW) {
+                error!("failed to compute averages error=\"{}\"", e);
+            }
+        })
+    }
+}
+
+/**
+ * Creates a fresh (empty) data directory for use in tests.
+ */
+#[cfg(test)]
+pub fn test_data_dir(name: &str) -> PathBuf {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    let mut path = env::temp_dir();
+    path.push(format!("stabping-test-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    path
+}
+
+// the target kind of the managers made by `test_manager()`
+#[cfg(test)]
+pub static TEST_KIND: TargetKind = TargetKind::TcpPing;
+
+/**
+ * Creates a `TargetManager` of `TEST_KIND` with default data file options,
+ * in a fresh data directory (see `test_data_dir()`) for use in tests, giving
+ * back both.
+ */
+#[cfg(test)]
+pub fn test_manager(name: &str) -> (PathBuf, TargetManager) {
+    test_manager_with(name, DataFileOptions::default())
+}
+
+/**
+ * Creates a `TargetManager` as `test_manager()` does, but with the given data
+ * file options.
+ */
+#[cfg(test)]
+pub fn test_manager_with(name: &str, options: DataFileOptions) -> (PathBuf, TargetManager) {
+    let data_path = test_data_dir(name);
+    let tm = TargetManager::new(&TEST_KIND, &data_path, options, false, false).unwrap();
+    (data_path, tm)
+}
+
+/**
+ * Appends a round of results to the given manager at each of the given times,
+ * in which every addr measured the time plus one.
+ */
+#[cfg(test)]
+pub fn append_rounds(tm: &TargetManager, times: &[i32]) {
+    let (nonce, num_addrs) = {
+        let options = tm.options_read();
+        (options.nonce, options.addrs.len())
+    };
+    for &t in times {
+        let mut data = vec![tm.kind.kind_id(), nonce, t, 0];
+        data.extend(iter::repeat(t + 1).take(num_addrs));
+        tm.append_data(&TargetResults(data, Vec::new())).unwrap();
+    }
+}
+
+#[test]
+fn errors_describe_themselves_and_their_causes() {
+    use std::error::Error;
+
+    let e = ManagerError::OptionsFileIO(SPIOError::Write(Some(PathBuf::from("/data/tcpping.options.json"))));
+    assert_eq!(e.to_string(), "Unable to write '/data/tcpping.options.json' options file");
+    assert_eq!(e.cause().unwrap().to_string(), "Unable to write '/data/tcpping.options.json'");
+
+    let e = ManagerError::ExportIO(io::Error::new(io::ErrorKind::Other, "disk full"));
+    assert_eq!(e.to_string(), "Unable to write export: disk full");
+    assert_eq!(e.cause().unwrap().to_string(), "disk full");
+
+    let e = ManagerError::InvalidImport(3, "Missing addr".to_owned());
+    assert_eq!(e.to_string(), "Invalid import at line 3: Missing addr");
+    assert!(e.cause().is_none());
+
+    let e = ManagerError::InvalidInterval(5);
+    assert_eq!(e.to_string(), format!("Invalid interval of 5 ms (must be at least {} ms)", MIN_INTERVAL));
+    assert!(e.cause().is_none());
+
+    let e = ManagerError::AlreadyLocked(PathBuf::from("/data/tcpping.lock"));
+    assert_eq!(e.to_string(), "Data directory is in use by another instance (lock file '/data/tcpping.lock' is held)");
+
+    let e = ManagerError::TooManyAddrs(1001, 1000);
+    assert_eq!(e.to_string(), "Too many addresses (1001, at most 1000 allowed)");
+    assert!(e.cause().is_none());
+}
+
+#[test]
+fn removed_addr_stays_removed() {
+    let mut path = test_data_dir("removed_addr_stays_removed");
+    path.push("index");
+
+    {
+        let mut index = AddrIndex::from_path(&path, false, false).unwrap();
+        index.ensure_for_addrs(vec!["a", "b", "c"].iter()).unwrap();
+        index.remove_addr(AddrId::from(1)).unwrap();
+        // appending after removal must go to the rewritten file
+        index.add_addr("d").unwrap();
+    }
+
+    let index = AddrIndex::from_path(&path, false, false).unwrap();
+    assert_eq!(index.len(), 4);
+    assert_eq!(index.get_addr(AddrId::from(0)).map(|a| a.as_str()), Some("a"));
+    assert_eq!(index.get_addr(AddrId::from(1)), None);
+    assert_eq!(index.get_addr(AddrId::from(2)).map(|a| a.as_str()), Some("c"));
+    assert_eq!(index.get_addr(AddrId::from(3)).map(|a| a.as_str()), Some("d"));
+    assert!(!index.map.contains_key("b"));
+}
+
+#[test]
+fn tombstones_are_only_reused_when_asked() {
+    let mut path = test_data_dir("tombstones_are_only_reused_when_asked");
+    path.push("index");
+
+    {
+        let mut index = AddrIndex::from_path(&path, false, false).unwrap();
+        index.ensure_for_addrs(vec!["a", "b", "c", "d"].iter()).unwrap();
+        index.add_addr_labeled("c", "Sea").unwrap();
+        index.remove_addr(AddrId::from(2)).unwrap();
+        index.remove_addr(AddrId::from(1)).unwrap();
+
+        // by default, new addrs go at the end
+        assert_eq!(index.add_addr("e").unwrap(), AddrId::from(4));
+
+        // otherwise they fill the lowest tombstones first, with no label
+        index.set_reuse_tombstones(true);
+        assert_eq!(index.add_addr("f").unwrap(), AddrId::from(1));
+        assert_eq!(index.add_addr("g").unwrap(), AddrId::from(2));
+        assert_eq!(index.get_label(AddrId::from(2)), None);
+        assert_eq!(index.add_addr("h").unwrap(), AddrId::from(5));
+        assert_eq!(index.add_addr("f").unwrap(), AddrId::from(1));
+    }
+
+    let index = AddrIndex::from_path(&path, false, false).unwrap();
+    assert_eq!(index.len(), 6);
+    let addrs: Vec<_> = (0..6).map(|i| index.get_addr(AddrId::from(i)).unwrap().clone()).collect();
+    assert_eq!(addrs, vec!["a", "f", "g", "d", "e", "h"]);
+    assert_eq!(index.get_label(AddrId::from(2)), None);
+    assert_eq!(index.get_index("g"), AddrId::from(2));
+
+    // managers can be told to reuse them too
+    let (_, tm) = test_manager("tombstones_are_only_reused_when_asked_by_managers");
+    let first = tm.options_read().addrs[0].clone();
+    tm.remove_addr(&first).unwrap();
+    assert_eq!(tm.compact_index().unwrap(), vec![first]);
+    tm.set_reuse_tombstones(true);
+    tm.add_addr("example.com:80").unwrap();
+    assert_eq!(tm.addr_index("example.com:80"), Some(AddrId::from(0)));
+}
+
+#[test]
+fn adding_same_addr_reuses_index() {
+    let mut path = test_data_dir("adding_same_addr_reuses_index");
+    path.push("index");
+
+    let mut index = AddrIndex::from_path(&path, false, false).unwrap();
+    let first = index.add_addr("a").unwrap();
+    index.add_addr("b").unwrap();
+    assert_eq!(index.add_addr("a").unwrap(), first);
+    assert_eq!(index.index_of("a"), Some(first));
+    assert_eq!(index.index_of("z"), None);
+    assert_eq!(index.len(), 2);
+}
+
+#[test]
+fn labels_persist_alongside_addrs() {
+    let mut path = test_data_dir("labels_persist_alongside_addrs");
+    path.push("index");
+
+    {
+        let mut index = AddrIndex::from_path(&path, false, false).unwrap();
+        index.add_addr("192.168.1.1:80").unwrap();
+        index.add_addr_labeled("10.0.0.1:80", "Gateway").unwrap();
+        index.add_addr_labeled("192.168.1.1:80", "Home Router").unwrap();
+    }
+
+    // an index file without labels (as older ones are) still loads
+    let mut old_path = path.clone();
+    old_path.set_file_name("old_index");
+    File::create(&old_path).unwrap().write_all(b"a\nb\n").unwrap();
+    let old_index = AddrIndex::from_path(&old_path, false, false).unwrap();
+    assert_eq!(old_index.get_label(AddrId::from(1)), None);
+
+    let index = AddrIndex::from_path(&path, false, false).unwrap();
+    assert_eq!(index.len(), 2);
+    assert_eq!(index.get_addr(AddrId::from(0)).map(|a| a.as_str()), Some("192.168.1.1:80"));
+    assert_eq!(index.get_label(AddrId::from(0)).map(|l| l.as_str()), Some("Home Router"));
+    assert_eq!(index.get_label(AddrId::from(1)).map(|l| l.as_str()), Some("Gateway"));
+}
+
+#[test]
+fn addrs_added_in_bulk_are_written_once() {
+    let mut path = test_data_dir("addrs_added_in_bulk_are_written_once");
+    path.push("index");
+
+    let mut index = AddrIndex::from_path(&path, false, false).unwrap();
+    let first = index.add_addr("a").unwrap();
+    let rewrites = index.rewrites;
+    let addrs: Vec<String> = vec!["b".to_owned(), "a".to_owned(), "c".to_owned()];
+    let indices = index.add_addrs(&addrs).unwrap();
+    assert_eq!(index.rewrites, rewrites + 1);
+    assert_eq!(indices[1], first);
+    assert_eq!(indices, addrs.iter().map(|a| index.get_index(a)).collect::<Vec<_>>());
+
+    // nothing new, nothing written
+    assert_eq!(index.add_addrs(&addrs).unwrap(), indices);
+    assert_eq!(index.rewrites, rewrites + 1);
+
+    let index = AddrIndex::from_path(&path, false, false).unwrap();
+    assert_eq!(index.len(), 3);
+    assert_eq!(index.index_of("c"), Some(indices[2]));
+}
+
+#[test]
+fn corrupt_index_is_recovered_if_asked() {
+    use std::io::Read;
+
+    let mut path = test_data_dir("corrupt_index_is_recovered_if_asked");
+    path.push("index");
+    File::create(&path).unwrap().write_all(b"a\n\xff\xfe\n").unwrap();
+
+    assert!(AddrIndex::from_path(&path, false, false).is_err());
+
+    let mut index = AddrIndex::from_path(&path, true, false).unwrap();
+    assert_eq!(index.len(), 0);
+    assert_eq!(index.add_addr("b").unwrap(), AddrId::from(0));
+
+    let mut bad_path = path.clone();
+    bad_path.set_file_name("index.bad");
+    let mut bad = Vec::new();
+    File::open(&bad_path).unwrap().read_to_end(&mut bad).unwrap();
+    assert_eq!(bad, b"a\n\xff\xfe\n");
+}
+
+#[test]
+fn index_checksum_is_verified() {
+    use std::io::Read;
+
+    let mut path = test_data_dir("index_checksum_is_verified");
+    path.push("index");
+
+    // an index file from before the header line (version 0) is upgraded
+    File::create(&path).unwrap().write_all(b"a\n\nc\tSea\n").unwrap();
+    {
+        let index = AddrIndex::from_path(&path, false, false).unwrap();
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.get_label(AddrId::from(2)).map(|l| l.as_str()), Some("Sea"));
+    }
+    let mut contents = String::new();
+    File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, format!("#stabping-index {} {:08x}\na\n\nc\tSea\n",
+                                 INDEX_FILE_VERSION, crc32(b"a\n\nc\tSea\n")));
+    assert_eq!(AddrIndex::from_path(&path, false, false).unwrap().get_index("c"), AddrId::from(2));
+
+    // but one changed behind the header's back is not trusted
+    let tampered = contents.replace("\nc\t", "\nd\t");
+    File::create(&path).unwrap().write_all(tampered.as_bytes()).unwrap();
+    match AddrIndex::from_path(&path, false, false) {
+        Err(ManagerError::IndexFileIO(SPIOError::Corrupt(_))) => (),
+        _ => panic!("Expected a corrupt index error"),
+    }
+    assert_eq!(AddrIndex::from_path(&path, true, false).unwrap().len(), 0);
+    let mut bad_path = path.clone();
+    bad_path.set_file_name("index.bad");
+    let mut bad = String::new();
+    File::open(&bad_path).unwrap().read_to_string(&mut bad).unwrap();
+    assert_eq!(bad, tampered);
+
+    // and neither is one of a newer version
+    File::create(&path).unwrap().write_all(format!("#stabping-index {} 00000000\n", INDEX_FILE_VERSION + 1).as_bytes()).unwrap();
+    match AddrIndex::from_path(&path, false, false) {
+        Err(ManagerError::IndexFileIO(SPIOError::Parse(_))) => (),
+        _ => panic!("Expected an index parse error"),
+    }
+}
+
+#[test]
+fn tampered_options_are_set_aside() {
+    static KIND: TargetKind = TargetKind::TcpPing;
+    let data_path = test_data_dir("tampered_options_are_set_aside");
+    let options_path = data_path.join("tcpping.options.json");
+
+    // options files from before the wrapper (version 0) still load
+    let mut old_options = KIND.default_options();
+    old_options.interval += 1;
+    fs::create_dir_all(&data_path).unwrap();
+    File::create(&options_path).unwrap().write_all(json::encode(&old_options).unwrap().as_bytes()).unwrap();
+    {
+        let tm = TargetManager::new(&KIND, &data_path, DataFileOptions::default(), false, false).unwrap();
+        assert_eq!(*tm.options_read(), old_options);
+    }
+
+    let mut new_options = old_options.clone();
+    new_options.interval += 1;
+    overwrite_json(&new_options, &options_path, false).unwrap();
+    {
+        let tm = TargetManager::new(&KIND, &data_path, DataFileOptions::default(), false, false).unwrap();
+        assert_eq!(*tm.options_read(), new_options);
+    }
+
+    // a changed interval no longer matches the checksum
+    let tampered = {
+        use std::io::Read;
+        let mut contents = String::new();
+        File::open(&options_path).unwrap().read_to_string(&mut contents).unwrap();
+        contents.replace(&format!("\"interval\":{}", new_options.interval),
+                         &format!("\"interval\":{}", new_options.interval + 1))
+    };
+    File::create(&options_path).unwrap().write_all(tampered.as_bytes()).unwrap();
+    match TargetManager::new(&KIND, &data_path, DataFileOptions::default(), false, false) {
+        Err(ManagerError::OptionsFileIO(SPIOError::Corrupt(_))) => (),
+        _ => panic!("Expected a corrupt options file error"),
+    }
+
+    // unless asked to recover, when the addrs (and labels) are rebuilt from the index
+    {
+        let mut index = AddrIndex::from_path(&data_path.join("tcpping.index.json"), false, false).unwrap();
+        index.add_addr_labeled("example.com:80", "Example").unwrap();
+    }
+    let tm = TargetManager::new(&KIND, &data_path, DataFileOptions::default(), false, true).unwrap();
+    assert!(fs::metadata(data_path.join("tcpping.options.json.bad")).is_ok());
+    let options = tm.options_read();
+    let mut expected_addrs = KIND.default_options().addrs;
+    expected_addrs.push("example.com:80".to_owned());
+    assert_eq!(options.addrs, expected_addrs);
+    assert_eq!(options.labels.as_ref().and_then(|l| l.get("example.com:80")).map(|l| l.as_str()), Some("Example"));
+    assert_eq!(options.interval, KIND.default_options().interval);
+}
+
+#[test]
+fn new_manager_appends_data() {
+    let (data_path, tm) = test_manager("new_manager_appends_data");
+
+    let (nonce, num_addrs) = {
+        let options = tm.options_read();
+        (options.nonce, options.addrs.len())
+    };
+    let mut data = vec![TEST_KIND.kind_id(), nonce, 1_000, 0];
+    data.extend(iter::repeat(42).take(num_addrs));
+    tm.append_data(&TargetResults(data, Vec::new())).unwrap();
+
+    /*
+     * each datapoint is stored as a (time, index, value, millis) record of
+     * five i32s (the time taking two), after the header giving the (newer)
+     * record layout
+     */
+    let len = fs::metadata(data_path.join("tcpping.data.dat")).unwrap().len();
+    assert_eq!(len, (8 + num_addrs * 5 * 4) as u64);
+}
+
+#[test]
+fn stats_count_records_without_reading_them() {
+    let num_addrs = TEST_KIND.default_options().addrs.len();
+    // small segments, and a buffer, so the counts span both
+    let options = DataFileOptions { max_size: Some(8 + (num_addrs * 5 * 4) as u64), buffer_size: 1 << 20,
+                                    ..DataFileOptions::default() };
+    let (_, tm) = test_manager_with("stats_count_records_without_reading_them", options);
+
+    let empty = tm.stats().unwrap();
+    assert_eq!(empty.len(), ALL_FEEDS.len());
+    assert_eq!(empty[0], FeedStats { feed: Feed::Raw, records: 0, earliest: None, latest: None, disk_size: 0 });
+
+    append_rounds(&tm, &[100]);
+    tm.flush().unwrap();
+    append_rounds(&tm, &[200]);
+    tm.flush().unwrap();
+    append_rounds(&tm, &[300]);
+
+    let raw = &tm.stats().unwrap()[0];
+    assert_eq!((raw.records, raw.earliest, raw.latest), (3 * num_addrs as u64, Some(100), Some(300)));
+    // two segments written out (each with a header), and one round still buffered
+    assert_eq!(raw.disk_size, 2 * (8 + num_addrs * 5 * 4) as u64);
+    assert_eq!(raw.to_json().to_string(),
+               format!("{{\"disk_size\":{},\"earliest\":100,\"feed\":\"data\",\"latest\":300,\"records\":{}}}",
+                       raw.disk_size, raw.records));
+}
+
+#[test]
+fn clear_feed_drops_all_data_but_keeps_index_and_options() {
+    let num_addrs = TEST_KIND.default_options().addrs.len();
+    // small segments, so there are sealed ones to clear too
+    let options = DataFileOptions { max_size: Some(8 + (num_addrs * 5 * 4) as u64), ..DataFileOptions::default() };
+    let (data_path, tm) = test_manager_with("clear_feed_drops_all_data_but_keeps_index_and_options", options);
+    let nonce = tm.options_read().nonce;
+
+    append_rounds(&tm, &[100, 200, 300]);
+    let indices_before = tm.get_current_indices();
+
+    tm.clear_feed(Feed::Raw).unwrap();
+    let raw = &tm.stats().unwrap()[0];
+    assert_eq!((raw.records, raw.earliest, raw.disk_size), (0, None, 0));
+    assert_eq!(tm.get_current_indices(), indices_before);
+    assert_eq!(tm.options_read().nonce, nonce);
+
+    // appending carries on as usual, and survives reopening
+    append_rounds(&tm, &[400]);
+    drop(tm);
+
+    let tm = TargetManager::new(&TEST_KIND, &data_path, options, false, false).unwrap();
+    let raw = &tm.stats().unwrap()[0];
+    assert_eq!((raw.records, raw.earliest, raw.latest), (num_addrs as u64, Some(400), Some(400)));
+}
+
+#[test]
+fn clear_index_drops_only_that_addrs_data() {
+    let num_addrs = TEST_KIND.default_options().addrs.len();
+    let options = DataFileOptions { max_size: Some(8 + (num_addrs * 5 * 4) as u64), ..DataFileOptions::default() };
+    let (_, tm) = test_manager_with("clear_index_drops_only_that_addrs_data", options);
+
+    append_rounds(&tm, &[100, 200, 300]);
+    let (_, ordered, _) = tm.get_current_indices();
+    let cleared = ordered[0];
+
+    tm.clear_index(Feed::Raw, cleared).unwrap();
+    let records = tm.data_file_read(Feed::Raw).read_range(0, 1000).unwrap();
+    assert_eq!(records.len(), 3 * (num_addrs - 1) * 5);
+    assert!(records.chunks(5).all(|r| r[2] != cleared.word()));
+    assert_eq!(tm.get_current_indices().1, ordered);
+
+    // clearing an index with no data left is a no-op
+    tm.clear_index(Feed::Raw, cleared).unwrap();
+    assert_eq!(tm.stats().unwrap()[0].records, 3 * (num_addrs - 1) as u64);
+}
+
+#[test]
+fn second_manager_on_same_path_is_locked_out() {
+    let (data_path, tm) = test_manager("second_manager_on_same_path_is_locked_out");
+
+    match TargetManager::new(&TEST_KIND, &data_path, DataFileOptions::default(), false, false) {
+        Err(ManagerError::AlreadyLocked(p)) => assert_eq!(p, data_path.join("tcpping.lock")),
+        _ => panic!("Expected an already locked error"),
+    }
+
+    // other kinds have locks of their own
+    static OTHER_KIND: TargetKind = TargetKind::HttpPing;
+    TargetManager::new(&OTHER_KIND, &data_path, DataFileOptions::default(), false, false).unwrap();
+
+    // and the lock is released once the first manager is gone
+    drop(tm);
+    TargetManager::new(&TEST_KIND, &data_path, DataFileOptions::default(), false, false).unwrap();
+}
+
+#[test]
+fn options_update_rejects_zero_interval() {
+    let (data_path, tm) = test_manager("options_update_rejects_zero_interval");
+
+    let mut new_options = TEST_KIND.default_options();
+    new_options.interval = 0;
+    match tm.options_update(new_options) {
+        Err(ManagerError::InvalidInterval(0)) => (),
+        _ => panic!("Expected an invalid interval error"),
+    }
+
+    // neither the options in memory nor on disk were touched
+    assert_eq!(tm.options_read().interval, TEST_KIND.default_options().interval);
+    drop(tm);
+    let on_disk = TargetManager::new(&TEST_KIND, &data_path, DataFileOptions::default(), false, false).unwrap();
+    assert_eq!(on_disk.options_read().interval, TEST_KIND.default_options().interval);
+}
+
+#[test]
+fn invalid_options_on_disk_are_refused() {
+    let (data_path, tm) = test_manager("invalid_options_on_disk_are_refused");
+    let mut bad_options = tm.options_read().clone();
+    drop(tm);
+    let options_path = data_path.join("tcpping.options.json");
+
+    // options that would never get past `validate()` (e.g. edited by hand)
+    bad_options.retry_attempts = Some(0);
+    overwrite_json(&bad_options, &options_path, false).unwrap();
+    match TargetManager::new(&TEST_KIND, &data_path, DataFileOptions::default(), false, false) {
+        Err(ManagerError::InvalidOptions(_)) => (),
+        _ => panic!("Expected an invalid options error"),
+    }
+
+    // are set aside like corrupt ones when asked to recover
+    let tm = TargetManager::new(&TEST_KIND, &data_path, DataFileOptions::default(), false, true).unwrap();
+    assert_eq!(tm.options_read().retry_attempts, None);
+    assert_eq!(tm.options_read().addrs, bad_options.addrs);
+    assert!(fs::metadata(data_path.join("tcpping.options.json.bad")).is_ok());
+}
+
+#[test]
+fn latest_gives_last_record_of_each_addr() {
+    let record_size = Records::record_size(1) as u64;
+    let options = DataFileOptions { max_size: Some(4 * record_size), ..DataFileOptions::default() };
+    let (_, tm) = test_manager_with("latest_gives_last_record_of_each_addr", options);
+
+    let mut new_options = TEST_KIND.default_options();
+    new_options.addrs = vec!["a.com:80".to_owned(), "b.com:80".to_owned(),
+                             "c.com:80".to_owned(), "d.com:80".to_owned()];
+    tm.options_update(new_options).unwrap();
+    let nonce = tm.options_read().nonce;
+    let index_of = |a: &str| tm.index.read().unwrap().get_index(a).word();
+
+    // a is only measured early on (across several segments), and d never is
+    let n = SENTINEL_NODATA;
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 100, 0, 1, 2, 3, n], Vec::new())).unwrap();
+    for t in 101..200 {
+        tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, t, 0, n, t, 3, n], Vec::new())).unwrap();
+    }
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 200, 0, n, 5, SENTINEL_ERROR, n], Vec::new())).unwrap();
+
+    assert_eq!(tm.latest(Feed::Raw).unwrap(),
+               vec![100, 0, index_of("a.com:80"), 1,
+                    200, 0, index_of("b.com:80"), 5,
+                    200, 0, index_of("c.com:80"), SENTINEL_ERROR]);
+}
+
+#[test]
+fn append_data_stores_measured_times() {
+    let (_, tm) = test_manager("append_data_stores_measured_times");
+    let nonce = tm.options_read().nonce;
+    let (a, b) = {
+        let index = tm.index.read().unwrap();
+        let options = tm.options_read();
+        (index.get_index(&options.addrs[0]).word(), index.get_index(&options.addrs[1]).word())
+    };
+
+    // the second addr was measured before the first, within the same second
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 100, 0, 1, 2], vec![101_750, 101_250])).unwrap();
+    assert_eq!(tm.data_file_read(Feed::Raw).read_range(0, 200).unwrap(),
+               vec![101, 0, b, 2, 250, 101, 0, a, 1, 750]);
+}
+
+#[test]
+fn subscribers_each_get_appended_results() {
+    let (_, tm) = test_manager("subscribers_each_get_appended_results");
+    let nonce = tm.options_read().nonce;
+
+    let first = tm.subscribe();
+    let second = tm.subscribe();
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 100, 0, 1, 2], Vec::new())).unwrap();
+
+    // a subscriber hanging up doesn't get in the way of appending
+    drop(first);
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 101, 0, 3, 4], Vec::new())).unwrap();
+    assert_eq!(tm.subscribers.lock().unwrap().len(), 1);
+
+    let got: Vec<i32> = second.try_iter().map(|r| r.0[2]).collect();
+    assert_eq!(got, vec![100, 101]);
+
+    // one that falls too far behind is dropped, but keeps what it was sent
+    let stalled = tm.subscribe();
+    for t in 0..(SUBSCRIBER_CAPACITY + 1) as i32 {
+        tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 200 + t, 0, 5, 6], Vec::new())).unwrap();
+    }
+    assert_eq!(tm.subscribers.lock().unwrap().len(), 0);
+    assert_eq!(stalled.try_iter().count(), SUBSCRIBER_CAPACITY);
+}
+
+#[test]
+fn feeds_go_stale_without_appends() {
+    use std::thread;
+
+    let (_, tm) = test_manager("feeds_go_stale_without_appends");
+    let nonce = tm.options_read().nonce;
+    let max_age = Duration::from_secs(60);
+
+    // a fresh manager gets the benefit of the doubt, for a while
+    assert_eq!(tm.last_append_time(Feed::Raw), None);
+    assert!(!tm.is_stale(Feed::Raw, max_age));
+    let created = *tm.created.lock().unwrap();
+    assert!(tm.is_stale_at(Feed::Raw, max_age, created + 61));
+
+    // results with stale options don't keep it fresh
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce + 1, 100, 0, 1, 2], Vec::new())).unwrap();
+    assert_eq!(tm.last_append_time(Feed::Raw), None);
+
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 100, 0, 1, 2], Vec::new())).unwrap();
+    let last = tm.last_append_time(Feed::Raw).unwrap();
+    assert!(last >= created);
+    assert!(!tm.is_stale(Feed::Raw, max_age));
+    assert!(!tm.is_stale_at(Feed::Raw, max_age, last + 60));
+    assert!(tm.is_stale_at(Feed::Raw, max_age, last + 61));
+
+    // the derived feeds are tracked separately
+    assert_eq!(tm.last_append_time(Feed::Averaged), None);
+    thread::sleep(Duration::from_millis(1100));
+    assert!(tm.is_stale(Feed::Raw, Duration::from_secs(0)));
+}
+
+#[test]
+fn staleness_follows_the_managers_clock() {
+    use clock::ManualClock;
+
+    let (_, tm) = test_manager("staleness_follows_the_managers_clock");
+    // (a clock far from the system's, which the manager counts as created by)
+    let clock = Arc::new(ManualClock::new(1_000_000));
+    tm.set_clock(clock.clone());
+    let nonce = tm.options_read().nonce;
+    let max_age = Duration::from_secs(60);
+
+    clock.advance(Duration::from_secs(60));
+    assert!(!tm.is_stale(Feed::Raw, max_age));
+    clock.advance(Duration::from_secs(1));
+    assert!(tm.is_stale(Feed::Raw, max_age));
+
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 100, 0, 1, 2], Vec::new())).unwrap();
+    assert_eq!(tm.last_append_time(Feed::Raw), Some(1_061));
+    assert!(!tm.is_stale(Feed::Raw, max_age));
+    clock.advance(Duration::from_secs(61));
+    assert!(tm.is_stale(Feed::Raw, max_age));
+
+    // times past 32 bits are kept whole
+    tm.set_clock(Arc::new(ManualClock::new((1 << 33) * 1000)));
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 200, 0, 1, 2], Vec::new())).unwrap();
+    assert_eq!(tm.last_append_time(Feed::Raw), Some(1 << 33));
+}
+
+#[test]
+fn recent_records_are_queried_from_memory() {
+    let (_, tm) = test_manager("recent_records_are_queried_from_memory");
+    let nonce = tm.options_read().nonce;
+    let (_, ordered_list, _) = tm.get_current_indices();
+
+    for t in 100..110 {
+        tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, t, 0, t, 2 * t], Vec::new())).unwrap();
+    }
+    let from_file = |start: i64| {
+        let ints = tm.data_file_read(Feed::Raw).read_range(start, i64::MAX).unwrap();
+        encode_range(&Feed::Raw.records(&ints), start, i64::MAX, &ordered_list)
+    };
+    let all = from_file(100);
+    assert!(!all.is_empty());
+    assert_eq!(tm.query_range(Feed::Raw, 100, i64::MAX).unwrap(), all);
+
+    // empty the file behind the manager's back, which recent queries don't notice
+    tm.data_file_write(Feed::Raw).unwrap().clear().unwrap();
+    assert_eq!(tm.query_range(Feed::Raw, 100, i64::MAX).unwrap(), all);
+    assert_eq!(tm.query_range(Feed::Raw, 105, 106).unwrap().len(), all.len() / 5);
+    // but older ones do, as the file may have more from before the manager
+    assert!(tm.query_range(Feed::Raw, 99, i64::MAX).unwrap().is_empty());
+
+    // only the last rounds fit in a smaller capacity
+    tm.set_recent_capacity(5);
+    assert!(tm.query_range(Feed::Raw, 107, i64::MAX).unwrap().is_empty());
+    assert_eq!(tm.query_range(Feed::Raw, 108, i64::MAX).unwrap().len(), all.len() / 5);
+
+    // and clearing a feed through the manager clears its recent records too
+    tm.clear_feed(Feed::Raw).unwrap();
+    assert!(tm.query_range(Feed::Raw, 108, i64::MAX).unwrap().is_empty());
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 200, 0, 1, 2], Vec::new())).unwrap();
+    assert_eq!(tm.query_range(Feed::Raw, 0, i64::MAX).unwrap(), from_file(0));
+}
+
+#[test]
+fn append_data_without_raw_feed_is_an_error() {
+    let (_, mut tm) = test_manager("append_data_without_raw_feed_is_an_error");
+    tm.data_files.remove(&Feed::Raw);
+    let nonce = tm.options_read().nonce;
+
+    match tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 100, 0, 1, 2], Vec::new())) {
+        Err(ManagerError::FeedNotFound(Feed::Raw)) => (),
+        _ => panic!("Expected a missing feed error"),
+    }
+}
+
+#[test]
+fn append_data_survives_poisoned_lock() {
+    use std::thread;
+
+    let (_, tm) = test_manager("append_data_survives_poisoned_lock");
+    let tm = Arc::new(tm);
+    let nonce = tm.options_read().nonce;
+
+    let t = tm.clone();
+    assert!(thread::spawn(move || {
+        let _guard = t.data_files[&Feed::Raw].write().unwrap();
+        panic!("worker panicking while holding the data file");
+    }).join().is_err());
+
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 100, 0, 1, 2], Vec::new())).unwrap();
+    assert_eq!(tm.latest(Feed::Raw).unwrap().len(), 2 * 4);
+}
+
+#[test]
+fn options_update_rejects_timeout_over_interval() {
+    let (_, tm) = test_manager("options_update_rejects_timeout_over_interval");
+
+    let mut new_options = TEST_KIND.default_options();
+    new_options.timeout = Some(new_options.interval + 1);
+    match tm.options_update(new_options) {
+        Err(ManagerError::InvalidOptions(_)) => (),
+        _ => panic!("Expected an invalid options error"),
+    }
+
+    let mut new_options = TEST_KIND.default_options();
+    new_options.timeout = Some(new_options.interval);
+    tm.options_update(new_options).unwrap();
+    assert_eq!(tm.options_read().timeout, Some(TEST_KIND.default_options().interval));
+}
+
+#[test]
+fn options_update_rejects_invalid_addrs() {
+    let (_, tm) = test_manager("options_update_rejects_invalid_addrs");
+
+    let mut new_options = TEST_KIND.default_options();
+    new_options.addrs = vec!["example.com:80".to_owned(), "example.com".to_owned()];
+    match tm.options_update(new_options) {
+        Err(ManagerError::InvalidAddrArgument(ref a)) if a == "example.com" => (),
+        _ => panic!("Expected an invalid addr error"),
+    }
+    assert_eq!(tm.options_read().addrs, TEST_KIND.default_options().addrs);
+    assert!(tm.index.read().unwrap().index_of("example.com").is_none());
+}
+
+#[test]
+fn manager_set_dispatches_by_kind() {
+    use helpers::VecFromWireBytes;
+
+    let data_path = test_data_dir("manager_set_dispatches_by_kind");
+    let kinds = &TargetKind::all()[..2];
+    let set = ManagerSet::new(kinds, &data_path, DataFileOptions::default(), false, false).unwrap();
+    assert_eq!(set.iter().count(), 2);
+
+    for (k, val) in kinds.iter().zip([1, 2].iter()) {
+        let nonce = set.get(k.kind_id()).unwrap().options_read().nonce;
+        set.append_data(&TargetResults(vec![k.kind_id(), nonce, 100, 0, *val, *val], Vec::new())).unwrap();
+    }
+    for (k, val) in kinds.iter().zip([1, 2].iter()) {
+        let got = set.query_range(k.kind_id(), Feed::Raw, 0, 200).unwrap().from_wire_bytes();
+        assert_eq!(got, vec![100, 0, 0, *val, *val]);
+    }
+
+    match set.get(TargetKind::DnsPing.kind_id()) {
+        Err(ManagerError::KindNotFound(_)) => (),
+        _ => panic!("Expected a missing kind error"),
+    }
+}
+
+#[test]
+fn manager_set_flushes_all_its_managers() {
+    let data_path = test_data_dir("manager_set_flushes_all_its_managers");
+    // buffer far more than we'll ever append
+    let options = DataFileOptions { buffer_size: 1 << 20, ..DataFileOptions::default() };
+    let set = ManagerSet::new(&TargetKind::all()[..1], &data_path, options, false, false).unwrap();
+    let data_len = || fs::metadata(data_path.join("tcpping.data.dat")).unwrap().len();
+
+    append_rounds(set.get(TEST_KIND.kind_id()).unwrap(), &[100]);
+    let buffered_len = data_len();
+    set.flush_all().unwrap();
+    assert!(data_len() > buffered_len);
+}
+
+#[test]
+fn options_update_proceeds_past_snapshots() {
+    use std::thread;
+
+    let (_, tm) = test_manager("options_update_proceeds_past_snapshots");
+    let tm = Arc::new(tm);
+    let old_interval = tm.options_read().interval;
+
+    let (taken_tx, taken_rx) = channel();
+    let (done_tx, done_rx) = channel();
+    let holder = {
+        let tm = tm.clone();
+        thread::spawn(move || {
+            let snapshot = tm.options_snapshot();
+            taken_tx.send(()).unwrap();
+            // still "working" with the snapshot until the update is through
+            done_rx.recv().unwrap();
+            snapshot.interval
+        })
+    };
+
+    taken_rx.recv().unwrap();
+    let mut new_options = TEST_KIND.default_options();
+    new_options.interval = old_interval + 1;
+    tm.options_update(new_options).unwrap();
+    assert_eq!(tm.options_read().interval, old_interval + 1);
+    done_tx.send(()).unwrap();
+
+    // the snapshot is left as it was taken
+    assert_eq!(holder.join().unwrap(), old_interval);
+}
+
+#[test]
+fn unchanged_options_are_not_rewritten() {
+    let (data_path, tm) = test_manager("unchanged_options_are_not_rewritten");
+
+    // a rewrite would bring the options file back
+    let options_path = data_path.join("tcpping.options.json");
+    fs::remove_file(&options_path).unwrap();
+    tm.options_update(TEST_KIND.default_options()).unwrap();
+    assert!(fs::metadata(&options_path).is_err());
+
+    let mut new_options = TEST_KIND.default_options();
+    new_options.interval += 1;
+    tm.options_update(new_options).unwrap();
+    assert!(fs::metadata(&options_path).is_ok());
+}
+
+#[test]
+fn compact_index_removes_only_orphaned_addrs() {
+    let (data_path, tm) = test_manager("compact_index_removes_only_orphaned_addrs");
+    for addr in ["a.com:80", "b.com:80", "c.com:80"].iter() {
+        tm.add_addr(addr).unwrap();
+    }
+
+    // everything but c.com gets measured
+    let mut data = vec![TEST_KIND.kind_id(), tm.options_read().nonce, 100, 0];
+    data.extend(tm.options_read().addrs.iter().map(|a| if a == "c.com:80" { SENTINEL_NODATA } else { 10 }));
+    tm.append_data(&TargetResults(data, Vec::new())).unwrap();
+
+    // b.com and c.com are then removed, and d.com comes and goes without data
+    tm.remove_addr("b.com:80").unwrap();
+    tm.remove_addr("c.com:80").unwrap();
+    tm.add_addr("d.com:80").unwrap();
+    tm.remove_addr("d.com:80").unwrap();
+    let b = tm.index.read().unwrap().get_index("b.com:80");
+
+    assert_eq!(tm.compact_index().unwrap(), vec!["c.com:80".to_owned(), "d.com:80".to_owned()]);
+    {
+        let index = tm.index.read().unwrap();
+        assert!(index.index_of("a.com:80").is_some());
+        assert_eq!(index.index_of("b.com:80"), Some(b));
+        assert!(index.index_of("c.com:80").is_none() && index.index_of("d.com:80").is_none());
+    }
+    assert!(tm.compact_index().unwrap().is_empty());
+
+    // the tombstones are what's left of them once reopened
+    drop(tm);
+    let tm = TargetManager::new(&TEST_KIND, &data_path, DataFileOptions::default(), false, false).unwrap();
+    let index = tm.index.read().unwrap();
+    assert_eq!(index.index_of("b.com:80"), Some(b));
+    assert!(index.index_of("c.com:80").is_none());
+}
+
+#[test]
+fn removing_an_addr_drops_its_overrides_and_bumps_the_nonce() {
+    let (_, tm) = test_manager("removing_an_addr_drops_its_overrides_and_bumps_the_nonce");
+    let nonce = tm.options_read().nonce;
+
+    assert_eq!(tm.add_addr("example.com:80").unwrap(), nonce + 1);
+    // adding it again changes nothing
+    assert_eq!(tm.add_addr("example.com:80").unwrap(), nonce + 1);
+
+    let mut new_options = tm.options_read().clone();
+    new_options.labels = Some(vec![("example.com:80".to_owned(), "Example".to_owned())].into_iter().collect());
+    new_options.addr_intervals = Some(vec![("example.com:80".to_owned(), MIN_INTERVAL)].into_iter().collect());
+    assert_eq!(tm.options_replace(new_options).unwrap(), nonce + 2);
+
+    assert_eq!(tm.remove_addr("example.com:80").unwrap(), nonce + 3);
+    {
+        let options = tm.options_read();
+        assert!(!options.addrs.contains(&"example.com:80".to_owned()));
+        assert_eq!(options.labels.as_ref().map(|l| l.len()), Some(0));
+        assert_eq!(options.addr_intervals.as_ref().map(|i| i.len()), Some(0));
+    }
+    match tm.remove_addr("example.com:80") {
+        Err(ManagerError::InvalidAddrArgument(ref a)) if a == "example.com:80" => (),
+        _ => panic!("Expected an invalid addr error"),
+    }
+
+    // options based on stale ones are refused
+    match tm.options_replace(TEST_KIND.default_options()) {
+        Err(ManagerError::NonceConflict(n)) => assert_eq!(n, nonce),
+        _ => panic!("Expected a nonce conflict"),
+    }
+}
+
+#[test]
+fn only_one_of_concurrent_replacements_succeeds() {
+    use std::sync::Barrier;
+    use std::thread;
+
+    let (_, tm) = test_manager("only_one_of_concurrent_replacements_succeeds");
+    let tm = Arc::new(tm);
+    for round in 0..20 {
+        let barrier = Arc::new(Barrier::new(2));
+        let replacers: Vec<_> = (0..2).map(|i| {
+            let (tm, barrier) = (tm.clone(), barrier.clone());
+            let mut new_options = tm.options_snapshot();
+            new_options.addrs.push(format!("r{}-{}.example.com:80", round, i));
+            thread::spawn(move || {
+                barrier.wait();
+                tm.options_replace(new_options)
+            })
+        }).collect();
+
+        let results: Vec<_> = replacers.into_iter().map(|r| r.join().unwrap()).collect();
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(results.iter().any(|r| match *r {
+            Err(ManagerError::NonceConflict(_)) => true,
+            _ => false,
+        }));
+    }
+}
+
+#[test]
+fn renamed_addr_keeps_its_data() {
+    static KIND: TargetKind = TargetKind::TcpPing;
+    let data_path = test_data_dir("renamed_addr_keeps_its_data");
+
+    {
+        let tm = TargetManager::new(&KIND, &data_path, DataFileOptions::default(), false, false).unwrap();
+        let mut new_options = KIND.default_options();
+        new_options.addrs = vec!["a.com:80".to_owned(), "b.com:80".to_owned()];
+        new_options.labels = Some(vec![("a.com:80".to_owned(), "A".to_owned())].into_iter().collect());
+        tm.options_update(new_options).unwrap();
+        let nonce = tm.options_read().nonce;
+        tm.append_data(&TargetResults(vec![KIND.kind_id(), nonce, 100, 0, 1, 2], Vec::new())).unwrap();
+        let a = tm.index.read().unwrap().get_index("a.com:80");
+
+        assert_eq!(tm.rename_addr("a.com:80", "c.com:80").unwrap(), nonce + 1);
+        {
+            let options = tm.options_read();
+            assert_eq!(options.addrs, vec!["c.com:80".to_owned(), "b.com:80".to_owned()]);
+            assert_eq!(options.labels.as_ref().unwrap().get("c.com:80").map(|l| l.as_str()), Some("A"));
+        }
+
+        // bad or taken new addrs are refused, leaving everything as it was
+        match tm.rename_addr("b.com:80", "b.com") {
+            Err(ManagerError::InvalidAddrArgument(ref a)) if a == "b.com" => (),
+            _ => panic!("Expected an invalid addr error"),
+        }
+        match tm.rename_addr("b.com:80", "c.com:80") {
+            Err(ManagerError::InvalidAddrArgument(ref a)) if a == "c.com:80" => (),
+            _ => panic!("Expected an invalid addr error"),
+        }
+        match tm.rename_addr("a.com:80", "d.com:80") {
+            Err(ManagerError::InvalidAddrArgument(ref a)) if a == "a.com:80" => (),
+            _ => panic!("Expected an invalid addr error"),
+        }
+        assert_eq!(tm.options_read().nonce, nonce + 1);
+        assert_eq!(tm.index.read().unwrap().get_index("c.com:80"), a);
+    }
+
+    // the old data reads back under the new name, even after a restart
+    let tm = TargetManager::new(&KIND, &data_path, DataFileOptions::default(), false, false).unwrap();
+    assert_eq!(tm.query_resolved(Feed::Raw, 0, 200).unwrap(),
+               vec![("c.com:80".to_owned(), 100, vec![Some(1)]),
+                    ("b.com:80".to_owned(), 100, vec![Some(2)])]);
+    let index = tm.index.read().unwrap();
+    let c = index.get_index("c.com:80");
+    assert_eq!(index.index_of("a.com:80"), None);
+    assert_eq!(index.get_label(c).map(|l| l.as_str()), Some("A"));
+}
+
+#[test]
+fn options_update_refuses_too_many_addrs() {
+    let (_, tm) = test_manager("options_update_refuses_too_many_addrs");
+    tm.set_max_addrs(3);
+
+    // up to the limit is fine, duplicates included
+    let mut new_options = TEST_KIND.default_options();
+    new_options.addrs = vec!["a.com:80".to_owned(), "b.com:80".to_owned(), "a.com:80".to_owned()];
+    new_options.addr_ports = Some(vec![("c.com".to_owned(), vec![80])].into_iter().collect());
+    tm.options_update(new_options.clone()).unwrap();
+    assert_eq!(tm.options_read().addrs.len(), 3);
+
+    // but one more (even by way of addr_ports) is refused
+    new_options.addr_ports = Some(vec![("c.com".to_owned(), vec![80, 81])].into_iter().collect());
+    match tm.options_update(new_options) {
+        Err(ManagerError::TooManyAddrs(4, 3)) => (),
+        _ => panic!("Expected a too many addrs error"),
+    }
+    assert_eq!(tm.options_read().addrs.len(), 3);
+}
+
+#[test]
+fn options_update_spreads_addr_ports() {
+    let (_, tm) = test_manager("options_update_spreads_addr_ports");
+
+    let mut new_options = TEST_KIND.default_options();
+    new_options.addrs = vec!["example.com:80".to_owned()];
+    let mut addr_ports = BTreeMap::new();
+    addr_ports.insert("example.com".to_owned(), vec![80, 443, 22]);
+    addr_ports.insert("::1".to_owned(), vec![22]);
+    new_options.addr_ports = Some(addr_ports);
+    tm.options_update(new_options).unwrap();
+
+    // each port gets its own addr (and index), without duplicating existing ones
+    let options = tm.options_read();
+    assert_eq!(options.addrs, vec!["example.com:80", "[::1]:22", "example.com:443", "example.com:22"]);
+    assert!(options.addr_ports.is_none());
+    let index = tm.index.read().unwrap();
+    let indices: Vec<AddrId> = options.addrs.iter().map(|a| index.get_index(a)).collect();
+    for (i, a) in indices.iter().enumerate() {
+        assert!(!indices[..i].contains(a));
+    }
+}
+
+#[test]
+fn options_update_writes_index_once() {
+    let (_, tm) = test_manager("options_update_writes_index_once");
+    let rewrites = tm.index.read().unwrap().rewrites;
+
+    // ten new (labeled) addrs make for a single write of the index
+    let mut new_options = TEST_KIND.default_options();
+    new_options.addrs = (0..10).map(|i| format!("10.0.0.{}:80", i)).collect();
+    let mut labels = BTreeMap::new();
+    for addr in new_options.addrs.iter() {
+        labels.insert(addr.clone(), format!("Host {}", addr));
+    }
+    new_options.labels = Some(labels);
+    tm.options_update(new_options).unwrap();
+
+    let index = tm.index.read().unwrap();
+    assert_eq!(index.rewrites, rewrites + 1);
+    assert_eq!(index.get_label(index.get_index("10.0.0.9:80")).map(|l| l.as_str()), Some("Host 10.0.0.9:80"));
+}
+
+#[test]
+fn options_update_collapses_duplicate_addrs() {
+    let (_, tm) = test_manager("options_update_collapses_duplicate_addrs");
+
+    let mut new_options = tm.options_read().clone();
+    new_options.addrs = vec!["b.com:80", "a.com:80", "b.com:80", "c.com:80", "a.com:80"]
+        .into_iter().map(|a| a.to_owned()).collect();
+    let nonce = tm.options_replace(new_options).unwrap();
+
+    // the first of each stays where it was
+    let options = tm.options_read().clone();
+    assert_eq!(options.addrs, vec!["b.com:80", "a.com:80", "c.com:80"]);
+    let (_, ordered_list, _) = tm.get_current_indices();
+    assert_eq!(ordered_list.len(), 3);
+
+    // and a round of results has one value for each
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 100, 0, 1, 2, 3], Vec::new())).unwrap();
+    assert_eq!(tm.latest(Feed::Raw).unwrap().len(), 3 * 4);
+}
+
+#[test]
+fn prune_before_drops_old_data() {
+    use helpers::VecFromWireBytes;
+
+    let (data_path, tm) = test_manager("prune_before_drops_old_data");
+    let num_addrs = tm.options_read().addrs.len();
+
+    append_rounds(&tm, &[100, 200, 300, 400, 500]);
+    tm.prune_before(Feed::Raw, 300).unwrap();
+    // appending after pruning should still go on the end
+    append_rounds(&tm, &[600]);
+
+    let len = fs::metadata(data_path.join("tcpping.data.dat")).unwrap().len();
+    assert_eq!(len, (8 + 4 * num_addrs * 5 * 4) as u64);
+
+    let times: Vec<i32> = tm.query_range(Feed::Raw, 0, 1000).unwrap().from_wire_bytes()
+                            .chunks(3 + num_addrs).map(|s| s[0]).collect();
+    assert_eq!(times, vec![300, 400, 500, 600]);
+}
+
+#[test]
+fn data_files_rotate_at_max_size() {
+    use helpers::VecFromWireBytes;
+
+    let num_addrs = TEST_KIND.default_options().addrs.len();
+
+    // allow two rounds of data (after the header) per segment
+    let round_size = (num_addrs * 5 * 4) as u64;
+    let options = DataFileOptions { max_size: Some(8 + 2 * round_size), ..DataFileOptions::default() };
+    let (data_path, tm) = test_manager_with("data_files_rotate_at_max_size", options);
+
+    append_rounds(&tm, &[100, 200, 300, 400, 500]);
+
+    for name in vec!["tcpping.data.0.dat", "tcpping.data.1.dat", "tcpping.data.dat"] {
+        assert!(fs::metadata(data_path.join(name)).unwrap().len() <= 8 + 2 * round_size);
+    }
+
+    // range queries read across all the segments (also when reopened)
+    drop(tm);
+    let tm = TargetManager::new(&TEST_KIND, &data_path, options, false, false).unwrap();
+    let times: Vec<i32> = tm.query_range(Feed::Raw, 200, 1000).unwrap().from_wire_bytes()
+                            .chunks(3 + num_addrs).map(|s| s[0]).collect();
+    assert_eq!(times, vec![200, 300, 400, 500]);
+}
+
+#[test]
+fn compact_averages_excludes_errors() {
+    use helpers::VecFromRawBytes;
+    use std::io::Read;
+    let (data_path, tm) = test_manager("compact_averages_excludes_errors");
+    let (nonce, num_addrs) = {
+        let options = tm.options_read();
+        (options.nonce, options.addrs.len())
+    };
+
+    // the first addr gets 10, 20, error, 30; the rest always error
+    for (t, v) in vec![(100, 10), (103, 20), (106, SENTINEL_ERROR), (109, 30), (110, 99)] {
+        let mut data = vec![TEST_KIND.kind_id(), nonce, t, 0, v];
+        data.extend(iter::repeat(SENTINEL_ERROR).take(num_addrs - 1));
+        tm.append_data(&TargetResults(data, Vec::new())).unwrap();
+    }
+
+    tm.compact_averages(10).unwrap();
+    // compacting again should not average the same window twice
+    tm.compact_averages(10).unwrap();
+
+    let mut buf = Vec::new();
+    File::open(data_path.join("tcpping.averaged.dat")).unwrap().read_to_end(&mut buf).unwrap();
+    // skip the header giving the (newer) record layout
+    let avgs = buf[8..].from_raw_bytes();
+
+    // only the [100, 110) window is complete; sd of 10, 20, 30 is ~8.16
+    assert_eq!(avgs.len(), 7 * num_addrs);
+    assert_eq!(&avgs[..7], &[100, 0, 0, 20, 8, 10, 30]);
+    assert_eq!(&avgs[7..14], &[100, 0, 1, SENTINEL_ERROR, SENTINEL_ERROR, SENTINEL_ERROR, SENTINEL_ERROR]);
+
+    /*
+     * the error between 20 and 30 breaks the chain, leaving only 10 -> 20;
+     * addrs that always error have no jitter at all
+     */
+    let mut buf = Vec::new();
+    File::open(data_path.join("tcpping.jitter.dat")).unwrap().read_to_end(&mut buf).unwrap();
+    let jitters = buf[8..].from_raw_bytes();
+    assert_eq!(jitters.len(), 4 * num_addrs);
+    assert_eq!(&jitters[..4], &[100, 0, 0, 10]);
+    assert_eq!(&jitters[4..8], &[100, 0, 1, SENTINEL_ERROR]);
+}
+
+#[test]
+fn aggregator_computes_averages_in_the_background() {
+    let data_path = test_data_dir("aggregator_computes_averages_in_the_background");
+    let kind = &TargetKind::all()[0];
+    let set = ManagerSet::new(&TargetKind::all()[..1], &data_path, DataFileOptions::default(), false, false).unwrap();
+    let tm = set.get(kind.kind_id()).unwrap().clone();
+    let (nonce, num_addrs) = {
+        let options = tm.options_read();
+        (options.nonce, options.addrs.len())
+    };
+
+    // data spanning the first window, and some from after it (completing it)
+    for t in vec![0, AVERAGED_WINDOW / 2, AVERAGED_WINDOW] {
+        let mut data = vec![kind.kind_id(), nonce, t, 0];
+        data.extend(iter::repeat(10).take(num_addrs));
+        tm.append_data(&TargetResults(data, Vec::new())).unwrap();
+    }
+
+    // the aggregator computes averages right away, even if stopped straight after starting
+    let aggregator = set.spawn_aggregator(Duration::from_millis(10));
+    aggregator.stop();
+    aggregator.join().unwrap();
+
+    let averaged = &tm.stats().unwrap()[1];
+    assert_eq!(averaged.feed, Feed::Averaged);
+    assert_eq!((averaged.records, averaged.earliest, averaged.latest), (num_addrs as u64, Some(0), Some(0)));
+    let avgs = tm.data_file_read(Feed::Averaged).read_range(0, 0).unwrap();
+    assert_eq!(&avgs[..7], &[0, 0, 0, 10, 0, 10, 10]);
+}
+
+#[test]
+fn days_start_at_midnight_on_the_given_clock() {
+    use chrono::FixedOffset;
+
+    // 2017-03-01T20:00:00Z
+    let time = 1488398400;
+    assert_eq!(day_start(&FixedOffset::east(0), time), 1488326400);
+    // already 01:00 on the 2nd five hours east, but only 12:00 on the 1st eight hours west
+    assert_eq!(day_start(&FixedOffset::east(5 * 3600), time), 1488394800);
+    assert_eq!(day_start(&FixedOffset::west(8 * 3600), time), 1488355200);
+}
+
+#[test]
+fn compact_daily_rolls_up_each_complete_day() {
+    let (_, tm) = test_manager("compact_daily_rolls_up_each_complete_day");
+    let (nonce, num_addrs) = {
+        let options = tm.options_read();
+        (options.nonce, options.addrs.len())
+    };
+
+    // hourly data for two (local) days and a bit of a third; the second addr errors every fourth hour
+    let day0 = Local.ymd(2017, 3, 1).and_hms(0, 0, 0).timestamp();
+    for h in 0..51 {
+        let tw = time_words(day0 + h * 3600);
+        let mut data = vec![TEST_KIND.kind_id(), nonce, tw[0], tw[1], 1000 + h as i32];
+        data.push(if h % 4 == 0 { SENTINEL_ERROR } else { 2000 });
+        tm.append_data(&TargetResults(data, Vec::new())).unwrap();
+    }
+
+    tm.compact_averages(AVERAGED_WINDOW).unwrap();
+    tm.compact_daily().unwrap();
+    // rolling up again should not roll up the same day twice
+    tm.compact_daily().unwrap();
+
+    let ints = tm.data_file_read(Feed::Daily).read_range(i64::MIN, i64::MAX).unwrap();
+    let daily = Feed::Daily.records(&ints);
+    assert_eq!(daily.len(), 2 * num_addrs);
+    for day in 0..2 {
+        let first = day * num_addrs;
+        let day_start = Local.ymd(2017, 3, 1 + day as u32).and_hms(0, 0, 0).timestamp();
+        assert_eq!((daily.time(first), daily.index(first)), (day_start, AddrId::from(0)));
+
+        let h = 24 * day as i32;
+        assert_eq!(daily.vals(first), &[(1000 + h) + 12, 1000 + h, 1000 + h + 23, 0]);
+        assert_eq!(daily.vals(first + 1), &[2000, 2000, 2000, 25]);
+    }
+}
+
+#[test]
+fn compact_averages_tracks_loss() {
+    use helpers::VecFromWireBytes;
+
+    let (_, tm) = test_manager("compact_averages_tracks_loss");
+    let nonce = tm.options_read().nonce;
+
+    // the first addr errors half the time, the second never does
+    for (t, v) in vec![(100, 10), (102, SENTINEL_ERROR), (104, 20), (106, SENTINEL_ERROR), (110, 5)] {
+        tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, t, 0, v, 7], Vec::new())).unwrap();
+    }
+    tm.compact_averages(10).unwrap();
+
+    // loss is exported over the wire (and as CSV) like any other feed
+    let loss = tm.query_range(Feed::Loss, 0, 1000).unwrap().from_wire_bytes();
+    assert_eq!(loss, vec![100, 0, 0, 50, 0]);
+
+    let mut out = Vec::new();
+    tm.export_csv(Feed::Loss, &mut out).unwrap();
+    let csv = String::from_utf8(out).unwrap();
+    assert_eq!(csv.lines().collect::<Vec<_>>(),
+               vec!["time,index,addr,kind,family,loss",
+                    "100,0,google.com:80,tcpping,,50", "100,1,8.8.8.8:53,tcpping,,0"]);
+}
+
+#[test]
+fn feeds_turned_off_are_left_alone_until_turned_back_on() {
+    let (_, tm) = test_manager("feeds_turned_off_are_left_alone_until_turned_back_on");
+    let records = |feed: Feed| tm.stats().unwrap().into_iter().find(|s| s.feed == feed).unwrap().records;
+    let set_jitter = |enabled: bool| {
+        let mut options = tm.options_snapshot();
+        options.feeds_enabled = Some(vec![("jitter".to_owned(), enabled)].into_iter().collect());
+        tm.options_replace(options).unwrap();
+    };
+    let append_and_compact = |times: &[i32]| {
+        for t in times.iter() {
+            let nonce = tm.options_read().nonce;
+            tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, *t, 0, 10, 20], Vec::new())).unwrap();
+        }
+        tm.compact_averages(10).unwrap();
+    };
+
+    append_and_compact(&[100, 105, 110]);
+    assert_eq!((records(Feed::Averaged), records(Feed::Jitter)), (2, 2));
+
+    // while off, nothing more is computed for jitter, but what it has stays
+    set_jitter(false);
+    append_and_compact(&[115, 120]);
+    assert_eq!((records(Feed::Averaged), records(Feed::Jitter)), (4, 2));
+    assert!(!tm.query_range(Feed::Jitter, 0, 1000).unwrap().is_empty());
+
+    // once back on, it catches up on the windows it missed
+    set_jitter(true);
+    append_and_compact(&[125, 130]);
+    assert_eq!((records(Feed::Averaged), records(Feed::Jitter)), (6, 6));
+}
+
+#[test]
+fn compact_averages_computes_percentiles() {
+    use helpers::VecFromWireBytes;
+
+    let (_, tm) = test_manager("compact_averages_computes_percentiles");
+    let nonce = tm.options_read().nonce;
+
+    // the first addr gets 100, 99, ..., 1 (and an error), the second just 7
+    for t in 0..100 {
+        tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, t, 0, 100 - t, 7], Vec::new())).unwrap();
+    }
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 100, 0, SENTINEL_ERROR, 7], Vec::new())).unwrap();
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 200, 0, 1, 7], Vec::new())).unwrap();
+    tm.compact_averages(101).unwrap();
+
+    let pcts = tm.query_range(Feed::Percentiles, 0, 1000).unwrap().from_wire_bytes();
+    assert_eq!(pcts, vec![0, 0, 0, 50, 95, 99, 7, 7, 7]);
+}
+
+#[test]
+fn query_range_selects_times() {
+    use helpers::VecFromWireBytes;
+
+    let (_, tm) = test_manager("query_range_selects_times");
+    let num_addrs = tm.options_read().addrs.len();
+
+    append_rounds(&tm, &[100, 200, 300]);
+
+    let segments = tm.query_range(Feed::Raw, 150, 300).unwrap().from_wire_bytes();
+    let mut expected = Vec::new();
+    for t in vec![200, 300] {
+        expected.extend(&[t, 0, 0]);
+        expected.extend(iter::repeat(t + 1).take(num_addrs));
+    }
+    assert_eq!(segments, expected);
+
+    assert!(tm.query_range(Feed::Raw, 400, 500).unwrap().is_empty());
+    assert!(tm.query_range(Feed::Averaged, 0, 500).unwrap().is_empty());
+}
+
+#[test]
+fn second_resolution_data_is_migrated() {
+    use helpers::VecFromWireBytes;
+
+    static KIND: TargetKind = TargetKind::TcpPing;
+    let data_path = test_data_dir("second_resolution_data_is_migrated");
+
+    // a raw data file from before sub-second times, as bare triplets (with its index)
+    File::create(data_path.join("tcpping.data.dat")).unwrap()
+        .write_all(&vec![100, 0, 5, 100, 1, 6].into_raw_bytes()).unwrap();
+    File::create(data_path.join("tcpping.index.json")).unwrap()
+        .write_all(format!("{}\n", KIND.default_options().addrs.join("\n")).as_bytes()).unwrap();
+
+    let tm = TargetManager::new(&KIND, &data_path, DataFileOptions::default(), false, false).unwrap();
+    let nonce = tm.options_read().nonce;
+    tm.append_data(&TargetResults(vec![KIND.kind_id(), nonce, 200, 0, 7, 8], vec![200_100, 200_900])).unwrap();
+
+    // the old data is on the second, and the new data keeps its millis
+    let segments = tm.query_range(Feed::Raw, 0, 1000).unwrap().from_wire_bytes();
+    assert_eq!(segments, vec![100, 0, 0, 5, 6,
+                              200, 0, 100, 7, SENTINEL_NODATA,
+                              200, 0, 900, SENTINEL_NODATA, 8]);
+}
+
+#[test]
+fn lost_index_never_reassigns_recorded_indices() {
+    let (data_path, tm) = test_manager("lost_index_never_reassigns_recorded_indices");
+    let nonce = tm.options_read().nonce;
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 100, 0, 5, 6], Vec::new())).unwrap();
+    drop(tm);
+    let index_path = data_path.join("tcpping.index.json");
+    File::create(&index_path).unwrap().write_all(b"a\n\xff\xfe\n").unwrap();
+
+    // an unreadable index keeps the manager from starting, unless asked to recover
+    match TargetManager::new(&TEST_KIND, &data_path, DataFileOptions::default(), false, false) {
+        Err(ManagerError::IndexFileIO(_)) => (),
+        _ => panic!("Expected an index file error"),
+    }
+    let tm = TargetManager::new(&TEST_KIND, &data_path, DataFileOptions::default(), false, true).unwrap();
+
+    // the data recorded so far is left to the removed addrs, not the current ones
+    let rows = tm.query_resolved(Feed::Raw, 0, 200).unwrap();
+    assert_eq!(rows, vec![(REMOVED_ADDR.to_owned(), 100, vec![Some(5)]),
+                          (REMOVED_ADDR.to_owned(), 100, vec![Some(6)])]);
+    let index = tm.index.read().unwrap();
+    for addr in tm.options_read().addrs.iter() {
+        assert!(index.get_index(addr) > AddrId::from(1));
+    }
+}
+
+#[test]
+fn times_past_32_bits_are_kept() {
+    use std::i32;
+    use helpers::VecFromWireBytes;
+
+    let (_, tm) = test_manager("times_past_32_bits_are_kept");
+    let nonce = tm.options_read().nonce;
+
+    // some time in the year 2128
+    let t: i64 = 5_000_000_000;
+    let mut data = vec![TEST_KIND.kind_id(), nonce];
+    data.extend_from_slice(&time_words(t));
+    data.extend_from_slice(&[1, 2]);
+    tm.append_data(&TargetResults(data, Vec::new())).unwrap();
+
+    let segments = tm.query_range(Feed::Raw, t, t).unwrap().from_wire_bytes();
+    assert_eq!(time_from_words(segments[0], segments[1]), t);
+    assert_eq!(&segments[2..], &[0, 1, 2]);
+    assert!(tm.query_range(Feed::Raw, 0, i32::MAX as i64).unwrap().is_empty());
+}
+
+#[test]
+fn query_resolved_gives_addrs() {
+    let (_, tm) = test_manager("query_resolved_gives_addrs");
+
+    let mut new_options = TEST_KIND.default_options();
+    new_options.addrs = vec!["a.com:80".to_owned(), "b.com:80".to_owned()];
+    tm.options_update(new_options).unwrap();
+    let nonce = tm.options_read().nonce;
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 100, 0, 1, SENTINEL_ERROR], Vec::new())).unwrap();
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 200, 0, 3, 4], Vec::new())).unwrap();
+
+    // b's data outlives b itself
+    {
+        let mut index = tm.index.write().unwrap();
+        let b = index.get_index("b.com:80");
+        index.remove_addr(b).unwrap();
+    }
+
+    assert_eq!(tm.query_resolved(Feed::Raw, 150, 300).unwrap(),
+               vec![("a.com:80".to_owned(), 200, vec![Some(3)]),
+                    (REMOVED_ADDR.to_owned(), 200, vec![Some(4)])]);
+    assert_eq!(tm.query_resolved(Feed::Raw, 0, 100).unwrap()[1],
+               (REMOVED_ADDR.to_owned(), 100, vec![None]));
+
+    // as does data under an index the index never had (e.g. having lost its file)
+    tm.data_file_write(Feed::Raw).unwrap()
+      .append(&timed_records(vec![(300, 0, AddrId::from(40), 5)]).into_raw_bytes()).unwrap();
+    assert_eq!(tm.query_resolved(Feed::Raw, 250, 350).unwrap(),
+               vec![(REMOVED_ADDR.to_owned(), 300, vec![Some(5)])]);
+    tm.export_csv(Feed::Raw, &mut Vec::new()).unwrap();
+}
+
+#[test]
+fn query_json_names_values_and_nulls_errors() {
+    let (_, tm) = test_manager("query_json_names_values_and_nulls_errors");
+
+    let mut new_options = TEST_KIND.default_options();
+    new_options.addrs = vec!["a.com:80".to_owned(), "b.com:80".to_owned()];
+    tm.options_update(new_options).unwrap();
+    let nonce = tm.options_read().nonce;
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 100, 0, 12, SENTINEL_ERROR], Vec::new())).unwrap();
+
+    assert_eq!(tm.query_json(Feed::Raw, 0, 200).unwrap().to_string(),
+               "[{\"addr\":\"a.com:80\",\"time\":100,\"value\":12},\
+                 {\"addr\":\"b.com:80\",\"time\":100,\"value\":null}]");
+    assert_eq!(tm.query_json(Feed::Raw, 300, 400).unwrap(), Json::Array(Vec::new()));
+}
+
+#[test]
+fn dropped_manager_flushes_buffered_data() {
+    use helpers::VecFromWireBytes;
+
+    // buffer far more than we'll ever append
+    let options = DataFileOptions { buffer_size: 1 << 20, ..DataFileOptions::default() };
+    let (data_path, tm) = test_manager_with("dropped_manager_flushes_buffered_data", options);
+    let num_addrs = tm.options_read().addrs.len();
+
+    append_rounds(&tm, &[100]);
+    tm.flush_all().unwrap();
+    assert!(fs::metadata(data_path.join("tcpping.data.dat")).unwrap().len() > 0);
+
+    // data appended after the last flush is written out when dropped
+    append_rounds(&tm, &[200]);
+    drop(tm);
+
+    let tm = TargetManager::new(&TEST_KIND, &data_path, options, false, false).unwrap();
+    let times: Vec<i32> = tm.query_range(Feed::Raw, 0, 1000).unwrap().from_wire_bytes()
+                            .chunks(3 + num_addrs).map(|s| s[0]).collect();
+    assert_eq!(times, vec![100, 200]);
+}
+
+#[test]
+fn export_csv_round_trips() {
+    let (_, tm) = test_manager("export_csv_round_trips");
+    let (nonce, addrs) = {
+        let options = tm.options_read();
+        (options.nonce, options.addrs.clone())
+    };
+
+    for (t, v) in vec![(100, 10), (200, SENTINEL_ERROR)] {
+        let mut data = vec![TEST_KIND.kind_id(), nonce, t, 0];
+        data.extend(iter::repeat(v).take(addrs.len()));
+        tm.append_data(&TargetResults(data, Vec::new())).unwrap();
+    }
+    // only the first addr has been measured over a known family
+    tm.record_addr_family(&addrs[0], AddrFamily::V6);
+
+    let mut out = Vec::new();
+    tm.export_csv(Feed::Raw, &mut out).unwrap();
+    let csv = String::from_utf8(out).unwrap();
+    let mut lines = csv.lines();
+    assert_eq!(lines.next(), Some("time,index,addr,kind,family,value,reason"));
+
+    let rows: Vec<Vec<&str>> = lines.map(|l| l.split(',').collect()).collect();
+    assert_eq!(rows.len(), 2 * addrs.len());
+    for (i, row) in rows.iter().enumerate() {
+        let (t, v) = if i < addrs.len() { ("100", "10") } else { ("200", "") };
+        assert_eq!(row[0], t);
+        assert_eq!(AddrId::from(row[1].parse::<u32>().unwrap()), tm.index.read().unwrap().get_index(row[2]));
+        assert_eq!(row[2], addrs[i % addrs.len()]);
+        assert_eq!(row[3], "tcpping");
+        assert_eq!(row[4], if i % addrs.len() == 0 { "v6" } else { "" });
+        assert_eq!(row[5], v);
+        assert_eq!(row[6], "");
+    }
+}
+
+#[test]
+fn import_csv_round_trips_exports() {
+    let (_, tm) = test_manager("import_csv_round_trips_exports");
+    let nonce = tm.options_read().nonce;
+
+    for (t, v0, v1) in vec![(100, 10, 20), (110, SENTINEL_ERROR, 21), (120, 12, SENTINEL_ERROR)] {
+        tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, t, 0, v0, v1], Vec::new())).unwrap();
+    }
+    tm.compact_averages(10).unwrap();
+
+    // exporting, clearing and importing gives back the same export
+    for &feed in [Feed::Raw, Feed::Averaged].iter() {
+        let mut exported = Vec::new();
+        tm.export_csv(feed, &mut exported).unwrap();
+        let records = tm.data_file_read(feed).record_count().unwrap() as usize;
+        assert!(records > 0);
+
+        tm.clear_feed(feed).unwrap();
+        assert_eq!(tm.import_csv(feed, &exported[..], false).unwrap(), records);
+        let mut reexported = Vec::new();
+        tm.export_csv(feed, &mut reexported).unwrap();
+        assert_eq!(String::from_utf8(reexported).unwrap(), String::from_utf8(exported).unwrap());
+    }
+
+    // rows out of order are refused (and nothing imported) unless sorted
+    let csv = "addr,time,value\n\"new.example.com:80\",90,5\n8.8.8.8:53,50,\n";
+    match tm.import_csv(Feed::Raw, csv.as_bytes(), false) {
+        Err(ManagerError::InvalidImport(3, _)) => (),
+        r => panic!("Expected an invalid import error, got {:?}", r),
+    }
+    assert!(tm.index.read().unwrap().index_of("new.example.com:80").is_none());
+    let rewrites = tm.index.read().unwrap().rewrites;
+    assert_eq!(tm.import_csv(Feed::Raw, csv.as_bytes(), true).unwrap(), 2);
+    assert_eq!(tm.index.read().unwrap().rewrites, rewrites + 1);
+
+    // new addrs are indexed, and older data is put in place straight away
+    let new_index = tm.index.read().unwrap().get_index("new.example.com:80");
+    let data = tm.data_file_read(Feed::Raw).read_range(0, 100).unwrap();
+    let records = Feed::Raw.records(&data);
+    let got: Vec<(i64, i32)> = (0..records.len()).map(|i| (records.time(i), records.vals(i)[0])).collect();
+    assert_eq!(got, vec![(50, SENTINEL_ERROR), (90, 5), (100, 10), (100, 20)]);
+    assert_eq!(records.index(1), new_index);
+
+    // as are rows for other kinds, or with bad addrs, times or values
+    for csv in vec!["time,addr,kind,value\n1,a:80,icmpping,5\n", "time,addr,value\n1,a:80,fast\n",
+                    "time,value\n1,5\n", "time,addr,value\n1,not an addr,5\n",
+                    "time,addr,value\n99999999999,example.com:80,5\n",
+                    "time,addr,value\n-99999999999,example.com:80,5\n"] {
+        assert!(tm.import_csv(Feed::Raw, csv.as_bytes(), false).is_err());
+    }
+}
+
+#[test]
+fn failure_reasons_are_recorded_when_asked() {
+    let (_, tm) = test_manager("failure_reasons_are_recorded_when_asked");
+    let addrs = tm.options_read().addrs.clone();
+    let round = |t: i32, nonce: i32| {
+        tm.record_failure(&addrs[0], FailureReason::Refused);
+        // (the second addr failed once, but then succeeded on a retry)
+        tm.record_failure(&addrs[1], FailureReason::TimedOut);
+        tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, t, 0, SENTINEL_ERROR, 10], Vec::new())).unwrap();
+    };
+
+    // nothing is recorded unless asked
+    round(100, tm.options_read().nonce);
+    assert_eq!(tm.data_file_read(Feed::Failures).record_count().unwrap(), 0);
+
+    let mut new_options = tm.options_read().clone();
+    new_options.record_failure_reasons = Some(true);
+    let nonce = tm.options_replace(new_options).unwrap();
+    round(110, nonce);
+    let ints = tm.data_file_read(Feed::Failures).read_range(i64::MIN, i64::MAX).unwrap();
+    let failures = Feed::Failures.records(&ints);
+    assert_eq!(failures.len(), 1);
+    assert_eq!((failures.time(0), failures.vals(0)), (110, &[FailureReason::Refused.code()][..]));
+
+    // and exports of the raw data give the reason alongside the error
+    let mut out = Vec::new();
+    tm.export_csv(Feed::Raw, &mut out).unwrap();
+    let csv = String::from_utf8(out).unwrap();
+    let reasons: Vec<&str> = csv.lines().skip(1).map(|l| l.rsplit(',').next().unwrap()).collect();
+    assert_eq!(reasons, vec!["", "", "refused", ""]);
+
+    let mut out = Vec::new();
+    tm.export_ndjson(Feed::Raw, &mut out).unwrap();
+    let lines: Vec<json::Json> = String::from_utf8(out).unwrap().lines()
+                                     .map(|l| json::Json::from_str(l).unwrap()).collect();
+    assert_eq!(lines[2]["reason"].as_string(), Some("refused"));
+    assert!(lines[3]["reason"].is_null());
+}
+
+#[test]
+fn handshakes_are_recorded_for_successes() {
+    let (_, tm) = test_manager("handshakes_are_recorded_for_successes");
+    let (nonce, addrs) = {
+        let options = tm.options_read();
+        (options.nonce, options.addrs.clone())
+    };
+
+    tm.record_handshake(&addrs[0], 1500);
+    // (the second addr's handshake went through, but its measurement then failed)
+    tm.record_handshake(&addrs[1], 2500);
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 100, 0, 10, SENTINEL_ERROR], Vec::new())).unwrap();
+
+    let ints = tm.data_file_read(Feed::Handshakes).read_range(i64::MIN, i64::MAX).unwrap();
+    let handshakes = Feed::Handshakes.records(&ints);
+    assert_eq!(handshakes.len(), 1);
+    assert_eq!((handshakes.time(0), handshakes.vals(0)), (100, &[1500][..]));
+
+    // and they're only ever stored with the results they were recorded for
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 110, 0, 10, 10], Vec::new())).unwrap();
+    assert_eq!(tm.data_file_read(Feed::Handshakes).record_count().unwrap(), 1);
+}
+
+#[test]
+fn export_ndjson_writes_nulls_for_errors() {
+    let (_, tm) = test_manager("export_ndjson_writes_nulls_for_errors");
+    let (nonce, addrs) = {
+        let options = tm.options_read();
+        (options.nonce, options.addrs.clone())
+    };
+
+    let mut data = vec![TEST_KIND.kind_id(), nonce, 100, 0, 10];
+    data.extend(iter::repeat(SENTINEL_ERROR).take(addrs.len() - 1));
+    tm.append_data(&TargetResults(data, Vec::new())).unwrap();
+    tm.record_addr_family(&addrs[0], AddrFamily::V4);
+
+    let mut out = Vec::new();
+    tm.export_ndjson(Feed::Raw, &mut out).unwrap();
+    let lines: Vec<json::Json> = String::from_utf8(out).unwrap().lines()
+                                     .map(|l| json::Json::from_str(l).unwrap()).collect();
+    assert_eq!(lines.len(), addrs.len());
+    for (line, addr) in lines.iter().zip(addrs.iter()) {
+        assert_eq!(line["time"].as_i64(), Some(100));
+        assert_eq!(line["addr"].as_string(), Some(&addr[..]));
+        assert_eq!(line["kind"].as_string(), Some("tcpping"));
+        assert!(line["sd"].is_null());
+    }
+    assert_eq!(lines[0]["val"].as_i64(), Some(10));
+    assert_eq!(lines[0]["family"].as_string(), Some("v4"));
+    assert!(lines[1]["val"].is_null());
+    assert!(lines[1]["family"].is_null());
+}
+
+#[test]
+fn prometheus_metrics_gives_latest_values() {
+    let (_, tm) = test_manager("prometheus_metrics_gives_latest_values");
+    let nonce = tm.options_read().nonce;
+
+    // the first addr goes from 12.3 ms to an error, the second to 4.5 ms
+    for (t, v0, v1) in vec![(100, 12300, 9000), (110, SENTINEL_ERROR, 4500)] {
+        tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, t, 0, v0, v1], Vec::new())).unwrap();
+    }
+
+    let metrics = tm.prometheus_metrics(115).unwrap();
+    let samples: Vec<&str> = metrics.lines().filter(|l| !l.starts_with('#')).collect();
+    assert_eq!(samples, vec![
+        "stabping_latency_ms{kind=\"tcpping\",addr=\"8.8.8.8:53\"} 4.5",
+        "stabping_up{kind=\"tcpping\",addr=\"google.com:80\"} 0",
+        "stabping_up{kind=\"tcpping\",addr=\"8.8.8.8:53\"} 1",
+        "stabping_addrs_alive{kind=\"tcpping\"} 1",
+        "stabping_addrs_lost{kind=\"tcpping\"} 1",
+    ]);
+
+    // long after the last measurement, there's nothing to report
+    let metrics = tm.prometheus_metrics(10_000).unwrap();
+    assert_eq!(metrics.lines().filter(|l| !l.starts_with('#')).count(), 0);
+
+    // but for the retries counted so far
+    tm.record_retries("google.com:80", 3);
+    assert_eq!(tm.retries("google.com:80"), 3);
+    let metrics = tm.prometheus_metrics(10_000).unwrap();
+    let samples: Vec<&str> = metrics.lines().filter(|l| !l.starts_with('#')).collect();
+    assert_eq!(samples, vec!["stabping_retries_total{kind=\"tcpping\",addr=\"google.com:80\"} 3"]);
+
+    // with an interval that long (even too long to double in millis), the values are still fresh
+    let mut options = tm.options_snapshot();
+    options.interval = u32::MAX;
+    *tm.options.write().unwrap() = options;
+    let metrics = tm.prometheus_metrics(10_000).unwrap();
+    assert_eq!(metrics.lines().filter(|l| l.starts_with("stabping_up")).count(), 2);
+}
+
+#[test]
+fn influx_lines_skip_errors_and_escape_tags() {
+    let (_, tm) = test_manager("influx_lines_skip_errors_and_escape_tags");
+
+    let mut options = TEST_KIND.default_options();
+    options.addrs = vec!["a b,c=d:80".to_owned(), "8.8.8.8:53".to_owned()];
+    // such addrs are refused by options_update, but may predate validation
+    *tm.options.write().unwrap() = options;
+    tm.index.write().unwrap().ensure_for_options(&tm.options_read()).unwrap();
+    let nonce = tm.options_read().nonce;
+
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 100, 0, 12300, SENTINEL_ERROR], Vec::new())).unwrap();
+
+    let mut out = Vec::new();
+    tm.to_influx_lines(Feed::Raw, &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(),
+               "ping,kind=tcpping,addr=a\\ b\\,c\\=d:80 latency_ms=12.3 100000000000\n");
+
+    // the family is tagged once it's known
+    tm.record_addr_family("a b,c=d:80", AddrFamily::V6);
+    let mut out = Vec::new();
+    tm.to_influx_lines(Feed::Raw, &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(),
+               "ping,kind=tcpping,addr=a\\ b\\,c\\=d:80,family=v6 latency_ms=12.3 100000000000\n");
+}
+
+#[test]
+fn live_status_reflects_latest_results() {
+    let (_, tm) = test_manager("live_status_reflects_latest_results");
+    let addrs = tm.options_read().addrs.clone();
+    let nonce = tm.options_read().nonce;
+    let (first, second) = {
+        let index = tm.index.read().unwrap();
+        (index.get_index(&addrs[0]), index.get_index(&addrs[1]))
+    };
+    assert!(tm.live_status().is_empty());
+
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 100, 0, 10, 20], Vec::new())).unwrap();
+    let status = tm.live_status();
+    assert_eq!(status[&first], LastResult { time: 100_000, val: 10, status: AddrStatus::Up });
+    assert_eq!(status[&second], LastResult { time: 100_000, val: 20, status: AddrStatus::Up });
+
+    // addrs not measured in a round keep their last result
+    tm.record_failure(&addrs[0], FailureReason::Refused);
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce, 110, 0, SENTINEL_ERROR, SENTINEL_NODATA],
+                                  vec![110_250, 0])).unwrap();
+    let status = tm.live_status();
+    assert_eq!(status[&first], LastResult {
+        time: 110_250,
+        val: SENTINEL_ERROR,
+        status: AddrStatus::Down(Some(FailureReason::Refused)),
+    });
+    assert_eq!(status[&second].time, 100_000);
+
+    // results with stale options are ignored
+    tm.append_data(&TargetResults(vec![TEST_KIND.kind_id(), nonce - 1, 120, 0, 30, 40], Vec::new())).unwrap();
+    assert_eq!(tm.live_status(), status);
+}
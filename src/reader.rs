@@ -11,39 +11,739 @@
  * associated target's data file.
  */
 use std::mem;
+use std::iter;
 use std::slice;
+#[cfg(test)]
+use std::fmt::{self, Display};
 use std::io;
-use std::io::{Write, BufWriter};
+#[cfg(all(test, feature = "wire-compression"))]
+use std::io::Read;
+#[cfg(feature = "wire-compression")]
+use std::io::Write;
+use std::fs::File;
 use std::sync::Arc;
 
+#[cfg(feature = "wire-compression")]
+use flate2::Compression;
+#[cfg(all(test, feature = "wire-compression"))]
+use flate2::read::GzDecoder;
+#[cfg(feature = "wire-compression")]
+use flate2::write::GzEncoder;
 use memmap::{Mmap, Protection};
 use iron::response::{WriteBody};
+#[cfg(test)]
+use rustc_serialize::{Encodable, Encoder};
 
-use helpers::VecIntoRawBytes;
-use persist::TargetManager;
-use options::SENTINEL_NODATA;
+use helpers::{VecIntoWireBytes, VecFromWireBytes, time_words, time_from_words};
+use persist::{TargetManager, Feed, AddrId};
+use options::{SENTINEL_ERROR, SENTINEL_NODATA};
 
 /**
  * A request from the client for persistent data for a target in the time range
  * `lower` to `upper` in context of the target's current options, verified
- * with `nonce`.
+ * with `nonce`, from the feed identified by `feed` (the raw data if not given),
+ * gzipped at the level given by `compression` (0 to 9), if given (and built
+ * with the `wire-compression` feature), and in the
+ * columnar layout (see `to_columnar()`) if `columnar`, or with bitmaps of
+ * absent values (see `to_bitmapped()`) if `bitmapped` (but not both).
  */
 #[derive(RustcEncodable, RustcDecodable, Debug)]
 pub struct DataRequest {
     nonce: i32,
-    lower: i32,
-    upper: i32,
+    lower: i64,
+    upper: i64,
+    feed: Option<u8>,
+    compression: Option<u32>,
+    columnar: Option<bool>,
+    bitmapped: Option<bool>,
+    big_endian: Option<bool>,
 }
 
 /**
  * Representation of data elements on-disk in a target's data file. They are
  * 32-bit back-to-back integers.
  */
+#[cfg(test)]
+#[derive(Clone, Copy)]
 #[repr(C, packed)]
-struct DataElement {
-    time: i32,
-    index: i32,
-    val: i32,
+pub struct DataElement {
+    pub time: i64,
+    pub index: AddrId,
+    pub val: i32,
+    // milliseconds past `time` (0 to 999)
+    pub millis: i32,
+}
+
+/*
+ * (encoded by hand, as the fields of a packed struct can't be borrowed as a
+ * derived encoding would, with errors encoded as null values)
+ */
+#[cfg(test)]
+impl Encodable for DataElement {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let (time, index, val, millis) = (self.time, self.index, self.val, self.millis);
+        s.emit_struct("DataElement", 4, |s| {
+            try!(s.emit_struct_field("time", 0, |s| time.encode(s)));
+            try!(s.emit_struct_field("index", 1, |s| u32::from(index).encode(s)));
+            try!(s.emit_struct_field("val", 2, |s| {
+                if val == SENTINEL_ERROR { s.emit_option_none() } else { s.emit_option_some(|s| val.encode(s)) }
+            }));
+            s.emit_struct_field("millis", 3, |s| millis.encode(s))
+        })
+    }
+}
+
+/**
+ * A view of a (time-ordered) series of records of any feed, stored back-to-back
+ * as 32-bit integers, where each record is a time (as two integers, low word
+ * first), an index, and `val_count` values (e.g. a `DataElement` for the raw
+ * feed), optionally followed by the milliseconds past the time.
+ */
+pub struct Records<'a> {
+    ints: &'a [i32],
+    val_count: usize,
+    stride: usize,
+}
+
+impl<'a> Records<'a> {
+    pub fn new(ints: &'a [i32], val_count: usize) -> Self {
+        Records::with_stride(ints, val_count, 3 + val_count)
+    }
+
+    /**
+     * Creates a view of records that end with the milliseconds past their
+     * time.
+     */
+    pub fn with_millis(ints: &'a [i32], val_count: usize) -> Self {
+        Records::with_stride(ints, val_count, 4 + val_count)
+    }
+
+    fn with_stride(ints: &'a [i32], val_count: usize, stride: usize) -> Self {
+        assert!(ints.len() % stride == 0);
+        Records {
+            ints: ints,
+            val_count: val_count,
+            stride: stride,
+        }
+    }
+
+    /**
+     * The size (in bytes) of each record with the given number of values.
+     */
+    pub fn record_size(val_count: usize) -> usize {
+        (3 + val_count) * mem::size_of::<i32>()
+    }
+
+    /**
+     * The size (in bytes) of each record with the given number of values
+     * that ends with the milliseconds past its time.
+     */
+    pub fn record_size_with_millis(val_count: usize) -> usize {
+        Records::record_size(val_count) + mem::size_of::<i32>()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ints.len() / self.stride
+    }
+
+    pub fn val_count(&self) -> usize {
+        self.val_count
+    }
+
+    pub fn time(&self, i: usize) -> i64 {
+        time_from_words(self.ints[i * self.stride], self.ints[i * self.stride + 1])
+    }
+
+    /**
+     * The milliseconds past the time of the record at the given position,
+     * which are zero for records without them (including those written before
+     * the raw feed had sub-second times, which are padded out with
+     * `SENTINEL_NODATA`).
+     */
+    pub fn millis(&self, i: usize) -> i32 {
+        if self.stride == 3 + self.val_count {
+            return 0;
+        }
+        let millis = self.ints[i * self.stride + 3 + self.val_count];
+        if millis == SENTINEL_NODATA { 0 } else { millis }
+    }
+
+    pub fn index(&self, i: usize) -> AddrId {
+        AddrId::from_word(self.ints[i * self.stride + 2])
+    }
+
+    pub fn vals(&self, i: usize) -> &'a [i32] {
+        let start = i * self.stride + 3;
+        &self.ints[start..start + self.val_count]
+    }
+}
+
+// first byte of every response in the wire format
+pub static WIRE_MAGIC: u8 = 0x53;
+
+/*
+ * version of the wire format; bump this whenever its layout changes so that
+ * clients can refuse data they don't know how to read
+ */
+pub static WIRE_VERSION: u8 = 3;
+
+// set in the version byte of the header when the data following it is gzipped
+pub static WIRE_FLAG_GZIP: u8 = 0x80;
+
+// set in the version byte of the header when the data following it is columnar
+pub static WIRE_FLAG_COLUMNAR: u8 = 0x40;
+
+// set in the version byte of the header when the data following it has bitmaps of absent values
+pub static WIRE_FLAG_BITMAPPED: u8 = 0x20;
+
+// set in the version byte of the header when the integers on the wire are big-endian
+pub static WIRE_FLAG_BIG_ENDIAN: u8 = 0x10;
+
+// highest gzip level data in the wire format can be compressed at
+pub static MAX_WIRE_COMPRESSION: u32 = 9;
+
+// length (in bytes) of the header preceding data in the wire format
+#[cfg(test)]
+pub const WIRE_HEADER_LEN: usize = 8;
+
+/**
+ * The header preceding data in the wire format, laid out as:
+ *
+ * [magic (u8), version (u8), feed_id (u8), kind_id (u8), val_count (i32)]
+ *
+ * where `val_count` is the number of values following the time (and millis)
+ * in each time segment. The header is 8 bytes long so that the data following it stays
+ * aligned for reading as 32-bit integers.
+ *
+ * If the data following the header is gzipped (`compressed`), the version is
+ * flagged with `WIRE_FLAG_GZIP`, if it's in the columnar layout (see
+ * `to_columnar()`), with `WIRE_FLAG_COLUMNAR`, and if it marks absent values
+ * with bitmaps (see `to_bitmapped()`), with `WIRE_FLAG_BITMAPPED` (so that
+ * clients not expecting any of these refuse it).
+ *
+ * Like all 32-bit integers on the wire, `val_count` is little-endian, unless
+ * the version is flagged with `WIRE_FLAG_BIG_ENDIAN` (see `swap_byte_order()`).
+ */
+#[derive(Debug, PartialEq)]
+pub struct WireHeader {
+    pub feed_id: u8,
+    pub kind_id: u8,
+    pub val_count: i32,
+    pub compressed: bool,
+    pub columnar: bool,
+    pub bitmapped: bool,
+    pub big_endian: bool,
+}
+
+impl WireHeader {
+    /**
+     * Makes a header for the given number of values of the given feed and
+     * kind (by id) per round, sent as is (plain, row by row, little-endian).
+     */
+    pub fn new(feed: Feed, kind_id: u8, val_count: i32) -> Self {
+        WireHeader {
+            feed_id: feed.feed_id(),
+            kind_id: kind_id,
+            val_count: val_count,
+            compressed: false,
+            columnar: false,
+            bitmapped: false,
+            big_endian: false,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut version = WIRE_VERSION;
+        if self.compressed {
+            version |= WIRE_FLAG_GZIP;
+        }
+        if self.columnar {
+            version |= WIRE_FLAG_COLUMNAR;
+        }
+        if self.bitmapped {
+            version |= WIRE_FLAG_BITMAPPED;
+        }
+        if self.big_endian {
+            version |= WIRE_FLAG_BIG_ENDIAN;
+        }
+        let mut bytes = vec![WIRE_MAGIC, version, self.feed_id, self.kind_id];
+        let val_count = vec![self.val_count].into_wire_bytes();
+        bytes.extend(if self.big_endian { swap_byte_order(&val_count) } else { val_count });
+        bytes
+    }
+
+    /**
+     * Encodes the given data in the wire format (without a header, as from
+     * `encode_range()`, or `to_columnar()` if this header says so), gzipped at
+     * the given level (0 to 9), preceded by this header (flagged as
+     * compressed).
+     */
+    #[cfg(feature = "wire-compression")]
+    pub fn to_wire_compressed(&self, wire: &[u8], level: u32) -> io::Result<Vec<u8>> {
+        let header = WireHeader { compressed: true, ..*self };
+        let mut encoder = GzEncoder::new(header.to_bytes(), compression_for(level));
+        try!(encoder.write_all(wire));
+        encoder.finish()
+    }
+
+    /**
+     * Reads the header at the start of the given data in the wire format,
+     * refusing data in an unknown version of the format.
+     */
+    #[cfg(test)]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WireError> {
+        if bytes.len() < WIRE_HEADER_LEN {
+            return Err(WireError::MissingHeader);
+        }
+        if bytes[0] != WIRE_MAGIC {
+            return Err(WireError::BadMagic(bytes[0]));
+        }
+        let flags = WIRE_FLAG_GZIP | WIRE_FLAG_COLUMNAR | WIRE_FLAG_BITMAPPED | WIRE_FLAG_BIG_ENDIAN;
+        if bytes[1] & !flags != WIRE_VERSION {
+            return Err(WireError::UnknownVersion(bytes[1]));
+        }
+
+        let big_endian = bytes[1] & WIRE_FLAG_BIG_ENDIAN != 0;
+        let val_count = if big_endian {
+            swap_byte_order(&bytes[4..WIRE_HEADER_LEN]).from_wire_bytes()[0]
+        } else {
+            bytes[4..WIRE_HEADER_LEN].from_wire_bytes()[0]
+        };
+        Ok(WireHeader {
+            feed_id: bytes[2],
+            kind_id: bytes[3],
+            val_count: val_count,
+            compressed: bytes[1] & WIRE_FLAG_GZIP != 0,
+            columnar: bytes[1] & WIRE_FLAG_COLUMNAR != 0,
+            bitmapped: bytes[1] & WIRE_FLAG_BITMAPPED != 0,
+            big_endian: big_endian,
+        })
+    }
+}
+
+/**
+ * Swaps the byte order of each 32-bit integer of the given data in the wire
+ * format (without a header), turning little-endian integers into big-endian
+ * ones (e.g. for browsers reading them with a `DataView`, where big-endian is
+ * the default) and back again. Any trailing bytes are left out.
+ *
+ * Only the fixed-size integers of the plain and bitmapped layouts have a byte
+ * order; the variable-length integers of the columnar layout are the same
+ * either way.
+ */
+pub fn swap_byte_order(wire: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(wire.len());
+    for word in wire.chunks(mem::size_of::<i32>()).filter(|w| w.len() == mem::size_of::<i32>()) {
+        out.extend(word.iter().rev());
+    }
+    out
+}
+
+/**
+ * Appends the given integer as a (zigzag-encoded) LEB128 variable-length
+ * integer, so that integers near zero take up a single byte.
+ */
+fn put_varint(out: &mut Vec<u8>, n: i64) {
+    let mut z = ((n << 1) ^ (n >> 63)) as u64;
+    while z >= 0x80 {
+        out.push(z as u8 | 0x80);
+        z >>= 7;
+    }
+    out.push(z as u8);
+}
+
+/**
+ * Reads the variable-length integer (see `put_varint()`) at the given
+ * position, moving the position past it.
+ */
+#[cfg(test)]
+fn get_varint(bytes: &[u8], pos: &mut usize) -> Result<i64, WireError> {
+    let mut z: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let b = match bytes.get(*pos) {
+            Some(&b) if shift < 64 => b,
+            _ => return Err(WireError::MalformedColumns),
+        };
+        *pos += 1;
+        z |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok((z >> 1) as i64 ^ -((z & 1) as i64));
+        }
+        shift += 7;
+    }
+}
+
+/**
+ * Re-encodes the given data in the wire format (without a header, as from
+ * `encode_range()`) with `val_count` values in each time segment, in the
+ * columnar layout.
+ *
+ * The columnar layout is the number of time segments, followed by a column of
+ * their times, then of their millis, and then one for each of their values,
+ * each column holding the difference of each element from the one before it
+ * (the first from 0). Everything is a variable-length integer (see
+ * `put_varint()`), so times at regular intervals and values that change
+ * slowly take up a byte or two each, and compress well.
+ */
+pub fn to_columnar(wire: &[u8], val_count: usize) -> Vec<u8> {
+    let stride = 3 + val_count;
+    let ints = wire.from_wire_bytes();
+    let segments: Vec<&[i32]> = ints.chunks(stride).filter(|s| s.len() == stride).collect();
+
+    let mut out = Vec::new();
+    put_varint(&mut out, segments.len() as i64);
+    let mut prev: i64 = 0;
+    for s in segments.iter() {
+        let time = time_from_words(s[0], s[1]);
+        put_varint(&mut out, time.wrapping_sub(prev));
+        prev = time;
+    }
+    for column in 2..stride {
+        let mut prev: i64 = 0;
+        for s in segments.iter() {
+            put_varint(&mut out, s[column] as i64 - prev);
+            prev = s[column] as i64;
+        }
+    }
+    out
+}
+
+/**
+ * Decodes data in the columnar layout (see `to_columnar()`) with `val_count`
+ * values in each time segment back into its time segments.
+ */
+#[cfg(test)]
+fn from_columnar(bytes: &[u8], val_count: usize) -> Result<Vec<i32>, WireError> {
+    let stride = 3 + val_count;
+    let mut pos = 0;
+    let count = try!(get_varint(bytes, &mut pos));
+
+    // every segment takes up at least a byte in each column
+    if count < 0 || (count as u64).saturating_mul(stride as u64 - 1) > bytes.len() as u64 {
+        return Err(WireError::MalformedColumns);
+    }
+    let count = count as usize;
+
+    let mut ints = vec![0; count * stride];
+    let mut time: i64 = 0;
+    for i in 0..count {
+        time = time.wrapping_add(try!(get_varint(bytes, &mut pos)));
+        ints[i * stride..i * stride + 2].copy_from_slice(&time_words(time));
+    }
+    for column in 2..stride {
+        let mut val: i64 = 0;
+        for i in 0..count {
+            val = val.wrapping_add(try!(get_varint(bytes, &mut pos)));
+            ints[i * stride + column] = val as i32;
+        }
+    }
+
+    if pos != bytes.len() {
+        return Err(WireError::MalformedColumns);
+    }
+    Ok(ints)
+}
+
+/**
+ * Gets the number of 32-bit integers a bitmap of the given number of values
+ * (see `to_bitmapped()`) takes up.
+ */
+fn bitmap_len(val_count: usize) -> usize {
+    (val_count + 31) / 32
+}
+
+/**
+ * Re-encodes the given data in the wire format (without a header, as from
+ * `encode_range()`) with `val_count` values in each time segment, with the
+ * values marking the absence of data replaced by bitmaps.
+ *
+ * Each time segment keeps its time and millis, followed by a bitmap of which
+ * of its values are errors, then one of which have no data (each of
+ * `val_count` bits padded out to whole 32-bit integers, the first value being
+ * the lowest bit of the first integer), and then only the values that are
+ * neither. Clients can then tell which values are absent without knowing the
+ * sentinel values, and time segments where everything is down take up hardly
+ * any room.
+ */
+pub fn to_bitmapped(wire: &[u8], val_count: usize) -> Vec<u8> {
+    let stride = 3 + val_count;
+    let bitmap_len = bitmap_len(val_count);
+    let ints = wire.from_wire_bytes();
+
+    let mut out: Vec<i32> = Vec::with_capacity(ints.len());
+    for segment in ints.chunks(stride).filter(|s| s.len() == stride) {
+        out.extend_from_slice(&segment[..3]);
+        let errors = out.len();
+        let nodata = errors + bitmap_len;
+        out.extend(iter::repeat(0).take(2 * bitmap_len));
+        for (i, &val) in segment[3..].iter().enumerate() {
+            let bitmap = if val == SENTINEL_ERROR {
+                errors
+            } else if val == SENTINEL_NODATA {
+                nodata
+            } else {
+                out.push(val);
+                continue;
+            };
+            out[bitmap + i / 32] |= 1 << (i % 32);
+        }
+    }
+    out.into_wire_bytes()
+}
+
+/**
+ * Decodes data with bitmaps of absent values (see `to_bitmapped()`) with
+ * `val_count` values in each time segment back into its time segments, with
+ * sentinel values in place of absent ones.
+ */
+#[cfg(test)]
+fn from_bitmapped(bytes: &[u8], val_count: usize) -> Result<Vec<i32>, WireError> {
+    if bytes.len() % mem::size_of::<i32>() != 0 {
+        return Err(WireError::MalformedBitmaps);
+    }
+    let bitmap_len = bitmap_len(val_count);
+    let ints = bytes.from_wire_bytes();
+
+    let mut out = Vec::with_capacity(ints.len());
+    let mut pos = 0;
+    while pos < ints.len() {
+        if pos + 3 + 2 * bitmap_len > ints.len() {
+            return Err(WireError::MalformedBitmaps);
+        }
+        out.extend_from_slice(&ints[pos..pos + 3]);
+        let errors = &ints[pos + 3..pos + 3 + bitmap_len];
+        let nodata = &ints[pos + 3 + bitmap_len..pos + 3 + 2 * bitmap_len];
+        pos += 3 + 2 * bitmap_len;
+
+        for i in 0..val_count {
+            let bit = 1 << (i % 32);
+            if errors[i / 32] & bit != 0 {
+                out.push(SENTINEL_ERROR);
+            } else if nodata[i / 32] & bit != 0 {
+                out.push(SENTINEL_NODATA);
+            } else {
+                match ints.get(pos) {
+                    Some(&val) => out.push(val),
+                    None => return Err(WireError::MalformedBitmaps),
+                }
+                pos += 1;
+            }
+        }
+    }
+    Ok(out)
+}
+
+/**
+ * Gets the compression for the given gzip level (0 to 9).
+ */
+#[cfg(feature = "wire-compression")]
+fn compression_for(level: u32) -> Compression {
+    match level {
+        0 => Compression::None,
+        1...3 => Compression::Fast,
+        4...6 => Compression::Default,
+        _ => Compression::Best,
+    }
+}
+
+/**
+ * Error container representing the ways in which data in the wire format (as
+ * written by `SPDataReader`) can fail to be decoded.
+ */
+#[cfg(test)]
+#[derive(Debug)]
+pub enum WireError {
+    MissingHeader,
+    BadMagic(u8),
+    UnknownVersion(u8),
+    ValCountMismatch(i32, usize),
+    IncorrectMultiple(usize, usize),
+    #[cfg(feature = "wire-compression")]
+    BadCompression(io::Error),
+    #[cfg(not(feature = "wire-compression"))]
+    CompressionUnsupported,
+    MalformedColumns,
+    MalformedBitmaps,
+}
+
+#[cfg(test)]
+impl WireError {
+    pub fn description(&self) -> String {
+        match *self {
+            WireError::MissingHeader => "Wire data too short to contain a header".to_owned(),
+            WireError::BadMagic(b) => format!(
+                "Wire data begins with {:#x} rather than the expected {:#x}", b, WIRE_MAGIC),
+            WireError::UnknownVersion(v) => format!(
+                "Wire data is in format version {}, but only version {} is supported",
+                v, WIRE_VERSION),
+            WireError::ValCountMismatch(count, expected) => format!(
+                "Wire data has {} values per segment, but {} were expected",
+                count, expected),
+            WireError::IncorrectMultiple(len, segment_len) => format!(
+                "Wire data of {} bytes is not a multiple of the {}-byte segment size",
+                len, segment_len),
+            #[cfg(feature = "wire-compression")]
+            WireError::BadCompression(ref e) => format!(
+                "Wire data is flagged as gzipped, but could not be decompressed: {}", e),
+            #[cfg(not(feature = "wire-compression"))]
+            WireError::CompressionUnsupported =>
+                "Wire data is gzipped, but stabping was built without the wire-compression feature".to_owned(),
+            WireError::MalformedColumns => "Wire data is flagged as columnar, but its columns are malformed".to_owned(),
+            WireError::MalformedBitmaps => "Wire data is flagged as bitmapped, but its bitmaps are malformed".to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.description())
+    }
+}
+
+/**
+ * Decompresses gzipped wire data (see `WireHeader::to_wire_compressed()`).
+ */
+#[cfg(all(test, feature = "wire-compression"))]
+fn gunzip(bytes: &[u8]) -> Result<Vec<u8>, WireError> {
+    let mut decompressed = Vec::new();
+    try!(
+        GzDecoder::new(bytes)
+        .and_then(|mut d| d.read_to_end(&mut decompressed))
+        .map_err(|e| WireError::BadCompression(e))
+    );
+    Ok(decompressed)
+}
+
+#[cfg(all(test, not(feature = "wire-compression")))]
+fn gunzip(_: &[u8]) -> Result<Vec<u8>, WireError> {
+    Err(WireError::CompressionUnsupported)
+}
+
+/**
+ * Decodes data in the wire format (as written by `SPDataReader`) back into
+ * its header and the data elements it represents, given the ordered list of
+ * indices the data was written with (see
+ * `TargetManager.get_current_indices()`).
+ *
+ * The wire format is a header (see `WireHeader`) followed by a series of time
+ * segments, each a time (as two integers, low word first) and the milliseconds
+ * past it followed by one value for each index in `ordered_list` (all
+ * little-endian 32-bit integers back-to-back), in the columnar layout (see
+ * `to_columnar()`) or with bitmaps of absent values (see `to_bitmapped()`),
+ * and/or gzipped, if the header says so. Values marking the absence of data
+ * are not decoded into elements (and errors are decoded as `SENTINEL_ERROR`,
+ * even when marked in a bitmap).
+ *
+ * Clients decode the wire format themselves, so this (along with the rest of
+ * the decoding) is only built for tests, to check the encoding against.
+ */
+#[cfg(test)]
+pub fn from_wire(bytes: &[u8], ordered_list: &[AddrId])
+                 -> Result<(WireHeader, Vec<DataElement>), WireError> {
+    let header = try!(WireHeader::from_bytes(bytes));
+    if header.val_count as usize != ordered_list.len() {
+        return Err(WireError::ValCountMismatch(header.val_count, ordered_list.len()));
+    }
+
+    let decompressed;
+    let body = if header.compressed {
+        decompressed = try!(gunzip(&bytes[WIRE_HEADER_LEN..]));
+        &decompressed[..]
+    } else {
+        &bytes[WIRE_HEADER_LEN..]
+    };
+    let swapped;
+    let body = if header.big_endian && !header.columnar {
+        if body.len() % mem::size_of::<i32>() != 0 {
+            return Err(WireError::IncorrectMultiple(body.len(), mem::size_of::<i32>()));
+        }
+        swapped = swap_byte_order(body);
+        &swapped[..]
+    } else {
+        body
+    };
+    let raw = if header.columnar {
+        try!(from_columnar(body, ordered_list.len()))
+    } else if header.bitmapped {
+        try!(from_bitmapped(body, ordered_list.len()))
+    } else {
+        let segment_len = (3 + ordered_list.len()) * mem::size_of::<i32>();
+        if body.len() % segment_len != 0 {
+            return Err(WireError::IncorrectMultiple(body.len(), segment_len));
+        }
+        body.from_wire_bytes()
+    };
+    let mut data = Vec::with_capacity(raw.len());
+    for segment in raw.chunks(3 + ordered_list.len()) {
+        let (time, millis) = (time_from_words(segment[0], segment[1]), segment[2]);
+        for (&index, &val) in ordered_list.iter().zip(segment[3..].iter()) {
+            if val != SENTINEL_NODATA {
+                data.push(DataElement {
+                    time: time,
+                    index: index,
+                    val: val,
+                    millis: millis,
+                });
+            }
+        }
+    }
+
+    Ok((header, data))
+}
+
+/**
+ * Attempts to `mmap` the given data file and calls `f` with its contents
+ * interpreted as a series of back-to-back records of type `T`.
+ */
+pub fn with_mapped_records<T, F, R>(file: &File, f: F) -> io::Result<R>
+        where F: FnOnce(&[T]) -> R {
+    // an empty file can't be mapped, but it certainly has no records
+    if try!(file.metadata()).len() == 0 {
+        return Ok(f(&[]));
+    }
+
+    let map = try!(
+        Mmap::open(file, Protection::Read)
+        .map_err(|e| {
+            error!("failed to map data file error=\"{}\"", e);
+            e
+        })
+    );
+
+    let records: &[T] = unsafe {
+        let orig = map.as_slice();
+        let raw_ptr = orig.as_ptr();
+
+        if orig.len() % mem::size_of::<T>() != 0 {
+            error!("data file not a multiple of the record size len={}", orig.len());
+            return Err(io::Error::new(io::ErrorKind::Other, "Data file incorrect multiple!"));
+        }
+        let new_len = orig.len() / mem::size_of::<T>();
+
+        slice::from_raw_parts(raw_ptr as *const T, new_len)
+    };
+
+    Ok(f(records))
+}
+
+/**
+ * Finds the position of the first of the given time-ordered records whose
+ * time is at or after the given time.
+ */
+pub fn lower_bound(records: &Records, time: i64) -> usize {
+    let mut lo = 0;
+    let mut hi = records.len();
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if records.time(mid) < time {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    lo
 }
 
 /**
@@ -51,8 +751,13 @@ struct DataElement {
  * data of a target.
  */
 pub struct SPDataReader {
-    lower: i32,
-    upper: i32,
+    lower: i64,
+    upper: i64,
+    feed: Feed,
+    compression: Option<u32>,
+    columnar: bool,
+    bitmapped: bool,
+    big_endian: bool,
     tm: Arc<TargetManager>,
 }
 
@@ -63,9 +768,36 @@ impl SPDataReader {
             return None;
         }
 
+        // refuse feeds we don't know about
+        let feed = match dr.feed {
+            Some(feed_id) => match Feed::from_feed_id(feed_id) {
+                Some(feed) => feed,
+                None => return None,
+            },
+            None => Feed::Raw,
+        };
+
+        // refuse compression levels gzip doesn't have (or any, if built without it)
+        let can_compress = cfg!(feature = "wire-compression");
+        if dr.compression.map_or(false, |level| level > MAX_WIRE_COMPRESSION || !can_compress) {
+            return None;
+        }
+
+        // columns must all be the same length, so they can't leave out absent values
+        let columnar = dr.columnar.unwrap_or(false);
+        let bitmapped = dr.bitmapped.unwrap_or(false);
+        if columnar && bitmapped {
+            return None;
+        }
+
         Some(SPDataReader{
             lower: dr.lower,
             upper: dr.upper,
+            feed: feed,
+            compression: dr.compression,
+            columnar: columnar,
+            bitmapped: bitmapped,
+            big_endian: dr.big_endian.unwrap_or(false),
             tm: tm,
         })
     }
@@ -80,142 +812,436 @@ impl WriteBody for SPDataReader {
          * acquire nonce and current indices (current state of addrs for this
          * target) from the TargetManager
          */
-        let (nonce, ordered_list, mut membership) = self.tm.get_current_indices();
+        let (nonce, ordered_list, _) = self.tm.get_current_indices();
 
         // verify that the request nonce and the manager's nonce match
         if nonce != self.tm.options_read().nonce {
-            println!("Nonce mismatch in WriteBody for SPDatReader!");
+            debug!("ignoring data request for stale options kind={}", self.tm.kind.compact_name());
             return Ok(())
         }
 
-        // get a lock on the target's data file
-        let guard = self.tm.data_file_read();
+        let header = WireHeader {
+            columnar: self.columnar,
+            bitmapped: self.bitmapped,
+            big_endian: self.big_endian,
+            ..WireHeader::new(self.feed, self.tm.kind.kind_id() as u8,
+                              (ordered_list.len() * self.feed.val_count()) as i32)
+        };
 
-        // attempt to mmap the target's data file
-        let map = try!(
-            Mmap::open(&*guard, Protection::Read)
-            .map_err(|e| {
-                println!("ERROR: Mmap failed!");
-                e
-            })
+        let mut data = try!(
+            self.tm.query_range(self.feed, self.lower, self.upper)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.description()))
         );
+        if self.columnar {
+            data = to_columnar(&data, header.val_count as usize);
+        } else if self.bitmapped {
+            data = to_bitmapped(&data, header.val_count as usize);
+        }
+        if self.big_endian && !self.columnar {
+            data = swap_byte_order(&data);
+        }
+
+        // write out the data (preceded by a header describing it) and flush
+        match self.compression {
+            #[cfg(feature = "wire-compression")]
+            Some(level) => try!(res.write_all(&try!(header.to_wire_compressed(&data, level)))),
+            _ => {
+                try!(res.write_all(&header.to_bytes()));
+                try!(res.write_all(&data));
+            },
+        }
+        try!(res.flush());
+
+        Ok(())
+    }
+}
+
+/**
+ * Encodes the given (time-ordered) records with times in the range `lower` to
+ * `upper` (inclusive) in the wire format (without a header), as a series of
+ * time segments (one for each distinct time, to the millisecond) with values
+ * in the order given by `ordered_list`.
+ *
+ * Records with indices not in `ordered_list` are left out, and indices without
+ * a record at some time are given a sentinel value for that time. If there are
+ * no records in the range, nothing at all is encoded.
+ */
+pub fn encode_range(records: &Records, lower: i64, upper: i64,
+                    ordered_list: &[AddrId]) -> Vec<u8> {
+    let val_count = records.val_count();
+
+    // search for the requested start/lower/begin and end/upper times
+    let begin = lower_bound(records, lower);
+    let end = lower_bound(records, upper.saturating_add(1));
+
+    // if our search reveals that the range is empty, then we don't have data
+    if begin >= end {
+        return Vec::new();
+    }
+
+    /*
+     * map each index in ordered_list to where its values go in a time segment
+     * (after the time and millis)
+     */
+    let slots_len = ordered_list.iter().map(|&i| u32::from(i) as usize + 1).max().unwrap_or(0);
+    let mut slots: Vec<Option<usize>> = vec![None; slots_len];
+    for (pos, &i) in ordered_list.iter().enumerate() {
+        slots[u32::from(i) as usize] = Some(3 + pos * val_count);
+    }
+
+    let segment_len = 3 + ordered_list.len() * val_count;
+    let mut out: Vec<i32> = Vec::new();
+    let mut segment = vec![SENTINEL_NODATA; segment_len];
+    let (mut time, mut millis) = (records.time(begin), records.millis(begin));
+    segment[..2].copy_from_slice(&time_words(time));
+    segment[2] = millis;
 
+    // loop through all the records we have between begin and end
+    for r in begin..end {
         /*
-         * attempt to read the raw bytes of the mapped data file as a series of
-         * DataElements (three 32-bit integers back-to-back)
+         * if we encounter a different time, we've completed a time segment,
+         * so store it and start on a new one
          */
-        let data: &[DataElement] = unsafe {
-            let orig = map.as_slice();
-            let raw_ptr = orig.as_ptr();
-
-            let orig_len = orig.len();
-            if orig_len % mem::size_of::<DataElement>() != 0 {
-                println!("ERROR: data file not a multiple 3 * 4 bytes!");
-                return Err(io::Error::new(io::ErrorKind::Other, "Data file incorrect multiple!"));
+        if records.time(r) != time || records.millis(r) != millis {
+            out.extend(segment.iter());
+            for v in segment.iter_mut() {
+                *v = SENTINEL_NODATA;
             }
-            let new_len = orig.len() / mem::size_of::<DataElement>();
+            time = records.time(r);
+            millis = records.millis(r);
+            segment[..2].copy_from_slice(&time_words(time));
+            segment[2] = millis;
+        }
 
-            mem::forget(orig);
-            slice::from_raw_parts(raw_ptr as *const DataElement, new_len)
-        };
+        // store the record's values if its index is one we're interested in
+        if let Some(&Some(s)) = slots.get(u32::from(records.index(r)) as usize) {
+            segment[s..s + val_count].copy_from_slice(records.vals(r));
+        }
+    }
 
-        // search for the requested start/lower/begin time of the data
-        let begin = match data.binary_search_by_key(&self.lower, |d| d.time) {
-            Ok(mut i) => {
-                /*
-                 * we may end up in the middle of a series of data points taken
-                 * at the same time; we seek to the first
-                 */
-                while i > 0 && data[i].time == self.lower {
-                    i -= 1;
-                }
-                i
-            },
-            Err(i) => i
-        };
+    // store the final time segment
+    out.extend(segment.iter());
 
-        // search for the requested end/upper time of the data
-        let end = match data.binary_search_by_key(&self.upper, |d| d.time) {
-            Ok(mut i) => {
-                /*
-                 * we may end up in the middle of a series of data points taken
-                 * at the same time; we seek to the last
-                 */
-                while i < data.len() && data[i].time == self.upper {
-                    i += 1;
-                }
-                i
-            },
-            Err(i) => i
-        };
+    out.into_wire_bytes()
+}
 
-        /*
-         * if our search reveals that we need to start past the data we have,
-         * then we don't have that data
-         */
-        if begin >= data.len() {
-            return Ok(())
+#[test]
+fn from_wire_round_trips() {
+    use std::i64;
+    use rustc_serialize::json;
+    use options::SENTINEL_ERROR;
+
+    let ordered_list = vec![AddrId::from(1), AddrId::from(0)];
+    let (a, b) = (ordered_list[0], ordered_list[1]);
+
+    let data = vec![
+        DataElement { time: 10, index: a, val: 1, millis: 0 },
+        DataElement { time: 10, index: b, val: 2, millis: 0 },
+        // samples within the same second are kept apart
+        DataElement { time: 10, index: a, val: 4, millis: 500 },
+        DataElement { time: 20, index: a, val: SENTINEL_ERROR, millis: 250 },
+        DataElement { time: 30, index: b, val: 3, millis: 999 },
+        // past the 32-bit time
+        DataElement { time: 5_000_000_000, index: a, val: 5, millis: 0 },
+    ];
+
+    let mut bytes = WireHeader::new(Feed::Raw, 0, ordered_list.len() as i32).to_bytes();
+    let ints: Vec<i32> = data.iter().flat_map(|d| {
+        let t = time_words(d.time);
+        vec![t[0], t[1], d.index.word(), d.val, d.millis]
+    }).collect();
+    bytes.extend(encode_range(&Records::with_millis(&ints, 1), 0, i64::MAX, &ordered_list));
+
+    let (header, decoded) = from_wire(&bytes, &ordered_list).unwrap();
+    assert_eq!(header.feed_id, Feed::Raw.feed_id());
+    assert_eq!(decoded.len(), data.len());
+    for (d, e) in decoded.iter().zip(data.iter()) {
+        assert_eq!((d.time, d.millis, d.index, d.val), (e.time, e.millis, e.index, e.val));
+    }
+
+    assert!(from_wire(&bytes[..bytes.len() - 1], &ordered_list).is_err());
+
+    // decoded data can be handed straight to JSON APIs, errors and all
+    let json = json::encode(&decoded[2..4].to_vec()).unwrap();
+    assert_eq!(json, "[{\"time\":10,\"index\":1,\"val\":4,\"millis\":500},\
+                      {\"time\":20,\"index\":1,\"val\":null,\"millis\":250}]");
+
+    // data in a future version of the format is refused
+    bytes[1] = WIRE_VERSION + 1;
+    match from_wire(&bytes, &ordered_list) {
+        Err(WireError::UnknownVersion(v)) => assert_eq!(v, WIRE_VERSION + 1),
+        _ => panic!("Expected an unknown version error"),
+    }
+}
+
+#[test]
+fn data_requests_are_checked() {
+    use persist::test_manager;
+
+    let (_, tm) = test_manager("data_requests_are_checked");
+    let tm = Arc::new(tm);
+    let nonce = tm.options_read().nonce;
+    let request = |compression, columnar, bitmapped| DataRequest {
+        nonce: nonce, lower: 0, upper: 100, feed: None,
+        compression: compression, columnar: columnar, bitmapped: bitmapped, big_endian: None,
+    };
+
+    assert!(SPDataReader::new(tm.clone(), request(None, Some(true), None)).is_some());
+    assert!(SPDataReader::new(tm.clone(), request(None, Some(true), Some(true))).is_none());
+    assert!(SPDataReader::new(tm.clone(), request(Some(MAX_WIRE_COMPRESSION + 1), None, None)).is_none());
+
+    // gzip is only on offer when built in
+    assert_eq!(SPDataReader::new(tm.clone(), request(Some(6), None, None)).is_some(),
+               cfg!(feature = "wire-compression"));
+}
+
+#[cfg(feature = "wire-compression")]
+#[test]
+fn from_wire_decompresses_gzipped_data() {
+    use std::i64;
+
+    let ordered_list = vec![AddrId::from(0), AddrId::from(1)];
+    let ints: Vec<i32> = (0..100).flat_map(|t| vec![t * 10, 0, t % 2, 20_000 + t, 0]).collect();
+    let wire = encode_range(&Records::with_millis(&ints, 1), 0, i64::MAX, &ordered_list);
+
+    let header = WireHeader::new(Feed::Raw, 0, ordered_list.len() as i32);
+    let mut plain = header.to_bytes();
+    plain.extend_from_slice(&wire);
+    let compressed = header.to_wire_compressed(&wire, 6).unwrap();
+    assert!(compressed.len() < plain.len());
+    assert_eq!(compressed[1], WIRE_VERSION | WIRE_FLAG_GZIP);
+
+    let (plain_header, plain_data) = from_wire(&plain, &ordered_list).unwrap();
+    let (compressed_header, compressed_data) = from_wire(&compressed, &ordered_list).unwrap();
+    assert!(compressed_header.compressed && !plain_header.compressed);
+    assert_eq!(compressed_data.len(), plain_data.len());
+    for (c, p) in compressed_data.iter().zip(plain_data.iter()) {
+        assert_eq!((c.time, c.millis, c.index, c.val), (p.time, p.millis, p.index, p.val));
+    }
+
+    // plain data flagged as gzipped doesn't decompress
+    let mut flagged = plain.clone();
+    flagged[1] |= WIRE_FLAG_GZIP;
+    match from_wire(&flagged, &ordered_list) {
+        Err(WireError::BadCompression(_)) => (),
+        _ => panic!("Expected a bad compression error"),
+    }
+}
+
+#[test]
+fn from_wire_decodes_columnar_data() {
+    use std::i64;
+    use options::SENTINEL_ERROR;
+
+    let ordered_list = vec![AddrId::from(1), AddrId::from(0)];
+    // segments at irregular times (some within the same second), with gaps and errors
+    let mut ints = Vec::new();
+    for (i, &(time, millis)) in [(10, 0), (10, 500), (20, 250), (25, 999), (5_000_000_000, 0)].iter().enumerate() {
+        let tw = time_words(time);
+        ints.extend_from_slice(&[tw[0], tw[1], 0, 100 + i as i32, millis]);
+        if i != 2 {
+            let val = if i == 3 { SENTINEL_ERROR } else { 200 - i as i32 };
+            ints.extend_from_slice(&[tw[0], tw[1], 1, val, millis]);
         }
+    }
+    let wire = encode_range(&Records::with_millis(&ints, 1), 0, i64::MAX, &ordered_list);
 
-        // initialize a buffered writer to actually write the response body
-        let mut writer = BufWriter::new(res);
+    let mut plain = WireHeader::new(Feed::Raw, 0, ordered_list.len() as i32).to_bytes();
+    plain.extend_from_slice(&wire);
+    let header = WireHeader { columnar: true, ..WireHeader::from_bytes(&plain).unwrap() };
+    let mut columnar = header.to_bytes();
+    columnar.extend(to_columnar(&wire, ordered_list.len()));
+    assert!(columnar.len() < plain.len());
+    assert_eq!(columnar[1], WIRE_VERSION | WIRE_FLAG_COLUMNAR);
 
-        /*
-         * we process the data in time-based segments, initialize a buffer of
-         * the appropriate size to store that data until we write it
-         */
-        let mut buf: Vec<i32> = Vec::with_capacity(1 + ordered_list.len());
-        let mut cur = data[begin].time;
-
-        // loop through all the data points we have between begin and end
-        for d in &data[begin..end] {
-            /*
-             * if we encounter a different time, process one complete time
-             * segment and write it
-             */
-            if cur != d.time {
-                // first element is time
-                buf.push(cur);
-
-                /*
-                 * followed by data values in-order in which they appear in the
-                 * target's current addrs (here tracked by the ordered_list
-                 * of indices obtained from manager)
-                 */
-                for &i in ordered_list.iter() {
-                    buf.push(membership[i as usize]);
-                    membership[i as usize] = SENTINEL_NODATA;
-                }
+    // columnar data decodes the same, whether or not it's gzipped too
+    let (_, plain_data) = from_wire(&plain, &ordered_list).unwrap();
+    #[cfg(feature = "wire-compression")]
+    let encodings = vec![columnar.clone(),
+                         header.to_wire_compressed(&to_columnar(&wire, ordered_list.len()), 6).unwrap()];
+    #[cfg(not(feature = "wire-compression"))]
+    let encodings = vec![columnar.clone()];
+    for bytes in encodings {
+        let (decoded_header, data) = from_wire(&bytes, &ordered_list).unwrap();
+        assert!(decoded_header.columnar);
+        assert_eq!(data.len(), plain_data.len());
+        for (c, p) in data.iter().zip(plain_data.iter()) {
+            assert_eq!((c.time, c.millis, c.index, c.val), (p.time, p.millis, p.index, p.val));
+        }
+    }
 
-                // write out the data and reset our buffer and time tracker
-                try!(writer.write_all(&buf.into_raw_bytes()));
-                buf = Vec::with_capacity(1 + ordered_list.len());
-                cur = d.time;
-            }
+    // truncated or padded columns are refused
+    for bytes in vec![columnar[..columnar.len() - 1].to_vec(), { let mut c = columnar.clone(); c.push(0); c }] {
+        match from_wire(&bytes, &ordered_list) {
+            Err(WireError::MalformedColumns) => (),
+            _ => panic!("Expected a malformed columns error"),
+        }
+    }
+}
 
-            /*
-             * if this data point is relevant to us, meaning the addr
-             * represented by its index is in the target's current addrs (here
-             * tracked by membership), then we store it (cheatingly in
-             * membership indexed by its index -- this way we don't need to
-             * allocate another buffer to store it)
-             */
-            if membership[d.index as usize] != 0 {
-                membership[d.index as usize] = d.val;
+#[test]
+fn from_wire_decodes_bitmapped_data() {
+    use std::i64;
+    use options::SENTINEL_ERROR;
+
+    // 40 addrs, so the bitmaps take up two integers each
+    let ordered_list: Vec<AddrId> = (0..40).map(AddrId::from).collect();
+    let mut ints = Vec::new();
+    for &(time, down) in [(10, false), (20, true)].iter() {
+        for index in ordered_list.iter().map(|i| i.word()) {
+            // (in the first segment, every fifth addr is down and the last has no data)
+            let val = if down || index % 5 == 0 { SENTINEL_ERROR } else { 1000 + index };
+            if index != 39 || down {
+                ints.extend_from_slice(&[time, 0, index, val, 0]);
             }
         }
+    }
+    let wire = encode_range(&Records::with_millis(&ints, 1), 0, i64::MAX, &ordered_list);
+
+    let header = WireHeader {
+        bitmapped: true,
+        ..WireHeader::new(Feed::Raw, 0, ordered_list.len() as i32)
+    };
+    let bitmapped = to_bitmapped(&wire, ordered_list.len());
+    let segments: Vec<i32> = bitmapped.from_wire_bytes();
+    // the first segment keeps only the values that are there...
+    assert_eq!(&segments[3..7], &[0x4210_8421, 1 << 3, 0, 1 << 7]);
+    assert_eq!(segments[7], 1001);
+    // ...and the all-down one is just its time, millis, and bitmaps
+    let down = &segments[7 + 31..];
+    assert_eq!(down, &[20, 0, 0, -1, 0xff, 0, 0]);
+
+    let mut plain = WireHeader { bitmapped: false, ..header }.to_bytes();
+    plain.extend_from_slice(&wire);
+    let mut bytes = header.to_bytes();
+    bytes.extend_from_slice(&bitmapped);
+    assert_eq!(bytes[1], WIRE_VERSION | WIRE_FLAG_BITMAPPED);
+
+    // absent values decode as they would have without bitmaps
+    let (_, plain_data) = from_wire(&plain, &ordered_list).unwrap();
+    let (decoded_header, data) = from_wire(&bytes, &ordered_list).unwrap();
+    assert!(decoded_header.bitmapped);
+    assert_eq!(data.len(), 39 + 40);
+    assert_eq!(data.len(), plain_data.len());
+    for (b, p) in data.iter().zip(plain_data.iter()) {
+        assert_eq!((b.time, b.millis, b.index, b.val), (p.time, p.millis, p.index, p.val));
+    }
+
+    // truncated bitmaps are refused
+    match from_wire(&bytes[..bytes.len() - 4], &ordered_list) {
+        Err(WireError::MalformedBitmaps) => (),
+        _ => panic!("Expected a malformed bitmaps error"),
+    }
+}
 
-        // process the final time segment, and flush our writer
-        buf.push(cur);
-        for &i in ordered_list.iter() {
-            buf.push(membership[i as usize]);
-            membership[i as usize] = SENTINEL_NODATA;
+#[test]
+fn from_wire_decodes_big_endian_data() {
+    use std::i64;
+
+    let ordered_list = vec![AddrId::from(0), AddrId::from(1)];
+    let ints = vec![10, 0, 0, 0x01020304, 0, 10, 0, 1, -2, 0];
+    let wire = encode_range(&Records::with_millis(&ints, 1), 0, i64::MAX, &ordered_list);
+
+    let header = WireHeader {
+        big_endian: true,
+        ..WireHeader::new(Feed::Raw, 0, ordered_list.len() as i32)
+    };
+    let mut bytes = header.to_bytes();
+    assert_eq!(bytes[1], WIRE_VERSION | WIRE_FLAG_BIG_ENDIAN);
+    assert_eq!(&bytes[4..], &[0, 0, 0, 2]);
+    bytes.extend(swap_byte_order(&wire));
+    // time (low word first), millis, then the values, each most significant byte first
+    assert_eq!(&bytes[WIRE_HEADER_LEN..], &[0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0,
+                                            0x01, 0x02, 0x03, 0x04, 0xff, 0xff, 0xff, 0xfe][..]);
+
+    let (decoded_header, data) = from_wire(&bytes, &ordered_list).unwrap();
+    assert_eq!(decoded_header, header);
+    assert_eq!(data.iter().map(|d| (d.time, d.index, d.val)).collect::<Vec<_>>(),
+               vec![(10, AddrId::from(0), 0x01020304), (10, AddrId::from(1), -2)]);
+}
+
+#[cfg(feature = "wire-compression")]
+#[test]
+fn from_wire_decodes_gzipped_bitmapped_big_endian_data() {
+    use std::i64;
+
+    let ordered_list = vec![AddrId::from(0), AddrId::from(1)];
+    let ints = vec![10, 0, 0, 0x01020304, 0, 10, 0, 1, -2, 0];
+    let wire = encode_range(&Records::with_millis(&ints, 1), 0, i64::MAX, &ordered_list);
+
+    let header = WireHeader {
+        compressed: true,
+        bitmapped: true,
+        big_endian: true,
+        ..WireHeader::new(Feed::Raw, 0, ordered_list.len() as i32)
+    };
+    let compressed = header.to_wire_compressed(&swap_byte_order(&to_bitmapped(&wire, 2)), 6).unwrap();
+    assert_eq!(from_wire(&compressed, &ordered_list).unwrap().1.len(), 2);
+}
+
+/**
+ * Measures how well a realistic day of raw data (two addrs measured every ten
+ * seconds, one of them down for two hours) compresses on the wire, in the
+ * columnar layout and at each gzip level. Run with:
+ *
+ *     cargo test --release wire_compression_bench -- --ignored --nocapture
+ */
+#[cfg(feature = "wire-compression")]
+#[test]
+#[ignore]
+fn wire_compression_bench() {
+    use std::i64;
+    use time::precise_time_ns;
+    use options::SENTINEL_ERROR;
+
+    let ordered_list = vec![AddrId::from(0), AddrId::from(1)];
+    let mut ints = Vec::new();
+    let (mut noise, mut millis) = (12345u32, 0);
+    for round in 0..(24 * 60 * 6) {
+        let t = 1_500_000_000 + round as i64 * 10;
+        // rounds start a few milliseconds later each time, then catch up
+        millis = (millis + 3) % 1000;
+        for index in 0..2 {
+            noise = noise.wrapping_mul(1103515245).wrapping_add(12345);
+            let jitter = (noise >> 16) as i32 % 3000;
+            let val = match index {
+                1 if round >= 6 * 360 && round < 8 * 360 => SENTINEL_ERROR,
+                _ => 20_000 + index * 15_000 + jitter,
+            };
+            let tw = time_words(t);
+            ints.extend_from_slice(&[tw[0], tw[1], index, val, millis]);
         }
-        try!(writer.write_all(&buf.into_raw_bytes()));
-        try!(writer.flush());
+    }
+    let wire = encode_range(&Records::with_millis(&ints, 1), 0, i64::MAX, &ordered_list);
 
-        Ok(())
+    let original = (wire.len() + WIRE_HEADER_LEN) as f64;
+    println!("\na day of raw data for two addrs: {} bytes on the wire", original);
+
+    let columns = to_columnar(&wire, 2);
+    println!("columnar: {} bytes ({:.1}% of the original)", columns.len() + WIRE_HEADER_LEN,
+             100.0 * (columns.len() + WIRE_HEADER_LEN) as f64 / original);
+
+    for &(columnar, body) in [(false, &wire), (true, &columns)].iter() {
+        let header = WireHeader { columnar: columnar, ..WireHeader::new(Feed::Raw, 0, 2) };
+        for level in vec![1, 6, 9] {
+            let begin = precise_time_ns();
+            let compressed = header.to_wire_compressed(body, level).unwrap();
+            println!("{}gzip level {}: {} bytes ({:.1}% of the original) in {}us",
+                     if columnar { "columnar, " } else { "" }, level, compressed.len(),
+                     100.0 * compressed.len() as f64 / original, (precise_time_ns() - begin) / 1000);
+        }
     }
 }
 
+#[test]
+fn records_without_millis_are_on_the_second() {
+    // as read back from a raw data file written before sub-second times
+    let padded = vec![10, 0, 0, 1, SENTINEL_NODATA, 11, 0, 0, 2, 250];
+    let records = Records::with_millis(&padded, 1);
+    assert_eq!(records.len(), 2);
+    assert_eq!((records.millis(0), records.vals(0)), (0, &[1][..]));
+    assert_eq!((records.millis(1), records.vals(1)), (250, &[2][..]));
 
+    let plain = vec![10, 0, 0, 1, 2];
+    assert_eq!(Records::new(&plain, 2).millis(0), 0);
+}
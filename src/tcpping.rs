@@ -6,121 +6,375 @@
  * details.
  */
 
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
 use std::thread;
-use std::sync::mpsc::{channel, Sender};
-use std::sync::Arc;
 
 use std::time::Duration;
 use time::precise_time_ns;
-use chrono::Local;
 
-use std::net::TcpStream;
+use std::net::{TcpStream, SocketAddr, Ipv6Addr, ToSocketAddrs};
 
-use options::SENTINEL_ERROR;
-use options::TargetResults;
+use helpers::{is_valid_host, recover};
+use options::{AddrFamily, FailureReason, DEFAULT_RESOLVE_EVERY, DEFAULT_RESOLVE_TTL};
 use persist::TargetManager;
+use worker::{run_worker, Worker, ResultsSender};
 
 /**
- * Runs the TCP Ping target's data-collection worker.
+ * Splits an address of the form `host:port[/v4|/v6]` into the `host:port` to
+ * connect to and the address family it must be connected over (if any).
+ *
+ * Bracketed IPv6 literals (e.g. `[::1]:80`) are always connected to over
+ * IPv6, so a suffix is only needed to pin hostnames to a family (e.g. to
+ * measure the same hostname over both as two separate addresses).
  */
-pub fn run_tcpping_worker(manager: Arc<TargetManager>,
-                          results_out: Sender<TargetResults>) -> thread::JoinHandle<()> {
-    // start a new thread for the worker
+fn parse_addr(addr: &str) -> (&str, Option<AddrFamily>) {
+    let (host_port, family) = if addr.ends_with("/v4") {
+        (&addr[..addr.len() - 3], Some(AddrFamily::V4))
+    } else if addr.ends_with("/v6") {
+        (&addr[..addr.len() - 3], Some(AddrFamily::V6))
+    } else {
+        (addr, None)
+    };
+
+    if family.is_none() && host_port.starts_with('[') {
+        (host_port, Some(AddrFamily::V6))
+    } else {
+        (host_port, family)
+    }
+}
+
+/**
+ * Checks (syntactically, without looking anything up) that the given address
+ * is a valid `host:port` address (see `parse_addr()`).
+ */
+pub fn is_valid_addr(addr: &str) -> bool {
+    let (host_port, _) = parse_addr(addr);
+    let i = match host_port.rfind(':') {
+        Some(i) => i,
+        None => return false,
+    };
+    let (host, port) = (&host_port[..i], &host_port[i + 1..]);
+
+    let host_ok = if host.starts_with('[') && host.ends_with(']') {
+        host[1..host.len() - 1].parse::<Ipv6Addr>().is_ok()
+    } else {
+        !host.contains(':') && is_valid_host(host)
+    };
+    host_ok && port.parse::<u16>().map(|p| p != 0).unwrap_or(false)
+}
+
+pub fn family_of(sock_addr: &SocketAddr) -> AddrFamily {
+    if sock_addr.is_ipv6() { AddrFamily::V6 } else { AddrFamily::V4 }
+}
+
+/**
+ * Looks up the socket addresses of the given `host:port`, giving up (with
+ * `None`) after the given timeout.
+ *
+ * The system's resolver can't be given a timeout of its own, so the lookup
+ * is made on a thread of its own, which a lookup that is given up on is left
+ * to finish (and be forgotten) in the background.
+ */
+fn lookup(host_port: &str, timeout: Duration) -> Option<Vec<SocketAddr>> {
+    let (tx, rx) = channel();
+    let host_port = host_port.to_owned();
     thread::spawn(move || {
-        let mut handles = Vec::new();
-
-        // continue to collect data forever
-        loop {
-            // retrieve the target's current options
-            let (dur_interval, avg_across, dur_pause, num_addrs) = {
-                let ref opt = manager.options_read();
-                (
-                    Duration::from_millis(opt.interval as u64),
-                    opt.avg_across,
-                    Duration::from_millis(opt.pause as u64),
-                    opt.addrs.len(),
-                )
-            };
-
-            // get the current time (to timestamp this round of data with)
-            let timestamp: i32 = Local::now().timestamp() as i32;
-
-            let nonce = {
-                let ref t_opt = manager.options_read();
-                for addr in t_opt.addrs.iter() {
-                    let a = addr.clone();
-
-                    /*
-                     * create channels so the per-addr threads can send back
-                     * their data to the worker thread
-                     */
-                    let (tx, rx) = channel();
-                    handles.push(rx);
-
-                    /*
-                     * spawn a thread to actually collect the data for each
-                     * separate address
-                     */
-                    thread::spawn(move || {
-                        let mut sum = 0;
-                        let mut denom = 0;
-                        // average the results across the given number of times
-                        for _ in 0..avg_across {
-                            /*
-                             * time the duration of a TCP handshake to the
-                             * address
-                             */
-                            let start = precise_time_ns();
-                            if TcpStream::connect(a.as_str()).is_ok() {
-                                sum += precise_time_ns() - start;
-                                denom += 1;
-                            }
-                            thread::sleep(dur_pause);
-                        }
-
-                        if denom != 0 {
-                            /*
-                             * send back micro-second average.
-                             *
-                             * we don't care if send fails as that likely means
-                             * we took too long and the control thread is no longer
-                             * waiting for us
-                             */
-                            let _ = tx.send((sum / denom / 1000) as i32);
-                        }
-                    });
+        let _ = tx.send(host_port.to_socket_addrs().map(|i| i.collect()).ok());
+    });
+
+    match rx.recv_timeout(timeout) {
+        Ok(sock_addrs) => sock_addrs,
+        Err(_) => None,
+    }
+}
+
+/**
+ * Resolves the given address (see `parse_addr()`) to the socket address to
+ * connect to, only considering IPv6 or IPv4 ones if the address is pinned to
+ * that family, and otherwise trying IPv6 ones first if `prefer_ipv6` is set.
+ * Gives up after the given timeout (see `lookup()`).
+ */
+pub fn resolve(addr: &str, prefer_ipv6: bool, timeout: Duration) -> Option<SocketAddr> {
+    let (host_port, family) = parse_addr(addr);
+    let sock_addrs = match lookup(host_port, timeout) {
+        Some(s) => s,
+        None => return None,
+    };
+
+    match family {
+        Some(f) => sock_addrs.into_iter().find(|sa| family_of(sa) == f),
+        None if prefer_ipv6 => sock_addrs.iter().find(|sa| sa.is_ipv6()).cloned()
+                                          .or_else(|| sock_addrs.first().cloned()),
+        None => sock_addrs.first().cloned(),
+    }
+}
+
+/**
+ * A socket address an address was resolved to, and until when it's used.
+ */
+struct Resolved {
+    sock_addr: SocketAddr,
+    uses_left: u32,
+    expires: u64,  // in nanoseconds, as from `precise_time_ns()`
+}
+
+/**
+ * The socket addresses that addresses (see `parse_addr()`) were last resolved
+ * to, so that only the first measurement after a lookup pays for it (and
+ * measurements stay comparable with each other). Each is reused for a given
+ * number of measurements, and for no longer than a given time (like a DNS
+ * TTL), before being looked up again, to pick up DNS changes (e.g. failovers
+ * or CDNs moving things around).
+ */
+struct ResolveCache<R> {
+    resolve: R,
+    cached: Mutex<HashMap<(String, bool), Resolved>>,
+}
+
+impl<R: Fn(&str, bool, Duration) -> Option<SocketAddr>> ResolveCache<R> {
+    fn new(resolve: R) -> Self {
+        ResolveCache {
+            resolve: resolve,
+            cached: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /**
+     * Gives the socket address to measure the given address with, resolving
+     * it (giving up after `timeout`) if it hasn't been yet, or if it was
+     * resolved `every` uses or `ttl` ago.
+     */
+    fn get(&self, addr: &str, prefer_ipv6: bool, every: u32, ttl: Duration,
+           timeout: Duration) -> Option<SocketAddr> {
+        self.get_at(addr, prefer_ipv6, every, ttl, timeout, precise_time_ns())
+    }
+
+    fn get_at(&self, addr: &str, prefer_ipv6: bool, every: u32, ttl: Duration, timeout: Duration,
+              now: u64) -> Option<SocketAddr> {
+        let key = (addr.to_owned(), prefer_ipv6);
+        let previous = match recover(self.cached.lock()).get_mut(&key) {
+            Some(ref mut r) if r.uses_left > 0 && now < r.expires => {
+                r.uses_left -= 1;
+                return Some(r.sock_addr);
+            },
+            Some(r) => Some(r.sock_addr),
+            None => None,
+        };
+
+        // looked up without the lock held, as lookups can be slow
+        let start = precise_time_ns();
+        let resolved = (self.resolve)(addr, prefer_ipv6, timeout);
+        debug!("resolved addr={} found={} micros={}",
+               addr, resolved.is_some(), (precise_time_ns() - start) / 1000);
+
+        let mut cached = recover(self.cached.lock());
+        match (resolved, previous) {
+            (Some(sa), _) => {
+                // so that a jump in latency can be put down to it
+                if previous.map_or(false, |p| p != sa) {
+                    info!("resolved address changed addr={} from={} to={}", addr, previous.unwrap(), sa);
                 }
-                t_opt.nonce
-            };
-
-            /*
-             * wait out the designated data-collectiong interval, while giving
-             * the give the per-addr subthreads the entire interval of time to
-             * come back
-             */
-            thread::sleep(dur_interval);
-
-            let mut data: Vec<i32> = Vec::with_capacity(3 + num_addrs);
-
-            data.push(manager.kind.kind_id());
-            data.push(nonce);
-            data.push(timestamp);
-
-            // read back the data from the per-addr subthreads
-            for h in handles.drain(..) {
-                if let Ok(val) = h.try_recv() {
-                    data.push(val);
-                } else {
-                    // on error or timeout, hand back a sentinel value
-                    data.push(SENTINEL_ERROR);
+                cached.insert(key, Resolved {
+                    sock_addr: sa,
+                    uses_left: every - 1,
+                    expires: now + ttl.as_secs() * 1_000_000_000 + ttl.subsec_nanos() as u64,
+                });
+            },
+            // kept (though not used) to tell whether it changes once resolved again
+            (None, Some(_)) => {
+                if let Some(r) = cached.get_mut(&key) {
+                    r.uses_left = 0;
                 }
-            }
+            },
+            (None, None) => (),
+        }
+        resolved
+    }
+
+    /**
+     * Has the given address resolved again the next time it's measured, e.g.
+     * because the socket address it was resolved to stopped answering.
+     */
+    fn forget(&self, addr: &str, prefer_ipv6: bool) {
+        if let Some(r) = recover(self.cached.lock()).get_mut(&(addr.to_owned(), prefer_ipv6)) {
+            r.uses_left = 0;
+        }
+    }
+}
+
+/**
+ * Measures the duration (in microseconds) of a TCP handshake to the given
+ * socket address, giving up after the given timeout. Also gives the address
+ * family the handshake was made over, or why it failed.
+ */
+fn tcpping(sock_addr: SocketAddr, timeout: Duration) -> Result<(i32, AddrFamily), FailureReason> {
+    let start = precise_time_ns();
+    match TcpStream::connect_timeout(&sock_addr, timeout) {
+        Ok(_) => Ok((((precise_time_ns() - start) / 1000) as i32, family_of(&sock_addr))),
+        Err(e) => Err(FailureReason::of_error(&e)),
+    }
+}
+
+/**
+ * Runs the TCP Ping target's data-collection worker.
+ */
+pub fn run_tcpping_worker(manager: Arc<TargetManager>,
+                          results_out: ResultsSender) -> Worker {
+    let m = manager.clone();
+    let cache = ResolveCache::new(resolve);
+    run_worker(manager, results_out, move |addr: &str, timeout: Duration| {
+        let (prefer_ipv6, every, ttl) = {
+            let opt = m.options_read();
+            (opt.prefer_ipv6.unwrap_or(false),
+             opt.resolve_every.unwrap_or(DEFAULT_RESOLVE_EVERY),
+             Duration::from_secs(opt.resolve_ttl.unwrap_or(DEFAULT_RESOLVE_TTL) as u64))
+        };
+        // (the lookup counts against the timeout, so the attempt never outlasts it)
+        let start = precise_time_ns();
+        let sock_addr = match cache.get(addr, prefer_ipv6, every, ttl, timeout) {
+            Some(sa) => sa,
+            None => {
+                m.record_failure(addr, FailureReason::Unresolved);
+                return None;
+            },
+        };
+        let elapsed = precise_time_ns() - start;
+        let elapsed = Duration::new(elapsed / 1_000_000_000, (elapsed % 1_000_000_000) as u32);
+        if elapsed >= timeout {
+            m.record_failure(addr, FailureReason::TimedOut);
+            return None;
+        }
 
-            // send off our results to the main thread
-            if results_out.send(TargetResults(data)).is_err() {
-                println!("Worker Control: failed to send final results back.");
-            }
+        match tcpping(sock_addr, timeout - elapsed) {
+            Ok((val, family)) => {
+                m.record_addr_family(addr, family);
+                Some(val)
+            },
+            Err(reason) => {
+                m.record_failure(addr, reason);
+                // the host may well have moved, so look it up again next time
+                cache.forget(addr, prefer_ipv6);
+                None
+            },
         }
     })
 }
 
+#[test]
+fn parse_addr_splits_family() {
+    assert_eq!(parse_addr("google.com:80"), ("google.com:80", None));
+    assert_eq!(parse_addr("google.com:80/v4"), ("google.com:80", Some(AddrFamily::V4)));
+    assert_eq!(parse_addr("google.com:80/v6"), ("google.com:80", Some(AddrFamily::V6)));
+    assert_eq!(parse_addr("[::1]:80"), ("[::1]:80", Some(AddrFamily::V6)));
+}
+
+#[test]
+fn addrs_are_validated() {
+    assert!(is_valid_addr("google.com:80"));
+    assert!(is_valid_addr("google.com:80/v6"));
+    assert!(is_valid_addr("8.8.8.8:53"));
+    assert!(is_valid_addr("[::1]:22"));
+    assert!(!is_valid_addr("google.com"));
+    assert!(!is_valid_addr("google.com:http"));
+    assert!(!is_valid_addr("google.com:0"));
+    assert!(!is_valid_addr("::1:22"));
+    assert!(!is_valid_addr("[nonsense]:22"));
+    assert!(!is_valid_addr("a b:80"));
+}
+
+#[test]
+fn ipv6_literals_connect_over_ipv6() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("[::1]:0").unwrap();
+    let addr = format!("[::1]:{}", listener.local_addr().unwrap().port());
+    let timeout = Duration::from_millis(1000);
+
+    let (_, family) = tcpping(resolve(&addr, false, timeout).unwrap(), timeout).unwrap();
+    assert_eq!(family, AddrFamily::V6);
+
+    // a v6 literal can't be pinned to v4
+    assert!(resolve(&format!("{}/v4", addr), false, timeout).is_none());
+}
+
+#[test]
+fn addrs_can_be_pinned_to_a_family() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = format!("127.0.0.1:{}", listener.local_addr().unwrap().port());
+    let timeout = Duration::from_millis(1000);
+
+    // preferring v6 falls back to v4 if that's all there is
+    assert_eq!(tcpping(resolve(&addr, true, timeout).unwrap(), timeout).unwrap().1, AddrFamily::V4);
+    assert_eq!(tcpping(resolve(&format!("{}/v4", addr), false, timeout).unwrap(), timeout).unwrap().1, AddrFamily::V4);
+    assert!(resolve(&format!("{}/v6", addr), false, timeout).is_none());
+}
+
+#[test]
+fn refused_connections_are_told_apart() {
+    use std::net::TcpListener;
+
+    // nothing listens on a port just given up
+    let sock_addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    assert_eq!(tcpping(sock_addr, Duration::from_millis(1000)), Err(FailureReason::Refused));
+}
+
+#[test]
+fn hostnames_are_resolved_once_every_few_measurements() {
+    use std::cell::Cell;
+
+    let lookups = Cell::new(0);
+    let cache = ResolveCache::new(|addr: &str, prefer_ipv6: bool, timeout: Duration| {
+        lookups.set(lookups.get() + 1);
+        resolve(addr, prefer_ipv6, timeout)
+    });
+    let (ttl, timeout) = (Duration::from_secs(3600), Duration::from_secs(5));
+
+    for _ in 0..10 {
+        assert!(cache.get("localhost:80", false, 5, ttl, timeout).is_some());
+    }
+    assert_eq!(lookups.get(), 2);
+
+    // preferring another family is a different lookup
+    assert!(cache.get("localhost:80", true, 5, ttl, timeout).is_some());
+    assert_eq!(lookups.get(), 3);
+
+    // a failed measurement has it looked up again straight away
+    cache.forget("localhost:80", false);
+    assert!(cache.get("localhost:80", false, 5, ttl, timeout).is_some());
+    assert_eq!(lookups.get(), 4);
+
+    // as does a failed lookup
+    assert!(cache.get("127.0.0.1:80/v6", false, 5, ttl, timeout).is_none());
+    assert!(cache.get("127.0.0.1:80/v6", false, 5, ttl, timeout).is_none());
+    assert_eq!(lookups.get(), 6);
+}
+
+#[test]
+fn changed_resolutions_are_picked_up() {
+    use std::cell::Cell;
+
+    let first: SocketAddr = "192.0.2.1:80".parse().unwrap();
+    let second: SocketAddr = "192.0.2.2:80".parse().unwrap();
+    let current = Cell::new(Some(first));
+    let cache = ResolveCache::new(|_: &str, _: bool, _: Duration| current.get());
+    let ttl = Duration::from_secs(60);
+    let get = |now: u64| cache.get_at("example.com:80", false, 100, ttl, Duration::from_secs(1), now * 1_000_000_000);
+
+    assert_eq!(get(0), Some(first));
+    current.set(Some(second));
+    assert_eq!(get(59), Some(first));
+    // looked up again once the TTL is up, even with uses left
+    assert_eq!(get(60), Some(second));
+
+    // a failed lookup isn't cached, so the next measurement tries again
+    current.set(None);
+    cache.forget("example.com:80", false);
+    assert_eq!(get(61), None);
+    current.set(Some(first));
+    assert_eq!(get(62), Some(first));
+    assert_eq!(get(63), Some(first));
+}
@@ -0,0 +1,160 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * The UDP Ping target, measuring how long it takes a service that only speaks
+ * UDP (e.g. a DNS, game or STUN server) to respond to a probe datagram.
+ */
+use std::sync::Arc;
+
+use std::time::Duration;
+use time::precise_time_ns;
+
+use std::net::{SocketAddr, UdpSocket};
+
+use options::AddrFamily;
+use persist::TargetManager;
+use tcpping;
+use worker::{run_worker, Worker, ResultsSender};
+
+/**
+ * Splits an address of the form `host:port[/v4|/v6][#payload]` into the
+ * `host:port[/v4|/v6]` to send to (see `tcpping::parse_addr()`) and the
+ * probe datagram to send, given in hex (empty if not given).
+ */
+fn parse_addr(addr: &str) -> Option<(&str, Vec<u8>)> {
+    let mut parts = addr.splitn(2, '#');
+    let host_port = parts.next().unwrap_or("");
+    let hex = parts.next().unwrap_or("");
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    let mut payload = Vec::with_capacity(hex.len() / 2);
+    for pair in hex.as_bytes().chunks(2) {
+        match ((pair[0] as char).to_digit(16), (pair[1] as char).to_digit(16)) {
+            (Some(high), Some(low)) => payload.push((high << 4 | low) as u8),
+            _ => return None,
+        }
+    }
+    Some((host_port, payload))
+}
+
+/**
+ * Checks (syntactically, without looking anything up) that the given address
+ * is a valid `host:port[/v4|/v6][#payload]` address (see `parse_addr()`).
+ */
+pub fn is_valid_addr(addr: &str) -> bool {
+    parse_addr(addr).map(|(host_port, _)| tcpping::is_valid_addr(host_port)).unwrap_or(false)
+}
+
+/**
+ * Measures the time (in microseconds) from sending the probe datagram of the
+ * given address (see `parse_addr()`) to receiving a response, giving up after
+ * the given timeout. Also gives the address family it was sent over.
+ *
+ * As UDP is connectionless, a service that doesn't respond (or a probe that
+ * is lost) is only noticed by the timeout running out.
+ */
+fn udpping(addr: &str, timeout: Duration, prefer_ipv6: bool) -> Option<(i32, AddrFamily)> {
+    let (host_port, payload) = match parse_addr(addr) {
+        Some(p) => p,
+        None => return None,
+    };
+    let sock_addr = match tcpping::resolve(host_port, prefer_ipv6, timeout) {
+        Some(sa) => sa,
+        None => return None,
+    };
+
+    let bind_addr = match sock_addr {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    /*
+     * a fresh (connected) socket for each probe only ever receives responses
+     * from the address probed, and never late responses to earlier probes
+     */
+    let socket = match UdpSocket::bind(bind_addr) {
+        Ok(s) => s,
+        Err(_) => return None,
+    };
+    if socket.connect(sock_addr).is_err() || socket.set_read_timeout(Some(timeout)).is_err() {
+        return None;
+    }
+
+    let start = precise_time_ns();
+    if socket.send(&payload).is_err() {
+        return None;
+    }
+
+    let mut buf = [0u8; 1500];
+    match socket.recv(&mut buf) {
+        Ok(_) => Some((((precise_time_ns() - start) / 1000) as i32, tcpping::family_of(&sock_addr))),
+        Err(_) => None,
+    }
+}
+
+/**
+ * Runs the UDP Ping target's data-collection worker.
+ */
+pub fn run_udpping_worker(manager: Arc<TargetManager>,
+                          results_out: ResultsSender) -> Worker {
+    let m = manager.clone();
+    run_worker(manager, results_out, move |addr: &str, timeout: Duration| {
+        let prefer_ipv6 = m.options_read().prefer_ipv6.unwrap_or(false);
+        udpping(addr, timeout, prefer_ipv6).map(|(val, family)| {
+            m.record_addr_family(addr, family);
+            val
+        })
+    })
+}
+
+#[test]
+fn parse_addr_splits_payload() {
+    assert_eq!(parse_addr("8.8.8.8:53"), Some(("8.8.8.8:53", vec![])));
+    assert_eq!(parse_addr("8.8.8.8:53#00ff1A"), Some(("8.8.8.8:53", vec![0x00, 0xff, 0x1a])));
+    assert_eq!(parse_addr("example.com:9/v6#01"), Some(("example.com:9/v6", vec![0x01])));
+    assert_eq!(parse_addr("8.8.8.8:53#0"), None);
+    assert_eq!(parse_addr("8.8.8.8:53#zz"), None);
+    assert_eq!(parse_addr("8.8.8.8:53#+1"), None);
+}
+
+#[test]
+fn addrs_are_validated() {
+    assert!(is_valid_addr("8.8.8.8:53"));
+    assert!(is_valid_addr("stun.example.com:3478#0001"));
+    assert!(is_valid_addr("[::1]:53/v6"));
+    assert!(!is_valid_addr("8.8.8.8"));
+    assert!(!is_valid_addr("8.8.8.8:53#nothex"));
+    assert!(!is_valid_addr("#00"));
+}
+
+#[test]
+fn responses_are_timed_and_silence_times_out() {
+    use std::thread;
+
+    let echo = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buf = [0u8; 16];
+        // echo the probe back once
+        if let Ok((n, from)) = echo.recv_from(&mut buf) {
+            assert_eq!(&buf[..n], &[0xca, 0xfe]);
+            echo.send_to(&buf[..n], from).unwrap();
+        }
+    });
+    let timeout = Duration::from_millis(1000);
+    let (val, family) = udpping(&format!("{}#cafe", echo_addr), timeout, false).unwrap();
+    assert!(val >= 0);
+    assert_eq!(family, AddrFamily::V4);
+
+    // a socket that never responds
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = format!("{}", silent.local_addr().unwrap());
+    assert!(udpping(&addr, Duration::from_millis(100), false).is_none());
+}
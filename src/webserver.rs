@@ -7,8 +7,10 @@
  */
 
 use std::thread;
+use std::i64;
 use std::error::Error;
 use std::fmt;
+use std::io;
 use std::io::Read;
 use std::sync::Arc;
 use std::sync::RwLock;
@@ -19,14 +21,17 @@ use iron::method::Method;
 use iron::headers::ContentType;
 use iron::modifiers::Header;
 use iron::request::Body;
+use iron::response::WriteBody;
 use iron::status;
 use router::Router;
 use mount::Mount;
 
 use rustc_serialize::{json, Decodable};
+use rustc_serialize::json::{Json, ToJson};
 
+use helpers::{recover, split_millis};
 use reader::{SPDataReader, DataRequest};
-use persist::{TargetManager, ManagerError};
+use persist::{TargetManager, ManagerError, Feed, AddrId};
 use options::{MainConfiguration, TargetOptions};
 
 /**
@@ -119,7 +124,7 @@ impl<'a, 'b> JsonBody for Body<'a, 'b> {
         try!(
             self.read_to_string(&mut buf)
             .map_err(|_| {
-                println!("Failed to read request body.");
+                warn!("failed to read request body");
                 IronError::new(SPWebError::ServerError, status::InternalServerError)
             })
         );
@@ -127,13 +132,65 @@ impl<'a, 'b> JsonBody for Body<'a, 'b> {
         Ok(try!(
             json::decode::<T>(&buf)
             .map_err(|_| {
-                println!("Failed to parse request body.");
+                warn!("failed to parse request body");
                 IronError::new(SPWebError::BadRequest, status::BadRequest)
             })
         ))
     }
 }
 
+/**
+ * Gets the feed named (see `Feed::compact_name()`) by the `:feed` parameter of
+ * the route of the given request, or a 404 if there's no such feed.
+ */
+fn feed_param(req: &Request) -> Result<Feed, IronError> {
+    req.extensions.get::<Router>()
+        .and_then(|params| params.find("feed"))
+        .and_then(Feed::from_compact_name)
+        .ok_or_else(|| IronError::new(SPWebError::NotFound, status::NotFound))
+}
+
+/**
+ * Gets the value of the given parameter in the query string of the given
+ * request, if it was given.
+ */
+fn query_param(req: &Request, name: &str) -> Option<String> {
+    req.url.query().and_then(|query| {
+        query.split('&')
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(k), Some(v)) if k == name => Some(v.to_owned()),
+                    _ => None,
+                }
+            })
+            .next()
+    })
+}
+
+/**
+ * Gets the given integer parameter in the query string of the given request,
+ * or the given default if it wasn't given.
+ */
+fn int_query_param(req: &Request, name: &str, default: i64) -> Result<i64, IronError> {
+    match query_param(req, name) {
+        Some(v) => v.parse::<i64>().map_err(|_| {
+            warn!("invalid query parameter name={} value={}", name, v);
+            IronError::new(SPWebError::BadRequest, status::BadRequest)
+        }),
+        None => Ok(default),
+    }
+}
+
+/**
+ * Logs the given error the manager gave back and turns it into an internal
+ * server error response.
+ */
+fn server_error(manager: &TargetManager, what: &str, e: ManagerError) -> IronError {
+    error!("failed to {} kind={} error=\"{}\"", what, manager.kind.compact_name(), e);
+    IronError::new(SPWebError::ServerError, status::InternalServerError)
+}
+
 /**
  * Handler for each /api/target endpoint that handles returning and updating
  * target options, and retrieving persisted target data.
@@ -154,7 +211,7 @@ impl Handler for TargetHandler {
     fn handle(&self, req: &mut Request) -> IronResult<Response> {
         match req.method {
             Method::Get => { /* Get Options */
-                println!("Request for {} options.", self.manager.kind.compact_name());
+                info!("options requested kind={}", self.manager.kind.compact_name());
                 let options_ser = {
                     let options_guard = self.manager.options_read();
                     json::encode(&*options_guard).unwrap()
@@ -164,13 +221,13 @@ impl Handler for TargetHandler {
             Method::Post => { /* Retrieve Data */
                 // try and get the parameters of the request
                 let dr: DataRequest = try!(req.body.read_json());
-                println!("Request for {} data: {:?}", self.manager.kind.compact_name(), dr);
+                info!("data requested kind={} request={:?}", self.manager.kind.compact_name(), dr);
 
                 let body_writer = try!(
                     // try and create a data reader out of this request
                     SPDataReader::new(self.manager.clone(), dr)
                     .ok_or_else(|| {
-                        println!("Failed to create SPDataReader.");
+                        warn!("failed to create data reader kind={}", self.manager.kind.compact_name());
                         IronError::new(SPWebError::BadRequest, status::BadRequest)
                     })
                 );
@@ -186,28 +243,19 @@ impl Handler for TargetHandler {
             },
             Method::Put => { /* Update Options */
                 // try and get the new/updated options from the request
-                let mut new_options: TargetOptions = try!(req.body.read_json());
-
-                // make sure the received nonce matches the existing nonce
-                if new_options.nonce != self.manager.options_read().nonce {
-                    return Err(IronError::new(SPWebError::NonceConflict, status::Conflict));
-                }
-
-                // increment (and wrap-around if necessary) the nonce
-                let new_nonce = {
-                    let (n, over) = new_options.nonce.overflowing_add(1);
-                    if over {
-                        0
-                    } else {
-                        n
-                    }
-                };
-                new_options.nonce = new_nonce;
+                let new_options: TargetOptions = try!(req.body.read_json());
 
                 // actually update the options via the manager
-                try!(
-                    self.manager.options_update(new_options)
-                    .map_err(|_| IronError::new(SPWebError::ServerError, status::InternalServerError))
+                let new_nonce = try!(
+                    self.manager.options_replace(new_options)
+                    .map_err(|e| match e {
+                        ManagerError::NonceConflict(_) =>
+                            IronError::new(SPWebError::NonceConflict, status::Conflict),
+                        ManagerError::InvalidOptions(_) | ManagerError::InvalidInterval(_) |
+                        ManagerError::InvalidAddrArgument(_) | ManagerError::TooManyAddrs(..) =>
+                            IronError::new(SPWebError::BadRequest, status::BadRequest),
+                        _ => IronError::new(SPWebError::ServerError, status::InternalServerError),
+                    })
                 );
                 Ok(Response::with((format!("{}", new_nonce), status::Ok)))
             },
@@ -217,6 +265,244 @@ impl Handler for TargetHandler {
 }
 
 
+/**
+ * Handler for each /api/target/.../metrics endpoint that serves the latest
+ * target data for scraping by Prometheus.
+ */
+struct MetricsHandler {
+    manager: Arc<TargetManager>,
+}
+
+impl Handler for MetricsHandler {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        let now = split_millis(self.manager.clock().now()).0;
+        let metrics = try!(
+            self.manager.prometheus_metrics(now)
+            .map_err(|e| {
+                error!("failed to get metrics kind={} error=\"{}\"", self.manager.kind.compact_name(), e);
+                IronError::new(SPWebError::ServerError, status::InternalServerError)
+            })
+        );
+
+        let h = Header(ContentType("text/plain; version=0.0.4".parse().unwrap()));
+        Ok(Response::with((status::Ok, h, metrics)))
+    }
+}
+
+/**
+ * Handler for each /api/target/.../query/<feed> endpoint that serves the
+ * target's data for the feed (from the `start` to the `end` time given in the
+ * query string, both optional) as JSON, for clients that would rather not
+ * decode the wire format (see `TargetManager::query_json()`).
+ */
+struct QueryHandler {
+    manager: Arc<TargetManager>,
+}
+
+impl Handler for QueryHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let feed = try!(feed_param(req));
+        let start = try!(int_query_param(req, "start", i64::MIN));
+        let end = try!(int_query_param(req, "end", i64::MAX));
+        info!("json data requested kind={} feed={:?} start={} end={}",
+              self.manager.kind.compact_name(), feed, start, end);
+
+        let data = try!(
+            self.manager.query_json(feed, start, end)
+            .map_err(|e| server_error(&self.manager, "query data", e))
+        );
+
+        let h = Header(ContentType::json());
+        Ok(Response::with((status::Ok, h, data.to_string())))
+    }
+}
+
+/**
+ * The formats a target's data for a feed can be exported in (see
+ * `ExportHandler`).
+ */
+#[derive(Clone, Copy, Debug)]
+enum ExportFormat {
+    Csv,
+    Ndjson,
+    Influx,
+}
+
+impl ExportFormat {
+    /**
+     * Gets the format with the given name, as given in the query string.
+     */
+    fn from_name(name: &str) -> Option<ExportFormat> {
+        match name {
+            "csv" => Some(ExportFormat::Csv),
+            "ndjson" => Some(ExportFormat::Ndjson),
+            "influx" => Some(ExportFormat::Influx),
+            _ => None,
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match *self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Influx => "text/plain",
+        }
+    }
+}
+
+/**
+ * A body writer (see `WriteBody`) that exports a target's data for a feed
+ * straight into the response, so exporting doesn't take more memory the more
+ * data there is.
+ */
+struct Export {
+    manager: Arc<TargetManager>,
+    feed: Feed,
+    format: ExportFormat,
+}
+
+impl WriteBody for Export {
+    fn write_body(&mut self, res: &mut io::Write) -> io::Result<()> {
+        let result = match self.format {
+            ExportFormat::Csv => self.manager.export_csv(self.feed, &mut *res),
+            ExportFormat::Ndjson => self.manager.export_ndjson(self.feed, &mut *res),
+            ExportFormat::Influx => self.manager.to_influx_lines(self.feed, &mut *res),
+        };
+
+        // (the status is long sent by now, so all we can do is cut it short)
+        result.map_err(|e| {
+            error!("failed to export kind={} feed={:?} error=\"{}\"",
+                   self.manager.kind.compact_name(), self.feed, e);
+            match e {
+                ManagerError::ExportIO(e) => e,
+                e => io::Error::new(io::ErrorKind::Other, e.description()),
+            }
+        })
+    }
+}
+
+/**
+ * Handler for each /api/target/.../export/<feed> endpoint that serves all of
+ * the target's data for the feed as a download in the `format` given in the
+ * query string (CSV if not given).
+ */
+struct ExportHandler {
+    manager: Arc<TargetManager>,
+}
+
+impl Handler for ExportHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let feed = try!(feed_param(req));
+        let format = match query_param(req, "format") {
+            Some(name) => try!(
+                ExportFormat::from_name(&name)
+                .ok_or_else(|| {
+                    warn!("unknown export format format={}", name);
+                    IronError::new(SPWebError::BadRequest, status::BadRequest)
+                })
+            ),
+            None => ExportFormat::Csv,
+        };
+        info!("export requested kind={} feed={:?} format={:?}",
+              self.manager.kind.compact_name(), feed, format);
+
+        let h = Header(ContentType(format.content_type().parse().unwrap()));
+        let mut res = Response::with((status::Ok, h));
+        res.body = Some(Box::new(Export {
+            manager: self.manager.clone(),
+            feed: feed,
+            format: format,
+        }));
+        Ok(res)
+    }
+}
+
+/**
+ * Handler for each /api/target/.../import/<feed> endpoint that imports data
+ * for the feed `POST`ed as CSV in the format exported (see
+ * `TargetManager::import_csv()`), sorting the rows by time first if `sort` is
+ * set in the query string, and responds with the number of records imported.
+ */
+struct ImportHandler {
+    manager: Arc<TargetManager>,
+}
+
+impl Handler for ImportHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let feed = try!(feed_param(req));
+        let sort = query_param(req, "sort").map_or(false, |s| s == "true" || s == "1");
+        info!("import requested kind={} feed={:?} sort={}", self.manager.kind.compact_name(), feed, sort);
+
+        let records = try!(
+            self.manager.import_csv(feed, io::BufReader::new(&mut req.body), sort)
+            .map_err(|e| match e {
+                // (telling the client which row was refused and why)
+                ManagerError::InvalidImport(..) => {
+                    warn!("refused import kind={} error=\"{}\"", self.manager.kind.compact_name(), e);
+                    IronError::new(SPWebError::BadRequest, (status::BadRequest, e.to_string()))
+                },
+                e => server_error(&self.manager, "import", e),
+            })
+        );
+        Ok(Response::with((format!("{}", records), status::Ok)))
+    }
+}
+
+/**
+ * Handler for each /api/target/.../data/<feed> endpoint that, on `DELETE`s,
+ * clears all of the target's data for the feed, or only that of the addr with
+ * the `index` given in the query string (as given in exports), leaving its
+ * index and options as they are.
+ */
+struct ClearHandler {
+    manager: Arc<TargetManager>,
+}
+
+impl Handler for ClearHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let feed = try!(feed_param(req));
+        let index = match query_param(req, "index") {
+            Some(i) => Some(AddrId::from(try!(i.parse::<u32>().map_err(|_| {
+                warn!("invalid query parameter name=index value={}", i);
+                IronError::new(SPWebError::BadRequest, status::BadRequest)
+            })))),
+            None => None,
+        };
+        info!("clear requested kind={} feed={:?} index={:?}", self.manager.kind.compact_name(), feed, index);
+
+        try!(
+            match index {
+                Some(index) => self.manager.clear_index(feed, index),
+                None => self.manager.clear_feed(feed),
+            }
+            .map_err(|e| server_error(&self.manager, "clear data", e))
+        );
+        Ok(Response::with(status::Ok))
+    }
+}
+
+/**
+ * Handler for each /api/target/.../stats endpoint that serves statistics
+ * about the target's data file for each feed (see `TargetManager::stats()`)
+ * as JSON, for status pages and for deciding when to prune.
+ */
+struct StatsHandler {
+    manager: Arc<TargetManager>,
+}
+
+impl Handler for StatsHandler {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        let stats = try!(
+            self.manager.stats()
+            .map_err(|e| server_error(&self.manager, "get stats", e))
+        );
+
+        let h = Header(ContentType::json());
+        let body = Json::Array(stats.iter().map(|s| s.to_json()).collect());
+        Ok(Response::with((status::Ok, h, body.to_string())))
+    }
+}
+
 /**
  * Creates and starts the web server given the configuration (with the web
  * port) and a list of target managers.
@@ -233,7 +519,7 @@ pub fn web_server<'a, T>(configuration: Arc<RwLock<MainConfiguration>>,
      * serve the websockets port at /api/config/ws_port so that clients know
      * how to connect to the websockets server
      */
-    let ws_port_str = format!("{}", configuration.read().unwrap().ws_port);
+    let ws_port_str = format!("{}", recover(configuration.read()).ws_port);
     let ws_port_handler = move |_: &mut Request| -> IronResult<Response> {
         Ok(Response::with((status::Ok, ws_port_str.as_str())))
     };
@@ -244,6 +530,24 @@ pub fn web_server<'a, T>(configuration: Arc<RwLock<MainConfiguration>>,
         router.any(format!("/api/target/{}", tm.kind.compact_name()),
                    TargetHandler::new(tm.clone()),
                    format!("target_{}", tm.kind.compact_name()));
+        router.get(format!("/api/target/{}/metrics", tm.kind.compact_name()),
+                   MetricsHandler { manager: tm.clone() },
+                   format!("target_{}_metrics", tm.kind.compact_name()));
+        router.get(format!("/api/target/{}/stats", tm.kind.compact_name()),
+                   StatsHandler { manager: tm.clone() },
+                   format!("target_{}_stats", tm.kind.compact_name()));
+        router.get(format!("/api/target/{}/query/:feed", tm.kind.compact_name()),
+                   QueryHandler { manager: tm.clone() },
+                   format!("target_{}_query", tm.kind.compact_name()));
+        router.get(format!("/api/target/{}/export/:feed", tm.kind.compact_name()),
+                   ExportHandler { manager: tm.clone() },
+                   format!("target_{}_export", tm.kind.compact_name()));
+        router.post(format!("/api/target/{}/import/:feed", tm.kind.compact_name()),
+                    ImportHandler { manager: tm.clone() },
+                    format!("target_{}_import", tm.kind.compact_name()));
+        router.delete(format!("/api/target/{}/data/:feed", tm.kind.compact_name()),
+                      ClearHandler { manager: tm.clone() },
+                      format!("target_{}_clear", tm.kind.compact_name()));
     }
 
     let mut mount = Mount::new();
@@ -255,9 +559,9 @@ pub fn web_server<'a, T>(configuration: Arc<RwLock<MainConfiguration>>,
     let iron = Iron::new(mount);
 
     // actually spawn the Iron web server in a new thread
-    let web_port = configuration.read().unwrap().web_port;
+    let web_port = recover(configuration.read()).web_port;
     thread::spawn(move || {
-        println!("Web server listening on port {}.", web_port);
+        info!("web server listening port={}", web_port);
         iron.http(("0.0.0.0", web_port)).unwrap();
     })
 }
@@ -0,0 +1,991 @@
+/*
+ * Copyright 2016 icasdri
+ *
+ * This file is part of stabping. The original source code for stabping can be
+ * found at <https://github.com/icasdri/stabping>. See COPYING for licensing
+ * details.
+ */
+
+/*!
+ * The data-collection control loop shared by the workers of all target kinds.
+ */
+use std::cmp;
+use std::thread;
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use std::time::Duration;
+
+use clock::Clock;
+use helpers::{split_millis, time_words, recover};
+use options::{SENTINEL_ERROR, SENTINEL_NODATA};
+use options::{TargetResults, TargetOptions};
+use persist::{TargetManager, AddrId};
+
+// time (in millis) given to probers past their timeouts to send back results
+static COLLECT_SLACK: u64 = 50;
+
+// longest time (in millis) a stopped worker sleeps before noticing
+static STOP_CHECK_INTERVAL: u64 = 100;
+
+/**
+ * An instruction for a prober to measure its address once, for the given
+ * round of data collection.
+ */
+#[derive(Clone, Copy)]
+struct Tick {
+    round: u64,
+    avg_across: u32,
+    pause: Duration,
+    timeout: Duration,
+    attempts: u32,
+    backoff: Duration,
+    // how long into the round to wait before measuring (see `phase()`)
+    delay: Duration,
+}
+
+/**
+ * The control thread's handle on a persistent per-addr prober thread. The
+ * prober exits once this handle (and thus the sending end of its ticks) is
+ * dropped.
+ */
+struct Prober {
+    ticks: Sender<Tick>,
+    results: Receiver<(u64, i64, i32)>,
+    // when (in nanoseconds) and in which round the prober was last ticked
+    last_ticked: Option<(u64, u64)>,
+    // number of times the prober has retried a failed attempt (for diagnostics)
+    retries: Arc<AtomicUsize>,
+}
+
+/**
+ * A counting semaphore bounding how many measurements the probers of a pool
+ * take at once (if at all), so that a round over many addresses doesn't open
+ * all their connections (and file descriptors) at the same time.
+ */
+struct Limiter {
+    // the number of measurements being taken, and the most allowed (if limited)
+    state: Mutex<(usize, Option<usize>)>,
+    freed: Condvar,
+}
+
+/**
+ * A measurement's permission from a `Limiter` to go ahead, given back when
+ * dropped.
+ */
+struct Permit<'a> {
+    limiter: &'a Limiter,
+}
+
+impl Limiter {
+    fn new() -> Self {
+        Limiter {
+            state: Mutex::new((0, None)),
+            freed: Condvar::new(),
+        }
+    }
+
+    /**
+     * Sets the most measurements allowed at once, or lifts the limit.
+     */
+    fn set_limit(&self, limit: Option<usize>) {
+        recover(self.state.lock()).1 = limit;
+        self.freed.notify_all();
+    }
+
+    /**
+     * Waits until another measurement is allowed, and takes it.
+     */
+    fn acquire(&self) -> Permit {
+        let mut state = recover(self.state.lock());
+        while state.1.map_or(false, |limit| state.0 >= limit) {
+            state = recover(self.freed.wait(state));
+        }
+        state.0 += 1;
+        Permit { limiter: self }
+    }
+}
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        recover(self.limiter.state.lock()).0 -= 1;
+        self.limiter.freed.notify_one();
+    }
+}
+
+/**
+ * Takes a single measurement of the address, retrying a failed attempt (up to
+ * the tick's number of attempts in all) after a backoff that doubles with
+ * each retry, and counting each retry in `retries`.
+ */
+fn measure<P>(probe: &P, addr: &str, tick: &Tick, retries: &AtomicUsize) -> Option<i32>
+              where P: Fn(&str, Duration) -> Option<i32> {
+    let mut backoff = tick.backoff;
+    for attempt in 0..tick.attempts {
+        if attempt > 0 {
+            retries.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(backoff);
+            backoff = backoff * 2;
+        }
+        if let Some(val) = probe(addr, tick.timeout) {
+            return Some(val);
+        }
+    }
+    None
+}
+
+/**
+ * The body of a prober thread: measures the address (averaging across the
+ * given number of attempts) each time it receives a tick, and sends back the
+ * result tagged with the tick's round and the time (in milliseconds from
+ * epoch, by the given clock) it was measured at.
+ */
+fn prober_loop<P>(addr: String, probe: Arc<P>, retries: Arc<AtomicUsize>, limiter: Arc<Limiter>,
+                  clock: Arc<Clock>, ticks: Receiver<Tick>, results: Sender<(u64, i64, i32)>)
+                  where P: Fn(&str, Duration) -> Option<i32> {
+    while let Ok(mut tick) = ticks.recv() {
+        /*
+         * if we fell behind (e.g. the address took far too long to measure),
+         * skip straight to the most recent tick rather than working through a
+         * backlog of rounds the control thread has already given up on
+         */
+        while let Ok(t) = ticks.try_recv() {
+            tick = t;
+        }
+        thread::sleep(tick.delay);
+
+        let mut sum: i64 = 0;
+        let mut denom: i64 = 0;
+        // average the results across the given number of times
+        for _ in 0..tick.avg_across {
+            let measured = {
+                let _permit = limiter.acquire();
+                measure(&*probe, addr.as_str(), &tick, &retries)
+            };
+            if let Some(val) = measured {
+                sum += val as i64;
+                denom += 1;
+            }
+            thread::sleep(tick.pause);
+        }
+
+        let time = clock.now();
+        if denom != 0 && results.send((tick.round, time, (sum / denom) as i32)).is_err() {
+            // the control thread has gone away
+            break;
+        }
+    }
+}
+
+/**
+ * A set of persistent prober threads, one for each distinct address being
+ * measured, so that data collection does not spawn new threads every round.
+ */
+struct ProberPool<P> {
+    probe: Arc<P>,
+    probers: HashMap<String, Prober>,
+    limiter: Arc<Limiter>,
+    clock: Arc<Clock>,
+}
+
+impl<P> ProberPool<P> where P: Fn(&str, Duration) -> Option<i32> + Send + Sync + 'static {
+    /**
+     * Creates an empty pool whose probers measure with the given `probe`,
+     * telling the time (both when addresses are due, and when measurements
+     * were taken) by the given clock.
+     */
+    fn new(probe: Arc<P>, clock: Arc<Clock>) -> Self {
+        ProberPool {
+            probe: probe,
+            probers: HashMap::new(),
+            limiter: Arc::new(Limiter::new()),
+            clock: clock,
+        }
+    }
+
+    /**
+     * Sets the most measurements the probers may take at once, or lifts the
+     * limit (as it is to begin with). Measurements over the limit wait their
+     * turn, so too low a limit has them come back too late to be collected.
+     */
+    fn limit_concurrency(&self, limit: Option<u32>) {
+        self.limiter.set_limit(limit.map(|l| l as usize));
+    }
+
+    /**
+     * Tells the probers of all the given addresses that are due (i.e. whose
+     * given interval, in millis, has passed since they were last told) to
+     * measure, each after its given phase (in millis) into the round,
+     * spawning probers for new addresses and retiring those of addresses that
+     * are no longer given.
+     */
+    fn tick(&mut self, addrs: &[String], intervals: &[u32], phases: &[u64], tick: Tick) {
+        self.probers.retain(|addr, _| addrs.contains(addr));
+
+        let now = self.clock.monotonic();
+        for ((addr, &interval), &phase) in addrs.iter().zip(intervals.iter()).zip(phases.iter()) {
+            if !self.probers.contains_key(addr) {
+                let (ticks_tx, ticks_rx) = channel();
+                let (results_tx, results_rx) = channel();
+                let retries = Arc::new(AtomicUsize::new(0));
+                let a = addr.clone();
+                let p = self.probe.clone();
+                let r = retries.clone();
+                let l = self.limiter.clone();
+                let c = self.clock.clone();
+                thread::spawn(move || prober_loop(a, p, r, l, c, ticks_rx, results_tx));
+                self.probers.insert(addr.clone(), Prober {
+                    ticks: ticks_tx,
+                    results: results_rx,
+                    last_ticked: None,
+                    retries: retries,
+                });
+            }
+
+            let prober = self.probers.get_mut(addr).unwrap();
+            let due = match prober.last_ticked {
+                Some((t, _)) => now - t >= interval as u64 * 1_000_000,
+                None => true,
+            };
+            if due {
+                prober.last_ticked = Some((now, tick.round));
+                let _ = prober.ticks.send(Tick { delay: Duration::from_millis(phase), ..tick });
+            }
+        }
+    }
+
+    /**
+     * Collects the results of the given round (started at the given
+     * timestamp) for the given addresses (in order), using a sentinel value
+     * for those that haven't come back (or that weren't due to be measured
+     * this round). Gives the results along with the times (in milliseconds
+     * from epoch) they were measured at (the round's timestamp for sentinel
+     * values).
+     */
+    fn collect(&mut self, addrs: &[String], round: u64, timestamp: i64) -> (Vec<i32>, Vec<i64>) {
+        let mut latest = HashMap::new();
+        for (addr, prober) in self.probers.iter() {
+            // discard any late results from previous rounds
+            while let Ok((r, time, val)) = prober.results.try_recv() {
+                if r == round {
+                    latest.insert(addr.as_str(), (time, val));
+                }
+            }
+
+            if prober.last_ticked.map(|(_, r)| r) != Some(round) {
+                latest.insert(addr.as_str(), (timestamp, SENTINEL_NODATA));
+            }
+        }
+
+        addrs.iter()
+             .map(|a| latest.get(a.as_str()).cloned().unwrap_or((timestamp, SENTINEL_ERROR)))
+             .map(|(time, val)| (val, time))
+             .unzip()
+    }
+
+    /**
+     * Gets the number of times the prober of the given address has retried a
+     * failed attempt (see `measure()`).
+     */
+    fn retries(&self, addr: &str) -> usize {
+        self.probers.get(addr).map(|p| p.retries.load(Ordering::SeqCst)).unwrap_or(0)
+    }
+}
+
+/**
+ * Gets the time each attempt at measuring an address may take (in millis),
+ * given the interval (in millis) of the rounds it is measured in and the
+ * target's options.
+ *
+ * Each attempt (and retry) gets an equal share of the round (less the pauses
+ * between attempts and the backoffs before retries) so that all attempts for
+ * an address are guaranteed to finish before the round is over, and an
+ * unresponsive host can't hold up its prober into the next round. The
+ * target's own timeout (if it has one) may cut this shorter.
+ */
+fn attempt_timeout(interval: u32, opt: &TargetOptions) -> u64 {
+    let attempts = opt.retry_attempts.unwrap_or(1) as u64;
+    let budget = (interval as u64)
+        .saturating_sub(spread_window(interval, opt))
+        .saturating_sub((opt.pause as u64 + backoffs(opt)) * opt.avg_across as u64);
+    let share = cmp::max(budget / cmp::max(opt.avg_across as u64 * attempts, 1), 1);
+    match opt.timeout {
+        Some(t) => cmp::min(t as u64, share),
+        None => share,
+    }
+}
+
+/**
+ * Gets the total time (in millis) spent backing off before retries in a
+ * single measurement that needs all its attempts.
+ */
+fn backoffs(opt: &TargetOptions) -> u64 {
+    let attempts = opt.retry_attempts.unwrap_or(1) as u64;
+    opt.retry_backoff.unwrap_or(0) as u64 * ((1 << (attempts - 1)) - 1)
+}
+
+/**
+ * Gets the stretch (in millis) at the start of a round of the given interval
+ * (in millis) over which the addresses' measurements are spread out, if the
+ * target's options have them be (see `phase()`): the first half of it.
+ */
+fn spread_window(interval: u32, opt: &TargetOptions) -> u64 {
+    match opt.spread_measurements {
+        Some(true) => interval as u64 / 2,
+        _ => 0,
+    }
+}
+
+/**
+ * Gets how long (in millis) into a round of the given interval (in millis)
+ * the address with the given index starts being measured, given the target's
+ * options, so that not every address is measured at the same moment.
+ *
+ * Each index is given its own fixed phase within the spread window (see
+ * `spread_window()`), at multiples of the golden ratio (modulo 1) of it, which
+ * keep any number of consecutive indices about evenly apart. As the phase
+ * never changes, each address is still measured a whole interval apart.
+ */
+fn phase(index: AddrId, interval: u32, opt: &TargetOptions) -> u64 {
+    let fraction = (u32::from(index) as f64 * 0.618_033_988_749_895) % 1.0;
+    (fraction * spread_window(interval, opt) as f64) as u64
+}
+
+/**
+ * Gets how long (in millis) into a round of the given interval (in millis)
+ * its results are collected, given the target's options.
+ *
+ * Without a timeout, the probers have the whole round to come back. With one,
+ * they can take no longer than their attempts' timeouts (plus pauses and
+ * backoffs, and their phase), so the round's results are collected as soon as that has passed
+ * (with some slack), rather than waiting out the rest of the round.
+ */
+fn collect_after(interval: u32, opt: &TargetOptions) -> u64 {
+    if opt.timeout.is_none() {
+        return interval as u64;
+    }
+
+    let attempts = opt.retry_attempts.unwrap_or(1) as u64;
+    let per_measurement = opt.pause as u64 + backoffs(opt) + attempts * attempt_timeout(interval, opt);
+    cmp::min(interval as u64,
+             spread_window(interval, opt) + opt.avg_across as u64 * per_measurement + COLLECT_SLACK)
+}
+
+/**
+ * A handle on a running data-collection worker, through which it can be
+ * stopped.
+ */
+pub struct Worker {
+    handle: thread::JoinHandle<()>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Worker {
+    /**
+     * Signals the worker to stop collecting data. The worker finishes the
+     * round it is currently collecting (sending off its results) before
+     * actually stopping.
+     */
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /**
+     * Waits for the worker to stop (see `stop()`).
+     */
+    pub fn join(self) -> thread::Result<()> {
+        self.handle.join()
+    }
+}
+
+/**
+ * Sleeps for the given duration, or until the given flag is set (see
+ * `Worker::stop()`), whichever comes first, so that stopping a worker doesn't
+ * wait out the whole of its sleep.
+ */
+fn sleep_unless_stopped(dur: Duration, stopped: &AtomicBool) {
+    let check = Duration::from_millis(STOP_CHECK_INTERVAL);
+    let mut left = dur;
+    while left > Duration::from_millis(0) && !stopped.load(Ordering::SeqCst) {
+        let step = cmp::min(left, check);
+        thread::sleep(step);
+        left = left - step;
+    }
+}
+
+/**
+ * Starts a worker that calls `f` right away, and then again every `period`
+ * until it's stopped (finishing the call it's in, if any).
+ */
+pub fn run_periodically<F>(period: Duration, mut f: F) -> Worker
+                           where F: FnMut() + Send + 'static {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let worker_stopped = stopped.clone();
+
+    let handle = thread::spawn(move || {
+        loop {
+            f();
+            if worker_stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            sleep_unless_stopped(period, &worker_stopped);
+        }
+    });
+
+    Worker {
+        handle: handle,
+        stopped: stopped,
+    }
+}
+
+/**
+ * How a results channel (see `results_channel()`) behaves once it's full.
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Backpressure {
+    // block the sending worker until there's room (slowing down collection)
+    Block,
+    // drop the oldest results waiting in the channel to make room
+    DropOldest,
+}
+
+struct ResultsState {
+    queue: VecDeque<TargetResults>,
+    senders: usize,
+    receiving: bool,
+}
+
+struct ResultsShared {
+    state: Mutex<ResultsState>,
+    changed: Condvar,
+    capacity: usize,
+    backpressure: Backpressure,
+}
+
+/**
+ * The sending end of a results channel (see `results_channel()`), one clone
+ * of which is given to each worker.
+ */
+pub struct ResultsSender {
+    shared: Arc<ResultsShared>,
+}
+
+/**
+ * The receiving end of a results channel (see `results_channel()`), which
+ * iterates over results as they come in, until all senders are gone.
+ */
+pub struct ResultsReceiver {
+    shared: Arc<ResultsShared>,
+}
+
+/**
+ * Creates a channel for workers to send their results through, holding at
+ * most `capacity` results that have yet to be received, so that a stalled
+ * receiver can't have results pile up without limit. Once full, sending
+ * behaves according to the given `Backpressure`.
+ */
+pub fn results_channel(capacity: usize, backpressure: Backpressure) -> (ResultsSender, ResultsReceiver) {
+    let shared = Arc::new(ResultsShared {
+        state: Mutex::new(ResultsState {
+            queue: VecDeque::new(),
+            senders: 1,
+            receiving: true,
+        }),
+        changed: Condvar::new(),
+        capacity: cmp::max(capacity, 1),
+        backpressure: backpressure,
+    });
+    (ResultsSender { shared: shared.clone() }, ResultsReceiver { shared: shared })
+}
+
+impl ResultsSender {
+    /**
+     * Sends the given results, giving them back if the receiver is gone.
+     */
+    pub fn send(&self, res: TargetResults) -> Result<(), TargetResults> {
+        let mut state = recover(self.shared.state.lock());
+        while state.receiving && state.queue.len() >= self.shared.capacity {
+            match self.shared.backpressure {
+                Backpressure::Block => state = recover(self.shared.changed.wait(state)),
+                Backpressure::DropOldest => {
+                    if let Some(dropped) = state.queue.pop_front() {
+                        warn!("results channel full, dropping oldest results kind={} nonce={}",
+                              dropped.0[0], dropped.0[1]);
+                    }
+                }
+            }
+        }
+        if !state.receiving {
+            return Err(res);
+        }
+        state.queue.push_back(res);
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+}
+
+impl Clone for ResultsSender {
+    fn clone(&self) -> Self {
+        recover(self.shared.state.lock()).senders += 1;
+        ResultsSender { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for ResultsSender {
+    fn drop(&mut self) {
+        recover(self.shared.state.lock()).senders -= 1;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl ResultsReceiver {
+    /**
+     * Waits for the next results, or `None` once all senders are gone (and
+     * every result sent has been received).
+     */
+    pub fn recv(&self) -> Option<TargetResults> {
+        let mut state = recover(self.shared.state.lock());
+        loop {
+            if let Some(res) = state.queue.pop_front() {
+                self.shared.changed.notify_all();
+                return Some(res);
+            }
+            if state.senders == 0 {
+                return None;
+            }
+            state = recover(self.shared.changed.wait(state));
+        }
+    }
+}
+
+impl Iterator for ResultsReceiver {
+    type Item = TargetResults;
+
+    fn next(&mut self) -> Option<TargetResults> {
+        self.recv()
+    }
+}
+
+impl Drop for ResultsReceiver {
+    fn drop(&mut self) {
+        recover(self.shared.state.lock()).receiving = false;
+        self.shared.changed.notify_all();
+    }
+}
+
+/**
+ * Runs a target's data-collection worker, using the given `probe` to take a
+ * single measurement of an address.
+ *
+ * `probe` is given the address and the time it may take before giving up, and
+ * returns the measured value, or `None` if the measurement failed.
+ */
+pub fn run_worker<P>(manager: Arc<TargetManager>,
+                     results_out: ResultsSender,
+                     probe: P) -> Worker
+                     where P: Fn(&str, Duration) -> Option<i32> + Send + Sync + 'static {
+    let probe = Arc::new(probe);
+    let stopped = Arc::new(AtomicBool::new(false));
+    let worker_stopped = stopped.clone();
+
+    // start a new thread for the worker
+    let handle = thread::spawn(move || {
+        let clock = manager.clock();
+        let mut pool = ProberPool::new(probe, clock.clone());
+        let mut round: u64 = 0;
+
+        // continue to collect data until we're told to stop
+        while !worker_stopped.load(Ordering::SeqCst) {
+            round = round.wrapping_add(1);
+
+            // retrieve the target's current options (once, for the whole round)
+            let ref opt = manager.options_snapshot();
+            let (nonce, addrs, intervals, phases, dur_interval, dur_collect, tick, concurrency) = {
+                /*
+                 * rounds happen as often as the most frequently measured
+                 * address needs, with each address only measured in the
+                 * rounds it is due
+                 */
+                let intervals: Vec<u32> = opt.addrs.iter().map(|a| opt.addr_interval(a)).collect();
+                let interval = intervals.iter().cloned().min().unwrap_or(opt.interval);
+
+                let timeout = attempt_timeout(interval, opt);
+                let phases: Vec<u64> = opt.addrs.iter()
+                    .map(|a| manager.addr_index(a).map_or(0, |i| phase(i, interval, opt)))
+                    .collect();
+
+                (
+                    opt.nonce,
+                    opt.addrs.clone(),
+                    intervals,
+                    phases,
+                    Duration::from_millis(interval as u64),
+                    Duration::from_millis(collect_after(interval, opt)),
+                    Tick {
+                        round: round,
+                        avg_across: opt.avg_across,
+                        pause: Duration::from_millis(opt.pause as u64),
+                        timeout: Duration::from_millis(timeout),
+                        attempts: opt.retry_attempts.unwrap_or(1),
+                        backoff: Duration::from_millis(opt.retry_backoff.unwrap_or(0) as u64),
+                        delay: Duration::from_millis(0),
+                    },
+                    opt.max_concurrent_probes,
+                )
+            };
+
+            // get the current time (to timestamp this round of data with)
+            let timestamp = clock.now();
+
+            /*
+             * during a maintenance window, measure (and record) nothing at
+             * all, so that the window isn't mistaken for an outage
+             */
+            if opt.in_maintenance(split_millis(timestamp).0) {
+                sleep_unless_stopped(dur_interval, &worker_stopped);
+                continue;
+            }
+
+            // have the (due) per-addr probers go measure their addresses
+            pool.limit_concurrency(concurrency);
+            pool.tick(&addrs, &intervals, &phases, tick);
+
+            /*
+             * give the per-addr probers until they've either come back or
+             * timed out (at most the entire interval) before collecting
+             */
+            thread::sleep(dur_collect);
+
+            let mut data: Vec<i32> = Vec::with_capacity(4 + addrs.len());
+
+            data.push(manager.kind.kind_id());
+            data.push(nonce);
+            data.extend_from_slice(&time_words(split_millis(timestamp).0));
+
+            /*
+             * read back the data from the per-addr probers (with sentinel
+             * values for those that errored or timed out, or weren't due)
+             */
+            let (vals, times) = pool.collect(&addrs, round, timestamp);
+            data.extend(vals);
+            for addr in addrs.iter() {
+                manager.record_retries(addr, pool.retries(addr));
+            }
+
+            // send off our results to the main thread
+            if results_out.send(TargetResults(data, times)).is_err() {
+                warn!("failed to send results back kind={}", manager.kind.compact_name());
+            }
+
+            // wait out the rest of the designated data-collection interval
+            sleep_unless_stopped(dur_interval - dur_collect, &worker_stopped);
+        }
+
+        /*
+         * the probers are retired when the pool is dropped here, as dropping
+         * their handles hangs up on them
+         */
+    });
+
+    Worker {
+        handle: handle,
+        stopped: stopped,
+    }
+}
+
+/**
+ * Makes a tick for the given round that measures once, straight away, with a
+ * timeout of a millisecond, for use in tests.
+ */
+#[cfg(test)]
+fn tick(round: u64) -> Tick {
+    Tick {
+        round: round,
+        avg_across: 1,
+        pause: Duration::from_millis(0),
+        timeout: Duration::from_millis(1),
+        attempts: 1,
+        backoff: Duration::from_millis(0),
+        delay: Duration::from_millis(0),
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn prober_pool_threads_stay_bounded() {
+    use std::fs::File;
+    use std::io::Read;
+    use clock::SystemClock;
+
+    fn live_threads() -> usize {
+        let mut status = String::new();
+        File::open("/proc/self/status").unwrap().read_to_string(&mut status).unwrap();
+        status.lines()
+              .find(|l| l.starts_with("Threads:"))
+              .and_then(|l| l["Threads:".len()..].trim().parse().ok())
+              .unwrap()
+    }
+
+    let addrs: Vec<String> = (0..5).map(|i| format!("addr{}", i)).collect();
+    let mut pool = ProberPool::new(Arc::new(|a: &str, _: Duration| Some(a.len() as i32)), Arc::new(SystemClock));
+    let before = live_threads();
+
+    let intervals = vec![0; addrs.len()];
+    for round in 0..100 {
+        pool.tick(&addrs, &intervals, &vec![0; addrs.len()], tick(round));
+        thread::sleep(Duration::from_millis(2));
+        pool.collect(&addrs, round, 0);
+    }
+
+    assert_eq!(pool.probers.len(), addrs.len());
+    assert!(live_threads() <= before + addrs.len());
+}
+
+#[test]
+fn prober_pool_limits_concurrent_measurements() {
+    use clock::SystemClock;
+
+    // the number of measurements running, and the most that ever were at once
+    let running = Arc::new(Mutex::new((0, 0)));
+    let r = running.clone();
+    let mut pool = ProberPool::new(Arc::new(move |a: &str, _: Duration| {
+        {
+            let mut r = r.lock().unwrap();
+            r.0 += 1;
+            r.1 = cmp::max(r.0, r.1);
+        }
+        thread::sleep(Duration::from_millis(10));
+        r.lock().unwrap().0 -= 1;
+        Some(a.len() as i32)
+    }), Arc::new(SystemClock));
+    pool.limit_concurrency(Some(3));
+
+    let addrs: Vec<String> = (0..10).map(|i| format!("addr{}", i)).collect();
+    let intervals = vec![0; addrs.len()];
+    let slow = Tick { avg_across: 2, timeout: Duration::from_millis(10), ..tick(0) };
+    pool.tick(&addrs, &intervals, &vec![0; addrs.len()], slow);
+    thread::sleep(Duration::from_millis(200));
+
+    // everything is still measured in the round, just not all at once
+    assert_eq!(pool.collect(&addrs, 0, 0).0, vec![5; addrs.len()]);
+    assert_eq!(*running.lock().unwrap(), (0, 3));
+}
+
+#[test]
+fn prober_pool_honors_addr_intervals() {
+    use clock::SystemClock;
+
+    let addrs: Vec<String> = vec!["fast".to_owned(), "slow".to_owned()];
+    let intervals = vec![0, 60_000];
+    let mut pool = ProberPool::new(Arc::new(|a: &str, _: Duration| Some(a.len() as i32)), Arc::new(SystemClock));
+
+    for round in 0..3 {
+        pool.tick(&addrs, &intervals, &vec![0; addrs.len()], tick(round));
+        thread::sleep(Duration::from_millis(20));
+
+        // the slow addr is only due in the first round
+        let slow = if round == 0 { 4 } else { SENTINEL_NODATA };
+        assert_eq!(pool.collect(&addrs, round, 0).0, vec![4, slow]);
+    }
+}
+
+#[test]
+fn prober_pool_due_times_follow_its_clock() {
+    use clock::ManualClock;
+
+    let clock = Arc::new(ManualClock::new(1_000_000));
+    let addrs: Vec<String> = vec!["a".to_owned()];
+    let mut pool = ProberPool::new(Arc::new(|a: &str, _: Duration| Some(a.len() as i32)), clock.clone());
+
+    // due in the first round, then not until a full interval has passed
+    for (round, &(advance, due)) in [(0, true), (999, false), (1, true), (500, false)].iter().enumerate() {
+        clock.advance(Duration::from_millis(advance));
+        pool.tick(&addrs, &[1000], &[0], tick(round as u64));
+        thread::sleep(Duration::from_millis(20));
+
+        // and measurements are timed by the clock too
+        let (vals, times) = pool.collect(&addrs, round as u64, 0);
+        if due {
+            assert_eq!((vals, times), (vec![1], vec![clock.now()]));
+        } else {
+            assert_eq!(vals, vec![SENTINEL_NODATA]);
+        }
+    }
+}
+
+#[test]
+fn failed_attempts_are_retried() {
+    use std::sync::Mutex;
+    use clock::SystemClock;
+
+    // fails every other attempt
+    let calls = Mutex::new(0);
+    let probe = move |_: &str, _: Duration| {
+        let mut c = calls.lock().unwrap();
+        *c += 1;
+        if *c % 2 == 0 { Some(*c) } else { None }
+    };
+
+    let addrs: Vec<String> = vec!["flaky".to_owned()];
+    let mut pool = ProberPool::new(Arc::new(probe), Arc::new(SystemClock));
+    for round in 0..2 {
+        let retrying = Tick { attempts: 2 - round as u32, backoff: Duration::from_millis(1), ..tick(round) };
+        pool.tick(&addrs, &[0], &[0], retrying);
+        thread::sleep(Duration::from_millis(20));
+
+        // the retry succeeds, while a single attempt fails outright
+        let expected = if round == 0 { 2 } else { SENTINEL_ERROR };
+        assert_eq!(pool.collect(&addrs, round, 0).0, vec![expected]);
+    }
+    assert_eq!(pool.retries("flaky"), 1);
+}
+
+#[test]
+fn attempt_timeout_leaves_room_for_retries() {
+    use options::TargetKind;
+
+    let mut opt = TargetKind::TcpPing.default_options();
+    opt.avg_across = 2;
+    opt.pause = 100;
+    assert_eq!(attempt_timeout(1000, &opt), 400);
+
+    // three attempts each, with 50 + 100 millis of backoff between them
+    opt.retry_attempts = Some(3);
+    opt.retry_backoff = Some(50);
+    assert_eq!(attempt_timeout(1100, &opt), 100);
+}
+
+#[test]
+fn timeout_bounds_attempts_and_collection() {
+    use options::TargetKind;
+
+    let mut opt = TargetKind::TcpPing.default_options();
+    opt.avg_across = 2;
+    opt.pause = 100;
+    assert_eq!(collect_after(60_000, &opt), 60_000);
+
+    // results are collected once both attempts have had their time
+    opt.timeout = Some(1000);
+    assert_eq!(attempt_timeout(60_000, &opt), 1000);
+    assert_eq!(collect_after(60_000, &opt), 2 * 1100 + COLLECT_SLACK);
+
+    // but never later than the end of the round
+    assert_eq!(attempt_timeout(1000, &opt), 400);
+    assert_eq!(collect_after(1000, &opt), 1000);
+}
+
+#[test]
+fn full_results_channel_drops_oldest_if_asked() {
+    let (tx, rx) = results_channel(2, Backpressure::DropOldest);
+    for t in 0..5 {
+        tx.send(TargetResults(vec![0, 1, t, 0], Vec::new())).unwrap();
+    }
+    drop(tx);
+
+    let times: Vec<i32> = rx.map(|r| r.0[2]).collect();
+    assert_eq!(times, vec![3, 4]);
+}
+
+#[test]
+fn full_results_channel_blocks_sender_by_default() {
+    let (tx, rx) = results_channel(2, Backpressure::Block);
+    let sent = Arc::new(AtomicUsize::new(0));
+    let sender_sent = sent.clone();
+    let sender = thread::spawn(move || {
+        for t in 0..3 {
+            tx.send(TargetResults(vec![0, 1, t, 0], Vec::new())).unwrap();
+            sender_sent.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+
+    // the third send waits until there's room
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(sent.load(Ordering::SeqCst), 2);
+    assert_eq!(rx.recv().unwrap().0[2], 0);
+    sender.join().unwrap();
+    assert_eq!(sent.load(Ordering::SeqCst), 3);
+
+    // nothing was dropped, and the channel ends with its sender
+    let times: Vec<i32> = rx.map(|r| r.0[2]).collect();
+    assert_eq!(times, vec![1, 2]);
+}
+
+#[test]
+fn results_sent_without_a_receiver_are_given_back() {
+    let (tx, rx) = results_channel(1, Backpressure::Block);
+    tx.send(TargetResults(vec![0, 1, 0, 0], Vec::new())).unwrap();
+    drop(rx);
+    assert!(tx.send(TargetResults(vec![0, 1, 1, 0], Vec::new())).is_err());
+}
+
+#[test]
+fn nothing_is_recorded_during_maintenance() {
+    use std::i64;
+    use options::{MaintenanceWindow, MIN_INTERVAL};
+    use persist::{test_manager, TEST_KIND};
+
+    let (_, tm) = test_manager("nothing_is_recorded_during_maintenance");
+    let tm = Arc::new(tm);
+
+    let run_for = |tm: &Arc<TargetManager>, maintenance: Option<Vec<MaintenanceWindow>>| {
+        let mut options = TEST_KIND.default_options();
+        options.interval = MIN_INTERVAL;
+        options.maintenance = maintenance;
+        tm.options_update(options).unwrap();
+
+        let (tx, rx) = results_channel(16, Backpressure::Block);
+        let worker = run_worker(tm.clone(), tx, |_: &str, _: Duration| Some(1));
+        thread::sleep(Duration::from_millis(3 * MIN_INTERVAL as u64));
+        worker.stop();
+        worker.join().unwrap();
+        rx.count()
+    };
+
+    assert_eq!(run_for(&tm, Some(vec![MaintenanceWindow { start: 0, end: i64::MAX, every: None }])), 0);
+    assert!(run_for(&tm, None) > 0);
+}
+
+#[test]
+fn spread_measurements_start_apart() {
+    use std::time::Instant;
+    use clock::SystemClock;
+    use options::TargetKind;
+
+    let mut opt = TargetKind::TcpPing.default_options();
+    opt.interval = 800;
+    assert!((0..4).all(|i| phase(AddrId::from(i), opt.interval, &opt) == 0));
+    opt.spread_measurements = Some(true);
+    let phases: Vec<u64> = (0..4).map(|i| phase(AddrId::from(i), opt.interval, &opt)).collect();
+    assert!(phases.iter().all(|p| *p < 400));
+
+    let starts = Arc::new(Mutex::new(Vec::new()));
+    let s = starts.clone();
+    let addrs: Vec<String> = (0..4).map(|i| i.to_string()).collect();
+    let mut pool = ProberPool::new(Arc::new(move |_: &str, _: Duration| {
+        s.lock().unwrap().push(Instant::now());
+        Some(1)
+    }), Arc::new(SystemClock));
+    pool.tick(&addrs, &[opt.interval; 4], &phases, Tick { timeout: Duration::from_millis(10), ..tick(0) });
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(pool.collect(&addrs, 0, 0).0, vec![1; 4]);
+
+    // no two addrs were measured at (about) the same moment
+    let mut starts = starts.lock().unwrap().clone();
+    starts.sort();
+    for pair in starts.windows(2) {
+        assert!(pair[1] - pair[0] >= Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn stopping_doesnt_wait_out_the_period() {
+    use std::time::Instant;
+
+    let worker = run_periodically(Duration::from_secs(60 * 60), || ());
+    let start = Instant::now();
+    worker.stop();
+    worker.join().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
@@ -12,7 +12,9 @@ use std::sync::{Arc, Mutex, RwLock};
 use ws;
 use ws::{Settings, Builder};
 
-use options::MainConfiguration;
+use helpers::{VecIntoWireBytes, recover};
+use options::{MainConfiguration, TargetResults};
+use persist::{TargetManager, Feed};
 
 /**
  * Error container for websocket broadcasts.
@@ -40,7 +42,7 @@ impl Broadcaster {
      * Sets/updates the broadcast sender wrapped by this wrapper.
      */
     fn update(&self, new_sender: ws::Sender) {
-        let mut guard = self.sender.lock().unwrap();
+        let mut guard = recover(self.sender.lock());
         *guard = Some(new_sender);
     }
 
@@ -49,7 +51,7 @@ impl Broadcaster {
      * connected websocket clients.
      */
     pub fn send<M>(&self, msg: M) -> Result<(), BroadcastError> where M: Into<ws::Message> {
-        let guard = self.sender.lock().unwrap();
+        let guard = recover(self.sender.lock());
         if let Some(ref b) = *guard {
             b.send(msg).map_err(|e| BroadcastError::WebSocketError(e))
         } else {
@@ -58,9 +60,48 @@ impl Broadcaster {
     }
 }
 
+/**
+ * Frames the given live results of the given feed as a single websocket
+ * binary message, independent of any websocket library.
+ *
+ * The frame is an array of 32-bit integers (little-endian, like everything
+ * else sent over the network) [feed, kind, nonce, time, count, value1,
+ * value2, ...], with *time* (in seconds, shared by all the values) in two
+ * halves, and *count* the number of values that follow, so that a client can
+ * tell feeds apart and check it has the whole frame.
+ */
+pub fn frame_results(feed: Feed, results: &TargetResults) -> Vec<u8> {
+    let vals = results.vals();
+    let mut frame = Vec::with_capacity(6 + vals.len());
+    frame.push(feed.feed_id() as i32);
+    frame.extend_from_slice(&results.0[..4]);
+    frame.push(vals.len() as i32);
+    frame.extend_from_slice(vals);
+    frame.into_wire_bytes()
+}
+
+/**
+ * Subscribes to the given target's live data (see
+ * `TargetManager::subscribe()`), and forwards each set of results appended
+ * from now on, framed (see `frame_results()`), to all connected websocket
+ * clients through the given broadcaster. Should forwarding fall too far
+ * behind and be unsubscribed, it subscribes again, missing what was dropped.
+ */
+pub fn forward_live(manager: &Arc<TargetManager>, broadcaster: Arc<Broadcaster>) -> thread::JoinHandle<()> {
+    let manager = manager.clone();
+    thread::spawn(move || {
+        loop {
+            for results in manager.subscribe() {
+                // no clients (or no socket yet) isn't a problem for live data
+                let _ = broadcaster.send(frame_results(Feed::Raw, &results));
+            }
+        }
+    })
+}
+
 pub fn ws_server(configuration: Arc<RwLock<MainConfiguration>>,
                  broadcaster: Arc<Broadcaster>) -> thread::JoinHandle<()> {
-    let ws_port = configuration.read().unwrap().ws_port;
+    let ws_port = recover(configuration.read()).ws_port;
     thread::spawn(move || {
         loop {
             let socket = {
@@ -77,9 +118,31 @@ pub fn ws_server(configuration: Arc<RwLock<MainConfiguration>>,
                 }).unwrap()
             };
             broadcaster.update(socket.broadcaster());
-            println!("WebSocket server (re)listening on port {}.", ws_port);
+            info!("websocket server (re)listening port={}", ws_port);
             socket.listen(("0.0.0.0", ws_port))
                   .expect("Unable to listen on websocket.");
         }
     })
 }
+
+#[test]
+fn live_results_are_framed_with_their_feed() {
+    use helpers::{VecFromWireBytes, time_words};
+    use options::SENTINEL_ERROR;
+
+    let mut data = vec![0, 7];
+    data.extend_from_slice(&time_words(1_500_000_000));
+    data.extend_from_slice(&[12, SENTINEL_ERROR, 30]);
+    let results = TargetResults(data, Vec::new());
+
+    let frame = frame_results(Feed::Raw, &results);
+    assert_eq!(frame.len(), 4 * 9);
+    let mut expected = vec![Feed::Raw.feed_id() as i32, 0, 7];
+    expected.extend_from_slice(&time_words(1_500_000_000));
+    expected.extend_from_slice(&[3, 12, SENTINEL_ERROR, 30]);
+    assert_eq!(frame.from_wire_bytes(), expected);
+
+    // results without values still make a whole frame
+    let empty = frame_results(Feed::Raw, &TargetResults(vec![0, 7, 100, 0], Vec::new()));
+    assert_eq!(empty.from_wire_bytes(), vec![Feed::Raw.feed_id() as i32, 0, 7, 100, 0, 0]);
+}